@@ -0,0 +1,140 @@
+//! Decoding of many files, or encoding of many variants of an image at once, using all CPU cores.
+//!
+//! Every job is independent, so a broken file (even one that makes the decoder panic)
+//! only fails its own result.
+
+use crate::compress::Compress;
+use crate::decompress::DecompressConfig;
+use crate::error::Error;
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// RGB pixels of a file decoded by `decode_many()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub pixels: Vec<[u8; 3]>,
+    pub width: usize,
+    pub height: usize,
+    /// Corrupt-data warnings, see `DecompressStarted::warnings()`
+    pub warnings: Vec<String>,
+}
+
+/// Result of one quality of `encode_qualities()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trial {
+    pub quality: f32,
+    /// Size of the file in bytes
+    pub size: usize,
+    /// The file, if it has been kept
+    pub jpeg: Option<Vec<u8>>,
+}
+
+/// Decodes all `inputs` to RGB in parallel, and returns results in the same order.
+///
+/// `config` is called for every file, to set limits or markers, e.g. `|| DecompressConfig::new().max_pixels(50_000_000)`.
+/// CMYK files are converted like in `Decompress::rgb_from_any()`.
+pub fn decode_many<D, C>(inputs: &[D], config: C) -> Vec<Result<Image, Error>>
+where
+    D: AsRef<[u8]> + Sync,
+    C: Fn() -> DecompressConfig<'static> + Sync,
+{
+    parallel_map(inputs, |input| decode(input.as_ref(), config()))
+}
+
+/// Compresses the same image at each of the `qualities` in parallel, e.g. to pick the best one under a size limit.
+///
+/// `new_compress` is called for every trial, and must set up the color space and other settings (the size, quality,
+/// and destination are set here). `pixels` has `height` rows in the input color space.
+/// Files are returned only if `keep_files` is `true`, otherwise just their sizes.
+pub fn encode_qualities<C>(new_compress: C, width: usize, height: usize, pixels: &[u8], qualities: &[f32], keep_files: bool) -> Vec<Result<Trial, Error>>
+where
+    C: Fn() -> Compress + Sync,
+{
+    parallel_map(qualities, |&quality| {
+        let mut cinfo = new_compress();
+        cinfo.set_size(width, height);
+        cinfo.set_quality(quality);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress()?;
+        cinfo.write_scanlines(pixels)?;
+        let mut cinfo = cinfo.finish_compress()?;
+        let jpeg = cinfo.data_to_vec().map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        Ok(Trial { quality, size: jpeg.len(), jpeg: keep_files.then_some(jpeg) })
+    })
+}
+
+/// Runs `job` for every item on all cores, and returns results in the same order. Panics are returned as errors.
+fn parallel_map<T: Sync, R: Send>(items: &[T], job: impl Fn(&T) -> io::Result<R> + Sync) -> Vec<Result<R, Error>> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(items.len());
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads).map(|_| s.spawn(|| {
+            let mut done = Vec::new();
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(i) else { break };
+                let res = catch_unwind(AssertUnwindSafe(|| job(item)))
+                    .unwrap_or_else(|_| Err(io::Error::other("the job panicked")))
+                    .map_err(Error::from);
+                done.push((i, res));
+            }
+            done
+        })).collect();
+        workers.into_iter().flat_map(|w| w.join().unwrap_or_default()).collect()
+    });
+    results.sort_unstable_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, res)| res).collect()
+}
+
+fn decode(jpeg: &[u8], config: DecompressConfig<'static>) -> io::Result<Image> {
+    let mut image = config.from_mem(jpeg)?.rgb_from_any()?;
+    let (width, height) = (image.width(), image.height());
+    let pixels = image.read_scanlines()?;
+    let warnings = image.warnings().to_vec();
+    Ok(Image { pixels, width, height, warnings })
+}
+
+#[test]
+fn decode_batch() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let (pixels, width, height) = crate::decode_rgb(&data).unwrap();
+    let inputs = vec![data.clone(), b"not a jpeg".to_vec(), data[..data.len() / 2].to_vec(), data.clone()];
+    let results = decode_many(&inputs, DecompressConfig::new);
+    assert_eq!(4, results.len());
+    for i in [0, 3] {
+        let image = results[i].as_ref().unwrap();
+        assert_eq!((&pixels, width, height), (&image.pixels, image.width, image.height));
+        assert!(image.warnings.is_empty());
+    }
+    assert!(matches!(results[1], Err(Error::InvalidData { .. })));
+    assert!(!results[2].as_ref().unwrap().warnings.is_empty());
+
+    let limited = decode_many(&inputs, || DecompressConfig::new().max_pixels(100));
+    assert!(limited.iter().all(|res| res.is_err()));
+    assert!(decode_many::<Vec<u8>, _>(&[], DecompressConfig::new).is_empty());
+}
+
+#[test]
+fn encode_trials() {
+    use crate::ColorSpace;
+
+    let (width, height) = (48, 40);
+    let pixels: Vec<u8> = (0..width * height * 3).map(|i| (i * 31 % 253) as u8).collect();
+    let new_compress = || Compress::new(ColorSpace::JCS_RGB);
+    let trials = encode_qualities(new_compress, width, height, &pixels, &[30., 60., 90.], true);
+    let trials: Vec<Trial> = trials.into_iter().map(|t| t.unwrap()).collect();
+    assert_eq!([30., 60., 90.], [trials[0].quality, trials[1].quality, trials[2].quality]);
+    assert!(trials[0].size < trials[1].size && trials[1].size < trials[2].size);
+    for trial in &trials {
+        assert_eq!(trial.size, trial.jpeg.as_ref().unwrap().len());
+        let (decoded, w, h) = crate::decode_rgb(trial.jpeg.as_ref().unwrap()).unwrap();
+        assert_eq!((width * height, width, height), (decoded.len(), w, h));
+    }
+
+    let sizes = encode_qualities(new_compress, width, height, &pixels, &[60.], false);
+    assert_eq!(&Trial { quality: 60., size: trials[1].size, jpeg: None }, sizes[0].as_ref().unwrap());
+    // too few pixels
+    assert!(encode_qualities(new_compress, width, height + 1, &pixels, &[60.], false)[0].is_err());
+}
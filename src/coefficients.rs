@@ -0,0 +1,61 @@
+use crate::colorspace::ColorSpace;
+use crate::qtable::QTable;
+use crate::ffi::JBLOCK;
+use crate::vec::wipe;
+
+/// Quantized DCT coefficients of a whole image, for lossless transcoding
+#[derive(Clone, Debug)]
+pub struct Coefficients {
+    pub width: usize,
+    pub height: usize,
+    /// Color space of the JPEG data
+    pub color_space: ColorSpace,
+    pub components: Vec<ComponentCoefficients>,
+}
+
+/// DCT blocks of one component
+#[derive(Clone, Debug)]
+pub struct ComponentCoefficients {
+    pub component_id: u8,
+    /// h,v sampling factors
+    pub sampling: (u8, u8),
+    pub width_in_blocks: usize,
+    pub height_in_blocks: usize,
+    /// Quantization table the coefficients have been divided by
+    pub qtable: QTable,
+    /// `width_in_blocks` × `height_in_blocks` blocks, row by row.
+    /// Coefficients in each block are in natural (not zigzag) order.
+    pub blocks: Vec<JBLOCK>,
+}
+
+impl Drop for ComponentCoefficients {
+    fn drop(&mut self) {
+        wipe(&mut self.blocks);
+    }
+}
+
+impl ComponentCoefficients {
+    /// Row of blocks
+    #[inline]
+    pub fn row(&self, y: usize) -> &[JBLOCK] {
+        &self.blocks[y * self.width_in_blocks..(y + 1) * self.width_in_blocks]
+    }
+
+    /// Divides coefficients by a coarser table. Each value of the new table is at least the old one.
+    pub fn requantize(&mut self, target: &QTable) {
+        let mut new_table = self.qtable.clone();
+        for (new_q, &target_q) in new_table.coeffs.iter_mut().zip(target.coeffs.iter()) {
+            *new_q = (*new_q).max(target_q);
+        }
+        for block in &mut self.blocks {
+            for ((c, &old_q), &new_q) in block.iter_mut().zip(self.qtable.coeffs.iter()).zip(new_table.coeffs.iter()) {
+                if old_q != new_q {
+                    let value = i32::from(*c) * old_q as i32;
+                    let half = new_q as i32 / 2;
+                    *c = if value < 0 { -((half - value) / new_q as i32) } else { (value + half) / new_q as i32 } as i16;
+                }
+            }
+        }
+        self.qtable = new_table;
+    }
+}
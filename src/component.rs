@@ -23,15 +23,7 @@ pub trait CompInfoExt {
 
 impl CompInfoExt for CompInfo {
     fn qtable(&self) -> Option<QTable> {
-        unsafe {self.quant_table.as_ref()}.map(|q_in| {
-            let mut qtable = QTable {
-                coeffs: [0; 64],
-            };
-            for (out, q) in qtable.coeffs.iter_mut().zip(q_in.quantval.iter()) {
-                *out = *q as u32;
-            }
-            qtable
-        })
+        unsafe {self.quant_table.as_ref()}.map(QTable::from)
     }
 
     fn sampling(&self) -> (u8, u8) {
@@ -1,25 +1,46 @@
 use crate::colorspace::ColorSpace;
 use crate::colorspace::ColorSpaceExt;
+use crate::coefficients::Coefficients;
 use crate::component::CompInfo;
 use crate::component::CompInfoExt;
+use crate::density::DensityUnit;
+use crate::distortion::DistortionMap;
+use crate::error::Error;
+use crate::errormgr::catch_libjpeg;
 use crate::errormgr::unwinding_error_mgr;
 use crate::errormgr::ErrorMgr;
+use crate::errormgr::ErrorState;
 use crate::ffi;
 use crate::ffi::boolean;
 use crate::ffi::jpeg_compress_struct;
+use crate::ffi::jpeg_scan_info as ScanInfo;
 use crate::ffi::DCTSIZE;
 use crate::ffi::JDIMENSION;
 use crate::ffi::JPEG_LIB_VERSION;
 use crate::ffi::J_BOOLEAN_PARAM;
 use crate::ffi::J_INT_PARAM;
-use crate::marker::Marker;
+use crate::hidden;
+use crate::huffman::HuffmanTable;
+use crate::marker::{Marker, ICC_PREFIX};
+use crate::progress::{Progress, ProgressMonitor};
 use crate::qtable::QTable;
+use crate::scans::ScanPreset;
+use crate::vec::{try_to_vec, wipe, wipe_bytes};
+use crate::writedst::DestinationMgr;
+#[cfg(feature = "zeroize")]
+use crate::writedst::WipingVec;
+use crate::yuv::{self, YuvFormat};
 use crate::DctMethod;
 use arrayvec::ArrayVec;
 use libc::free;
+use std::any::Any;
 use std::cmp::min;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
 use std::mem;
-use std::os::raw::{c_int, c_uchar, c_uint, c_ulong, c_void};
+use std::os::raw::{c_int, c_long, c_uchar, c_uint, c_ulong, c_void};
+use std::path::Path;
 use std::ptr;
 use std::slice;
 
@@ -31,9 +52,43 @@ const MAX_COMPONENTS: usize = 4;
 /// Wrapper for `jpeg_compress_struct`
 pub struct Compress {
     cinfo: jpeg_compress_struct,
-    own_err: Box<ErrorMgr>,
-    outbuffer: *mut c_uchar,
-    outsize: c_ulong,
+    own_err: Box<ErrorState>,
+    /// libjpeg keeps pointers to it, so it's boxed to stay in place when `Compress` is moved
+    mem_output: Box<MemOutput>,
+    /// Copy of the input pixels, kept only when the distortion map is enabled
+    distortion_input: Option<Vec<u8>>,
+    distortion_map: Option<DistortionMap>,
+    /// Scan script has been set by `set_scan_script()`
+    custom_scans: bool,
+    /// Set by `set_scan_preset()`, to regenerate the script when the number of components changes
+    scan_preset: Option<ScanPreset>,
+    /// `false` after `write_tables()` or `set_suppress_tables(true)`, for abbreviated files without tables
+    write_all_tables: bool,
+    /// libjpeg's state is unknown after a fatal error
+    failed: bool,
+    /// Referenced by `cinfo.progress`
+    progress: Option<Box<ProgressMonitor>>,
+    /// `DestinationMgr<W>` set by `set_writer()`, referenced by `cinfo.dest`
+    writer_dest: Option<Box<dyn Any>>,
+    /// Set by `set_target_size()`
+    target_size: Option<TargetSize>,
+}
+
+/// File size `finish_compress()` searches the quality for, and what it needs to compress the image again
+struct TargetSize {
+    bytes: usize,
+    tolerance: usize,
+    /// Copy of the input pixels
+    input: Vec<u8>,
+    markers: Vec<(Marker, Vec<u8>)>,
+}
+
+impl TargetSize {
+    fn clear(&mut self) {
+        wipe(&mut self.input);
+        self.input = Vec::new();
+        self.markers = Vec::new();
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -43,15 +98,229 @@ pub enum ScanMode {
     Auto = 2,
 }
 
+/// Vetted combinations of settings, see `Compress::set_preset()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Quality 75, chroma subsampling, progressive, with all of MozJPEG's optimizations
+    Web,
+    /// Quality 95, no chroma subsampling, progressive, with all of MozJPEG's optimizations
+    Archival,
+    /// Quality 70, chroma subsampling, baseline (progressive scans don't pay off for small images)
+    Thumbnail,
+    /// Quality 75, chroma subsampling, baseline, libjpeg-turbo's speed and file sizes
+    Fastest,
+    /// Quality 85, for screenshots and text, see `Compress::set_screen_content_defaults()`
+    ScreenContent,
+}
+
+/// MozJPEG's built-in quantization tables, see `Compress::set_quant_table_preset()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QuantPreset {
+    /// Tables from the JPEG standard (Annex K), used by libjpeg
+    AnnexK = 0,
+    /// Flat table
+    Flat = 1,
+    /// Tuned for MS-SSIM
+    MsSsim = 2,
+    /// ImageMagick's table by N. Robidoux. MozJPEG's default.
+    ImageMagick = 3,
+    /// Tuned for PSNR-HVS
+    PsnrHvs = 4,
+    /// From the paper by Klein, Silverstein and Carney
+    KleinSilversteinCarney = 5,
+    /// From the paper by Watson, Taylor and Borthwick
+    WatsonTaylorBorthwick = 6,
+    /// From the paper by Ahumada, Watson and Peterson
+    AhumadaWatsonPeterson = 7,
+    /// From the paper by Peterson, Ahumada and Watson
+    PetersonAhumadaWatson = 8,
+}
+
+impl QuantPreset {
+    pub(crate) const ALL: [Self; 9] = [
+        Self::AnnexK, Self::Flat, Self::MsSsim, Self::ImageMagick, Self::PsnrHvs,
+        Self::KleinSilversteinCarney, Self::WatsonTaylorBorthwick, Self::AhumadaWatsonPeterson, Self::PetersonAhumadaWatson,
+    ];
+
+    /// Luma and chroma tables at quality 50 (unscaled)
+    pub fn tables(self) -> (&'static QTable, &'static QTable) {
+        use crate::qtable::*;
+        match self {
+            Self::AnnexK => (&ANNEX_K_LUMA, &ANNEX_K_CHROMA),
+            Self::Flat => (&FLAT, &FLAT),
+            Self::MsSsim => (&MSSSIM_LUMA, &MSSSIM_CHROMA),
+            Self::ImageMagick => (&N_ROBIDOUX, &N_ROBIDOUX),
+            Self::PsnrHvs => (&PSNRHVS_LUMA, &PSNRHVS_CHROMA),
+            Self::KleinSilversteinCarney => (&KLEIN_SILVERSTEIN_CARNEY, &KLEIN_SILVERSTEIN_CARNEY),
+            Self::WatsonTaylorBorthwick => (&WATSON_TAYLOR_BORTHWICK, &WATSON_TAYLOR_BORTHWICK),
+            Self::AhumadaWatsonPeterson => (&AHUMADA_WATSON_PETERSON, &AHUMADA_WATSON_PETERSON),
+            Self::PetersonAhumadaWatson => (&PETERSON_AHUMADA_WATSON, &PETERSON_AHUMADA_WATSON),
+        }
+    }
+}
+
+/// Settings for `Compress`, applied in the right order by `build()`. See `Compress::config()`.
+///
+/// Settings that aren't set keep libjpeg's (MozJPEG's) defaults.
+pub struct CompressConfig {
+    input_color_space: ColorSpace,
+    err: Option<ErrorMgr>,
+    preset: Option<Preset>,
+    color_space: Option<ColorSpace>,
+    size: Option<(usize, usize)>,
+    quality: Option<f32>,
+    chroma_subsampling: Option<((u8, u8), (u8, u8))>,
+    progressive: Option<bool>,
+    max_memory: Option<usize>,
+}
+
+impl Default for CompressConfig {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompressConfig {
+    /// Defaults for RGB input
+    #[inline]
+    pub fn new() -> Self {
+        CompressConfig {
+            input_color_space: ColorSpace::JCS_RGB,
+            err: None,
+            preset: None,
+            color_space: None,
+            size: None,
+            quality: None,
+            chroma_subsampling: None,
+            progressive: None,
+            max_memory: None,
+        }
+    }
+
+    /// Color space of pixels given to `write_scanlines()`. RGB by default.
+    #[inline]
+    pub fn input_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.input_color_space = color_space;
+        self
+    }
+
+    /// Uses a custom error manager, see `Compress::new_err()`
+    #[inline]
+    pub fn with_err(mut self, err: ErrorMgr) -> Self {
+        self.err = Some(err);
+        self
+    }
+
+    /// Starts from the preset's settings, which other settings of the config override
+    #[inline]
+    pub fn preset(mut self, preset: Preset) -> Self {
+        self.preset = Some(preset);
+        self
+    }
+
+    /// Color space of the JPEG file, see `Compress::set_color_space()`
+    #[inline]
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = Some(color_space);
+        self
+    }
+
+    /// Image size in pixels
+    #[inline]
+    pub fn size(mut self, width: usize, height: usize) -> Self {
+        self.size = Some((width, height));
+        self
+    }
+
+    /// See `Compress::set_quality()`
+    #[inline]
+    pub fn quality(mut self, quality: f32) -> Self {
+        self.quality = Some(quality);
+        self
+    }
+
+    /// Sizes of Cb and Cr "pixels", see `Compress::set_chroma_sampling_pixel_sizes()`
+    #[inline]
+    pub fn chroma_subsampling(mut self, cb: (u8, u8), cr: (u8, u8)) -> Self {
+        self.chroma_subsampling = Some((cb, cr));
+        self
+    }
+
+    /// Progressive JPEG, see `Compress::set_progressive_mode()`
+    #[inline]
+    pub fn progressive(mut self) -> Self {
+        self.progressive = Some(true);
+        self
+    }
+
+    /// Baseline (sequential) JPEG
+    #[inline]
+    pub fn baseline(mut self) -> Self {
+        self.progressive = Some(false);
+        self
+    }
+
+    /// See `Compress::set_max_memory()`
+    #[inline]
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Creates `Compress` with these settings. Set its destination before `start_compress()`.
+    pub fn build(self) -> Compress {
+        let mut cinfo = Compress::new_err(self.err.unwrap_or_else(unwinding_error_mgr), self.input_color_space);
+        // presets reset all settings, and jpeg_set_colorspace resets subsampling
+        if let Some(preset) = self.preset {
+            cinfo.set_preset(preset);
+        }
+        if let Some(color_space) = self.color_space.filter(|&c| c != cinfo.cinfo.jpeg_color_space) {
+            cinfo.set_color_space(color_space);
+        }
+        if let Some((width, height)) = self.size {
+            cinfo.set_size(width, height);
+        }
+        if let Some(quality) = self.quality {
+            cinfo.set_quality(quality);
+        }
+        if let Some((cb, cr)) = self.chroma_subsampling {
+            cinfo.set_chroma_sampling_pixel_sizes(cb, cr);
+        }
+        match self.progressive {
+            Some(true) => cinfo.set_progressive_mode(),
+            Some(false) => cinfo.set_optimize_scans(false),
+            None => {},
+        }
+        if let Some(bytes) = self.max_memory {
+            cinfo.set_max_memory(bytes);
+        }
+        cinfo
+    }
+}
+
 impl Compress {
+    /// Builder for settings that doesn't depend on the order of calls, e.g.
+    /// `Compress::config().quality(80.).progressive().build()`
+    #[inline]
+    pub fn config() -> CompressConfig {
+        CompressConfig::new()
+    }
+
     /// Compress image using input in this colorspace.
     ///
-    /// ## Panics
+    /// 4-byte formats with a padding byte, such as `JCS_EXT_RGBX`, `JCS_EXT_BGRX` and `JCS_EXT_XRGB`,
+    /// are accepted as-is, so surfaces and GPU readbacks don't need to be repacked to 3-byte RGB. The padding byte is ignored.
+    /// Formats with alpha, such as `JCS_EXT_RGBA` from canvases, work the same way: alpha is ignored, not blended.
+    /// Other channel orders are supported too, e.g. `JCS_EXT_BGR` and `JCS_EXT_BGRA`.
+    ///
+    /// ## Errors
     ///
-    /// You need to wrap all use of this library in `std::panic::catch_unwind()`
+    /// By default libjpeg's errors unwind through the C code, and are returned as `Err` by `start_compress()`,
+    /// `write_scanlines()`, `finish_compress()` and other fallible methods. Unwinding through C strictly speaking
+    /// is not guaranteed to work in Rust (but seems to work fine, at least on x86-64 and ARM).
     ///
-    /// By default errors cause unwind (panic) and unwind through the C code,
-    /// which strictly speaking is not guaranteed to work in Rust (but seems to work fine, at least on x86-64 and ARM).
+    /// Setters still panic if libjpeg rejects them.
     pub fn new(color_space: ColorSpace) -> Compress {
         Compress::new_err(unwinding_error_mgr(), color_space)
     }
@@ -59,22 +328,37 @@ impl Compress {
     /// Use a specific error handler instead of the default unwinding one.
     ///
     /// Note that the error handler must either abort the process or unwind,
-    /// it can't gracefully return due to the design of libjpeg.
+    /// it can't gracefully return due to the design of libjpeg. Its unwinding panics are propagated instead of being returned as `Err`.
     ///
     /// `color_space` refers to input color space
     pub fn new_err(err: ErrorMgr, color_space: ColorSpace) -> Compress {
         unsafe {
             let mut newself = Compress {
                 cinfo: mem::zeroed(),
-                own_err: Box::new(err),
-                outbuffer: ptr::null_mut(),
-                outsize: 0,
+                own_err: ErrorState::new(err),
+                mem_output: Box::new(MemOutput {
+                    buffer: ptr::null_mut(),
+                    size: 0,
+                    #[cfg(feature = "zeroize")]
+                    dest: None,
+                }),
+                distortion_input: None,
+                distortion_map: None,
+                custom_scans: false,
+                scan_preset: None,
+                write_all_tables: true,
+                failed: false,
+                progress: None,
+                writer_dest: None,
+                target_size: None,
             };
 
-            newself.cinfo.common.err = &mut *newself.own_err;
+            newself.cinfo.common.err = &mut newself.own_err.mgr;
 
             let s = mem::size_of_val(&newself.cinfo) as usize;
             ffi::jpeg_CreateCompress(&mut newself.cinfo, JPEG_LIB_VERSION, s);
+            #[cfg(feature = "rust_alloc")]
+            crate::memory::install(&mut newself.cinfo.common);
 
             newself.cinfo.in_color_space = color_space;
             newself.cinfo.input_components = color_space.num_components() as c_int;
@@ -84,44 +368,170 @@ impl Compress {
         }
     }
 
-    /// Settings can't be changed after this call
+    /// Settings can't be changed after this call. Write markers and pixels to the returned `CompressStarted`.
     ///
-    /// ## Panics
+    /// Fails if the settings are invalid, e.g. a bad scan script or image size.
+    pub fn start_compress(mut self) -> io::Result<CompressStarted> {
+        if !self.components().iter().any(|c| c.h_samp_factor == 1) || !self.components().iter().any(|c| c.v_samp_factor == 1) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "at least one sampling factor must be 1"));
+        }
+        self.check_dest()?;
+        if let Some(target) = &mut self.target_size {
+            if self.writer_dest.is_some() || self.cinfo.raw_data_in != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "target size needs set_mem_dest() and scanlines"));
+            }
+            target.clear();
+        }
+        let write_all_tables = self.write_all_tables as boolean;
+        self.catch(|cinfo| unsafe { ffi::jpeg_start_compress(cinfo, write_all_tables) })?;
+        Ok(CompressStarted { comp: self })
+    }
+
+    /// Writes a tables-only file (quantization and Huffman tables, no image) to the destination,
+    /// and makes the following images abbreviated, without the tables, e.g. for Motion JPEG.
     ///
-    /// It may panic, like all functions of this library.
-    #[track_caller]
-    pub fn start_compress(&mut self) {
-        assert!(
-            self.components().iter().any(|c| c.h_samp_factor == 1),
-            "at least one h_samp_factor must be 1"
-        );
-        assert!(
-            self.components().iter().any(|c| c.v_samp_factor == 1),
-            "at least one v_samp_factor must be 1"
-        );
+    /// Settings that change the tables, such as `set_quality()`, must be called before this.
+    /// Huffman tables are shared only with `set_optimize_coding(false)` in baseline mode;
+    /// otherwise each image has its own optimized Huffman tables.
+    pub fn write_tables(&mut self) -> io::Result<()> {
+        self.check_dest()?;
+        self.catch(|cinfo| unsafe { ffi::jpeg_write_tables(cinfo) })?;
+        self.write_all_tables = false;
+        Ok(())
+    }
+
+    /// Whether to omit the tables from the files, when the decoder already has them from `write_tables()`.
+    pub fn set_suppress_tables(&mut self, suppress: bool) {
         unsafe {
-            ffi::jpeg_start_compress(&mut self.cinfo, true as boolean);
+            ffi::jpeg_suppress_tables(&mut self.cinfo, suppress as boolean);
         }
+        self.write_all_tables = !suppress;
     }
 
-    /// Add a marker to compressed file
+    /// Calls `callback` periodically during compression, with progress of the current pass.
     ///
-    /// Data is max 64KB
+    /// Return `false` from the callback to cancel compression, which then fails with `Error::Cancelled`.
+    /// Most of the work for progressive files and optimized Huffman tables is done in `finish_compress()`.
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(Progress) -> bool + 'static) {
+        let monitor = self.progress.insert(ProgressMonitor::new(Box::new(callback)));
+        unsafe {
+            monitor.install(&mut self.cinfo.common);
+        }
+    }
+
+    fn check_dest(&mut self) -> io::Result<()> {
+        // libjpeg would append the next file to the previous one that hasn't been taken with `data_to_vec()`
+        if self.mem_dest_has_output() {
+            self.set_mem_dest();
+        }
+        if self.cinfo.dest.is_null() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no destination, use set_mem_dest() or set_writer()"));
+        }
+        Ok(())
+    }
+
+    /// Runs libjpeg's code, and returns its fatal errors. After an error, all further calls fail.
+    fn catch<R>(&mut self, f: impl FnOnce(&mut jpeg_compress_struct) -> R) -> io::Result<R> {
+        if self.failed {
+            return Err(Error::Poisoned.into());
+        }
+        let res = catch_libjpeg(|| f(&mut self.cinfo));
+        self.failed = res.is_err();
+        res
+    }
+
+    /// Compresses existing quantized DCT coefficients instead of pixels.
     ///
-    /// ## Panics
+    /// Use instead of `start_compress()`, and then call `finish_compress()` on the result.
+    /// Image size, color space, sampling and quantization tables are taken from the coefficients.
     ///
-    /// It may panic, like all functions of this library.
-    pub fn write_marker(&mut self, marker: Marker, data: &[u8]) {
+    /// With coefficients from `Decompress::coefficients()` this re-saves a JPEG without generation loss,
+    /// e.g. with optimized Huffman tables or progressive scans. Markers can be written before `finish_compress()`.
+    pub fn write_coefficients(mut self, coefficients: &Coefficients) -> io::Result<CompressStarted> {
+        let num_components = coefficients.components.len();
+        if num_components == 0 || num_components > MAX_COMPONENTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad number of components"));
+        }
+        self.check_dest()?;
+        if self.target_size.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "target size can't be used with coefficients"));
+        }
+
+        self.cinfo.in_color_space = coefficients.color_space;
+        self.cinfo.input_components = num_components as c_int;
+        self.set_size(coefficients.width, coefficients.height);
+        self.set_color_space(coefficients.color_space);
+        if self.components().len() != num_components {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "number of components doesn't match color space"));
+        }
+
+        let max_h = coefficients.components.iter().map(|c| c.sampling.0).max().unwrap_or(1) as usize;
+        let max_v = coefficients.components.iter().map(|c| c.sampling.1).max().unwrap_or(1) as usize;
+        for (comp, coefs) in self.components_mut().iter_mut().zip(&coefficients.components) {
+            let (h, v) = (coefs.sampling.0 as usize, coefs.sampling.1 as usize);
+            if coefs.width_in_blocks != (coefficients.width * h).div_ceil(max_h * DCTSIZE) ||
+                coefs.height_in_blocks != (coefficients.height * v).div_ceil(max_v * DCTSIZE) ||
+                coefs.blocks.len() != coefs.width_in_blocks * coefs.height_in_blocks {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "number of blocks doesn't match image size"));
+            }
+            comp.component_id = coefs.component_id.into();
+            comp.h_samp_factor = coefs.sampling.0.into();
+            comp.v_samp_factor = coefs.sampling.1.into();
+        }
+        self.set_component_qtables(&coefficients.components.iter().map(|c| &c.qtable).collect::<Vec<_>>());
+
+        // coefficients are already quantized
         unsafe {
-            ffi::jpeg_write_marker(
-                &mut self.cinfo,
-                marker.into(),
-                data.as_ptr(),
-                data.len() as c_uint,
-            );
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, false as boolean);
+        }
+
+        let mem = unsafe { &*self.cinfo.common.mem };
+        let (Some(alloc_small), Some(request_virt_barray), Some(realize_virt_arrays), Some(access_virt_barray)) =
+            (mem.alloc_small, mem.request_virt_barray, mem.realize_virt_arrays, mem.access_virt_barray) else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+
+        self.catch(|cinfo| unsafe {
+            // libjpeg keeps the pointer to the list until the end of compression
+            let arrays_ptr = alloc_small(&mut cinfo.common, ffi::JPOOL_IMAGE, num_components * mem::size_of::<*mut ffi::jvirt_barray_control>());
+            let arrays = slice::from_raw_parts_mut(arrays_ptr.cast::<*mut ffi::jvirt_barray_control>(), num_components);
+            for (array, coefs) in arrays.iter_mut().zip(&coefficients.components) {
+                let (h, v) = (coefs.sampling.0 as ffi::JDIMENSION, coefs.sampling.1 as ffi::JDIMENSION);
+                *array = request_virt_barray(&mut cinfo.common, ffi::JPOOL_IMAGE, true as boolean,
+                    (coefs.width_in_blocks as ffi::JDIMENSION).next_multiple_of(h),
+                    (coefs.height_in_blocks as ffi::JDIMENSION).next_multiple_of(v), v);
+            }
+            realize_virt_arrays(&mut cinfo.common);
+
+            for (&array, coefs) in arrays.iter().zip(&coefficients.components) {
+                for y in 0..coefs.height_in_blocks {
+                    let rows = access_virt_barray(&mut cinfo.common, array, y as _, 1, true as boolean);
+                    slice::from_raw_parts_mut(*rows, coefs.width_in_blocks).copy_from_slice(coefs.row(y));
+                }
+            }
+
+            ffi::jpeg_write_coefficients(cinfo, arrays_ptr.cast());
+        })?;
+        Ok(CompressStarted { comp: self })
+    }
+
+    /// Gives every component its own quantization table slot, with exact (unscaled) values
+    pub(crate) fn set_component_qtables(&mut self, qtables: &[&QTable]) {
+        for (ci, qtable) in qtables.iter().enumerate().take(ffi::NUM_QUANT_TBLS) {
+            unsafe {
+                ffi::jpeg_add_quant_table(&mut self.cinfo, ci as c_int, qtable.as_ptr(), 100, false as boolean);
+            }
+        }
+        for (ci, comp) in self.components_mut().iter_mut().enumerate().take(qtables.len()) {
+            comp.quant_tbl_no = ci as c_int;
         }
     }
 
+    /// Quantization table currently set in the given slot (0 = luma, 1 = chroma)
+    pub(crate) fn qtable(&self, slot: usize) -> Option<QTable> {
+        unsafe { self.cinfo.quant_tbl_ptrs.get(slot)?.as_ref() }.map(QTable::from)
+    }
+
     /// Expose components for modification, e.g. to set chroma subsampling
     pub fn components_mut(&mut self) -> &mut [CompInfo] {
         unsafe {
@@ -138,80 +548,77 @@ impl Compress {
         self.cinfo.next_scanline < self.cinfo.image_height
     }
 
-    /// Returns true if all lines in image_src (not necessarily all lines of the image) were written
-    ///
-    /// ## Panics
-    ///
-    /// It may panic, like all functions of this library.
     #[track_caller]
-    pub fn write_scanlines(&mut self, image_src: &[u8]) -> bool {
+    fn byte_width(&self) -> usize {
         assert_eq!(0, self.cinfo.raw_data_in);
         assert!(self.cinfo.input_components > 0);
         assert!(self.cinfo.image_width > 0);
+        self.cinfo.image_width as usize * self.cinfo.input_components as usize
+    }
 
-        let byte_width = self.cinfo.image_width as usize * self.cinfo.input_components as usize;
-        for rows in image_src.chunks(MAX_MCU_HEIGHT * byte_width) {
+    fn write_row_slices<'a>(&mut self, rows: impl Iterator<Item = &'a [u8]>) -> io::Result<bool> {
+        let byte_width = self.byte_width();
+        let mut rows = rows.peekable();
+        while rows.peek().is_some() {
             let mut row_pointers = ArrayVec::<_, MAX_MCU_HEIGHT>::new();
-            for row in rows.chunks(byte_width) {
-                debug_assert!(row.len() == byte_width);
+            for row in rows.by_ref().take(MAX_MCU_HEIGHT) {
+                if row.len() != byte_width {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("row is {} bytes, expected {byte_width}", row.len())));
+                }
+                if let Some(target) = &mut self.target_size {
+                    target.input.try_reserve(row.len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+                    target.input.extend_from_slice(row);
+                }
+                if let Some(input) = &mut self.distortion_input {
+                    // it's only a debugging aid, so it's not worth failing compression over
+                    if input.try_reserve(row.len()).is_ok() {
+                        input.extend_from_slice(row);
+                    } else {
+                        wipe(input);
+                        self.distortion_input = None;
+                    }
+                }
                 row_pointers.push(row.as_ptr());
             }
 
             let mut rows_left = row_pointers.len() as u32;
             let mut row_pointers = row_pointers.as_ptr();
             while rows_left > 0 {
-                unsafe {
-                    let rows_written =
-                        ffi::jpeg_write_scanlines(&mut self.cinfo, row_pointers, rows_left);
-                    debug_assert!(rows_left >= rows_written);
-                    if rows_written == 0 {
-                        return false;
-                    }
-                    rows_left -= rows_written;
-                    row_pointers = row_pointers.add(rows_written as usize);
+                let rows_written = self.catch(|cinfo| unsafe {
+                    ffi::jpeg_write_scanlines(cinfo, row_pointers, rows_left)
+                })?;
+                debug_assert!(rows_left >= rows_written);
+                if rows_written == 0 {
+                    return Ok(false);
                 }
+                rows_left -= rows_written;
+                row_pointers = unsafe { row_pointers.add(rows_written as usize) };
             }
         }
-        true
+        Ok(true)
     }
 
-    /// Advanced. Only possible after `set_raw_data_in()`.
-    /// Write YCbCr blocks pixels instead of usual color space
-    ///
-    /// See `raw_data_in` in libjpeg docs
-    ///
-    /// ## Panic
-    ///
-    /// Panics if raw write wasn't enabled
-    #[track_caller]
-    pub fn write_raw_data(&mut self, image_src: &[&[u8]]) -> bool {
+    fn write_raw_data(&mut self, image_src: &[&[u8]]) -> io::Result<()> {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
         if 0 == self.cinfo.raw_data_in {
-            panic!("Raw data not set");
+            return invalid("raw data not enabled".into());
         }
 
         let mcu_height = self.cinfo.max_v_samp_factor as usize * DCTSIZE;
-        if mcu_height > MAX_MCU_HEIGHT {
-            panic!("Subsampling factor too large");
+        if mcu_height == 0 || mcu_height > MAX_MCU_HEIGHT {
+            return invalid("subsampling factor too large".into());
         }
-        assert!(mcu_height > 0);
 
         let num_components = self.components().len();
         if num_components > MAX_COMPONENTS || num_components > image_src.len() {
-            panic!(
-                "Too many components: declared {}, got {}",
-                num_components,
-                image_src.len()
-            );
+            return invalid(format!("too many components: declared {}, got {}", num_components, image_src.len()));
         }
 
         for (ci, comp_info) in self.components().iter().enumerate() {
-            if comp_info.row_stride() * comp_info.col_stride() > image_src[ci].len() {
-                panic!(
-                    "Bitmap too small. Expected {}x{}, got {}",
-                    comp_info.row_stride(),
-                    comp_info.col_stride(),
-                    image_src[ci].len()
-                );
+            let height = (self.cinfo.image_height as usize * comp_info.v_samp_factor as usize)
+                .div_ceil(self.cinfo.max_v_samp_factor as usize);
+            if comp_info.row_stride() * height > image_src[ci].len() {
+                return invalid(format!("bitmap too small. Expected {}x{}, got {}", comp_info.row_stride(), height, image_src[ci].len()));
             }
         }
 
@@ -223,49 +630,47 @@ impl Compress {
 
                 for (ci, comp_info) in self.components().iter().enumerate() {
                     let row_stride = comp_info.row_stride();
-
-                    let input_height = image_src[ci].len() / row_stride;
+                    let last_row = image_src[ci].len() / row_stride - 1;
 
                     let comp_start_row = start_row * comp_info.v_samp_factor as usize
                         / self.cinfo.max_v_samp_factor as usize;
-                    let comp_height = min(
-                        input_height - comp_start_row,
-                        DCTSIZE * comp_info.v_samp_factor as usize,
-                    );
-                    assert!(comp_height >= 8);
-
-                    for ri in 0..comp_height {
-                        let start_offset = (comp_start_row + ri) * row_stride;
-                        row_ptrs[ci][ri] =
-                            image_src[ci][start_offset..start_offset + row_stride].as_ptr();
-                    }
-                    for ri in comp_height..mcu_height {
-                        row_ptrs[ci][ri] = ptr::null();
+                    let comp_height = DCTSIZE * comp_info.v_samp_factor as usize;
+
+                    for (ri, row_ptr) in row_ptrs[ci].iter_mut().enumerate().take(comp_height) {
+                        let start_offset = min(comp_start_row + ri, last_row) * row_stride;
+                        *row_ptr = image_src[ci][start_offset..start_offset + row_stride].as_ptr();
                     }
                     comp_ptrs[ci] = row_ptrs[ci].as_ptr();
                 }
 
-                let rows_written = ffi::jpeg_write_raw_data(
-                    &mut self.cinfo,
+                let rows_written = self.catch(|cinfo| ffi::jpeg_write_raw_data(
+                    cinfo,
                     comp_ptrs.as_ptr(),
                     mcu_height as u32,
-                ) as usize;
+                ))? as usize;
                 if 0 == rows_written {
-                    return false;
+                    return Err(io::ErrorKind::WouldBlock.into());
                 }
                 start_row += rows_written;
             }
         }
-        true
+        Ok(())
     }
 
     /// Set color space of JPEG being written, different from input color space
     ///
     /// See `jpeg_set_colorspace` in libjpeg docs
     pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        let had_scan_script = !self.cinfo.scan_info.is_null();
         unsafe {
             ffi::jpeg_set_colorspace(&mut self.cinfo, color_space);
         }
+        if let Some(preset) = self.scan_preset {
+            self.set_scan_preset(preset);
+        } else if had_scan_script && !self.custom_scans {
+            // the script depends on the number of components, so it needs to be regenerated
+            self.set_progressive_mode();
+        }
     }
 
     /// Image size of the input
@@ -274,6 +679,91 @@ impl Compress {
         self.cinfo.image_height = height as JDIMENSION;
     }
 
+    /// Makes `finish_compress()` search for the highest quality at which the file fits in `bytes`,
+    /// compressing the image again as many times as needed. Any size between `bytes - tolerance` and `bytes` is good enough,
+    /// so a larger tolerance needs fewer attempts.
+    ///
+    /// The first attempt uses the current settings, and later ones replace the quantization tables with `set_quality()`.
+    /// If the file doesn't fit even at quality 1, the result is the smallest file. A copy of the input pixels is kept
+    /// while compressing. Works only with `set_mem_dest()` and pixels written as scanlines.
+    pub fn set_target_size(&mut self, bytes: usize, tolerance: usize) {
+        self.target_size = Some(TargetSize { bytes, tolerance: tolerance.min(bytes), input: Vec::new(), markers: Vec::new() });
+    }
+
+    /// Compresses the image kept by `set_target_size()` at lower or higher qualities, until it fits
+    fn fit_target_size(&mut self, target: &TargetSize) -> io::Result<()> {
+        let fits = |size: usize| size <= target.bytes;
+        let good_enough = |size: usize| fits(size) && size + target.tolerance >= target.bytes;
+        if good_enough(self.mem_data().map_or(0, <[u8]>::len)) {
+            return Ok(());
+        }
+        let (mut low, mut high) = (1u8, 100u8);
+        let mut best = None;
+        let mut last = None;
+        while low <= high {
+            let quality = low + (high - low) / 2;
+            let size = self.compress_again(target, quality)?;
+            last = Some(quality);
+            if fits(size) {
+                best = Some(quality);
+                if good_enough(size) {
+                    break;
+                }
+                low = quality + 1;
+            } else {
+                high = quality - 1;
+            }
+        }
+        let best = best.unwrap_or(1);
+        if last != Some(best) {
+            self.compress_again(target, best)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the file size
+    fn compress_again(&mut self, target: &TargetSize, quality: u8) -> io::Result<usize> {
+        self.set_quality(quality.into());
+        self.set_mem_dest();
+        let write_all_tables = self.write_all_tables as boolean;
+        self.catch(|cinfo| unsafe { ffi::jpeg_start_compress(cinfo, write_all_tables) })?;
+        for (marker, data) in &target.markers {
+            self.catch(|cinfo| unsafe { ffi::jpeg_write_marker(cinfo, (*marker).into(), data.as_ptr(), data.len() as c_uint) })?;
+        }
+        let byte_width = self.byte_width();
+        self.write_row_slices(target.input.chunks(byte_width))?;
+        self.catch(|cinfo| unsafe { ffi::jpeg_finish_compress(cinfo) })?;
+        Ok(self.mem_data().map_or(0, <[u8]>::len))
+    }
+
+    /// Limits memory libjpeg can use for whole-image buffers, which progressive files and optimized scans need.
+    /// `start_compress()` fails with `Error::OutOfMemory` if more is needed.
+    ///
+    /// Only a few small buffers proportional to the image width are allocated outside of the limit,
+    /// and with the `rust_alloc` feature all allocations count towards it (then a very low limit makes setters panic).
+    pub fn set_max_memory(&mut self, bytes: usize) {
+        unsafe { (*self.cinfo.common.mem).max_memory_to_use = bytes.try_into().unwrap_or(c_long::MAX); }
+    }
+
+    /// Physical resolution (DPI) or pixel aspect ratio, written in the JFIF marker.
+    ///
+    /// Only grayscale and YCbCr files have the JFIF marker. Presets and `set_fastest_defaults()` reset it to 1×1 aspect ratio.
+    pub fn set_pixel_density(&mut self, unit: DensityUnit, x: u16, y: u16) {
+        self.cinfo.density_unit = unit.jfif_code();
+        // libjpeg doesn't accept zero densities
+        self.cinfo.X_density = x.max(1);
+        self.cinfo.Y_density = y.max(1);
+    }
+
+    /// Whether to write Adobe's APP14 marker. By default it's written only for CMYK and YCCK, and `set_color_space()` resets this setting.
+    ///
+    /// The marker's color transform code follows the JPEG color space: 1 for YCbCr, 2 for YCCK, and 0 for anything else,
+    /// so pick the transform with `set_color_space()`. Readers of files with this marker, such as Photoshop,
+    /// expect inverted CMYK (255 = no ink), so CMYK input should be inverted before writing.
+    pub fn set_adobe_marker(&mut self, write: bool) {
+        self.cinfo.write_Adobe_marker = write as boolean;
+    }
+
     /// libjpeg's `input_gamma` = image gamma of input image
     #[deprecated(note = "it doesn't do anything")]
     pub fn set_gamma(&mut self, gamma: f64) {
@@ -291,7 +781,27 @@ impl Compress {
         }
         if !opt {
             self.cinfo.scan_info = ptr::null();
+            self.custom_scans = false;
+            self.scan_preset = None;
+        }
+    }
+
+    /// Uses the given scans instead of MozJPEG's progression. Disables scan optimization.
+    ///
+    /// `component_index` in the scans refers to the order of components in the file.
+    /// The script is checked when compression starts, and invalid scripts make `start_compress()` fail.
+    pub fn set_scan_script(&mut self, scans: &[ScanInfo]) {
+        assert!(!scans.is_empty());
+        self.set_optimize_scans(false);
+        unsafe {
+            let mem = &*self.cinfo.common.mem;
+            let alloc_small = mem.alloc_small.expect("memory manager");
+            let script = alloc_small(&mut self.cinfo.common, ffi::JPOOL_PERMANENT, mem::size_of_val(scans)).cast::<ScanInfo>();
+            ptr::copy_nonoverlapping(scans.as_ptr(), script, scans.len());
+            self.cinfo.scan_info = script;
         }
+        self.cinfo.num_scans = scans.len() as c_int;
+        self.custom_scans = true;
     }
 
     /// If 1-100 (non-zero), it will use MozJPEG's smoothing.
@@ -299,11 +809,110 @@ impl Compress {
         self.cinfo.smoothing_factor = smoothing_factor as c_int;
     }
 
+    /// Writes a restart marker after every `rows` rows of MCUs (0 disables them).
+    ///
+    /// Restart markers make the file slightly larger, but limit damage from corrupted data,
+    /// and allow independently compressed parts to be joined (see `stripes`).
+    pub fn set_restart_in_rows(&mut self, rows: u16) {
+        self.cinfo.restart_interval = 0;
+        self.cinfo.restart_in_rows = rows.into();
+    }
+
     /// Set to `false` to make files larger for no reason
     pub fn set_optimize_coding(&mut self, opt: bool) {
         self.cinfo.optimize_coding = opt as boolean;
     }
 
+    /// Uses fixed Huffman tables instead of computing optimal ones for the image.
+    ///
+    /// This skips a statistics pass over the whole image, which reduces latency (e.g. for Motion JPEG or screen sharing),
+    /// but makes files larger. The standard tables from the JPEG spec are used, unless set with `set_huffman_tables()`.
+    ///
+    /// Progressive mode and trellis quantization are disabled, because they need extra passes.
+    pub fn set_fixed_huffman_tables(&mut self) {
+        self.set_optimize_coding(false);
+        self.set_optimize_scans(false);
+        unsafe {
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, false as boolean);
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC, false as boolean);
+        }
+    }
+
+    /// Huffman tables for `set_fixed_huffman_tables()`. Slot 0 is used by luma, slot 1 by chroma.
+    ///
+    /// Optimized tables replace them, unless fixed tables have been enabled.
+    pub fn set_huffman_tables(&mut self, slot: usize, dc: &HuffmanTable, ac: &HuffmanTable) {
+        assert!(slot < self.cinfo.dc_huff_tbl_ptrs.len());
+        let ptrs = [&mut self.cinfo.dc_huff_tbl_ptrs[slot] as *mut *mut ffi::JHUFF_TBL, &mut self.cinfo.ac_huff_tbl_ptrs[slot]];
+        for (ptr, table) in ptrs.into_iter().zip([dc, ac]) {
+            unsafe {
+                if (*ptr).is_null() {
+                    *ptr = ffi::jpeg_alloc_huff_table(&mut self.cinfo.common);
+                }
+                let tbl = &mut **ptr;
+                tbl.bits[0] = 0;
+                tbl.bits[1..].copy_from_slice(table.bits());
+                tbl.huffval = [0; 256];
+                tbl.huffval[..table.values().len()].copy_from_slice(table.values());
+                // sent_table is private, but jpeg_start_compress resets it to write all tables, unless they're suppressed
+            }
+        }
+    }
+
+    /// Trellis quantization picks quantized values that give the best size for the quality. Enabled by default.
+    pub fn set_trellis_quant(&mut self, opt: bool) {
+        unsafe {
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, opt as boolean);
+        }
+    }
+
+    /// Whether trellis quantization is applied to DC coefficients too. Enabled by default.
+    pub fn set_trellis_quant_dc(&mut self, opt: bool) {
+        unsafe {
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC, opt as boolean);
+        }
+    }
+
+    /// Whether trellis quantization optimizes placement of end-of-block codes. Disabled by default.
+    pub fn set_trellis_eob_opt(&mut self, opt: bool) {
+        unsafe {
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_EOB_OPT, opt as boolean);
+        }
+    }
+
+    /// Coefficient index (in zigzag order, up to 63) at which trellis quantization splits the spectrum
+    /// when scans are used in trellis. Default is 8.
+    pub fn set_trellis_freq_split(&mut self, index: u8) {
+        unsafe {
+            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_TRELLIS_FREQ_SPLIT, index.min(63).into());
+        }
+    }
+
+    /// Number of trellis quantization passes, at least 1. More passes are slower and rarely help. Default is 1.
+    pub fn set_trellis_num_loops(&mut self, loops: u8) {
+        unsafe {
+            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_TRELLIS_NUM_LOOPS, loops.max(1).into());
+        }
+    }
+
+    /// Rate-distortion tradeoff of trellis quantization. Distortion is weighted by `2^scale1 / (2^scale2 + block energy)`,
+    /// so higher `scale1` keeps more detail at the cost of file size, and higher `scale2` reduces the effect of block's contrast.
+    /// Defaults are 14.75 and 16.5. Higher `scale1` can keep edges of text in UI screenshots sharper.
+    pub fn set_lambda_log_scale(&mut self, scale1: f32, scale2: f32) {
+        unsafe {
+            ffi::jpeg_c_set_float_param(&mut self.cinfo, ffi::J_FLOAT_PARAM::JFLOAT_LAMBDA_LOG_SCALE1, scale1);
+            ffi::jpeg_c_set_float_param(&mut self.cinfo, ffi::J_FLOAT_PARAM::JFLOAT_LAMBDA_LOG_SCALE2, scale2);
+        }
+    }
+
+    /// How much trellis quantization of DC penalizes differences from the neighboring blocks' DC,
+    /// which can reduce blocking in smooth gradients. Default is 0.
+    pub fn set_trellis_delta_dc_weight(&mut self, weight: f32) {
+        unsafe {
+            ffi::jpeg_c_set_float_param(&mut self.cinfo, ffi::J_FLOAT_PARAM::JFLOAT_TRELLIS_DELTA_DC_WEIGHT, weight);
+        }
+    }
+
     /// Specifies whether multiple scans should be considered during trellis
     /// quantization.
     pub fn set_use_scans_in_trellis(&mut self, opt: bool) {
@@ -321,6 +930,21 @@ impl Compress {
         unsafe {
             ffi::jpeg_simple_progression(&mut self.cinfo);
         }
+        self.custom_scans = false;
+        self.scan_preset = None;
+    }
+
+    /// Use a ready-made progressive scan script, generated for the current number of components.
+    ///
+    /// Call it after `set_preset()`, which changes the scans too.
+    pub fn set_scan_preset(&mut self, preset: ScanPreset) {
+        match preset.scans(self.components().len()) {
+            Some(scans) => {
+                self.set_scan_script(&scans);
+                self.scan_preset = Some(preset);
+            },
+            None => self.set_progressive_mode(),
+        }
     }
 
     pub fn dct_method(&mut self, method: DctMethod) {
@@ -354,10 +978,12 @@ impl Compress {
         }
     }
 
+    /// Use arithmetic coding instead of Huffman coding. Files are a few percent smaller, but many decoders can't read them.
+    ///
+    /// Requires the `arith` feature, otherwise `start_compress()` fails with `Error::Unsupported`.
     pub fn enable_arith_code(&mut self) {
-        unsafe {
-            self.cinfo.arith_code = 1;
-        }
+        self.cinfo.arith_code = 1;
+        self.cinfo.optimize_coding = 0;
     }
 
     /// Reset to libjpeg v6 settings
@@ -379,6 +1005,59 @@ impl Compress {
         self.cinfo.raw_data_in = opt as boolean;
     }
 
+    /// Applies quality, chroma subsampling, progressive and trellis settings of the preset.
+    ///
+    /// Resets other settings to defaults, so call other setters after this one.
+    pub fn set_preset(&mut self, preset: Preset) {
+        match preset {
+            Preset::Fastest => self.set_fastest_defaults(),
+            _ => self.set_max_compression(),
+        }
+        let (quality, subsampled) = match preset {
+            Preset::Web | Preset::Fastest => (75., true),
+            Preset::Archival => (95., false),
+            Preset::Thumbnail => (70., true),
+            Preset::ScreenContent => return self.set_screen_content_defaults(85.),
+        };
+        if self.components().len() > 1 {
+            let chroma = if subsampled { (2, 2) } else { (1, 1) };
+            self.set_chroma_sampling_pixel_sizes(chroma, chroma);
+        }
+        if preset == Preset::Thumbnail {
+            self.set_optimize_scans(false);
+        }
+        self.set_quality(quality);
+    }
+
+    /// Settings for screenshots, text and UI graphics at the given quality
+    ///
+    /// Disables chroma subsampling, uses a flat quantization table that preserves sharp edges,
+    /// enables deringing and skips trellis quantization of DC, which can shift colors of flat areas.
+    /// Call other setters after this one.
+    pub fn set_screen_content_defaults(&mut self, quality: f32) {
+        for comp in self.components_mut() {
+            comp.h_samp_factor = 1;
+            comp.v_samp_factor = 1;
+        }
+        unsafe {
+            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX, QuantPreset::Flat as c_int);
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OVERSHOOT_DERINGING, true as boolean);
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, true as boolean);
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC, false as boolean);
+        }
+        self.set_smoothing_factor(0);
+        self.set_quality(quality);
+    }
+
+    /// Selects MozJPEG's built-in quantization tables.
+    ///
+    /// The tables are scaled and used by the next call to `set_quality()`, so call it before setting the quality.
+    pub fn set_quant_table_preset(&mut self, preset: QuantPreset) {
+        unsafe {
+            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX, preset as c_int);
+        }
+    }
+
     /// Set image quality. Values 60-80 are recommended.
     pub fn set_quality(&mut self, quality: f32) {
         unsafe {
@@ -386,6 +1065,18 @@ impl Compress {
         }
     }
 
+    /// Like `set_quality()`, but with a different quality for chroma, which can often be much lower than luma's.
+    pub fn set_quality_separate(&mut self, luma_quality: f32, chroma_quality: f32) {
+        self.set_quality(luma_quality);
+        let luma = self.qtable(0);
+        self.set_quality(chroma_quality);
+        if let Some(luma) = luma {
+            unsafe {
+                ffi::jpeg_add_quant_table(&mut self.cinfo, 0, luma.as_ptr(), 100, false as boolean);
+            }
+        }
+    }
+
     /// Instead of quality setting, use a specific quantization table.
     pub fn set_luma_qtable(&mut self, qtable: &QTable) {
         unsafe {
@@ -418,52 +1109,243 @@ impl Compress {
         }
     }
 
+    /// Estimated size of everything other than entropy-coded data: headers, tables, scan headers and markers.
+    ///
+    /// `marker_sizes` are lengths of data that will be written with `write_marker()`, such as EXIF or ICC.
+    /// Optimized Huffman tables and scans picked by `set_optimize_scans()` depend on the image,
+    /// so for them the estimate is an upper bound. Otherwise it's exact. Call it before `start_compress()`.
+    pub fn estimated_header_size(&self, marker_sizes: &[usize]) -> usize {
+        let comps = self.components();
+        let n = comps.len();
+        let mut size = 2 + 2; // SOI, EOI
+        size += marker_sizes.iter().map(|&len| len + 4 * len.div_ceil(65533).max(1)).sum::<usize>();
+        if 0 != self.cinfo.write_JFIF_header {
+            size += 18;
+        }
+        if 0 != self.cinfo.write_Adobe_marker {
+            size += 16;
+        }
+        if 0 != self.cinfo.restart_interval || 0 != self.cinfo.restart_in_rows {
+            size += 6;
+        }
+        size += 10 + 3 * n; // SOF
+
+        // tables are combined in one DQT and one DHT marker
+        let mut qtables: Vec<_> = comps.iter().map(|c| c.quant_tbl_no as usize).collect();
+        qtables.sort_unstable();
+        qtables.dedup();
+        size += 4;
+        for q in qtables {
+            let wide = unsafe { self.cinfo.quant_tbl_ptrs[q].as_ref() }.is_some_and(|t| t.quantval.iter().any(|&v| v > 255));
+            size += 1 + if wide { 128 } else { 64 };
+        }
+
+        let arith = 0 != self.cinfo.arith_code;
+        let optimize_scans = unsafe { 0 != ffi::jpeg_c_get_bool_param(&self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS) };
+        let scans = if self.cinfo.scan_info.is_null() || self.cinfo.num_scans <= 0 {
+            &[][..]
+        } else {
+            unsafe { slice::from_raw_parts(self.cinfo.scan_info, self.cinfo.num_scans as usize) }
+        };
+        let progressive = scans.iter().any(|s| s.Ss != 0 || s.Se != 63 || s.Ah != 0 || s.Al != 0);
+        // progressive mode always optimizes Huffman tables
+        let optimize_coding = progressive || 0 != self.cinfo.optimize_coding;
+        let huff_size = |dc: bool, slot: c_int| -> usize {
+            if arith {
+                return 0;
+            }
+            let fixed = if dc { self.cinfo.dc_huff_tbl_ptrs[slot as usize] } else { self.cinfo.ac_huff_tbl_ptrs[slot as usize] };
+            match unsafe { fixed.as_ref() } {
+                Some(table) if !optimize_coding => 17 + table.bits[1..].iter().map(|&b| usize::from(b)).sum::<usize>(),
+                // all symbols of 8-bit data
+                _ => 17 + if dc { 12 } else { 162 },
+            }
+        };
+        // SOS, and DAC for arithmetic coding
+        let scan_header = |comps_in_scan: usize| 8 + 2 * comps_in_scan + if arith { 4 + 4 * comps_in_scan } else { 0 };
+        let distinct_tables = |dc: bool, indices: &mut dyn Iterator<Item = usize>| {
+            let mut slots: Vec<_> = indices.map(|ci| if dc { comps[ci].dc_tbl_no } else { comps[ci].ac_tbl_no }).collect();
+            slots.sort_unstable();
+            slots.dedup();
+            slots.into_iter().map(|slot| huff_size(dc, slot)).sum::<usize>()
+        };
+        let dht = |tables: usize| if tables > 0 { 4 + tables } else { 0 };
+
+        if progressive && optimize_scans {
+            // the most scans mozjpeg can pick with its default Al limits (3 for luma, 2 for chroma)
+            let (luma_ac, chroma_ac) = (2 + 3, 2 * (2 + 2));
+            size += scan_header(n) + dht(distinct_tables(true, &mut (0..n)));
+            size += luma_ac * (scan_header(1) + dht(huff_size(false, comps[0].ac_tbl_no)));
+            if n > 1 {
+                size += 2 * scan_header(1);
+                size += chroma_ac * (scan_header(1) + dht(huff_size(false, comps[1].ac_tbl_no)));
+            }
+        } else if progressive {
+            for scan in scans {
+                let indices = &scan.component_index[..scan.comps_in_scan as usize];
+                size += scan_header(indices.len());
+                if scan.Ss == 0 && scan.Ah == 0 {
+                    size += dht(distinct_tables(true, &mut indices.iter().map(|&ci| ci as usize)));
+                } else if scan.Ss != 0 {
+                    size += dht(huff_size(false, comps[indices[0] as usize].ac_tbl_no));
+                }
+            }
+        } else {
+            let num_scans = if scans.is_empty() { 1 } else { scans.len() };
+            size += (0..num_scans).map(|i| scan_header(scans.get(i).map_or(n, |s| s.comps_in_scan as usize))).sum::<usize>();
+            size += dht(distinct_tables(true, &mut (0..n)) + distinct_tables(false, &mut (0..n)));
+        }
+        size
+    }
+
     /// Write to in-memory buffer
+    ///
+    /// With the `zeroize` feature, old copies of the buffer are wiped when it grows.
     pub fn set_mem_dest(&mut self) {
         self.free_mem_dest();
+        self.remove_writer();
+        #[cfg(feature = "zeroize")]
         unsafe {
-            ffi::jpeg_mem_dest(&mut self.cinfo, &mut self.outbuffer, &mut self.outsize);
+            self.mem_output.dest.insert(DestinationMgr::new(WipingVec::default())).install(&mut self.cinfo);
         }
-    }
-
-    /// Destroy in-memory buffer
-    fn free_mem_dest(&mut self) {
-        if !self.outbuffer.is_null() {
-            unsafe {
-                free(self.outbuffer as *mut c_void);
-            }
-            self.outbuffer = ptr::null_mut();
-            self.outsize = 0;
+        #[cfg(not(feature = "zeroize"))]
+        unsafe {
+            ffi::jpeg_mem_dest(&mut self.cinfo, &mut self.mem_output.buffer, &mut self.mem_output.size);
         }
     }
 
-    /// Finalize compression.
-    /// In case of progressive files, this may actually start processing.
+    /// Writes the compressed file to `writer` in chunks, while it's being compressed, instead of buffering the whole file in memory.
     ///
-    /// ## Panics
-    ///
-    /// It may panic, like all functions of this library.
-    pub fn finish_compress(&mut self) {
+    /// Call it before `start_compress()`. The writer is flushed in `finish_compress()`, and its errors are returned as-is
+    /// from the method that was writing. Use `take_writer()` to get it back.
+    pub fn set_writer<W: Write + 'static>(&mut self, writer: W) {
+        self.free_mem_dest();
+        let mut dest = DestinationMgr::new(writer);
         unsafe {
-            ffi::jpeg_finish_compress(&mut self.cinfo);
+            dest.install(&mut self.cinfo);
         }
+        self.writer_dest = Some(dest);
     }
 
-    /// If `set_mem_dest()` was enabled, this is the result
-    pub fn data_as_mut_slice(&mut self) -> Result<&[u8], ()> {
-        if self.outbuffer.is_null() || 0 == self.outsize {
-            return Err(());
+    /// Creates (or truncates) the file at `path`, and writes to it like `to_file()`
+    pub fn to_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.to_file(File::create(path)?);
+        Ok(())
+    }
+
+    /// Writes to an already-open `File`, with buffering. The file is flushed and closed by `finish_compress()`.
+    pub fn to_file(&mut self, file: File) {
+        self.set_writer(FileDest(BufWriter::new(file)));
+    }
+
+    /// Returns the writer given to `set_writer()`. `None` if it was of a different type, or while compression is in progress.
+    pub fn take_writer<W: Write + 'static>(&mut self) -> Option<W> {
+        // after an error libjpeg won't use it again
+        if !self.failed && self.cinfo.common.global_state != hidden::CSTATE_START {
+            return None;
+        }
+        let dest = match self.writer_dest.take()?.downcast::<DestinationMgr<W>>() {
+            Ok(dest) => dest,
+            Err(other) => {
+                self.writer_dest = Some(other);
+                return None;
+            },
+        };
+        self.cinfo.dest = ptr::null_mut();
+        Some((*dest).into_writer())
+    }
+
+    fn remove_writer(&mut self) {
+        if self.writer_dest.take().is_some() {
+            self.cinfo.dest = ptr::null_mut();
+        }
+    }
+
+    /// Destroy in-memory buffer
+    fn free_mem_dest(&mut self) {
+        #[cfg(feature = "zeroize")]
+        if self.mem_output.dest.take().is_some() {
+            self.cinfo.dest = ptr::null_mut();
+        }
+        if !self.mem_output.buffer.is_null() {
+            unsafe {
+                // libjpeg updates outbuffer only in finish_compress, and frees it when it grows the buffer,
+                // so the current buffer is taken from the destination manager. Old copies can't be wiped,
+                // so `zeroize` uses `WipingVec` instead.
+                let dest = &mut *self.cinfo.dest.cast::<hidden::MemDestination>();
+                if !dest.newbuffer.is_null() {
+                    wipe_bytes(dest.newbuffer, dest.bufsize);
+                    free(dest.newbuffer as *mut c_void);
+                    dest.newbuffer = ptr::null_mut();
+                    dest.buffer = ptr::null_mut();
+                }
+            }
+            self.mem_output.buffer = ptr::null_mut();
+            self.mem_output.size = 0;
+            // the destination would write to the freed buffer, so another image needs a new one
+            self.cinfo.dest = ptr::null_mut();
+        }
+    }
+
+    /// Debugging aid. Keeps a copy of the input, and after `finish_compress()`
+    /// compares it with the compressed result to make a map of the error in every MCU.
+    ///
+    /// It works only with `write_scanlines()` and `set_mem_dest()`. See `distortion_map()`.
+    pub fn enable_distortion_map(&mut self) {
+        self.distortion_input = Some(Vec::new());
+        self.distortion_map = None;
+    }
+
+    /// Available after `finish_compress()` if `enable_distortion_map()` was used
+    pub fn distortion_map(&self) -> Option<&DistortionMap> {
+        self.distortion_map.as_ref()
+    }
+
+    /// Output of `set_mem_dest()`
+    fn mem_data(&self) -> Option<&[u8]> {
+        #[cfg(feature = "zeroize")]
+        if let Some(dest) = &self.mem_output.dest {
+            return Some(&dest.writer().0[..]).filter(|data| !data.is_empty());
+        }
+        if self.mem_output.buffer.is_null() || 0 == self.mem_output.size {
+            return None;
         }
-        unsafe { Ok(slice::from_raw_parts(self.outbuffer, self.outsize as usize)) }
+        unsafe { Some(slice::from_raw_parts(self.mem_output.buffer, self.mem_output.size as usize)) }
+    }
+
+    /// `set_mem_dest()` has been used for a file (or tables) that hasn't been taken
+    fn mem_dest_has_output(&self) -> bool {
+        #[cfg(feature = "zeroize")]
+        if let Some(dest) = &self.mem_output.dest {
+            return !dest.writer().0.is_empty();
+        }
+        if self.writer_dest.is_some() || self.mem_output.buffer.is_null() || self.cinfo.dest.is_null() {
+            return false;
+        }
+        let dest = unsafe { &*self.cinfo.dest.cast::<hidden::MemDestination>() };
+        dest.pub_.next_output_byte != dest.buffer
+    }
+
+    /// If `set_mem_dest()` was enabled, this is the result
+    #[allow(clippy::result_unit_err)]
+    pub fn data_as_mut_slice(&mut self) -> Result<&[u8], ()> {
+        self.mem_data().ok_or(())
     }
 
     /// If `set_mem_dest()` was enabled, this is the result. Can be called once only.
+    #[allow(clippy::result_unit_err)]
     pub fn data_to_vec(&mut self) -> Result<Vec<u8>, ()> {
-        if self.outbuffer.is_null() || 0 == self.outsize {
+        #[cfg(feature = "zeroize")]
+        if let Some(dest) = &mut self.mem_output.dest {
+            let vec = mem::take(&mut dest.writer_mut().0);
+            self.free_mem_dest();
+            return if vec.is_empty() { Err(()) } else { Ok(vec) };
+        }
+        if self.mem_output.buffer.is_null() || 0 == self.mem_output.size {
             return Err(());
         }
         unsafe {
-            let slice = slice::from_raw_parts(self.outbuffer, self.outsize as usize);
+            let slice = slice::from_raw_parts(self.mem_output.buffer, self.mem_output.size as usize);
             let mut vec = Vec::new();
             let res = vec.try_reserve(slice.len());
             if res.is_ok() {
@@ -475,15 +1357,287 @@ impl Compress {
     }
 }
 
+/// Compression in progress, after `start_compress()` or `write_coefficients()`.
+///
+/// Settings can't be changed any more. Write markers, then all the scanlines, and call `finish_compress()`.
+pub struct CompressStarted {
+    comp: Compress,
+}
+
+impl CompressStarted {
+    /// Add a marker to compressed file
+    ///
+    /// Data is max 64KB. Markers can be written only before any scanlines.
+    pub fn write_marker(&mut self, marker: Marker, data: &[u8]) -> io::Result<()> {
+        if let Some(target) = &mut self.comp.target_size {
+            target.markers.try_reserve(1).map_err(|_| io::ErrorKind::OutOfMemory)?;
+            target.markers.push((marker, try_to_vec(data)?));
+        }
+        self.comp.catch(|cinfo| unsafe {
+            ffi::jpeg_write_marker(
+                cinfo,
+                marker.into(),
+                data.as_ptr(),
+                data.len() as c_uint,
+            );
+        })
+    }
+
+    /// Writes the ICC color profile, split into as many numbered APP2 markers as needed.
+    ///
+    /// Same requirements as `write_marker()`. Fails if the profile is empty or larger than 255 markers (about 16MB).
+    pub fn write_icc_profile(&mut self, profile: &[u8]) -> io::Result<()> {
+        // 65535 minus the length field, prefix, and sequence numbers
+        const MAX_CHUNK_LEN: usize = 65533 - ICC_PREFIX.len() - 2;
+        let count = u8::try_from(profile.len().div_ceil(MAX_CHUNK_LEN)).ok().filter(|&n| n > 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad ICC profile size"))?;
+        let mut marker = Vec::new();
+        marker.try_reserve_exact(ICC_PREFIX.len() + 2 + MAX_CHUNK_LEN.min(profile.len())).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        for (seq, chunk) in (1..=count).zip(profile.chunks(MAX_CHUNK_LEN)) {
+            marker.clear();
+            marker.extend_from_slice(ICC_PREFIX);
+            marker.extend_from_slice(&[seq, count]);
+            marker.extend_from_slice(chunk);
+            self.write_marker(Marker::APP(2), &marker)?;
+        }
+        Ok(())
+    }
+
+    /// Writes each string as a COM marker. Same requirements as `write_marker()`.
+    pub fn write_comments<S: AsRef<str>>(&mut self, comments: impl IntoIterator<Item = S>) -> io::Result<()> {
+        for comment in comments {
+            self.write_marker(Marker::COM, comment.as_ref().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Read-only view of component information
+    pub fn components(&self) -> &[CompInfo] {
+        self.comp.components()
+    }
+
+    /// Returns true if all lines in image_src (not necessarily all lines of the image) were written
+    ///
+    /// Fails if `image_src` ends in the middle of a row.
+    ///
+    /// ## Panics
+    ///
+    /// If raw data input is enabled.
+    #[track_caller]
+    pub fn write_scanlines(&mut self, image_src: &[u8]) -> io::Result<bool> {
+        let byte_width = self.comp.byte_width();
+        check_whole_rows(image_src, byte_width)?;
+        self.comp.write_row_slices(image_src.chunks(byte_width))
+    }
+
+    /// Like `write_scanlines()`, but rows in `image_src` are in bottom-up order (last row first), as in BMP files.
+    ///
+    /// Rows are written starting from the end of `image_src`. If the image is written in multiple calls,
+    /// pass the end of the buffer first.
+    #[track_caller]
+    pub fn write_scanlines_bottom_up(&mut self, image_src: &[u8]) -> io::Result<bool> {
+        let byte_width = self.comp.byte_width();
+        check_whole_rows(image_src, byte_width)?;
+        self.comp.write_row_slices(image_src.chunks(byte_width).rev())
+    }
+
+    /// Writes rows as they're produced by the iterator, e.g. from a scanner or a decoder of another format,
+    /// so the whole image never has to be in memory. Each row must be exactly `width * input components` bytes.
+    ///
+    /// Returns true if all rows were written, and false if the image was complete before the iterator ended.
+    pub fn write_rows<R: AsRef<[u8]>>(&mut self, rows: impl IntoIterator<Item = R>) -> io::Result<bool> {
+        let byte_width = self.comp.byte_width();
+        for row in rows {
+            let row = row.as_ref();
+            if row.len() != byte_width {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("row is {} bytes, expected {byte_width}", row.len())));
+            }
+            if !self.comp.write_row_slices(std::iter::once(row))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Like `write_scanlines()`, but rows start every `bytes_per_row` bytes, e.g. in 4-byte aligned bitmaps or GPU readbacks.
+    ///
+    /// Bytes after the pixels of each row are ignored. The last row doesn't need to be padded.
+    /// Fails if `bytes_per_row` is smaller than a row of pixels, or `image_src` ends in the middle of a row.
+    #[track_caller]
+    pub fn write_scanlines_strided(&mut self, image_src: &[u8], bytes_per_row: usize) -> io::Result<bool> {
+        let byte_width = self.comp.byte_width();
+        let last_row_len = image_src.len() % bytes_per_row.max(1);
+        if bytes_per_row < byte_width || (last_row_len != 0 && last_row_len < byte_width) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "rows are shorter than the image width"));
+        }
+        self.comp.write_row_slices(image_src.chunks(bytes_per_row).map(|row| &row[..byte_width]))
+    }
+
+    /// Like `write_scanlines()`, but takes pixels of any "plain old data" type, e.g. `rgb::RGB8` or `[u8; 4]`.
+    ///
+    /// ## Panics
+    ///
+    /// If the size of `T` isn't the number of input components.
+    #[track_caller]
+    pub fn write_pixels<T: rgb::Pod>(&mut self, pixels: &[T]) -> io::Result<bool> {
+        assert_eq!(self.comp.cinfo.input_components as usize, mem::size_of::<T>());
+        // Pod types can be used as bytes
+        let bytes = unsafe { slice::from_raw_parts(pixels.as_ptr().cast::<u8>(), mem::size_of_val(pixels)) };
+        self.write_scanlines(bytes)
+    }
+
+    /// Advanced. Only possible after `set_raw_data_in()`.
+    /// Write YCbCr blocks pixels instead of usual color space
+    ///
+    /// See `raw_data_in` in libjpeg docs
+    ///
+    /// Each plane is a whole component, with rows `row_stride()` bytes wide.
+    /// Planes can be `col_stride()` rows tall, or have the exact height of the (subsampled) component,
+    /// and then the last row is repeated to fill the last row of blocks, like libjpeg does for non-raw input.
+    ///
+    /// Fails if raw write wasn't enabled, or the planes don't match the components.
+    pub fn write_raw_data(&mut self, image_src: &[&[u8]]) -> io::Result<()> {
+        self.comp.write_raw_data(image_src)
+    }
+
+    /// Writes a whole 4:2:0 video frame (e.g. I420 or NV12 from a camera or a video decoder).
+    ///
+    /// Needs `set_raw_data_in(true)` and the default YCbCr 4:2:0 subsampling (e.g. `Compress::new(ColorSpace::JCS_YCbCr)`).
+    pub fn write_yuv420(&mut self, frame: &[u8], format: YuvFormat) -> io::Result<()> {
+        let comps = self.components();
+        let samp: Vec<_> = comps.iter().map(|c| (c.h_samp_factor, c.v_samp_factor)).collect();
+        if self.comp.cinfo.jpeg_color_space != ColorSpace::JCS_YCbCr || samp != [(2, 2), (1, 1), (1, 1)] {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the JPEG must be YCbCr with 4:2:0 subsampling"));
+        }
+        let row_strides = [0, 1, 2].map(|ci| comps[ci].row_stride());
+        let size = (self.comp.cinfo.image_width as usize, self.comp.cinfo.image_height as usize);
+        let mut planes = yuv::from_yuv420(frame, format, size, row_strides)?;
+        let res = self.write_raw_data(&[&planes[0], &planes[1], &planes[2]]);
+        planes.iter_mut().for_each(|plane| wipe(plane));
+        res
+    }
+
+    /// The writer given to `set_writer()`, while compression is in progress. `None` if it was of a different type.
+    ///
+    /// Output can be streamed without blocking (e.g. to an async writer) by setting a `Vec<u8>` as the writer,
+    /// and taking the bytes written so far with `mem::take()` after every `write_scanlines()`.
+    /// Writes happen in chunks of 16KB, and the rest is written in `finish_compress()`, to get with `take_writer()`.
+    /// Progressive mode and optimized Huffman tables need the whole image first, so then everything is written at the end.
+    pub fn writer_mut<W: Write + 'static>(&mut self) -> Option<&mut W> {
+        let dest = self.comp.writer_dest.as_mut()?.downcast_mut::<DestinationMgr<W>>()?;
+        Some(dest.writer_mut())
+    }
+
+    /// Finalize compression.
+    /// In case of progressive files, this may actually start processing.
+    ///
+    /// Returns the `Compress` with the same settings, to get the data with `data_to_vec()`, or to compress another image.
+    /// The instance, its tables and memory can be reused for any number of images: set the next image's size
+    /// and a destination (`set_mem_dest()` again after `data_to_vec()`), and call `start_compress()`.
+    /// If the data hasn't been taken, the next image replaces it.
+    pub fn finish_compress(mut self) -> io::Result<Compress> {
+        let comp = &mut self.comp;
+        comp.catch(|cinfo| unsafe { ffi::jpeg_finish_compress(cinfo) })?;
+        // closes the file of `to_file()`
+        comp.take_writer::<FileDest>();
+        if let Some(mut target) = comp.target_size.take() {
+            // the distortion map needs the input only once
+            let distortion_input = comp.distortion_input.take();
+            let res = comp.fit_target_size(&target);
+            comp.distortion_input = distortion_input;
+            target.clear();
+            comp.target_size = Some(target);
+            res?;
+        }
+        if let Some(mut input) = comp.distortion_input.take() {
+            let mcu_size = (
+                comp.cinfo.max_h_samp_factor as usize * DCTSIZE,
+                comp.cinfo.max_v_samp_factor as usize * DCTSIZE,
+            );
+            let color_space = comp.cinfo.in_color_space;
+            comp.distortion_map = comp.data_as_mut_slice().ok()
+                .and_then(|jpeg| DistortionMap::new(&input, jpeg, color_space, mcu_size).ok());
+            wipe(&mut input);
+        }
+        Ok(self.comp)
+    }
+
+    /// Abandons the image, and returns the `Compress` to compress another one, like `finish_compress()`.
+    ///
+    /// The incomplete output of `set_mem_dest()` is discarded. A writer keeps what has been written so far.
+    pub fn abort_compress(mut self) -> io::Result<Compress> {
+        let comp = &mut self.comp;
+        comp.catch(|cinfo| unsafe { ffi::jpeg_abort_compress(cinfo) })?;
+        comp.take_writer::<FileDest>();
+        comp.free_mem_dest();
+        if let Some(target) = &mut comp.target_size {
+            target.clear();
+        }
+        if let Some(mut input) = comp.distortion_input.take() {
+            wipe(&mut input);
+        }
+        Ok(self.comp)
+    }
+}
+
+/// Output of `jpeg_mem_dest`, set in `finish_compress()`
+struct MemOutput {
+    buffer: *mut c_uchar,
+    size: c_ulong,
+    /// Used instead of `jpeg_mem_dest` with the `zeroize` feature
+    #[cfg(feature = "zeroize")]
+    dest: Option<Box<DestinationMgr<WipingVec>>>,
+}
+
+/// Destination of `to_file()`
+struct FileDest(BufWriter<File>);
+
+impl Write for FileDest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Checks up front, so that a partial row doesn't get compressed before the error
+fn check_whole_rows(image_src: &[u8], byte_width: usize) -> io::Result<()> {
+    if !image_src.len().is_multiple_of(byte_width) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} bytes is not a multiple of the row size {byte_width}", image_src.len())));
+    }
+    Ok(())
+}
+
 impl Drop for Compress {
     fn drop(&mut self) {
         self.free_mem_dest();
+        if let Some(input) = &mut self.distortion_input {
+            wipe(input);
+        }
+        if let Some(target) = &mut self.target_size {
+            target.clear();
+        }
         unsafe {
             ffi::jpeg_destroy_compress(&mut self.cinfo);
         }
     }
 }
 
+/// Compresses the whole image to memory. `setup` changes the settings after `set_size()`.
+#[cfg(test)]
+#[track_caller]
+pub(crate) fn compress_for_test(color_space: ColorSpace, width: usize, height: usize, pixels: &[u8], setup: impl FnOnce(&mut Compress)) -> Vec<u8> {
+    let mut cinfo = Compress::new(color_space);
+    cinfo.set_size(width, height);
+    setup(&mut cinfo);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(pixels).unwrap());
+    cinfo.finish_compress().unwrap().data_to_vec().unwrap()
+}
+
 #[test]
 fn write_mem() {
     let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
@@ -518,9 +1672,9 @@ fn write_mem() {
         assert_eq!(c.h_samp_factor, samp);
     }
 
-    cinfo.start_compress();
+    let mut cinfo = cinfo.start_compress().unwrap();
 
-    cinfo.write_marker(Marker::APP(2), "Hello World".as_bytes());
+    cinfo.write_marker(Marker::APP(2), "Hello World".as_bytes()).unwrap();
 
     assert_eq!(24, cinfo.components()[0].row_stride());
     assert_eq!(40, cinfo.components()[0].col_stride());
@@ -535,9 +1689,9 @@ fn write_mem() {
         .map(|c| vec![128u8; c.row_stride() * c.col_stride()])
         .collect::<Vec<_>>();
 
-    assert!(cinfo.write_raw_data(&bitmaps.iter().map(|c| &c[..]).collect::<Vec<_>>()));
+    cinfo.write_raw_data(&bitmaps.iter().map(|c| &c[..]).collect::<Vec<_>>()).unwrap();
 
-    cinfo.finish_compress();
+    let mut cinfo = cinfo.finish_compress().unwrap();
 
     cinfo.data_to_vec().unwrap();
 }
@@ -552,12 +1706,844 @@ fn convert_colorspace() {
     cinfo.set_quality(44.);
 
     cinfo.set_mem_dest();
-    cinfo.start_compress();
+    let mut cinfo = cinfo.start_compress().unwrap();
 
     let scanlines = vec![127u8; 33 * 15 * 3];
-    assert!(cinfo.write_scanlines(&scanlines));
+    assert!(cinfo.write_scanlines(&scanlines).unwrap());
 
-    cinfo.finish_compress();
+    let mut cinfo = cinfo.finish_compress().unwrap();
 
     cinfo.data_to_vec().unwrap();
 }
+
+#[test]
+fn fixed_huffman_tables() {
+    use crate::segments::Segments;
+
+    let pixels: Vec<u8> = (0..64 * 48 * 3).map(|i| (i * 7 % 251) as u8).collect();
+    let encode = |setup: &dyn Fn(&mut Compress)| compress_for_test(ColorSpace::JCS_RGB, 64, 48, &pixels, setup);
+    let dht = |jpeg: &[u8]| Segments::new(jpeg).unwrap().map(Result::unwrap)
+        .filter(|s| s.marker == 0xC4).map(|s| s.data.to_vec()).collect::<Vec<_>>().concat();
+    let table_bytes = |class: u8, table: &HuffmanTable| [&[class][..], table.bits(), table.values()].concat();
+
+    let fixed = encode(&|c| c.set_fixed_huffman_tables());
+    let fixed_dht = dht(&fixed);
+    assert!(fixed_dht.windows(179).any(|w| w == table_bytes(0x10, &HuffmanTable::std_luma_ac())));
+    assert_eq!(1, Segments::new(&fixed).unwrap().filter(|s| s.as_ref().unwrap().marker == crate::segments::SOS).count());
+    assert_ne!(fixed_dht, dht(&encode(&|_| {})));
+
+    let custom = encode(&|c| {
+        c.set_fixed_huffman_tables();
+        c.set_huffman_tables(0, &HuffmanTable::std_chroma_dc(), &HuffmanTable::std_chroma_ac());
+    });
+    assert!(dht(&custom).windows(29).any(|w| w == table_bytes(0x00, &HuffmanTable::std_chroma_dc())));
+    crate::Decompress::new_mem(&custom).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+}
+
+#[test]
+fn screen_content_defaults() {
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(32, 16);
+    cinfo.set_screen_content_defaults(85.);
+    assert!(cinfo.components().iter().all(|c| c.sampling() == (1, 1)));
+    let luma = cinfo.qtable(0).unwrap();
+    assert!(luma.coeffs.iter().all(|&q| q == luma.coeffs[0]));
+
+    // black text-like stripes on a colored background
+    let pixels: Vec<u8> = (0..32 * 16).flat_map(|i| if i % 32 % 4 == 0 { [0, 0, 0] } else { [250, 120, 20] }).collect();
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&pixels).unwrap());
+    let mut cinfo = cinfo.finish_compress().unwrap();
+    let jpeg = cinfo.data_to_vec().unwrap();
+
+    let decoded = crate::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+    let max_err = decoded.iter().flatten().zip(&pixels).map(|(&a, &b)| a.abs_diff(b)).max().unwrap();
+    assert!(max_err < 40, "{max_err}");
+}
+
+#[test]
+fn presets() {
+    let pixels: Vec<u8> = (0..48 * 32 * 3).map(|i| (i * 13 % 256) as u8).collect();
+    let encode = |preset| {
+        let mut sampling = (0, 0);
+        let jpeg = compress_for_test(ColorSpace::JCS_RGB, 48, 32, &pixels, |c| {
+            c.set_preset(preset);
+            sampling = c.components()[0].sampling();
+        });
+        let progressive = crate::Decompress::new_mem(&jpeg).unwrap().summary().progressive;
+        (jpeg.len(), sampling, progressive)
+    };
+    let web = encode(Preset::Web);
+    let archival = encode(Preset::Archival);
+    let thumbnail = encode(Preset::Thumbnail);
+    let fastest = encode(Preset::Fastest);
+    assert_eq!(((2, 2), true), (web.1, web.2));
+    assert_eq!(((1, 1), true), (archival.1, archival.2));
+    assert_eq!(((2, 2), false), (thumbnail.1, thumbnail.2));
+    assert_eq!(((2, 2), false), (fastest.1, fastest.2));
+    assert!(archival.0 > web.0);
+    assert!(fastest.0 > web.0);
+    assert_eq!((1, 1), encode(Preset::ScreenContent).1);
+}
+
+#[test]
+fn write_bottom_up() {
+    let (width, height) = (8, 20);
+    let rows: Vec<[u8; 3]> = (0..height).map(|y| [y as u8 * 12, 255 - y as u8 * 12, 128]).collect();
+    let top_down: Vec<u8> = rows.iter().flat_map(|px| px.repeat(width)).collect();
+    let bottom_up: Vec<u8> = rows.iter().rev().flat_map(|px| px.repeat(width)).collect();
+
+    let encode = |data: &[u8], bottom_up: bool| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        if bottom_up {
+            // in two parts, from the end of the buffer
+            let (first, last) = data.split_at(width * 3 * 7);
+            assert!(cinfo.write_scanlines_bottom_up(last).unwrap());
+            assert!(cinfo.write_scanlines_bottom_up(first).unwrap());
+        } else {
+            assert!(cinfo.write_scanlines(data).unwrap());
+        }
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        cinfo.data_to_vec().unwrap()
+    };
+    assert_eq!(encode(&top_down, false), encode(&bottom_up, true));
+}
+
+#[test]
+fn partial_row() {
+    let start = || {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(16, 2);
+        cinfo.set_mem_dest();
+        cinfo.start_compress().unwrap()
+    };
+    let data = [128; 16 * 3 + 5];
+    assert_eq!(io::ErrorKind::InvalidInput, start().write_scanlines(&data).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::InvalidInput, start().write_scanlines_bottom_up(&data).unwrap_err().kind());
+    let pixels = [[128u8; 3]; 16 + 1];
+    assert_eq!(io::ErrorKind::InvalidInput, start().write_pixels(&pixels[..]).unwrap_err().kind());
+
+    // nothing has been written, so the whole image can still be written
+    let mut cinfo = start();
+    assert!(cinfo.write_scanlines(&data).is_err());
+    assert!(cinfo.write_scanlines(&[128; 16 * 3 * 2]).unwrap());
+    cinfo.finish_compress().unwrap();
+}
+
+#[test]
+fn padded_input() {
+    let (width, height) = (24, 16);
+    let rgb: Vec<u8> = (0..width * height).flat_map(|i| [(i * 3) as u8, (i / 2) as u8, 200 - (i % 50) as u8]).collect();
+    let encode = |color_space, pixels: &[u8]| {
+        let mut cinfo = Compress::new(color_space);
+        cinfo.set_size(width, height);
+        cinfo.enable_distortion_map();
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        assert!(cinfo.write_scanlines(pixels).unwrap());
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        let map = cinfo.distortion_map().cloned().unwrap();
+        (cinfo.data_to_vec().unwrap(), map)
+    };
+    let expected = encode(ColorSpace::JCS_RGB, &rgb);
+
+    let rgbx: Vec<u8> = rgb.chunks_exact(3).enumerate().flat_map(|(i, p)| [p[0], p[1], p[2], i as u8]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_RGBX, &rgbx));
+    let rgba: Vec<u8> = rgb.chunks_exact(3).enumerate().flat_map(|(i, p)| [p[0], p[1], p[2], (i * 7) as u8]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_RGBA, &rgba));
+    let xrgb: Vec<u8> = rgb.chunks_exact(3).flat_map(|p| [0, p[0], p[1], p[2]]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_XRGB, &xrgb));
+    let bgrx: Vec<u8> = rgb.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0], 7]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_BGRX, &bgrx));
+}
+
+#[test]
+fn swizzled_input() {
+    let (width, height) = (24, 16);
+    let rgb: Vec<u8> = (0..width * height).flat_map(|i| [(i * 5) as u8, (i / 3) as u8, 100 + (i % 70) as u8]).collect();
+    let encode = |color_space, pixels: &[u8]| compress_for_test(color_space, width, height, pixels, |_| {});
+    let expected = encode(ColorSpace::JCS_RGB, &rgb);
+
+    let bgr: Vec<u8> = rgb.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_BGR, &bgr));
+    let bgra: Vec<u8> = rgb.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0], 255]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_BGRA, &bgra));
+    let argb: Vec<u8> = rgb.chunks_exact(3).flat_map(|p| [9, p[0], p[1], p[2]]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_ARGB, &argb));
+    let abgr: Vec<u8> = rgb.chunks_exact(3).flat_map(|p| [0, p[2], p[1], p[0]]).collect();
+    assert_eq!(expected, encode(ColorSpace::JCS_EXT_ABGR, &abgr));
+    // a row of BGRA is 4 bytes per pixel
+    let mut cinfo = Compress::new(ColorSpace::JCS_EXT_BGRA);
+    cinfo.set_size(width, height);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_rows([&bgr[..width * 3]]).is_err());
+}
+
+#[test]
+fn raw_data_exact_height() {
+    let encode = |padded: bool| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+        cinfo.set_raw_data_in(true);
+        cinfo.set_size(20, 21);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        let bitmaps = cinfo.components().iter().map(|c| {
+            let height = if c.v_samp_factor == 2 { 21 } else { 11 };
+            let rows = if padded { c.col_stride() } else { height };
+            (0..rows * c.row_stride()).map(|i| (i / c.row_stride()).min(height - 1) as u8 * 10).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        cinfo.write_raw_data(&bitmaps.iter().map(|c| &c[..]).collect::<Vec<_>>()).unwrap();
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        cinfo.data_to_vec().unwrap()
+    };
+    assert_eq!(encode(true), encode(false));
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+    cinfo.set_raw_data_in(true);
+    cinfo.set_size(20, 21);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    let short = vec![0; 24 * 20];
+    assert!(cinfo.write_raw_data(&[&short, &short, &short]).is_err());
+    assert!(cinfo.write_raw_data(&[&short]).is_err());
+}
+
+#[test]
+fn write_yuv420() {
+    use crate::Decompress;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let i420 = Decompress::new_mem(&data).unwrap().raw().unwrap().read_yuv420(YuvFormat::I420).unwrap();
+    let nv12 = Decompress::new_mem(&data).unwrap().raw().unwrap().read_yuv420(YuvFormat::NV12).unwrap();
+
+    let encode = |frame: &[u8], format| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+        cinfo.set_size(45, 30);
+        cinfo.set_quality(95.);
+        cinfo.set_raw_data_in(true);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        cinfo.write_yuv420(frame, format).unwrap();
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        cinfo.data_to_vec().unwrap()
+    };
+    let jpeg = encode(&i420, YuvFormat::I420);
+    assert_eq!(jpeg, encode(&nv12, YuvFormat::NV12));
+
+    let decoded = Decompress::new_mem(&jpeg).unwrap().raw().unwrap().read_yuv420(YuvFormat::I420).unwrap();
+    assert_eq!(i420.len(), decoded.len());
+    assert!(i420.iter().zip(&decoded).all(|(&a, &b)| a.abs_diff(b) < 16));
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+    cinfo.set_size(45, 30);
+    cinfo.set_raw_data_in(true);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_yuv420(&i420[..100], YuvFormat::I420).is_err());
+}
+
+#[test]
+fn estimated_header_size() {
+    use crate::segments::Segments;
+
+    let pixels: Vec<u8> = (0..48 * 32 * 3).map(|i| (i * 7 % 251) as u8).collect();
+    let encode = |configure: &dyn Fn(&mut Compress)| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(48, 32);
+        configure(&mut cinfo);
+        let estimate = cinfo.estimated_header_size(&[1000]);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        cinfo.write_marker(Marker::APP(1), &[0; 1000]).unwrap();
+        assert!(cinfo.write_scanlines(&pixels).unwrap());
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        let jpeg = cinfo.data_to_vec().unwrap();
+        let scan_data: usize = Segments::new(&jpeg).unwrap().map(|s| s.unwrap().scan_data.len()).sum();
+        (estimate, jpeg.len() - scan_data)
+    };
+
+    let (estimate, actual) = encode(&|c| c.set_fixed_huffman_tables());
+    assert_eq!(estimate, actual);
+    let (estimate, actual) = encode(&|c| { c.set_fixed_huffman_tables(); c.set_restart_in_rows(1) });
+    assert_eq!(estimate, actual);
+    let (estimate, actual) = encode(&|c| c.set_optimize_scans(false));
+    assert!(estimate >= actual && estimate < actual + 400, "{estimate} {actual}");
+    let (estimate, actual) = encode(&|c| c.set_progressive_mode());
+    assert!(estimate >= actual, "{estimate} {actual}");
+    let (estimate, actual) = encode(&|_| {});
+    assert!(estimate >= actual, "{estimate} {actual}");
+}
+
+#[test]
+fn write_coefficients_losslessly() {
+    use crate::Decompress;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let coefficients = Decompress::new_mem(&data).unwrap().coefficients().unwrap();
+
+    for progressive in [false, true] {
+        let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+        if !progressive {
+            cinfo.set_optimize_scans(false);
+        }
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.write_coefficients(&coefficients).unwrap();
+        // unsaved markers must be skipped correctly when reading back
+        cinfo.write_marker(Marker::APP(2), b"hello").unwrap();
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        let jpeg = cinfo.data_to_vec().unwrap();
+
+        let dinfo = Decompress::new_mem(&jpeg).unwrap();
+        assert_eq!(progressive, dinfo.summary().progressive);
+        let saved = dinfo.coefficients().unwrap();
+        for (a, b) in coefficients.components.iter().zip(&saved.components) {
+            assert_eq!(a.blocks, b.blocks);
+            assert_eq!(a.qtable, b.qtable);
+        }
+    }
+}
+
+#[test]
+fn write_icc_profile() {
+    use crate::Decompress;
+
+    let profile: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(8, 8);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_icc_profile(&[]).is_err());
+    cinfo.write_icc_profile(&profile).unwrap();
+    assert!(cinfo.write_scanlines(&[128; 8 * 8 * 3]).unwrap());
+    let mut cinfo = cinfo.finish_compress().unwrap();
+    let jpeg = cinfo.data_to_vec().unwrap();
+
+    let dinfo = Decompress::with_markers(&[Marker::APP(2)]).from_mem(&jpeg).unwrap();
+    assert_eq!(3, dinfo.markers().count());
+    assert_eq!(Some(profile), dinfo.icc_profile());
+}
+
+#[test]
+fn fatal_errors_are_results() {
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(8, 16);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&[0; 8 * 8 * 3]).unwrap());
+    let err = cinfo.write_marker(Marker::COM, b"too late").unwrap_err();
+    assert!(err.to_string().contains("Improper call"), "{err}");
+    assert_eq!(Some(ffi::JERR_BAD_STATE), Error::from(err).code());
+    let err = cinfo.write_scanlines(&[0; 8 * 8 * 3]).unwrap_err();
+    assert_eq!(io::ErrorKind::Other, err.kind());
+    assert!(matches!(Error::from(err), Error::Poisoned));
+    assert!(cinfo.finish_compress().is_err());
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    cinfo.set_size(8, 8);
+    cinfo.set_scan_script(&[ScanInfo { comps_in_scan: 1, component_index: [0; 4], Ss: 1, Se: 63, Ah: 0, Al: 0 }]);
+    cinfo.set_mem_dest();
+    assert_eq!(io::ErrorKind::InvalidData, cinfo.start_compress().err().unwrap().kind());
+
+    // dropped unfinished after libjpeg has grown the output buffer
+    let pixels: Vec<u8> = (0..256 * 256 * 3).map(|i| (i * 7919 % 251) as u8).collect();
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(256, 256);
+    // writes data as it goes, without buffering the whole image
+    cinfo.set_fastest_defaults();
+    cinfo.set_optimize_coding(false);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&pixels).unwrap());
+}
+
+#[test]
+fn write_to_writer() {
+    struct FailingWriter;
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let pixels: Vec<u8> = (0..256 * 256 * 3u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    let compress = |mut cinfo: Compress| {
+        cinfo.set_size(256, 256);
+        let mut cinfo = cinfo.start_compress()?;
+        assert!(cinfo.write_scanlines(&pixels)?);
+        cinfo.finish_compress()
+    };
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_mem_dest();
+    let expected = compress(cinfo).unwrap().data_to_vec().unwrap();
+    assert!(expected.len() > 16 * 1024);
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_writer(Vec::new());
+    let mut cinfo = compress(cinfo).unwrap();
+    assert!(cinfo.take_writer::<FailingWriter>().is_none());
+    assert_eq!(Some(expected), cinfo.take_writer::<Vec<u8>>());
+    assert!(cinfo.start_compress().is_err());
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_writer(FailingWriter);
+    assert_eq!(io::ErrorKind::BrokenPipe, compress(cinfo).err().unwrap().kind());
+}
+
+#[test]
+fn reuse_after_finish() {
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(8, 8);
+    cinfo.set_quality(70.);
+    let mut images = Vec::new();
+    for value in [10, 250] {
+        cinfo.set_mem_dest();
+        let mut started = cinfo.start_compress().unwrap();
+        started.write_comments(["reused"]).unwrap();
+        assert_eq!(3, started.components().len());
+        assert!(started.write_scanlines(&[value; 8 * 8 * 3]).unwrap());
+        cinfo = started.finish_compress().unwrap();
+        images.push(cinfo.data_to_vec().unwrap());
+    }
+    assert_ne!(images[0], images[1]);
+    let pixels = crate::Decompress::new_mem(&images[1]).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    assert!(pixels.iter().all(|&v| v.abs_diff(250) < 2));
+}
+
+#[test]
+fn write_typed_pixels() {
+    let pixels = vec![rgb::RGB8::new(200, 100, 50); 16 * 8];
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(16, 8);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_pixels(&pixels).unwrap());
+    let jpeg = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+
+    let mut flat = Compress::new(ColorSpace::JCS_RGB);
+    flat.set_size(16, 8);
+    flat.set_mem_dest();
+    let mut flat = flat.start_compress().unwrap();
+    assert!(flat.write_scanlines(&[200, 100, 50].repeat(16 * 8)).unwrap());
+    assert_eq!(jpeg, flat.finish_compress().unwrap().data_to_vec().unwrap());
+
+    // transparent pixels are encoded with their color
+    let mut rgba = Compress::new(ColorSpace::JCS_EXT_RGBA);
+    rgba.set_size(16, 8);
+    rgba.set_mem_dest();
+    let mut rgba = rgba.start_compress().unwrap();
+    assert!(rgba.write_pixels(&vec![rgb::RGBA8::new(200, 100, 50, 0); 16 * 8]).unwrap());
+    assert_eq!(jpeg, rgba.finish_compress().unwrap().data_to_vec().unwrap());
+}
+
+#[test]
+fn write_strided() {
+    let (width, height, stride) = (10, 9, 32);
+    let mut padded = vec![255; stride * (height - 1) + width * 3];
+    let mut packed = Vec::new();
+    for (y, row) in padded.chunks_mut(stride).enumerate() {
+        for (i, v) in row[..width * 3].iter_mut().enumerate() {
+            *v = (y * 20 + i * 5) as u8;
+        }
+        packed.extend_from_slice(&row[..width * 3]);
+    }
+    let compress = |write: &dyn Fn(&mut CompressStarted) -> io::Result<bool>| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        assert!(write(&mut cinfo).unwrap());
+        cinfo.finish_compress().unwrap().data_to_vec().unwrap()
+    };
+    assert_eq!(compress(&|c| c.write_scanlines(&packed)), compress(&|c| c.write_scanlines_strided(&padded, stride)));
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(width, height);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines_strided(&padded, width * 3 - 1).is_err());
+    assert!(cinfo.write_scanlines_strided(&padded[..stride + 5], stride).is_err());
+}
+
+#[test]
+fn write_rows_from_iterator() {
+    let (width, height) = (24, 20);
+    let row = |y: usize| (0..width * 3).map(|x| (x * 7 + y * 11) as u8).collect::<Vec<u8>>();
+    let compress = |write: &dyn Fn(&mut CompressStarted) -> io::Result<bool>| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        assert!(write(&mut cinfo).unwrap());
+        cinfo.finish_compress().unwrap().data_to_vec().unwrap()
+    };
+    let whole: Vec<u8> = (0..height).flat_map(row).collect();
+    assert_eq!(compress(&|c| c.write_scanlines(&whole)), compress(&|c| c.write_rows((0..height).map(row))));
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(width, 2);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_rows([&whole[..width * 3 - 3]]).is_err());
+    assert!(!cinfo.write_rows((0..3).map(row)).unwrap());
+}
+
+#[test]
+fn trellis_settings() {
+    let pixels: Vec<u8> = (0..64 * 64 * 3).map(|i| (i * 31 % 253) as u8).collect();
+    let compress = |setup: &dyn Fn(&mut Compress)| compress_for_test(ColorSpace::JCS_RGB, 64, 64, &pixels, setup);
+    let default = compress(&|_| {});
+    let no_trellis = compress(&|c| c.set_trellis_quant(false));
+    assert!(default.len() < no_trellis.len());
+    let tuned = compress(&|c| {
+        c.set_trellis_quant_dc(false);
+        c.set_trellis_eob_opt(true);
+        c.set_trellis_freq_split(16);
+        c.set_trellis_num_loops(2);
+    });
+    assert_ne!(default, tuned);
+    let detailed = compress(&|c| c.set_lambda_log_scale(17., 16.5));
+    assert!(detailed.len() > default.len());
+    assert_ne!(default, compress(&|c| c.set_trellis_delta_dc_weight(1.)));
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_trellis_freq_split(200);
+    cinfo.set_trellis_num_loops(0);
+    unsafe {
+        assert_eq!(63, ffi::jpeg_c_get_int_param(&cinfo.cinfo, J_INT_PARAM::JINT_TRELLIS_FREQ_SPLIT));
+        assert_eq!(1, ffi::jpeg_c_get_int_param(&cinfo.cinfo, J_INT_PARAM::JINT_TRELLIS_NUM_LOOPS));
+    }
+}
+
+#[test]
+fn quant_table_presets() {
+    use crate::qtable;
+
+    assert_eq!((&qtable::AnnexK_Luma, &qtable::AnnexK_Chroma), QuantPreset::AnnexK.tables());
+    for preset in QuantPreset::ALL {
+        let (luma, chroma) = preset.tables();
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_quant_table_preset(preset);
+        cinfo.set_quality(50.);
+        assert_eq!(Some(luma), cinfo.qtable(0).as_ref(), "{preset:?}");
+        assert_eq!(Some(chroma), cinfo.qtable(1).as_ref(), "{preset:?}");
+    }
+}
+
+#[test]
+fn separate_luma_chroma_quality() {
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_quality(90.);
+    let luma = cinfo.qtable(0);
+    cinfo.set_quality(40.);
+    let chroma = cinfo.qtable(1);
+    cinfo.set_quality_separate(90., 40.);
+    assert_eq!(luma, cinfo.qtable(0));
+    assert_eq!(chroma, cinfo.qtable(1));
+}
+
+#[test]
+fn abbreviated_files() {
+    let has_dqt = |jpeg: &[u8]| jpeg.windows(2).any(|w| w == [0xFF, 0xDB]);
+    let has_dht = |jpeg: &[u8]| jpeg.windows(2).any(|w| w == [0xFF, 0xC4]);
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_fastest_defaults();
+    cinfo.set_size(16, 16);
+    cinfo.set_quality(80.);
+    cinfo.set_mem_dest();
+    cinfo.write_tables().unwrap();
+    let tables = cinfo.data_to_vec().unwrap();
+    assert!(has_dqt(&tables) && has_dht(&tables));
+    assert_eq!(&[0xFF, 0xD9], &tables[tables.len() - 2..]);
+
+    let mut frames = Vec::new();
+    for _ in 0..2 {
+        cinfo.set_mem_dest();
+        let mut started = cinfo.start_compress().unwrap();
+        assert!(started.write_scanlines(&[99; 16 * 16 * 3]).unwrap());
+        cinfo = started.finish_compress().unwrap();
+        frames.push(cinfo.data_to_vec().unwrap());
+    }
+    assert!(frames.iter().all(|frame| !has_dqt(frame) && !has_dht(frame)));
+
+    cinfo.set_suppress_tables(false);
+    cinfo.set_mem_dest();
+    let mut started = cinfo.start_compress().unwrap();
+    assert!(started.write_scanlines(&[99; 16 * 16 * 3]).unwrap());
+    let full = started.finish_compress().unwrap().data_to_vec().unwrap();
+    assert!(has_dqt(&full) && has_dht(&full));
+}
+
+#[test]
+fn write_to_path() {
+    use crate::Decompress;
+
+    let path = std::env::temp_dir().join(format!("mozjpeg-write-to-path-{}.jpg", std::process::id()));
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(8, 8);
+    cinfo.to_path(&path).unwrap();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&[200; 8 * 8 * 3]).unwrap());
+    let cinfo = cinfo.finish_compress().unwrap();
+    // the file has been closed
+    assert!(cinfo.start_compress().is_err());
+
+    let pixels = Decompress::new_path(&path).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(pixels.iter().all(|&v| v.abs_diff(200) < 2));
+}
+
+#[test]
+fn config_builder() {
+    let pixels: Vec<u8> = (0..64 * 48 * 3u32).map(|i| (i * 3 % 256) as u8).collect();
+    let encode = |mut cinfo: Compress| {
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        assert!(cinfo.write_scanlines(&pixels).unwrap());
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        cinfo.data_to_vec().unwrap()
+    };
+
+    // the quality would be reset if it was set before the preset
+    let built = Compress::config().quality(60.).size(64, 48).preset(Preset::Archival).chroma_subsampling((2, 1), (2, 1)).build();
+    assert_eq!([(2, 1), (1, 1), (1, 1)], *built.components().iter().map(|c| (c.h_samp_factor, c.v_samp_factor)).collect::<Vec<_>>());
+    let mut manual = Compress::new(ColorSpace::JCS_RGB);
+    manual.set_preset(Preset::Archival);
+    manual.set_size(64, 48);
+    manual.set_quality(60.);
+    manual.set_chroma_sampling_pixel_sizes((2, 1), (2, 1));
+    assert_eq!(encode(manual), encode(built));
+
+    let gray = Compress::config().input_color_space(ColorSpace::JCS_GRAYSCALE).size(8, 8).baseline().build();
+    assert_eq!(1, gray.components().len());
+    assert!(gray.cinfo.scan_info.is_null());
+}
+
+#[test]
+fn pixel_density() {
+    use crate::{Decompress, DensityUnit, PixelDensity};
+
+    let jpeg = compress_for_test(ColorSpace::JCS_RGB, 8, 8, &[0; 8 * 8 * 3], |c| c.set_pixel_density(DensityUnit::Inch, 300, 150));
+
+    let density = Decompress::new_mem(&jpeg).unwrap().pixel_density().unwrap();
+    assert_eq!(PixelDensity { unit: DensityUnit::Inch, x: 300, y: 150 }, density);
+    assert_eq!(Some((300., 150.)), density.dpi());
+}
+
+#[test]
+fn adobe_marker() {
+    use crate::segments::Segments;
+
+    let compress = |input, output, adobe: Option<bool>| {
+        let jpeg = compress_for_test(input, 8, 8, &[0; 8 * 8 * 4][..8 * 8 * input.num_components()], |c| {
+            c.set_color_space(output);
+            if let Some(adobe) = adobe {
+                c.set_adobe_marker(adobe);
+            }
+        });
+        // color transform code is the last byte
+        Segments::new(&jpeg).unwrap().map(|s| s.unwrap())
+            .find(|s| s.marker == 0xEE && s.data.starts_with(b"Adobe"))
+            .map(|s| *s.data.last().unwrap())
+    };
+    assert_eq!(Some(0), compress(ColorSpace::JCS_CMYK, ColorSpace::JCS_CMYK, None));
+    assert_eq!(Some(2), compress(ColorSpace::JCS_CMYK, ColorSpace::JCS_YCCK, None));
+    assert_eq!(None, compress(ColorSpace::JCS_CMYK, ColorSpace::JCS_CMYK, Some(false)));
+    assert_eq!(None, compress(ColorSpace::JCS_RGB, ColorSpace::JCS_YCbCr, None));
+    assert_eq!(Some(1), compress(ColorSpace::JCS_RGB, ColorSpace::JCS_YCbCr, Some(true)));
+}
+
+#[test]
+fn arithmetic_coding() {
+    use crate::{Decompress, Error};
+
+    let pixels: Vec<u8> = (0..32 * 32 * 3).map(|i| (i * 7 % 253) as u8).collect();
+    let compress = |arith: bool| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        // trellis quantization depends on the cost of entropy coding
+        cinfo.set_fastest_defaults();
+        cinfo.set_size(32, 32);
+        if arith {
+            cinfo.enable_arith_code();
+        }
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress()?;
+        assert!(cinfo.write_scanlines(&pixels).unwrap());
+        Ok::<_, io::Error>(cinfo.finish_compress().unwrap().data_to_vec().unwrap())
+    };
+    let arith = compress(true);
+    if cfg!(not(feature = "arith")) {
+        let err = Error::from(arith.err().unwrap());
+        assert!(matches!(err, Error::Unsupported { .. }), "{err}");
+
+        // SOF10 instead of SOF2
+        let mut jpeg = std::fs::read("tests/test.jpg").unwrap();
+        let sof = jpeg.windows(2).position(|m| m == [0xFF, 0xC2]).unwrap();
+        jpeg[sof + 1] = 0xCA;
+        let dinfo = Decompress::new_mem(&jpeg).unwrap();
+        assert!(dinfo.uses_arithmetic_coding());
+        let err = Error::from(dinfo.rgb().err().unwrap());
+        assert!(matches!(err, Error::Unsupported { .. }), "{err}");
+        return;
+    }
+    let jpeg = arith.unwrap();
+    let dinfo = Decompress::new_mem(&jpeg).unwrap();
+    assert!(dinfo.uses_arithmetic_coding());
+    let decoded = dinfo.rgb().unwrap().read_scanlines_flat().unwrap();
+
+    // entropy coding is lossless, so the pixels are the same as with Huffman coding
+    let huffman = compress(false).unwrap();
+    assert!(!Decompress::new_mem(&huffman).unwrap().uses_arithmetic_coding());
+    assert!(Decompress::new_mem(&huffman).unwrap().rgb().unwrap().read_scanlines_flat().unwrap() == decoded);
+
+    // MozJPEG's default progressive mode with optimized Huffman tables
+    let jpeg = compress_for_test(ColorSpace::JCS_RGB, 32, 32, &pixels, |c| c.enable_arith_code());
+    assert_eq!(pixels.len(), Decompress::new_mem(&jpeg).unwrap().rgb().unwrap().read_scanlines_flat().unwrap().len());
+}
+
+#[test]
+fn memory_limit() {
+    use crate::Error;
+
+    // progressive encoding buffers coefficients of the whole image
+    let compress = |limit| {
+        let mut cinfo = Compress::config().size(256, 256).progressive().max_memory(limit).build();
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress()?;
+        assert!(cinfo.write_scanlines(&[128; 256 * 256 * 3])?);
+        cinfo.finish_compress()?.data_to_vec().map_err(|_| io::Error::other("no data"))
+    };
+    let err = Error::from(compress(100_000).unwrap_err());
+    assert!(matches!(err, Error::OutOfMemory { .. }), "{err}");
+    assert!(compress(10 << 20).is_ok());
+}
+
+#[test]
+fn reuse() {
+    let image = |width: usize, height: usize| -> Vec<u8> { (0..width * height * 3).map(|i| (i * 5 % 251) as u8).collect() };
+    let configure = |cinfo: &mut Compress, width, height| {
+        cinfo.set_size(width, height);
+        cinfo.set_mem_dest();
+    };
+    let fresh = |width, height| {
+        let mut cinfo = Compress::config().quality(80.).build();
+        configure(&mut cinfo, width, height);
+        let mut cinfo = cinfo.start_compress().unwrap();
+        assert!(cinfo.write_scanlines(&image(width, height)).unwrap());
+        cinfo.finish_compress().unwrap().data_to_vec().unwrap()
+    };
+
+    let mut cinfo = Compress::config().quality(80.).build();
+    for (width, height) in [(16, 16), (33, 9), (8, 40)] {
+        configure(&mut cinfo, width, height);
+        let mut started = cinfo.start_compress().unwrap();
+        assert!(started.write_scanlines(&image(width, height)).unwrap());
+        cinfo = started.finish_compress().unwrap();
+        assert_eq!(fresh(width, height), cinfo.data_to_vec().unwrap());
+    }
+    // the buffer has been taken
+    let err = cinfo.start_compress().err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+    // the old destination is still set, but the next image must not be appended to the previous one
+    let mut cinfo = Compress::config().quality(80.).build();
+    configure(&mut cinfo, 16, 16);
+    for _ in 0..2 {
+        let mut started = cinfo.start_compress().unwrap();
+        assert!(started.write_scanlines(&image(16, 16)).unwrap());
+        cinfo = started.finish_compress().unwrap();
+    }
+    assert_eq!(fresh(16, 16), cinfo.data_to_vec().unwrap());
+
+    let mut cinfo = Compress::config().quality(80.).build();
+    configure(&mut cinfo, 20, 20);
+    let mut started = cinfo.start_compress().unwrap();
+    assert!(started.write_scanlines(&image(20, 3)).unwrap());
+    let mut cinfo = started.abort_compress().unwrap();
+    assert!(cinfo.data_to_vec().is_err());
+    configure(&mut cinfo, 12, 7);
+    let mut started = cinfo.start_compress().unwrap();
+    assert!(started.write_scanlines(&image(12, 7)).unwrap());
+    assert_eq!(fresh(12, 7), started.finish_compress().unwrap().data_to_vec().unwrap());
+}
+
+#[test]
+fn stream_output() {
+    let pixels: Vec<u8> = (0..256 * 256 * 3).map(|i| (i * 13 % 255) as u8).collect();
+    let expected = compress_for_test(ColorSpace::JCS_RGB, 256, 256, &pixels, |c| {
+        c.set_fastest_defaults();
+        c.set_optimize_coding(false);
+    });
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(256, 256);
+    cinfo.set_fastest_defaults();
+    cinfo.set_optimize_coding(false);
+    cinfo.set_writer(Vec::<u8>::new());
+    let mut started = cinfo.start_compress().unwrap();
+    assert!(started.writer_mut::<File>().is_none());
+    let mut streamed = Vec::new();
+    let mut chunks = 0;
+    for rows in pixels.chunks(256 * 3 * 16) {
+        assert!(started.write_scanlines(rows).unwrap());
+        let written = std::mem::take(started.writer_mut::<Vec<u8>>().unwrap());
+        chunks += usize::from(!written.is_empty());
+        streamed.extend_from_slice(&written);
+    }
+    let mut cinfo = started.finish_compress().unwrap();
+    streamed.extend_from_slice(&cinfo.take_writer::<Vec<u8>>().unwrap());
+    assert!(chunks > 1);
+    assert_eq!(expected, streamed);
+}
+
+#[test]
+fn target_size() {
+    use crate::Decompress;
+
+    let (width, height) = (64, 64);
+    let pixels: Vec<u8> = (0..width * height * 3).map(|i| (i * 7 % 251 + i / (width * 3)) as u8).collect();
+    let encode = |target: Option<(usize, usize)>, quality: f32| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.set_quality(quality);
+        if let Some((bytes, tolerance)) = target {
+            cinfo.set_target_size(bytes, tolerance);
+        }
+        cinfo.set_mem_dest();
+        let mut started = cinfo.start_compress().unwrap();
+        started.write_comments(["hello"]).unwrap();
+        assert!(started.write_scanlines(&pixels).unwrap());
+        started.finish_compress().unwrap().data_to_vec().unwrap()
+    };
+    let small = encode(None, 20.).len();
+    let large = encode(None, 95.).len();
+    let target = (small + large) / 2;
+
+    for start_quality in [10., 100.] {
+        let jpeg = encode(Some((target, target / 10)), start_quality);
+        assert!(jpeg.len() <= target && jpeg.len() >= target - target / 10, "{} {target}", jpeg.len());
+        let decompress = Decompress::with_markers(&[Marker::COM]).from_mem(&jpeg).unwrap();
+        assert_eq!(b"hello", decompress.markers().next().unwrap().data);
+        assert_eq!(pixels.len(), decompress.rgb().unwrap().read_scanlines_flat().unwrap().len());
+    }
+    // already fits
+    assert_eq!(encode(None, 20.), encode(Some((small, small)), 20.));
+    // impossible
+    let smallest = encode(Some((10, 0)), 90.);
+    assert_eq!(encode(None, 1.), smallest);
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(width, height);
+    cinfo.set_target_size(1000, 100);
+    cinfo.set_writer(Vec::<u8>::new());
+    assert_eq!(io::ErrorKind::InvalidInput, cinfo.start_compress().err().unwrap().kind());
+}
@@ -1,27 +1,43 @@
 //! See the `Decompress` struct instead. You don't need to use this module directly.
 use std::io::BufRead;
 use std::io::BufReader;
-use crate::readsrc::SourceMgr;
+use crate::push::PushDecoder;
+use crate::readsrc::{PushSource, SourceMgr};
 use crate::ffi;
 use crate::ffi::jpeg_decompress_struct;
 use crate::ffi::DCTSIZE;
 use crate::ffi::JPEG_LIB_VERSION;
 use crate::ffi::J_COLOR_SPACE as COLOR_SPACE;
-use std::os::raw::{c_int, c_uchar, c_ulong, c_void};
+use std::os::raw::{c_int, c_long, c_uchar, c_ulong, c_void};
 use crate::colorspace::ColorSpace;
 use crate::colorspace::ColorSpaceExt;
+use crate::coefficients::{Coefficients, ComponentCoefficients};
 use crate::component::CompInfo;
 use crate::component::CompInfoExt;
+use crate::error::Error;
 use crate::errormgr::ErrorMgr;
 use crate::errormgr::unwinding_error_mgr;
-use crate::marker::Marker;
-use crate::vec::VecUninitExtender;
+use crate::errormgr::{catch_libjpeg, error, ErrorState, MessageHandler};
+use crate::downscale::{Downscaler, ScaledImage};
+use crate::exif::Orientation;
+use crate::density::PixelDensity;
+use crate::tiles::Tiles;
+use crate::hidden;
+use crate::marker::{Marker, ICC_PREFIX};
+use crate::progress::{Progress, ProgressMonitor};
+use crate::huffman::HuffmanTable;
+use crate::qtable::QTable;
+use crate::ScanInfo;
+use crate::vec::{try_filled, wipe};
+use crate::yuv::{self, Plane, YuvFormat};
 use libc::fdopen;
+use std::borrow::Cow;
 use std::cmp::min;
 use std::fs::File;
 use std::io;
 use std::marker::PhantomData;
 use std::mem;
+use std::mem::MaybeUninit;
 use std::path::Path;
 use std::ptr;
 use std::slice;
@@ -58,10 +74,34 @@ pub enum DctMethod {
     Float,
 }
 
+/// Dithering of colors reduced to a palette
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DitherMode {
+    /// Each pixel gets the nearest color
+    None,
+    /// Fast, but with a visible pattern. Only for single-pass quantization.
+    Ordered,
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+}
+
 /// Use `Decompress` static methods instead of creating this directly
 pub struct DecompressConfig<'markers> {
     save_markers: &'markers [Marker],
     err: Option<ErrorMgr>,
+    message_handler: Option<MessageHandler>,
+    /// Tables-only file, read before the abbreviated image
+    tables: Option<&'markers [u8]>,
+    max_memory: Option<usize>,
+    max_dimensions: Option<(usize, usize)>,
+    max_pixels: Option<usize>,
+}
+
+impl Default for DecompressConfig<'_> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'markers> DecompressConfig<'markers> {
@@ -70,30 +110,88 @@ impl<'markers> DecompressConfig<'markers> {
         DecompressConfig {
             err: None,
             save_markers: NO_MARKERS,
+            message_handler: None,
+            tables: None,
+            max_memory: None,
+            max_dimensions: None,
+            max_pixels: None,
         }
     }
 
     #[inline]
-    fn create<'a>(self) -> Decompress<'a> {
-        let mut d = Decompress::new_err(self.err.unwrap_or_else(unwinding_error_mgr));
+    fn create<'a>(self) -> io::Result<Decompress<'a>> {
+        let mut d = Decompress::new_err(self.err.unwrap_or_else(unwinding_error_mgr))?;
+        if let Some(handler) = self.message_handler {
+            let handler = d.message_handler.insert(Box::new(handler));
+            unsafe { handler.install(&mut d.cinfo.common); }
+        }
+        if let Some(bytes) = self.max_memory {
+            unsafe { (*d.cinfo.common.mem).max_memory_to_use = bytes.try_into().unwrap_or(c_long::MAX); }
+        }
         for &marker in self.save_markers {
-            d.save_marker(marker);
+            d.save_marker(marker)?;
         }
-        d
+        Ok(d)
     }
 
+    /// Uses a custom error manager. Its `error_exit` must unwind or abort, and its unwinding panics
+    /// are propagated instead of being returned as `Err`.
     #[inline]
     pub fn with_err(mut self, err: ErrorMgr) -> Self {
         self.err = Some(err);
         self
     }
 
+    /// Calls `handler(level, message)` for libjpeg's warnings and trace messages, instead of ignoring them,
+    /// e.g. to send "Premature end of JPEG file" to your logger.
+    ///
+    /// Warnings about corrupted data have level `-1`. Informational trace messages have levels 0 and higher (up to 3 for the most verbose),
+    /// and only messages with level up to `max_level` are passed to the handler. Panics in the handler are ignored.
+    #[inline]
+    pub fn with_message_handler(mut self, max_level: i32, handler: impl FnMut(i32, &str) + 'static) -> Self {
+        self.message_handler = Some(MessageHandler { max_level, callback: Box::new(handler) });
+        self
+    }
+
     #[inline]
     pub fn with_markers(mut self, save_markers: &'markers [Marker]) -> Self {
         self.save_markers = save_markers;
         self
     }
 
+    /// Reads quantization and Huffman tables from a tables-only file (e.g. from `Compress::write_tables()`) first,
+    /// so that an abbreviated file without the tables can be decoded. Tables in the image override these.
+    #[inline]
+    pub fn with_tables(mut self, tables: &'markers [u8]) -> Self {
+        self.tables = Some(tables);
+        self
+    }
+
+    /// Limits memory libjpeg can use for whole-image buffers, which progressive files and `coefficients()` need.
+    /// Decoding fails with `Error::OutOfMemory` if more is needed.
+    ///
+    /// Only a few small buffers proportional to the image width are allocated outside of the limit,
+    /// and with the `rust_alloc` feature all allocations count towards it.
+    #[inline]
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Rejects images wider or taller than this, right after reading the header, before any pixels are decoded.
+    #[inline]
+    pub fn max_dimensions(mut self, width: usize, height: usize) -> Self {
+        self.max_dimensions = Some((width, height));
+        self
+    }
+
+    /// Rejects images with more than this many pixels (width × height), right after reading the header.
+    #[inline]
+    pub fn max_pixels(mut self, pixels: usize) -> Self {
+        self.max_pixels = Some(pixels);
+        self
+    }
+
     #[inline]
     pub fn from_path<P: AsRef<Path>>(self, path: P) -> io::Result<Decompress<'static>> {
         self.from_file(File::open(path)?)
@@ -112,15 +210,36 @@ impl<'markers> DecompressConfig<'markers> {
         self.from_reader(mem)
     }
 
+    /// Decodes data pushed in chunks as it arrives, e.g. from the network, instead of reading from a blocking reader.
+    /// Scanlines are converted to `out_color_space`.
+    pub fn push_decoder(self, out_color_space: ColorSpace) -> io::Result<PushDecoder> {
+        let tables = self.tables;
+        let (max_dimensions, max_pixels) = (self.max_dimensions, self.max_pixels);
+        let mut d = self.create()?;
+        d.max_dimensions = max_dimensions;
+        d.max_pixels = max_pixels;
+        if let Some(tables) = tables {
+            d.read_tables(tables)?;
+        }
+        PushSource::set_src(&mut d.cinfo).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        d.own_src = d.cinfo.src.cast();
+        Ok(PushDecoder::new_internal(d, out_color_space))
+    }
+
     /// Takes `BufReader`. If you have `io::Read`, wrap it in `io::BufReader::new(read)`.
     ///
     /// Requires `Send + Sync`, because `B` gets type-erased.
     #[inline]
     pub fn from_reader<'src, B: BufRead + 'src + Send + Sync>(self, mem: B) -> io::Result<Decompress<'src>> {
-        let mut d = self.create();
-        SourceMgr::set_src(&mut d.cinfo, mem).map_err(|_| io::ErrorKind::OutOfMemory)?;
-        d.own_src = d.cinfo.src.cast();
-        d.read_header()?;
+        let tables = self.tables;
+        let (max_dimensions, max_pixels) = (self.max_dimensions, self.max_pixels);
+        let mut d = self.create()?;
+        d.max_dimensions = max_dimensions;
+        d.max_pixels = max_pixels;
+        if let Some(tables) = tables {
+            d.read_tables(tables)?;
+        }
+        d.start_reading(mem)?;
         Ok(d)
     }
 }
@@ -129,6 +248,9 @@ impl<'markers> DecompressConfig<'markers> {
 ///
 /// High-level wrapper for `jpeg_decompress_struct`
 ///
+/// MozJPEG is built for 8-bit samples only, and has no lossless mode.
+/// 12-bit and lossless files (e.g. from DICOM or raw cameras) fail with `Error::Unsupported`.
+///
 /// ```rust
 /// # use mozjpeg::*;
 /// # fn t() -> std::io::Result<()> {
@@ -137,7 +259,16 @@ impl<'markers> DecompressConfig<'markers> {
 /// ```
 pub struct Decompress<'src> {
     cinfo: jpeg_decompress_struct,
-    own_error: Box<ErrorMgr>,
+    own_error: Box<ErrorState>,
+    /// Referenced by `cinfo.client_data`
+    message_handler: Option<Box<MessageHandler>>,
+    /// Referenced by `cinfo.progress`
+    progress: Option<Box<ProgressMonitor>>,
+    /// libjpeg's state is inconsistent after a fatal error, so it can't be called again
+    failed: bool,
+    /// From `DecompressConfig`, checked after reading every header
+    max_dimensions: Option<(usize, usize)>,
+    max_pixels: Option<usize>,
 
     // This is non-owning used to double-check that cinfo->src is ours
     own_src: *const c_void,
@@ -175,6 +306,30 @@ impl<'a> Iterator for MarkerIter<'a> {
     }
 }
 
+/// Entropy coding method of the file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntropyCoding {
+    Huffman,
+    Arithmetic,
+}
+
+/// Overview of the file's structure, see `Decompress::summary()`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub width: usize,
+    pub height: usize,
+    /// Bits per sample, usually 8
+    pub precision: u8,
+    /// Color space of the JPEG data (not the output)
+    pub color_space: ColorSpace,
+    /// h,v sampling factors of each component
+    pub sampling: Vec<(u8, u8)>,
+    pub entropy_coding: EntropyCoding,
+    pub progressive: bool,
+    /// Type and length of markers. Only markers enabled via `with_markers()` are listed.
+    pub markers: Vec<(Marker, usize)>,
+}
+
 impl<'src> Decompress<'src> {
     #[inline]
     pub fn with_err(err: ErrorMgr) -> DecompressConfig<'static> {
@@ -186,6 +341,12 @@ impl<'src> Decompress<'src> {
         Self::config().with_markers(save_markers)
     }
 
+    /// Decodes abbreviated files, which don't have tables, see `DecompressConfig::with_tables()`
+    #[inline]
+    pub fn with_tables(tables: &[u8]) -> DecompressConfig<'_> {
+        Self::config().with_tables(tables)
+    }
+
     #[inline]
     /// Decode file at path
     pub fn new_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
@@ -209,21 +370,51 @@ impl<'src> Decompress<'src> {
     }
 
     #[inline]
-    fn new_err(err: ErrorMgr) -> Self {
+    fn new_err(err: ErrorMgr) -> io::Result<Self> {
         unsafe {
             let mut newself = Decompress {
                 cinfo: mem::zeroed(),
                 own_src: ptr::null(),
-                own_error: Box::new(err),
+                own_error: ErrorState::new(err),
+                message_handler: None,
+                progress: None,
+                failed: false,
+                max_dimensions: None,
+                max_pixels: None,
                 _mem_marker: PhantomData,
             };
-            newself.cinfo.common.err = &mut *newself.own_error;
+            newself.cinfo.common.err = &mut newself.own_error.mgr;
 
             let s = mem::size_of_val(&newself.cinfo);
-            ffi::jpeg_CreateDecompress(&mut newself.cinfo, JPEG_LIB_VERSION, s);
+            newself.catch(|cinfo| ffi::jpeg_CreateDecompress(cinfo, JPEG_LIB_VERSION, s))?;
+            #[cfg(feature = "rust_alloc")]
+            crate::memory::install(&mut newself.cinfo.common);
+
+            Ok(newself)
+        }
+    }
 
-            newself
+    /// Calls `callback` periodically during decompression, with progress of the current pass.
+    ///
+    /// Set it before starting decompression. Return `false` from the callback to cancel decoding,
+    /// which then fails with `Error::Cancelled`.
+    pub fn set_progress_callback(&mut self, callback: impl FnMut(Progress) -> bool + 'static) {
+        let monitor = self.progress.insert(ProgressMonitor::new(Box::new(callback)));
+        unsafe {
+            monitor.install(&mut self.cinfo.common);
+        }
+    }
+
+    /// Runs libjpeg calls, and returns libjpeg's fatal errors as `Err`.
+    ///
+    /// After an error all further calls fail, instead of using libjpeg's inconsistent state.
+    fn catch<R>(&mut self, f: impl FnOnce(&mut jpeg_decompress_struct) -> R) -> io::Result<R> {
+        if self.failed {
+            return Err(Error::Poisoned.into());
         }
+        let res = catch_libjpeg(|| f(&mut self.cinfo));
+        self.failed = res.is_err();
+        res
     }
 
     #[inline]
@@ -238,14 +429,175 @@ impl<'src> Decompress<'src> {
         }
     }
 
-    /// Result here is mostly useless, because it will panic if the file is invalid
+    /// Sets the source, and reads the header
+    fn start_reading<B: BufRead + 'src + Send + Sync>(&mut self, reader: B) -> io::Result<()> {
+        SourceMgr::set_src(&mut self.cinfo, reader).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.own_src = self.cinfo.src.cast();
+        self.read_header()?;
+        self.check_size()
+    }
+
+    /// Enforces `DecompressConfig::max_dimensions()` and `max_pixels()`
+    fn check_size(&self) -> io::Result<()> {
+        let (width, height) = self.size();
+        if self.max_dimensions.is_some_and(|(w, h)| width > w || height > h) || self.max_pixels.is_some_and(|max| width * height > max) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("image size {width}x{height} exceeds the limit")));
+        }
+        Ok(())
+    }
+
+    /// Decodes another file with the same libjpeg instance, keeping its memory pools, tables, and settings of the config
+    /// (saved markers, limits, message handler). Decoding of the current file is abandoned.
+    ///
+    /// Fails if an earlier error left the decoder unusable.
+    pub fn reuse_for_reader<'new, B: BufRead + 'new + Send + Sync>(mut self, reader: B) -> io::Result<Decompress<'new>> {
+        self.catch(|cinfo| unsafe { ffi::jpeg_abort_decompress(cinfo) })?;
+        self.close_src();
+        self.own_error.last_warning = None;
+        self.own_error.warnings.clear();
+        self.own_error.scans.clear();
+        let this = mem::ManuallyDrop::new(self);
+        // only the lifetime changes, and the source has been closed
+        let mut next = unsafe {
+            Decompress {
+                cinfo: ptr::read(&this.cinfo),
+                own_error: ptr::read(&this.own_error),
+                message_handler: ptr::read(&this.message_handler),
+                progress: ptr::read(&this.progress),
+                failed: this.failed,
+                max_dimensions: this.max_dimensions,
+                max_pixels: this.max_pixels,
+                own_src: ptr::null(),
+                _mem_marker: PhantomData,
+            }
+        };
+        next.start_reading(reader)?;
+        Ok(next)
+    }
+
+    /// Same as `reuse_for_reader()`, for a `Vec` or a slice
+    #[inline]
+    pub fn reuse_for_mem<'new>(self, mem: &'new [u8]) -> io::Result<Decompress<'new>> {
+        self.reuse_for_reader(mem)
+    }
+
+    /// Drops the reader, if it's still set
+    fn close_src(&mut self) {
+        unsafe {
+            // unfortunately term_source alone can't be used as a reliable destructor callback,
+            // because libjpeg only calls it in finish_decompress.
+            // If you abort without completing decompression, it won't be called.
+            // In libjpeg this isn't a problem, because source fd isn't owned (so never closed),
+            // and all memory is allocated from the pool (so never freed individually).
+            // Rust has its Drop that should always run, so here's the kludge.
+
+            if let Some(src) = self.cinfo.src.as_mut() {
+                let src_ptr = src as *const _ as *const c_void;
+                // this wrapper is not supposed to allow setting other managers, but just to be 100% safe
+                // it checks if libjpeg still has our manager instance set
+                if src_ptr == self.own_src {
+                    if let Some(term) = src.term_source {
+                        (term)(&mut self.cinfo);
+                        // our srcmanager is expected to null this ptr to prevent double-free,
+                        // and we've compared src pointers to ensure it was our src manager.
+                        debug_assert!(self.cinfo.src.is_null());
+                    }
+                }
+            }
+        }
+    }
+
     #[inline]
     fn read_header(&mut self) -> io::Result<()> {
-        let res = unsafe { ffi::jpeg_read_header(&mut self.cinfo, 0) };
-        if res == 1 {
-            Ok(())
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, format!("JPEG err {}", res)))
+        if !self.try_read_header()? {
+            return Err(error(io::ErrorKind::UnexpectedEof, "incomplete JPEG header", &self.cinfo.common));
+        }
+        Ok(())
+    }
+
+    /// `false` if the source has suspended before the end of the header
+    fn try_read_header(&mut self) -> io::Result<bool> {
+        let res = self.catch(|cinfo| unsafe { ffi::jpeg_read_header(cinfo, 0) })?;
+        match res {
+            // JPEG_HEADER_OK
+            1 => Ok(true),
+            // JPEG_HEADER_TABLES_ONLY
+            2 => Err(error(io::ErrorKind::InvalidData, "the file has only tables, without an image. See DecompressConfig::with_tables()", &self.cinfo.common)),
+            _ => Ok(false),
+        }
+    }
+
+    /// Appends data for `PushDecoder`, and reads the header if it's complete. `true` when the header has been read.
+    pub(crate) fn push_data(&mut self, data: &[u8], header_read: bool) -> io::Result<bool> {
+        if self.failed {
+            return Err(Error::Poisoned.into());
+        }
+        PushSource::push(&mut self.cinfo, data)?;
+        if header_read {
+            return Ok(true);
+        }
+        let done = self.try_read_header()?;
+        if done {
+            self.check_size()?;
+        }
+        Ok(done)
+    }
+
+    /// No more data will be pushed, so missing data is treated as a truncated file
+    pub(crate) fn push_eof(&mut self, header_read: bool) -> io::Result<()> {
+        PushSource::set_eof(&mut self.cinfo);
+        if !header_read {
+            return Err(error(io::ErrorKind::UnexpectedEof, "incomplete JPEG header", &self.cinfo.common));
+        }
+        Ok(())
+    }
+
+    /// Starts decompression for `PushDecoder`. `false` if it needs more data (progressive files need all of it).
+    pub(crate) fn try_start_decompress(&mut self, out_color_space: ColorSpace) -> io::Result<bool> {
+        self.cinfo.out_color_space = out_color_space;
+        Ok(0 != self.catch(|cinfo| unsafe { ffi::jpeg_start_decompress(cinfo) })?)
+    }
+
+    /// Reads up to `n_rows` rows that can be decoded from the data pushed so far, for `PushDecoder`
+    pub(crate) fn read_available_rows(&mut self, dest: &mut [u8], n_rows: usize) -> io::Result<usize> {
+        let row_len = self.cinfo.output_width as usize * self.cinfo.output_components as usize;
+        let n_rows = n_rows.min((self.cinfo.output_height - self.cinfo.output_scanline) as usize);
+        assert!(dest.len() >= n_rows * row_len);
+        let mut row_pointers = [ptr::null_mut::<u8>(); MAX_MCU_HEIGHT];
+        let mut rows_done = 0;
+        while rows_done < n_rows {
+            let batch = (n_rows - rows_done).min(MAX_MCU_HEIGHT);
+            for (i, row) in row_pointers[..batch].iter_mut().enumerate() {
+                *row = dest[(rows_done + i) * row_len..].as_mut_ptr();
+            }
+            let rows_read = self.catch(|cinfo| unsafe { ffi::jpeg_read_scanlines(cinfo, row_pointers.as_mut_ptr(), batch as ffi::JDIMENSION) })? as usize;
+            if rows_read == 0 {
+                // suspended
+                break;
+            }
+            rows_done += rows_read;
+        }
+        Ok(rows_done)
+    }
+
+    /// Index of the next scanline to read
+    pub(crate) fn output_scanline(&self) -> usize {
+        self.cinfo.output_scanline as usize
+    }
+
+    fn read_tables(&mut self, tables: &[u8]) -> io::Result<()> {
+        SourceMgr::set_src(&mut self.cinfo, tables).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        let res = self.catch(|cinfo| unsafe { ffi::jpeg_read_header(cinfo, 0) });
+        unsafe {
+            // libjpeg doesn't finish the source when it stops after the tables
+            if let Some(term_source) = self.cinfo.src.as_ref().and_then(|src| src.term_source) {
+                term_source(&mut self.cinfo);
+            }
+        }
+        match res? {
+            // JPEG_HEADER_TABLES_ONLY
+            2 => Ok(()),
+            _ => Err(error(io::ErrorKind::InvalidData, "expected a tables-only file", &self.cinfo.common)),
         }
     }
 
@@ -259,6 +611,68 @@ impl<'src> Decompress<'src> {
         self.cinfo.output_gamma
     }
 
+    /// EXIF orientation of the image. Pixels are not rotated.
+    ///
+    /// Requires APP1 markers to be saved via `with_markers()`. `None` if there's no EXIF or no orientation tag.
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.markers()
+            .filter(|m| m.marker == Marker::APP(1))
+            .find_map(|m| crate::exif::orientation(m.data))
+    }
+
+    /// Pixel density (DPI) or aspect ratio from the JFIF marker. `None` if the file has no JFIF marker.
+    pub fn pixel_density(&self) -> Option<PixelDensity> {
+        if !self.has_jfif_marker() {
+            return None;
+        }
+        let header = hidden::header_fields(&self.cinfo);
+        Some(PixelDensity::from_jfif(header.density_unit, header.X_density, header.Y_density))
+    }
+
+    /// The file starts with a JFIF APP0 marker
+    pub fn has_jfif_marker(&self) -> bool {
+        0 != hidden::header_fields(&self.cinfo).saw_JFIF_marker
+    }
+
+    /// ICC color profile, reassembled from its `ICC_PROFILE` chunks in order of their sequence numbers.
+    ///
+    /// Requires APP2 markers to be saved via `with_markers()`. `None` if there's no profile, or if any chunk is missing or inconsistent.
+    pub fn icc_profile(&self) -> Option<Vec<u8>> {
+        let mut chunks = Vec::new();
+        for m in self.markers().filter(|m| m.marker == Marker::APP(2)) {
+            if let Some(chunk) = m.data.strip_prefix(ICC_PREFIX) {
+                let (&[seq, count], data) = chunk.split_first_chunk()?;
+                chunks.push((seq, count, data));
+            }
+        }
+        let count = chunks.first()?.1;
+        if chunks.len() != usize::from(count) || chunks.iter().any(|&(_, c, _)| c != count) {
+            return None;
+        }
+        chunks.sort_by_key(|&(seq, ..)| seq);
+        if chunks.iter().enumerate().any(|(i, &(seq, ..))| usize::from(seq) != i + 1) {
+            return None;
+        }
+        let mut profile = Vec::new();
+        profile.try_reserve_exact(chunks.iter().map(|&(.., data)| data.len()).sum()).ok()?;
+        for (.., data) in chunks {
+            profile.extend_from_slice(data);
+        }
+        Some(profile)
+    }
+
+    /// Text of COM markers, decoded as UTF-8 (invalid bytes are replaced), without trailing NUL bytes.
+    ///
+    /// Requires COM markers to be saved via `with_markers()`.
+    pub fn comments(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.markers()
+            .filter(|m| m.marker == Marker::COM)
+            .map(|m| {
+                let len = m.data.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+                String::from_utf8_lossy(&m.data[..len])
+            })
+    }
+
     /// Markers are available only if you enable them via `with_markers()`
     #[inline]
     pub fn markers(&self) -> MarkerIter<'_> {
@@ -268,10 +682,92 @@ impl<'src> Decompress<'src> {
         }
     }
 
-    fn save_marker(&mut self, marker: Marker) {
-        unsafe {
-            ffi::jpeg_save_markers(&mut self.cinfo, marker.into(), 0xFFFF);
+    /// Quantization table in the given slot (usually 0 = luma, 1 = chroma). Tables of abbreviated files are known only after decoding starts.
+    pub fn qtable(&self, slot: usize) -> Option<QTable> {
+        unsafe { self.cinfo.quant_tbl_ptrs.get(slot)?.as_ref() }.map(QTable::from)
+    }
+
+    /// DC Huffman table in the given slot (usually 0 = luma, 1 = chroma), as defined in the file.
+    /// Compare with `HuffmanTable::std_luma_dc()` etc. to tell whether the encoder optimized the tables.
+    pub fn dc_huffman_table(&self, slot: usize) -> Option<HuffmanTable> {
+        unsafe { hidden::table_fields(&self.cinfo).dc_huff_tbl_ptrs.get(slot)?.as_ref() }.map(HuffmanTable::from)
+    }
+
+    /// AC Huffman table in the given slot, see `dc_huffman_table()`
+    pub fn ac_huffman_table(&self, slot: usize) -> Option<HuffmanTable> {
+        unsafe { hidden::table_fields(&self.cinfo).ac_huff_tbl_ptrs.get(slot)?.as_ref() }.map(HuffmanTable::from)
+    }
+
+    /// Estimates the quality setting the file was saved with (1-100), by comparing its quantization tables
+    /// with libjpeg's and MozJPEG's built-in tables scaled to every quality.
+    ///
+    /// It's exact for files from libjpeg and MozJPEG, and approximate for other encoders. `None` if there are no tables.
+    pub fn estimated_quality(&self) -> Option<u8> {
+        let luma_slot = self.components().first()?.quant_tbl_no as usize;
+        let luma = self.qtable(luma_slot)?;
+        let chroma = self.components().get(1).and_then(|c| self.qtable(c.quant_tbl_no as usize));
+        Some(crate::qtable::estimate_quality(&luma, chroma.as_ref()))
+    }
+
+    /// Entropy-coded data uses arithmetic coding instead of Huffman coding.
+    ///
+    /// Decoding such files requires the `arith` feature, otherwise it fails with `Error::Unsupported`.
+    pub fn uses_arithmetic_coding(&self) -> bool {
+        0 != hidden::header_fields(&self.cinfo).arith_code
+    }
+
+    /// The file is progressive, i.e. the image is refined in multiple scans
+    pub fn is_progressive(&self) -> bool {
+        0 != hidden::header_fields(&self.cinfo).progressive_mode
+    }
+
+    /// The file is progressive, or has components in separate scans
+    pub fn has_multiple_scans(&self) -> bool {
+        // libjpeg fails if the header hasn't been read, but Decompress always has it
+        !self.failed && 0 != unsafe { ffi::jpeg_has_multiple_scans(&self.cinfo) }
+    }
+
+    /// Reads the whole file, and returns parameters of all of its scans, in the form `Compress::set_scan_script()` takes
+    ///
+    /// Coefficients are decoded, but not converted to pixels. Scans aren't recorded when using a custom error manager (`with_err()`).
+    pub fn scans(mut self) -> io::Result<Vec<ScanInfo>> {
+        self.catch(|cinfo| unsafe { ffi::jpeg_read_coefficients(cinfo) })?;
+        if self.own_error.scans.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "scans are recorded only by the default error manager"));
         }
+        let mut scans = mem::take(&mut self.own_error.scans);
+        for scan in &mut scans {
+            for id in &mut scan.component_index[..scan.comps_in_scan as usize] {
+                *id = self.components().iter().position(|c| c.component_id == *id)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "scan of an unknown component"))? as _;
+            }
+        }
+        Ok(scans)
+    }
+
+    /// All of the basic information about the file at once
+    pub fn summary(&self) -> Summary {
+        let header = hidden::header_fields(&self.cinfo);
+        let mut marker_list = self.cinfo.marker_list;
+        let mut markers = Vec::new();
+        while let Some(m) = unsafe { marker_list.as_ref() } {
+            markers.push((m.marker.into(), m.original_length as usize));
+            marker_list = m.next;
+        }
+        Summary {
+            width: self.width(),
+            height: self.height(),
+            precision: hidden::table_fields(&self.cinfo).data_precision as u8,
+            color_space: self.color_space(),
+            sampling: self.components().iter().map(|c| c.sampling()).collect(),
+            entropy_coding: if 0 != header.arith_code { EntropyCoding::Arithmetic } else { EntropyCoding::Huffman },
+            progressive: 0 != header.progressive_mode,
+            markers,
+        }
+    }
+
+    fn save_marker(&mut self, marker: Marker) -> io::Result<()> {
+        self.catch(|cinfo| unsafe { ffi::jpeg_save_markers(cinfo, marker.into(), 0xFFFF) })
     }
 
     /// width,height
@@ -301,6 +797,25 @@ impl<'src> Decompress<'src> {
         DecompressStarted::start_decompress(self)
     }
 
+    /// Start decompression with conversion to RGB from any color space, including CMYK and YCCK.
+    ///
+    /// libjpeg can't convert CMYK to RGB, so for such files the conversion is done when reading scanlines,
+    /// using a naive formula without color management. Inverted CMYK written by Adobe apps is detected from the Adobe marker.
+    #[doc(alias = "rgb_from_cmyk")]
+    pub fn rgb_from_any(mut self) -> io::Result<DecompressStarted<'src>> {
+        use crate::ffi::J_COLOR_SPACE::*;
+        match self.color_space() {
+            JCS_CMYK | JCS_YCCK => {
+                let inverted = hidden::header_fields(&self.cinfo).saw_Adobe_marker != 0;
+                self.cinfo.out_color_space = JCS_CMYK;
+                let mut started = DecompressStarted::start_decompress(self)?;
+                started.cmyk_to_rgb = Some(CmykToRgb { inverted, row: Vec::new() });
+                Ok(started)
+            },
+            _ => self.rgb(),
+        }
+    }
+
     /// Start decompression with conversion to `colorspace`
     pub fn to_colorspace(mut self, colorspace: ColorSpace) -> io::Result<DecompressStarted<'src>> {
         self.cinfo.out_color_space = colorspace;
@@ -314,6 +829,106 @@ impl<'src> Decompress<'src> {
         DecompressStarted::start_decompress(self)
     }
 
+    /// Start decompression with conversion to BGR, the channel order of OpenCV and Windows DIBs
+    #[inline(always)]
+    pub fn bgr(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_EXT_BGR;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with conversion to BGRA. Alpha is always 255.
+    #[inline(always)]
+    pub fn bgra(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_EXT_BGRA;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with conversion to ARGB. Alpha is always 255.
+    #[inline(always)]
+    pub fn argb(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_EXT_ARGB;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with conversion to ABGR. Alpha is always 255.
+    #[inline(always)]
+    pub fn abgr(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_EXT_ABGR;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with conversion to RGB with a padding byte (RGBX), for 4-byte-aligned pixels.
+    ///
+    /// The padding byte has no meaning, but is always set to 255, so the pixels can be used as opaque RGBA.
+    #[inline(always)]
+    pub fn rgbx(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_EXT_RGBX;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with conversion to BGR with a leading padding byte (XBGR).
+    ///
+    /// The padding byte is always set to 255.
+    #[inline(always)]
+    pub fn xbgr(mut self) -> io::Result<DecompressStarted<'src>> {
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_EXT_XBGR;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with colors mapped to a fixed RGB palette of at most 256 colors, e.g. a hardware palette.
+    ///
+    /// Rows have one byte per pixel, which is an index into the `palette`. With `dither`, Floyd-Steinberg dithering is used.
+    pub fn to_palette(mut self, palette: &[[u8; 3]], dither: bool) -> io::Result<DecompressStarted<'src>> {
+        if palette.is_empty() || palette.len() > 256 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the palette must have 1 to 256 colors"));
+        }
+        let alloc_sarray = unsafe { (*self.cinfo.common.mem).alloc_sarray }.ok_or(io::ErrorKind::Unsupported)?;
+        // libjpeg reads the colormap until the end of decompression
+        let colormap = self.catch(|cinfo| unsafe { alloc_sarray(&mut cinfo.common, ffi::JPOOL_IMAGE, palette.len() as ffi::JDIMENSION, 3) })?;
+        unsafe {
+            for (c, &row) in slice::from_raw_parts(colormap, 3).iter().enumerate() {
+                let row = slice::from_raw_parts_mut(row, palette.len());
+                row.iter_mut().zip(palette).for_each(|(out, color)| *out = color[c]);
+            }
+            self.cinfo.colormap = colormap;
+        }
+        self.cinfo.actual_number_of_colors = palette.len() as c_int;
+        self.cinfo.quantize_colors = 1;
+        self.cinfo.dither_mode = if dither { ffi::J_DITHER_MODE::JDITHER_FS } else { ffi::J_DITHER_MODE::JDITHER_NONE };
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_RGB;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression with colors reduced to a palette of at most `max_colors` (2-256) chosen by libjpeg.
+    ///
+    /// Rows have one byte per pixel, which is an index into `DecompressStarted::colormap()`.
+    /// With `two_pass` the palette is chosen for this image (at least 8 colors), which looks much better,
+    /// but the whole image is decoded twice. Otherwise the palette is a uniform grid of colors.
+    pub fn to_quantized(mut self, max_colors: usize, two_pass: bool, dither: DitherMode) -> io::Result<DecompressStarted<'src>> {
+        if !(2..=256).contains(&max_colors) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the palette must have 2 to 256 colors"));
+        }
+        self.cinfo.quantize_colors = 1;
+        self.cinfo.desired_number_of_colors = max_colors as c_int;
+        self.cinfo.two_pass_quantize = two_pass as ffi::boolean;
+        self.cinfo.dither_mode = match dither {
+            DitherMode::None => ffi::J_DITHER_MODE::JDITHER_NONE,
+            DitherMode::Ordered => ffi::J_DITHER_MODE::JDITHER_ORDERED,
+            DitherMode::FloydSteinberg => ffi::J_DITHER_MODE::JDITHER_FS,
+        };
+        self.cinfo.out_color_space = ffi::J_COLOR_SPACE::JCS_RGB;
+        DecompressStarted::start_decompress(self)
+    }
+
+    /// Start decompression in buffered-image mode, with conversion to `colorspace`.
+    ///
+    /// Progressive files can be shown after every scan, getting sharper each time. See `DecompressBuffered::next_pass()`.
+    pub fn start_buffered(mut self, colorspace: ColorSpace) -> io::Result<DecompressBuffered<'src>> {
+        self.cinfo.buffered_image = true as ffi::boolean;
+        self.cinfo.out_color_space = colorspace;
+        Ok(DecompressBuffered { dec: DecompressStarted::start_decompress(self)?, in_pass: false })
+    }
+
     /// Start decompression with conversion to grayscale.
     #[inline(always)]
     pub fn grayscale(mut self) -> io::Result<DecompressStarted<'src>> {
@@ -353,6 +968,65 @@ impl<'src> Decompress<'src> {
         DecompressStarted::start_decompress(self)
     }
 
+    /// Reads quantized DCT coefficients of all components, without decoding pixels.
+    ///
+    /// They can be analyzed, modified, and saved again without generation loss.
+    pub fn coefficients(mut self) -> io::Result<Coefficients> {
+        self.read_coefficients()
+    }
+
+    /// Reads quantized DCT blocks of all components, without decoding pixels
+    pub(crate) fn read_coefficients(&mut self) -> io::Result<Coefficients> {
+        let arrays = self.catch(|cinfo| unsafe { ffi::jpeg_read_coefficients(cinfo) })?;
+        if arrays.is_null() {
+            return Err(io::Error::other("can't read coefficients"));
+        }
+        unsafe {
+            let access_virt_barray = (*self.cinfo.common.mem).access_virt_barray.ok_or(io::ErrorKind::Unsupported)?;
+
+            let mut components = Vec::new();
+            components.try_reserve(self.components().len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+            for comp in self.components() {
+                let qtable = self.cinfo.quant_tbl_ptrs.get(comp.quant_tbl_no as usize)
+                    .and_then(|q| q.as_ref())
+                    .map(QTable::from)
+                    .ok_or_else(|| io::Error::other("missing quantization table"))?;
+                components.push(ComponentCoefficients {
+                    component_id: comp.component_id as u8,
+                    sampling: comp.sampling(),
+                    width_in_blocks: comp.width_in_blocks(),
+                    height_in_blocks: comp.height_in_blocks(),
+                    qtable,
+                    blocks: Vec::new(),
+                });
+            }
+            for (ci, comp) in components.iter_mut().enumerate() {
+                comp.blocks.try_reserve_exact(comp.width_in_blocks * comp.height_in_blocks).map_err(|_| io::ErrorKind::OutOfMemory)?;
+                for y in 0..comp.height_in_blocks {
+                    let rows = self.catch(|cinfo| access_virt_barray(&mut cinfo.common, *arrays.add(ci), y as _, 1, 0))?;
+                    comp.blocks.extend_from_slice(slice::from_raw_parts(*rows, comp.width_in_blocks));
+                }
+            }
+            let coefficients = Coefficients {
+                width: self.width(),
+                height: self.height(),
+                color_space: self.color_space(),
+                components,
+            };
+            self.catch(|cinfo| ffi::jpeg_finish_decompress(cinfo))?;
+            Ok(coefficients)
+        }
+    }
+
+    /// Number of corrupt-data warnings libjpeg has reported so far
+    pub(crate) fn num_warnings(&self) -> usize {
+        unsafe { self.cinfo.common.err.as_ref() }.map_or(0, |err| err.num_warnings as usize)
+    }
+
+    pub(crate) fn warnings(&self) -> &[String] {
+        &self.own_error.warnings
+    }
+
     fn out_color_space(&self) -> ColorSpace {
         self.cinfo.out_color_space
     }
@@ -364,8 +1038,30 @@ impl<'src> Decompress<'src> {
             JCS_RGB => Ok(Format::RGB(DecompressStarted::start_decompress(self)?)),
             JCS_CMYK => Ok(Format::CMYK(DecompressStarted::start_decompress(self)?)),
             JCS_GRAYSCALE => Ok(Format::Gray(DecompressStarted::start_decompress(self)?)),
-            format => Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", format))),
+            format => Err(io::Error::new(io::ErrorKind::Unsupported, format!("unsupported color space {:?}", format))),
+        }
+    }
+
+    /// Decodes to RGB (flat buffer) of exactly `width`×`height` pixels, without preserving the aspect ratio.
+    ///
+    /// The image is decoded with the smallest DCT scaling that is at least as large as the target size,
+    /// and the rest is done with a Lanczos3 filter.
+    #[cfg(feature = "resize")]
+    pub fn decode_resized(mut self, width: usize, height: usize) -> io::Result<Vec<u8>> {
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero size"));
         }
+        let (src_width, src_height) = (self.width(), self.height());
+        let numerator = (1..8).find(|&n| {
+            (src_width * n).div_ceil(8) >= width && (src_height * n).div_ceil(8) >= height
+        }).unwrap_or(8);
+        self.scale(numerator as u8);
+        let mut started = self.rgb_from_any()?;
+        let scaled_size = (started.width(), started.height());
+        let mut pixels = started.read_scanlines_flat()?;
+        let resized = crate::resize::resize(&pixels, scaled_size, 3, (width, height));
+        wipe(&mut pixels);
+        resized
     }
 
     /// Rescales the output image by `numerator / 8` during decompression.
@@ -374,7 +1070,7 @@ impl<'src> Decompress<'src> {
     #[track_caller]
     #[inline]
     pub fn scale(&mut self, numerator: u8) {
-        assert!(1 <= numerator && numerator <= 16, "numerator must be between 1 and 16");
+        assert!((1..=16).contains(&numerator), "numerator must be between 1 and 16");
         self.cinfo.scale_num = numerator.into();
         self.cinfo.scale_denom = 8;
     }
@@ -390,76 +1086,147 @@ pub enum Format<'a> {
 /// See methods on `Decompress`
 pub struct DecompressStarted<'src> {
     dec: Decompress<'src>,
+    /// Set by `rgb_from_any()` for CMYK files
+    cmyk_to_rgb: Option<CmykToRgb>,
+}
+
+struct CmykToRgb {
+    /// Adobe's CMYK has 255 for no ink
+    inverted: bool,
+    /// Scratch buffer for a row of CMYK pixels
+    row: Vec<u8>,
+}
+
+impl Drop for CmykToRgb {
+    fn drop(&mut self) {
+        wipe(&mut self.row);
+    }
+}
+
+impl CmykToRgb {
+    fn convert(&self, rgb: &mut [MaybeUninit<u8>]) {
+        for (rgb, cmyk) in rgb.chunks_exact_mut(3).zip(self.row.chunks_exact(4)) {
+            let k = if self.inverted { cmyk[3] } else { 255 - cmyk[3] };
+            for (out, &c) in rgb.iter_mut().zip(&cmyk[..3]) {
+                let c = if self.inverted { c } else { 255 - c };
+                out.write(((u16::from(c) * u16::from(k) + 127) / 255) as u8);
+            }
+        }
+    }
 }
 
 impl<'src> DecompressStarted<'src> {
     fn start_decompress(mut dec: Decompress<'src>) -> io::Result<Self> {
-        let res = unsafe { ffi::jpeg_start_decompress(&mut dec.cinfo) };
+        let res = dec.catch(|cinfo| unsafe { ffi::jpeg_start_decompress(cinfo) })?;
         if 0 != res {
-            Ok(DecompressStarted { dec })
+            Ok(DecompressStarted { dec, cmyk_to_rgb: None })
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, format!("JPEG err {}", res)))
+            Err(error(io::ErrorKind::UnexpectedEof, "not enough data to start decompression", &dec.cinfo.common))
         }
     }
 
+    /// Corrupt-data warnings libjpeg has reported so far, e.g. "Premature end of JPEG file".
+    ///
+    /// libjpeg recovers from these, but parts of the image may be gray or garbled, so check this after reading.
+    /// Only the first 100 messages are kept, see `num_warnings()`. Empty if a custom error manager has been set.
+    pub fn warnings(&self) -> &[String] {
+        self.dec.warnings()
+    }
+
+    /// Number of corrupt-data warnings, including ones not kept by `warnings()`
+    pub fn num_warnings(&self) -> usize {
+        self.dec.num_warnings()
+    }
+
     pub fn color_space(&self) -> ColorSpace {
+        if self.cmyk_to_rgb.is_some() {
+            return ColorSpace::JCS_RGB;
+        }
         self.dec.out_color_space()
     }
 
     /// Gets the minimal buffer size for using `DecompressStarted::read_scanlines_flat_into`
     #[inline(always)]
     pub fn min_flat_buffer_size(&self) -> usize {
-        self.color_space().num_components() * self.width() * self.height()
+        self.output_components() * self.width() * self.height()
     }
 
     fn read_more_chunks(&self) -> bool {
         self.dec.cinfo.output_scanline < self.dec.cinfo.output_height
     }
 
-    #[track_caller]
-    pub fn read_raw_data(&mut self, image_dest: &mut [&mut Vec<u8>]) {
+    /// Appends planes of raw data to the vectors. Only possible after `raw()`.
+    pub fn read_raw_data(&mut self, image_dest: &mut [&mut Vec<u8>]) -> io::Result<()> {
         while self.read_more_chunks() {
-            self.read_raw_data_chunk(image_dest);
+            self.read_raw_data_chunk(image_dest)?;
         }
+        Ok(())
     }
 
-    #[track_caller]
-    fn read_raw_data_chunk(&mut self, image_dest: &mut [&mut Vec<u8>]) {
-        assert!(0 != self.dec.cinfo.raw_data_out, "Raw data not set");
+    /// Reads the whole image as a 4:2:0 video frame (e.g. I420 or NV12). Only possible after `raw()`, and only for YCbCr files.
+    ///
+    /// Chroma of other subsamplings (such as 4:2:2 or 4:4:4) is resampled to 4:2:0.
+    pub fn read_yuv420(&mut self, format: YuvFormat) -> io::Result<Vec<u8>> {
+        let comps = self.dec.components();
+        if 0 == self.dec.cinfo.raw_data_out || self.dec.color_space() != ColorSpace::JCS_YCbCr || comps.len() != 3 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "only raw YCbCr data can be read as YUV"));
+        }
+        let samp = [0, 1, 2].map(|ci| (comps[ci].h_samp_factor as usize, comps[ci].v_samp_factor as usize, comps[ci].row_stride()));
+        let mut data = [Vec::new(), Vec::new(), Vec::new()];
+        let [y, u, v] = &mut data;
+        self.read_raw_data(&mut [y, u, v])?;
+        let planes = [0, 1, 2].map(|ci| Plane {
+            data: &data[ci],
+            row_stride: samp[ci].2,
+            h_samp: samp[ci].0,
+            v_samp: samp[ci].1,
+        });
+        let max_samp = (self.dec.cinfo.max_h_samp_factor as usize, self.dec.cinfo.max_v_samp_factor as usize);
+        let frame = yuv::to_yuv420(format, (self.width(), self.height()), max_samp, &planes);
+        data.iter_mut().for_each(|plane| wipe(plane));
+        frame
+    }
+
+    fn read_raw_data_chunk(&mut self, image_dest: &mut [&mut Vec<u8>]) -> io::Result<()> {
+        if 0 == self.dec.cinfo.raw_data_out {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "raw data not enabled, use raw()"));
+        }
 
         let mcu_height = self.dec.cinfo.max_v_samp_factor as usize * DCTSIZE;
         if mcu_height > MAX_MCU_HEIGHT {
-            panic!("Subsampling factor too large");
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "subsampling factor too large"));
         }
 
         let num_components = self.dec.components().len();
         if num_components > MAX_COMPONENTS || num_components > image_dest.len() {
-            panic!("Too many components. Image has {}, destination vector has {} (max supported is {})", num_components, image_dest.len(), MAX_COMPONENTS);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Too many components. Image has {}, destination vector has {} (max supported is {})", num_components, image_dest.len(), MAX_COMPONENTS)));
         }
 
-        unsafe {
-            let mut row_ptrs = [[ptr::null_mut::<u8>(); MAX_MCU_HEIGHT]; MAX_COMPONENTS];
-            let mut comp_ptrs = [ptr::null_mut::<*mut u8>(); MAX_COMPONENTS];
-            for (ci, comp_info) in self.dec.components().iter().enumerate() {
-                let row_stride = comp_info.row_stride();
-
-                let comp_height = comp_info.v_samp_factor as usize * DCTSIZE;
-                let original_len = image_dest[ci].len();
-                image_dest[ci].extend_uninit(comp_height * row_stride);
-                for ri in 0..comp_height {
-                    let start = original_len + ri * row_stride;
-                    row_ptrs[ci][ri] = (&mut image_dest[ci][start.. start + row_stride]).as_mut_ptr();
-                }
-                for ri in comp_height..mcu_height {
-                    row_ptrs[ci][ri] = ptr::null_mut();
-                }
-                comp_ptrs[ci] = row_ptrs[ci].as_mut_ptr();
+        // (rows, row_stride) of each component
+        let mut sizes = [(0, 0); MAX_COMPONENTS];
+        for ((size, comp_info), dest) in sizes.iter_mut().zip(self.dec.components()).zip(image_dest.iter_mut()) {
+            *size = (comp_info.v_samp_factor as usize * DCTSIZE, comp_info.row_stride());
+            dest.try_reserve(size.0 * size.1).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        }
+
+        let mut row_ptrs = [[ptr::null_mut::<u8>(); MAX_MCU_HEIGHT]; MAX_COMPONENTS];
+        let mut comp_ptrs = [ptr::null_mut::<*mut u8>(); MAX_COMPONENTS];
+        for (ci, &(comp_height, row_stride)) in sizes.iter().enumerate().take(num_components) {
+            // rows are written to the spare capacity, and become a part of the Vec only after they're read
+            let spare = image_dest[ci].spare_capacity_mut();
+            for (ri, row_ptr) in row_ptrs[ci].iter_mut().enumerate().take(comp_height) {
+                *row_ptr = spare[ri * row_stride..].as_mut_ptr().cast();
             }
+            comp_ptrs[ci] = row_ptrs[ci].as_mut_ptr();
+        }
 
-            let lines_read = ffi::jpeg_read_raw_data(&mut self.dec.cinfo, comp_ptrs.as_mut_ptr(), mcu_height as u32) as usize;
+        let lines_read = self.dec.catch(|cinfo| unsafe { ffi::jpeg_read_raw_data(cinfo, comp_ptrs.as_mut_ptr(), mcu_height as u32) })? as usize;
+        assert_eq!(lines_read, mcu_height); // Partial reads would make subsampled height tricky to define
 
-            assert_eq!(lines_read, mcu_height); // Partial reads would make subsampled height tricky to define
+        for (dest, &(comp_height, row_stride)) in image_dest.iter_mut().zip(&sizes).take(num_components) {
+            unsafe { dest.set_len(dest.len() + comp_height * row_stride); }
         }
+        Ok(())
     }
 
     pub fn width(&self) -> usize {
@@ -470,92 +1237,259 @@ impl<'src> DecompressStarted<'src> {
         self.dec.cinfo.output_height as usize
     }
 
+    /// Number of color components of the output color space
+    pub fn out_color_components(&self) -> usize {
+        if self.cmyk_to_rgb.is_some() {
+            return 3;
+        }
+        self.dec.cinfo.out_color_components as usize
+    }
+
+    /// Palette of color-mapped output of `to_quantized()` or `to_palette()`. `None` for other outputs.
+    pub fn colormap(&self) -> Option<Vec<[u8; 3]>> {
+        let cinfo = &self.dec.cinfo;
+        if 0 == cinfo.quantize_colors || cinfo.colormap.is_null() {
+            return None;
+        }
+        let rows = unsafe { slice::from_raw_parts(cinfo.colormap, cinfo.out_color_components as usize) };
+        let colors = cinfo.actual_number_of_colors as usize;
+        let channel = |c: usize| unsafe { slice::from_raw_parts(rows[c.min(rows.len() - 1)], colors) };
+        Some((0..colors).map(|i| [0, 1, 2].map(|c| channel(c)[i])).collect())
+    }
+
+    /// Number of bytes per pixel in decoded rows. It's 1 for color-mapped output, otherwise same as `out_color_components()`.
+    pub fn output_components(&self) -> usize {
+        if self.cmyk_to_rgb.is_some() {
+            return 3;
+        }
+        self.dec.cinfo.output_components as usize
+    }
+
+    /// Number of rows that libjpeg can decode at once most efficiently (usually 1 or 2, at most 4).
+    /// Buffers of a multiple of this height avoid extra copying in chunked reads.
+    pub fn rec_outbuf_height(&self) -> usize {
+        self.dec.cinfo.rec_outbuf_height as usize
+    }
+
     /// Supports any pixel type that is marked as "plain old data", see bytemuck crate.
     /// `[u8; 3]` and `rgb::RGB8` are fine, for example.
     #[track_caller]
-    pub fn read_scanlines<T: rgb::Pod>(&mut self) -> Option<Vec<T>> {
-        let num_components = self.color_space().num_components();
+    pub fn read_scanlines<T: rgb::Pod>(&mut self) -> io::Result<Vec<T>> {
+        let num_components = self.output_components();
         assert_eq!(num_components, mem::size_of::<T>());
         let width = self.width();
         let height = self.height();
         let mut image_dst: Vec<T> = Vec::new();
-        image_dst.try_reserve(height * width).ok()?;
-        unsafe { image_dst.extend_uninit(height * width); }
-        if self.read_scanlines_into(&mut image_dst) {
-            Some(image_dst)
-        } else {
-            None
+        image_dst.try_reserve_exact(height * width).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.read_scanlines_into_uninit(&mut image_dst.spare_capacity_mut()[..height * width])?;
+        unsafe { image_dst.set_len(height * width); }
+        Ok(image_dst)
+    }
+
+    /// Like `read_scanlines_into()`, but the buffer doesn't need to be initialized (e.g. it can be `Vec::spare_capacity_mut()`).
+    ///
+    /// Returns the now-initialized buffer. Fails if some scanlines have already been read, or if there's no more data.
+    #[track_caller]
+    pub fn read_scanlines_into_uninit<'buf, T: rgb::Pod>(&mut self, dest: &'buf mut [MaybeUninit<T>]) -> io::Result<&'buf mut [T]> {
+        let num_components = self.output_components();
+        assert_eq!(num_components, mem::size_of::<T>());
+        assert_eq!(self.height() * self.width(), dest.len());
+        if self.dec.cinfo.output_scanline != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "scanlines have already been read"));
         }
+        let row_len = self.width() * num_components;
+        // Pod types can be used as bytes
+        let bytes = unsafe { slice::from_raw_parts_mut(dest.as_mut_ptr().cast::<MaybeUninit<u8>>(), mem::size_of_val(dest)) };
+        self.read_remaining_rows(bytes, row_len)?;
+        // every row has been written
+        Ok(unsafe { slice::from_raw_parts_mut(dest.as_mut_ptr().cast::<T>(), dest.len()) })
     }
 
     /// Supports any pixel type that is marked as "plain old data", see bytemuck crate.
     /// `[u8; 3]` and `rgb::RGB8` are fine, for example.
     /// Allocation-less version of `read_scanlines`
-    /// Returns true on success
     #[track_caller]
-    pub fn read_scanlines_into<T: rgb::Pod>(&mut self, dest: &mut [T]) -> bool {
-        let num_components = self.color_space().num_components();
+    pub fn read_scanlines_into<T: rgb::Pod>(&mut self, dest: &mut [T]) -> io::Result<()> {
+        let num_components = self.output_components();
         assert_eq!(num_components, mem::size_of::<T>());
-        let width = self.width();
-        let height = self.height();
-        assert_eq!(height * width, dest.len());
-        unsafe {
-            while self.read_more_chunks() {
-                let start_line = self.dec.cinfo.output_scanline as usize;
-                let rest: &mut [T] = &mut dest[width * start_line..];
-                let rows = (&mut rest.as_mut_ptr()) as *mut *mut T;
-
-                let rows_read = ffi::jpeg_read_scanlines(&mut self.dec.cinfo, rows as *mut *mut u8, 1) as usize;
-                debug_assert_eq!(start_line + rows_read, self.dec.cinfo.output_scanline as usize, "wat {}/{} at {}", rows_read, height, start_line);
-
-                if 0 == rows_read {
-                    return false;
-                }
-            }
-        }
-        true
+        assert_eq!(self.height() * self.width(), dest.len());
+        // Pod types can be used as bytes
+        let dest = unsafe { slice::from_raw_parts_mut(dest.as_mut_ptr().cast::<u8>(), mem::size_of_val(dest)) };
+        self.read_scanlines_flat_into(dest)
     }
 
     /// Reads the whole image scanline by scanline & returning a RGB(A)RGB(A)... flat buffer.
     /// Those kinds of buffers are more friendly with the `image` crate
-    /// Returns Some(buffer) on success
-    pub fn read_scanlines_flat(&mut self) -> Option<Vec<u8>> {
-        let num_components = self.color_space().num_components();
+    pub fn read_scanlines_flat(&mut self) -> io::Result<Vec<u8>> {
+        let num_components = self.output_components();
         let width = self.width();
         let height = self.height();
-        let mut buf = vec![0; height * width * num_components];
-        if self.read_scanlines_flat_into(&mut buf) {
-            Some(buf)
-        } else {
-            None
-        }
+        let mut buf = try_filled(0, height * width * num_components)?;
+        self.read_scanlines_flat_into(&mut buf)?;
+        Ok(buf)
     }
 
     /// Reads the whole image scanline by scanline into a RGB(A)RGB(A)... flat buffer.
     /// Those kinds of buffers are more friendly with the `image` crate
-    /// Returns true on success
-    pub fn read_scanlines_flat_into(&mut self, dest: &mut [u8]) -> bool {
-        let num_components = self.color_space().num_components();
+    pub fn read_scanlines_flat_into(&mut self, dest: &mut [u8]) -> io::Result<()> {
+        let num_components = self.output_components();
         let width = self.width();
         let height = self.height();
         assert_eq!(height * width * num_components, dest.len());
-        let scanline_len = width * num_components;
-        unsafe {
-            while self.read_more_chunks() {
-                let start_line = self.dec.cinfo.output_scanline as usize;
-                let start_idx = start_line * scanline_len;
-                let rest: &mut [u8] = &mut dest[start_idx..start_idx + scanline_len];
-                let rows = (&mut rest.as_mut_ptr()) as *mut *mut u8;
+        // only initialized values are written to it
+        self.read_remaining_rows(unsafe { &mut *(dest as *mut [u8] as *mut [MaybeUninit<u8>]) }, width * num_components)
+    }
 
-                let rows_read = ffi::jpeg_read_scanlines(&mut self.dec.cinfo, rows as *mut *mut u8, 1) as usize;
-                debug_assert_eq!(start_line + rows_read, self.dec.cinfo.output_scanline as usize, "wat {}/{} at {}", rows_read, height, start_line);
+    /// Like `read_scanlines_flat_into()`, but rows start every `bytes_per_row` bytes, e.g. in a padded texture or an aligned buffer.
+    ///
+    /// Bytes after the pixels of each row are left unchanged. The last row doesn't need to be padded.
+    /// Fails if `bytes_per_row` is smaller than a row of pixels, or `dest` is too small for the image.
+    pub fn read_scanlines_into_strided(&mut self, dest: &mut [u8], bytes_per_row: usize) -> io::Result<()> {
+        let row_len = self.width() * self.output_components();
+        let height = self.height();
+        if bytes_per_row < row_len || (height > 0 && dest.len() < (height - 1) * bytes_per_row + row_len) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "buffer is too small for the image"));
+        }
+        // only initialized values are written to it
+        self.read_remaining_rows(unsafe { &mut *(dest as *mut [u8] as *mut [MaybeUninit<u8>]) }, bytes_per_row)
+    }
 
-                if 0 == rows_read {
-                    return false;
-                }
+    /// Reads up to `n_rows` of the next scanlines into `dest`, e.g. to process the image in stripes of 64 rows.
+    ///
+    /// Returns the number of rows read, which is less than `n_rows` only at the end of the image, and 0 after the last row.
+    /// `dest` must have space for `n_rows` rows of `width() * output_components()` bytes.
+    #[track_caller]
+    pub fn read_rows_into(&mut self, dest: &mut [u8], n_rows: usize) -> io::Result<usize> {
+        let row_len = self.width() * self.output_components();
+        assert!(dest.len() >= n_rows * row_len);
+        let n_rows = n_rows.min(self.height() - self.dec.cinfo.output_scanline as usize);
+        // only initialized values are written to it
+        self.read_rows_batched(unsafe { &mut *(dest as *mut [u8] as *mut [MaybeUninit<u8>]) }, row_len, n_rows)?;
+        Ok(n_rows)
+    }
+
+    /// Reads the remaining scanlines one by one into a reusable buffer, without allocating the whole image.
+    ///
+    /// Rows are `&[T]` of any "plain old data" type, e.g. `u8` for bytes, or `[u8; 3]` for RGB pixels.
+    /// It can't be a regular `Iterator`, because every row borrows the buffer, so use `while let Some(row) = rows.next_row()`.
+    #[track_caller]
+    pub fn rows<T: rgb::Pod>(&mut self) -> Rows<'_, 'src, T> {
+        let row_len = self.width() * self.output_components();
+        assert_eq!(0, row_len % mem::size_of::<T>().max(1));
+        Rows { dec: self, row: Vec::new(), row_len: row_len / mem::size_of::<T>().max(1) }
+    }
+
+    /// Reads the image once, and returns it downscaled by each of the `divisors` (e.g. `[1, 2, 4]`).
+    ///
+    /// Pixels are averaged in `divisor`×`divisor` boxes as scanlines are decoded, so this is much faster than
+    /// decoding the file for each size. Each image is a flat buffer in the output color space, with `width / divisor` pixels per row
+    /// (rounded up).
+    #[track_caller]
+    pub fn read_scanlines_downscaled(&mut self, divisors: &[usize]) -> io::Result<Vec<ScaledImage>> {
+        assert!(divisors.iter().all(|&d| d > 0));
+        let num_components = self.output_components();
+        let width = self.width();
+        let mut scalers = divisors.iter().map(|&d| Downscaler::new(width, self.height(), num_components, d)).collect::<Option<Vec<_>>>()
+            .ok_or(io::ErrorKind::OutOfMemory)?;
+        let mut row = try_filled(0, width * num_components)?;
+        while self.read_more_chunks() {
+            self.read_row(&mut row)?;
+            for scaler in &mut scalers {
+                scaler.add_row(&row);
             }
         }
-        true
+        Ok(scalers.into_iter().map(Downscaler::finish).collect())
+    }
+
+    /// Iterates the image in `tile_width`×`tile_height` tiles, e.g. for deep-zoom pyramids of huge images.
+    ///
+    /// Scanlines are decoded as needed, so only one row of tiles is held in memory.
+    /// Use `rgb()` to get RGB tiles.
+    ///
+    /// ## Panics
+    ///
+    /// If `tile_width` or `tile_height` is 0.
+    #[track_caller]
+    pub fn tiles(&mut self, tile_width: usize, tile_height: usize) -> Tiles<'_, 'src> {
+        Tiles::new(self, tile_width, tile_height)
+    }
+
+    /// Reads one scanline into `dest`
+    pub(crate) fn read_row(&mut self, dest: &mut [u8]) -> io::Result<()> {
+        // only initialized values are written to it
+        self.read_row_uninit(unsafe { &mut *(dest as *mut [u8] as *mut [MaybeUninit<u8>]) })
+    }
+
+    /// Reads all rows after `output_scanline` into `dest`, which has space for the whole image with rows `row_stride` bytes apart.
+    fn read_remaining_rows(&mut self, dest: &mut [MaybeUninit<u8>], row_stride: usize) -> io::Result<()> {
+        let start_line = self.dec.cinfo.output_scanline as usize;
+        let n_rows = self.height() - start_line;
+        self.read_rows_batched(dest.get_mut(start_line * row_stride..).unwrap_or_default(), row_stride, n_rows)
+    }
+
+    /// Reads `n_rows` rows from `output_scanline` into `dest`, with rows `row_stride` bytes apart.
+    ///
+    /// libjpeg gets as many rows as it can decode at once (up to an iMCU row), instead of one row per call.
+    fn read_rows_batched(&mut self, dest: &mut [MaybeUninit<u8>], row_stride: usize, n_rows: usize) -> io::Result<()> {
+        let row_len = self.width() * self.output_components();
+        let start_line = self.dec.cinfo.output_scanline as usize;
+        let end_line = start_line + n_rows;
+        assert!(end_line <= self.height());
+        assert!(row_stride >= row_len && (n_rows == 0 || dest.len() >= (n_rows - 1) * row_stride + row_len));
+        if self.cmyk_to_rgb.is_some() {
+            for i in 0..n_rows {
+                self.read_row_uninit(&mut dest[i * row_stride..i * row_stride + row_len])?;
+            }
+            return Ok(());
+        }
+        let mut row_pointers = [ptr::null_mut::<u8>(); MAX_MCU_HEIGHT];
+        loop {
+            let line = self.dec.cinfo.output_scanline as usize;
+            if line >= end_line {
+                return Ok(());
+            }
+            let batch = (end_line - line).min(MAX_MCU_HEIGHT);
+            for (i, row) in row_pointers[..batch].iter_mut().enumerate() {
+                // in bounds, checked above
+                *row = unsafe { dest.as_mut_ptr().add((line - start_line + i) * row_stride).cast() };
+            }
+            let rows_read = self.dec.catch(|cinfo| unsafe { ffi::jpeg_read_scanlines(cinfo, row_pointers.as_mut_ptr(), batch as ffi::JDIMENSION) })? as usize;
+            debug_assert_eq!(line + rows_read, self.dec.cinfo.output_scanline as usize);
+            if rows_read == 0 {
+                return Err(error(io::ErrorKind::UnexpectedEof, "missing scanlines", &self.dec.cinfo.common));
+            }
+        }
+    }
+
+    fn read_row_uninit(&mut self, dest: &mut [MaybeUninit<u8>]) -> io::Result<()> {
+        let start_line = self.dec.cinfo.output_scanline as usize;
+        let rows_read = if let Some(cmyk) = &mut self.cmyk_to_rgb {
+            cmyk.row.resize(self.dec.cinfo.output_width as usize * 4, 0);
+            let mut row = cmyk.row.as_mut_ptr();
+            let rows_read = self.dec.catch(|cinfo| unsafe { ffi::jpeg_read_scanlines(cinfo, &mut row, 1) })? as usize;
+            if rows_read != 0 {
+                cmyk.convert(dest);
+            }
+            rows_read
+        } else {
+            let mut row = dest.as_mut_ptr().cast::<u8>();
+            self.dec.catch(|cinfo| unsafe { ffi::jpeg_read_scanlines(cinfo, &mut row, 1) })? as usize
+        };
+        debug_assert_eq!(start_line + rows_read, self.dec.cinfo.output_scanline as usize, "wat {}/{} at {}", rows_read, self.height(), start_line);
+        if rows_read == 0 {
+            return Err(error(io::ErrorKind::UnexpectedEof, "missing scanlines", &self.dec.cinfo.common));
+        }
+        Ok(())
+    }
+
+    /// Skips `rows` rows without fully decoding them, e.g. to decode only the bottom part of the image.
+    ///
+    /// Returns the number of rows skipped, which is less than `rows` only at the end of the image.
+    /// Not supported with `to_palette()`.
+    pub fn skip_rows(&mut self, rows: usize) -> io::Result<usize> {
+        let rows = rows.min(self.height() - self.dec.cinfo.output_scanline as usize);
+        let skipped = self.dec.catch(|cinfo| unsafe { hidden::jpeg_skip_scanlines(cinfo, rows as ffi::JDIMENSION) })?;
+        Ok(skipped as usize)
     }
 
     pub fn components(&self) -> &[CompInfo] {
@@ -566,39 +1500,128 @@ impl<'src> DecompressStarted<'src> {
         self.dec.components_mut()
     }
 
-    pub fn finish_decompress(mut self) -> bool {
-        unsafe { 0 != ffi::jpeg_finish_decompress(&mut self.dec.cinfo) }
+    /// Reads the rest of the file, e.g. to check it for errors after the last scanline
+    pub fn finish_decompress(mut self) -> io::Result<()> {
+        if 0 == self.dec.catch(|cinfo| unsafe { ffi::jpeg_finish_decompress(cinfo) })? {
+            return Err(error(io::ErrorKind::UnexpectedEof, "can't finish decompression", &self.dec.cinfo.common));
+        }
+        Ok(())
+    }
+
+    /// Decodes another file with the same decoder, see `Decompress::reuse_for_reader()`.
+    /// Doesn't check the rest of the current file.
+    #[inline]
+    pub fn reuse_for_reader<'new, B: BufRead + 'new + Send + Sync>(self, reader: B) -> io::Result<Decompress<'new>> {
+        self.dec.reuse_for_reader(reader)
+    }
+
+    /// Same as `reuse_for_reader()`, for a `Vec` or a slice
+    #[inline]
+    pub fn reuse_for_mem<'new>(self, mem: &'new [u8]) -> io::Result<Decompress<'new>> {
+        self.dec.reuse_for_reader(mem)
     }
 }
 
-impl<'src> Drop for Decompress<'src> {
+/// Scanlines of `DecompressStarted::rows()`
+pub struct Rows<'a, 'src, T: Copy> {
+    dec: &'a mut DecompressStarted<'src>,
+    row: Vec<T>,
+    row_len: usize,
+}
+
+impl<T: rgb::Pod> Rows<'_, '_, T> {
+    /// The next scanline, or `None` after the last one. Fails if the file is truncated.
+    pub fn next_row(&mut self) -> Option<io::Result<&[T]>> {
+        if !self.dec.read_more_chunks() {
+            return None;
+        }
+        if self.row.is_empty() {
+            if let Err(e) = self.row.try_reserve_exact(self.row_len) {
+                return Some(Err(io::Error::new(io::ErrorKind::OutOfMemory, e)));
+            }
+            self.row.resize(self.row_len, T::zeroed());
+        }
+        // Pod types can be used as bytes
+        let bytes = unsafe { slice::from_raw_parts_mut(self.row.as_mut_ptr().cast::<u8>(), mem::size_of_val(&self.row[..])) };
+        Some(self.dec.read_row(bytes).map(|_| &self.row[..]))
+    }
+
+    /// Index of the row that `next_row()` returns
+    pub fn current_row(&self) -> usize {
+        self.dec.dec.cinfo.output_scanline as usize
+    }
+}
+
+impl<T: Copy> Drop for Rows<'_, '_, T> {
     fn drop(&mut self) {
-        unsafe {
-            // unfortunately term_source alone can't be used as a reliable destructor callback,
-            // because libjpeg only calls it in finish_decompress.
-            // If you abort without completing decompression, it won't be called.
-            // In libjpeg this isn't a problem, because source fd isn't owned (so never closed),
-            // and all memory is allocated from the pool (so never freed individually).
-            // Rust has its Drop that should always run, so here's the kludge.
+        wipe(&mut self.row);
+    }
+}
 
-            if let Some(src) = self.cinfo.src.as_mut() {
-                let src_ptr = src as *const _ as *const c_void;
-                // this wrapper is not supposed to allow setting other managers, but just to be 100% safe
-                // it checks if libjpeg still has our manager instance set
-                if src_ptr == self.own_src {
-                    if let Some(term) = src.term_source {
-                        (term)(&mut self.cinfo);
-                        // our srcmanager is expected to null this ptr to prevent double-free,
-                        // and we've compared src pointers to ensure it was our src manager.
-                        debug_assert!(self.cinfo.src.is_null());
-                    }
-                }
+/// Decoding of a progressive file one scan at a time, see `Decompress::start_buffered()`
+pub struct DecompressBuffered<'src> {
+    dec: DecompressStarted<'src>,
+    /// Between `jpeg_start_output` and `jpeg_finish_output`
+    in_pass: bool,
+}
+
+impl<'src> DecompressBuffered<'src> {
+    /// Reads the next scan, and starts an output pass with the image made of all scans read so far.
+    /// Read rows of the pass from the returned decoder. Unread rows are skipped when the next pass starts.
+    ///
+    /// `None` after the last scan. Baseline files have only one pass.
+    pub fn next_pass(&mut self) -> io::Result<Option<&mut DecompressStarted<'src>>> {
+        let in_pass = self.in_pass;
+        let started = self.dec.dec.catch(|cinfo| unsafe {
+            if in_pass {
+                ffi::jpeg_finish_output(cinfo);
+            }
+            if 0 != ffi::jpeg_input_complete(cinfo) && cinfo.output_scan_number >= cinfo.input_scan_number {
+                return false;
             }
+            // reads the whole scan, so that every pass shows one more scan
+            while 3 == ffi::jpeg_consume_input(cinfo) {} // JPEG_ROW_COMPLETED
+            ffi::jpeg_start_output(cinfo, cinfo.input_scan_number);
+            true
+        });
+        self.in_pass = matches!(started, Ok(true));
+        Ok(if started? { Some(&mut self.dec) } else { None })
+    }
+
+    /// Number of scans shown in the current pass
+    pub fn scan_number(&self) -> usize {
+        self.dec.dec.cinfo.output_scan_number as usize
+    }
+
+    pub fn finish_decompress(mut self) -> io::Result<()> {
+        if self.in_pass {
+            self.dec.dec.catch(|cinfo| unsafe { ffi::jpeg_finish_output(cinfo) })?;
+        }
+        self.dec.finish_decompress()
+    }
+}
+
+impl<'src> Drop for Decompress<'src> {
+    fn drop(&mut self) {
+        self.close_src();
+        unsafe {
             ffi::jpeg_destroy_decompress(&mut self.cinfo);
         }
     }
 }
 
+/// Decodes a whole JPEG file to RGB pixels in one call.
+///
+/// Returns pixels, width and height. Fails if the file is invalid, or truncated before the image data.
+/// Like in libjpeg, missing image data is only a warning, and the rest of the image is filled in.
+pub fn decode_rgb(jpeg: &[u8]) -> io::Result<(Vec<[u8; 3]>, usize, usize)> {
+    let mut image = Decompress::new_mem(jpeg)?.rgb()?;
+    let (width, height) = (image.width(), image.height());
+    let pixels = image.read_scanlines()?;
+    image.finish_decompress()?;
+    Ok((pixels, width, height))
+}
+
 #[test]
 fn read_incomplete_file() {
     use crate::colorspace::ColorSpace;
@@ -628,7 +1651,7 @@ fn read_file() {
 
     assert_eq!(1.0, dinfo.gamma());
     assert_eq!(ColorSpace::JCS_YCbCr, dinfo.color_space());
-    assert_eq!(dinfo.components().len(), dinfo.color_space().num_components() as usize);
+    assert_eq!(dinfo.components().len(), dinfo.color_space().num_components());
 
 
     assert_eq!((45, 30), dinfo.size());
@@ -657,7 +1680,7 @@ fn read_file() {
     let mut bitmaps = [&mut Vec::new(), &mut Vec::new(), &mut Vec::new()];
     while dinfo.read_more_chunks() {
         has_chunks = true;
-        dinfo.read_raw_data_chunk(&mut bitmaps);
+        dinfo.read_raw_data_chunk(&mut bitmaps).unwrap();
         assert_eq!(bitmaps[0].len(), 4 * bitmaps[1].len());
     }
     assert!(has_chunks);
@@ -666,7 +1689,7 @@ fn read_file() {
         assert_eq!(comp.row_stride() * comp.col_stride(), bitmap.len());
     }
 
-    assert!(dinfo.finish_decompress());
+    dinfo.finish_decompress().unwrap();
 }
 
 #[test]
@@ -688,6 +1711,347 @@ fn no_markers() {
     assert_eq!(0, dinfo.markers().count());
 }
 
+#[test]
+fn rgb_from_any() {
+    use crate::compress::compress_for_test;
+    use crate::Compress;
+
+    let cmyk = [[255u8, 0, 0, 255], [0, 0, 0, 0], [255, 255, 255, 255], [255, 255, 0, 128]];
+    let pixels: Vec<u8> = cmyk.iter().flat_map(|px| px.repeat(16 * 16)).collect();
+    let jpeg = compress_for_test(ColorSpace::JCS_CMYK, 16, 64, &pixels, |c| c.set_quality(100.));
+
+    // libjpeg writes an Adobe marker, so the values are treated as inverted
+    let mut dinfo = Decompress::new_mem(&jpeg).unwrap().rgb_from_any().unwrap();
+    assert_eq!(ColorSpace::JCS_RGB, dinfo.color_space());
+    let rgb = dinfo.read_scanlines::<[u8; 3]>().unwrap();
+    let expected = [[255, 0, 0], [0, 0, 0], [255, 255, 255], [128, 128, 0]];
+    for (block, expected) in rgb.chunks(16 * 16).zip(expected) {
+        for px in block {
+            assert!(px.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) < 4), "{px:?} {expected:?}");
+        }
+    }
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let from_any = Decompress::new_mem(&data).unwrap().rgb_from_any().unwrap().read_scanlines_flat().unwrap();
+    let rgb = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    assert_eq!(rgb, from_any);
+}
+
+#[test]
+fn orientation() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut editor = crate::MetadataEditor::new(&data).unwrap();
+    editor.add(Marker::APP(1), crate::exif::test_exif()).unwrap();
+    let with_exif = editor.to_vec().unwrap();
+
+    assert_eq!(None, Decompress::with_markers(ALL_MARKERS).from_mem(&data).unwrap().orientation());
+    assert_eq!(None, Decompress::new_mem(&with_exif).unwrap().orientation());
+    assert_eq!(Some(Orientation::Rotate90), Decompress::with_markers(&[Marker::APP(1)]).from_mem(&with_exif).unwrap().orientation());
+}
+
+#[test]
+fn icc_profile() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let chunk = |seq: u8, count: u8, data: &[u8]| [ICC_PREFIX, &[seq, count], data].concat();
+    let with_chunks = |chunks: &[Vec<u8>]| {
+        let mut editor = crate::MetadataEditor::new(&data).unwrap();
+        for c in chunks {
+            editor.add(Marker::APP(2), c.clone()).unwrap();
+        }
+        let jpeg = editor.to_vec().unwrap();
+        let profile = Decompress::with_markers(&[Marker::APP(2)]).from_mem(&jpeg).unwrap().icc_profile();
+        profile
+    };
+
+    assert_eq!(None, with_chunks(&[]));
+    assert_eq!(Some(b"one".to_vec()), with_chunks(&[chunk(1, 1, b"one")]));
+    // out of order
+    assert_eq!(Some(b"onetwo".to_vec()), with_chunks(&[chunk(2, 2, b"two"), chunk(1, 2, b"one")]));
+    // missing, duplicate and inconsistent chunks
+    assert_eq!(None, with_chunks(&[chunk(1, 2, b"one")]));
+    assert_eq!(None, with_chunks(&[chunk(1, 2, b"one"), chunk(1, 2, b"one")]));
+    assert_eq!(None, with_chunks(&[chunk(1, 2, b"one"), chunk(2, 3, b"two")]));
+}
+
+#[test]
+fn read_downscaled() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let full = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+    let images = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_downscaled(&[1, 2, 4]).unwrap();
+    assert_eq!(3, images.len());
+    assert_eq!(full.concat(), images[0].pixels);
+    assert_eq!((23, 15), (images[1].width, images[1].height));
+    assert_eq!((12, 8), (images[2].width, images[2].height));
+    assert_eq!(12 * 8 * 3, images[2].pixels.len());
+
+    // bottom-right 1x2 box of the half-size image
+    let avg = |c: usize| (u32::from(full[28 * 45 + 44][c]) + u32::from(full[29 * 45 + 44][c])).div_ceil(2) as u8;
+    let last = &images[1].pixels[images[1].pixels.len() - 3..];
+    assert_eq!([avg(0), avg(1), avg(2)], last);
+}
+
+#[test]
+fn message_handler() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let log = messages.clone();
+    let mut dinfo = Decompress::config()
+        .with_message_handler(-1, move |level, msg| log.borrow_mut().push((level, msg.to_string())))
+        .from_mem(&data[..data.len() / 2]).unwrap()
+        .rgb().unwrap();
+    dinfo.read_scanlines::<[u8; 3]>().unwrap();
+    assert!(messages.borrow().iter().any(|(level, msg)| *level == -1 && msg.contains("Premature end of JPEG file")), "{messages:?}");
+
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let log = messages.clone();
+    Decompress::config()
+        .with_message_handler(1, move |level, _| log.borrow_mut().push(level))
+        .from_mem(&data).unwrap();
+    assert!(!messages.borrow().is_empty());
+    assert!(messages.borrow().iter().all(|&level| (0..=1).contains(&level)));
+}
+
+#[test]
+fn error_messages() {
+    use crate::segments::{Segments, EOI, SOI};
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let dqt = Segments::new(&data).unwrap().map(Result::unwrap).find(|s| s.marker == 0xDB).unwrap();
+    let tables_only = [&[0xFF, SOI][..], &data[dqt.offset..dqt.offset + dqt.len()], b"xx", &[0xFF, EOI]].concat();
+    let err = Decompress::new_mem(&tables_only).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    let msg = err.to_string();
+    assert!(msg.contains("only tables") && msg.contains("2 extraneous bytes before marker 0xd9"), "{msg}");
+}
+
+#[test]
+fn fatal_errors_are_results() {
+    use crate::segments::Segments;
+
+    let err = Decompress::new_mem(b"GIF89a").err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    assert!(err.to_string().contains("Not a JPEG file"), "{err}");
+
+    // invalid Huffman table before the third scan
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let dht = Segments::new(&data).unwrap().map(Result::unwrap).filter(|s| s.marker == 0xC4).nth(2).unwrap();
+    let mut corrupted = data.clone();
+    corrupted[dht.data_range().start] = 0x1F;
+    let err = Decompress::new_mem(&corrupted).unwrap().rgb().err().unwrap();
+    assert!(err.to_string().contains("Bogus DHT index"), "{err}");
+
+    // scanlines can't be read in raw mode, and after that error the decoder can't be used
+    let mut dinfo = Decompress::new_mem(&data).unwrap().raw().unwrap();
+    assert!(dinfo.read_scanlines_flat().is_err());
+    assert_eq!(io::ErrorKind::Other, dinfo.read_raw_data(&mut [&mut Vec::new(), &mut Vec::new(), &mut Vec::new()]).unwrap_err().kind());
+}
+
+#[test]
+fn output_components() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let dinfo = Decompress::new_mem(&data).unwrap().rgba().unwrap();
+    assert_eq!((4, 4), (dinfo.out_color_components(), dinfo.output_components()));
+    assert!((1..=4).contains(&dinfo.rec_outbuf_height()));
+    let dinfo = Decompress::new_mem(&data).unwrap().grayscale().unwrap();
+    assert_eq!((1, 1), (dinfo.out_color_components(), dinfo.output_components()));
+}
+
+#[test]
+fn comments() {
+    use crate::Compress;
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    cinfo.set_size(8, 8);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    cinfo.write_comments(["hello", "wörld"]).unwrap();
+    cinfo.write_marker(Marker::COM, b"bad \xFF\0").unwrap();
+    assert!(cinfo.write_scanlines(&[0; 64]).unwrap());
+    let mut cinfo = cinfo.finish_compress().unwrap();
+    let data = cinfo.data_to_vec().unwrap();
+
+    let dinfo = Decompress::with_markers(&[Marker::COM]).from_mem(&data).unwrap();
+    assert_eq!(vec!["hello", "wörld", "bad \u{FFFD}"], dinfo.comments().collect::<Vec<_>>());
+    assert_eq!(0, Decompress::new_mem(&data).unwrap().comments().count());
+}
+
+#[test]
+fn read_uninit() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let expected = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let mut buf = Vec::<[u8; 3]>::with_capacity(45 * 30);
+    let pixels = dinfo.read_scanlines_into_uninit(&mut buf.spare_capacity_mut()[..45 * 30]).unwrap();
+    assert_eq!(expected, pixels.concat());
+    assert!(dinfo.read_scanlines_into_uninit(&mut buf.spare_capacity_mut()[..45 * 30]).is_err());
+}
+
+#[test]
+fn read_padded() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let rgb = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgbx().unwrap();
+    assert_eq!(4, dinfo.output_components());
+    let rgbx = dinfo.read_scanlines::<[u8; 4]>().unwrap();
+    assert!(rgb.iter().zip(&rgbx).all(|(&[r, g, b], &rgbx)| rgbx == [r, g, b, 255]));
+
+    let xbgr = Decompress::new_mem(&data).unwrap().xbgr().unwrap().read_scanlines::<[u8; 4]>().unwrap();
+    assert!(rgb.iter().zip(&xbgr).all(|(&[r, g, b], &xbgr)| xbgr == [255, b, g, r]));
+}
+
+#[test]
+fn read_swizzled() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let rgb = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+
+    let bgr = Decompress::new_mem(&data).unwrap().bgr().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+    assert!(rgb.iter().zip(&bgr).all(|(&[r, g, b], &bgr)| bgr == [b, g, r]));
+    let bgra = Decompress::new_mem(&data).unwrap().bgra().unwrap().read_scanlines::<[u8; 4]>().unwrap();
+    assert!(rgb.iter().zip(&bgra).all(|(&[r, g, b], &bgra)| bgra == [b, g, r, 255]));
+    let argb = Decompress::new_mem(&data).unwrap().argb().unwrap().read_scanlines::<[u8; 4]>().unwrap();
+    assert!(rgb.iter().zip(&argb).all(|(&[r, g, b], &argb)| argb == [255, r, g, b]));
+    let abgr = Decompress::new_mem(&data).unwrap().abgr().unwrap().read_scanlines::<[u8; 4]>().unwrap();
+    assert!(rgb.iter().zip(&abgr).all(|(&[r, g, b], &abgr)| abgr == [255, b, g, r]));
+    assert_eq!(rgb.len(), abgr.len());
+}
+
+#[test]
+#[cfg(feature = "resize")]
+fn decode_resized() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let full = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    let avg = |px: &[u8]| px.iter().map(|&c| u64::from(c)).sum::<u64>() / px.len() as u64;
+
+    for (width, height) in [(20, 13), (45, 30), (60, 50), (1, 1)] {
+        let resized = Decompress::new_mem(&data).unwrap().decode_resized(width, height).unwrap();
+        assert_eq!(width * height * 3, resized.len());
+        assert!(avg(&full).abs_diff(avg(&resized)) < 8);
+    }
+    assert!(Decompress::new_mem(&data).unwrap().decode_resized(0, 5).is_err());
+}
+
+#[test]
+fn read_yuv420() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let gray = Decompress::new_mem(&data).unwrap().grayscale().unwrap().read_scanlines_flat().unwrap();
+
+    let i420 = Decompress::new_mem(&data).unwrap().raw().unwrap().read_yuv420(YuvFormat::I420).unwrap();
+    assert_eq!(45 * 30 + 2 * 23 * 15, i420.len());
+    assert_eq!(gray, i420[..45 * 30]);
+
+    let nv12 = Decompress::new_mem(&data).unwrap().raw().unwrap().read_yuv420(YuvFormat::NV12).unwrap();
+    assert_eq!(i420[..45 * 30], nv12[..45 * 30]);
+    let (u, v) = i420[45 * 30..].split_at(23 * 15);
+    assert!(nv12[45 * 30..].chunks_exact(2).zip(u.iter().zip(v)).all(|(uv, (&u, &v))| uv == [u, v]));
+
+    assert!(Decompress::new_mem(&data).unwrap().rgb().unwrap().read_yuv420(YuvFormat::I420).is_err());
+}
+
+#[test]
+fn to_palette() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let rgb = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+    let palette: Vec<[u8; 3]> = (0..8).map(|i| [0, 1, 2].map(|c| if i & (1 << c) != 0 { 255 } else { 0 })).collect();
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap().to_palette(&palette, false).unwrap();
+    assert_eq!(1, dinfo.output_components());
+    let indices = dinfo.read_scanlines::<u8>().unwrap();
+    assert_eq!(45 * 30, indices.len());
+    let nearest = rgb.iter().zip(&indices)
+        .filter(|&(px, &i)| px.map(|c| if c >= 128 { 255 } else { 0 }) == palette[usize::from(i)])
+        .count();
+    assert!(nearest > indices.len() * 9 / 10);
+
+    let dithered = Decompress::new_mem(&data).unwrap().to_palette(&palette, true).unwrap().read_scanlines::<u8>().unwrap();
+    assert!(dithered.iter().all(|&i| i < 8));
+    assert_ne!(indices, dithered);
+
+    assert!(Decompress::new_mem(&data).unwrap().to_palette(&[], false).is_err());
+}
+
+#[test]
+fn to_quantized() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let rgb = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+    let error = |palette: &[[u8; 3]], indices: &[u8]| -> u64 {
+        rgb.iter().zip(indices).map(|(px, &i)| px.iter().zip(palette[usize::from(i)]).map(|(&a, b)| u64::from(a.abs_diff(b))).sum::<u64>()).sum()
+    };
+
+    let mut errors = Vec::new();
+    for (two_pass, dither) in [(false, DitherMode::None), (false, DitherMode::Ordered), (true, DitherMode::None), (true, DitherMode::FloydSteinberg)] {
+        let mut dinfo = Decompress::new_mem(&data).unwrap().to_quantized(16, two_pass, dither).unwrap();
+        assert_eq!(1, dinfo.output_components());
+        let palette = dinfo.colormap().unwrap();
+        assert!(palette.len() <= 16);
+        let indices = dinfo.read_scanlines::<u8>().unwrap();
+        assert_eq!(rgb.len(), indices.len());
+        assert!(indices.iter().all(|&i| usize::from(i) < palette.len()));
+        errors.push(error(&palette, &indices));
+    }
+    // a palette made for the image is closer to it
+    assert!(errors[2] < errors[0], "{errors:?}");
+
+    let palette = [[0, 0, 0], [255, 255, 255]];
+    assert_eq!(palette.to_vec(), Decompress::new_mem(&data).unwrap().to_palette(&palette, false).unwrap().colormap().unwrap());
+    assert_eq!(None, Decompress::new_mem(&data).unwrap().rgb().unwrap().colormap());
+    assert!(Decompress::new_mem(&data).unwrap().to_quantized(1, false, DitherMode::None).is_err());
+}
+
+#[test]
+fn skip_rows() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let full = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    assert_eq!(17, dinfo.skip_rows(17).unwrap());
+    let mut rows = vec![[0; 3]; 45 * 13];
+    for row in rows.chunks_exact_mut(45) {
+        dinfo.read_row(row.as_flattened_mut()).unwrap();
+    }
+    assert_eq!(full[45 * 17..], rows);
+    assert_eq!(0, dinfo.skip_rows(1).unwrap());
+}
+
+#[test]
+fn buffered_passes() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let full = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap();
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap().start_buffered(ColorSpace::JCS_RGB).unwrap();
+    let mut passes = Vec::new();
+    while let Some(pass) = dinfo.next_pass().unwrap() {
+        passes.push(pass.read_scanlines::<[u8; 3]>().unwrap());
+        assert_eq!(passes.len(), dinfo.scan_number());
+    }
+    dinfo.finish_decompress().unwrap();
+    assert_eq!(6, passes.len());
+    assert_eq!(&full, passes.last().unwrap());
+    assert_ne!(passes[0], passes[5]);
+
+    // passes can be skipped
+    let mut dinfo = Decompress::new_mem(&data).unwrap().start_buffered(ColorSpace::JCS_RGB).unwrap();
+    assert!(dinfo.next_pass().unwrap().is_some());
+    assert!(dinfo.next_pass().unwrap().is_some());
+    assert_eq!(passes[2], dinfo.next_pass().unwrap().unwrap().read_scanlines::<[u8; 3]>().unwrap());
+    dinfo.finish_decompress().unwrap();
+}
+
+#[test]
+fn read_coefficients() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let coefficients = Decompress::new_mem(&data).unwrap().coefficients().unwrap();
+    assert_eq!((45, 30), (coefficients.width, coefficients.height));
+    assert_eq!(ColorSpace::JCS_YCbCr, coefficients.color_space);
+    let sizes: Vec<_> = coefficients.components.iter().map(|c| (c.sampling, c.width_in_blocks, c.height_in_blocks, c.blocks.len())).collect();
+    assert_eq!(vec![((2, 2), 6, 4, 24), ((1, 1), 3, 2, 6), ((1, 1), 3, 2, 6)], sizes);
+    assert!(coefficients.components[0].blocks.iter().any(|block| block[1..].iter().any(|&c| c != 0)));
+    assert_eq!(6, coefficients.components[0].row(3).len());
+}
+
 #[test]
 fn read_file_rgb() {
     use crate::colorspace::ColorSpace;
@@ -704,14 +2068,35 @@ fn read_file_rgb() {
 
     let mut dinfo = dinfo.rgb().unwrap();
     assert_eq!(ColorSpace::JCS_RGB, dinfo.color_space());
-    assert_eq!(dinfo.components().len(), dinfo.color_space().num_components() as usize);
+    assert_eq!(dinfo.components().len(), dinfo.color_space().num_components());
 
     let bitmap: Vec<[u8; 3]> = dinfo.read_scanlines().unwrap();
     assert_eq!(bitmap.len(), 45 * 30);
 
     assert!(!bitmap.contains(&[0; 3]));
 
-    assert!(dinfo.finish_decompress());
+    dinfo.finish_decompress().unwrap();
+}
+
+#[test]
+fn summary() {
+    use crate::compress::compress_for_test;
+
+    let dinfo = Decompress::with_markers(ALL_MARKERS).from_path("tests/test.jpg").unwrap();
+    let summary = dinfo.summary();
+    assert_eq!((45, 30), (summary.width, summary.height));
+    assert_eq!(8, summary.precision);
+    assert_eq!(ColorSpace::JCS_YCbCr, summary.color_space);
+    assert_eq!(vec![(2, 2), (1, 1), (1, 1)], summary.sampling);
+    assert_eq!(EntropyCoding::Huffman, summary.entropy_coding);
+    assert!(summary.progressive);
+    assert_eq!(1, summary.markers.len());
+
+    let data = compress_for_test(ColorSpace::JCS_GRAYSCALE, 8, 8, &[77; 64], |c| c.set_fastest_defaults());
+    let summary = Decompress::new_mem(&data).unwrap().summary();
+    assert!(!summary.progressive);
+    assert_eq!(vec![(1, 1)], summary.sampling);
+    assert!(summary.markers.is_empty());
 }
 
 #[test]
@@ -721,7 +2106,7 @@ fn drops_reader() {
 
     impl<R> Drop for CountsDrops<'_, R> {
         fn drop(&mut self) {
-            assert!(self as *mut _ as usize % 1024 == 0); // alignment
+            assert!((self as *mut _ as usize).is_multiple_of(1024)); // alignment
             *self.drop_count += 1;
         }
     }
@@ -736,3 +2121,335 @@ fn drops_reader() {
     drop(r);
     assert_eq!(1, drop_count);
 }
+
+#[test]
+fn corrupt_data_warnings() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    dinfo.read_scanlines::<[u8; 3]>().unwrap();
+    assert!(dinfo.warnings().is_empty());
+    assert_eq!(0, dinfo.num_warnings());
+
+    let mut dinfo = Decompress::new_mem(&data[..data.len() / 2]).unwrap().rgb().unwrap();
+    dinfo.read_scanlines::<[u8; 3]>().unwrap();
+    assert!(dinfo.warnings().iter().any(|w| w.contains("Premature end of JPEG file")), "{:?}", dinfo.warnings());
+    assert_eq!(dinfo.warnings().len(), dinfo.num_warnings());
+}
+
+#[test]
+fn decode_rgb_in_one_call() {
+    let jpeg = std::fs::read("tests/test.jpg").unwrap();
+    let (pixels, width, height) = decode_rgb(&jpeg).unwrap();
+    assert_eq!((45, 30), (width, height));
+    assert_eq!(45 * 30, pixels.len());
+    assert_eq!(45 * 30, decode_rgb(&jpeg[..jpeg.len() / 2]).unwrap().0.len());
+    assert!(decode_rgb(&jpeg[..20]).is_err());
+    assert!(decode_rgb(b"not a jpeg").is_err());
+}
+
+#[test]
+fn abbreviated_file() {
+    use crate::{ColorSpace, Compress};
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_fastest_defaults();
+    cinfo.set_size(16, 16);
+    cinfo.set_mem_dest();
+    cinfo.write_tables().unwrap();
+    let tables = cinfo.data_to_vec().unwrap();
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&[200; 16 * 16 * 3]).unwrap());
+    let frame = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+
+    for _ in 0..2 {
+        let mut dinfo = Decompress::with_tables(&tables).from_mem(&frame).unwrap().rgb().unwrap();
+        let pixels: Vec<[u8; 3]> = dinfo.read_scanlines().unwrap();
+        assert!(pixels.iter().flatten().all(|&v| v.abs_diff(200) < 2));
+        dinfo.finish_decompress().unwrap();
+    }
+    assert!(Decompress::new_mem(&frame).and_then(|d| d.rgb()).and_then(|mut d| d.read_scanlines::<[u8; 3]>()).is_err());
+    assert!(Decompress::new_mem(&tables).is_err());
+    assert!(Decompress::config().with_tables(&frame).from_mem(&frame).is_err());
+}
+
+#[test]
+fn estimated_quality() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress, QuantPreset};
+
+    for (quality, preset, color_space) in [
+        (83, None, ColorSpace::JCS_YCbCr),
+        (35, Some(QuantPreset::AnnexK), ColorSpace::JCS_YCbCr),
+        (95, Some(QuantPreset::PsnrHvs), ColorSpace::JCS_GRAYSCALE),
+        (60, Some(QuantPreset::Flat), ColorSpace::JCS_YCbCr),
+    ] {
+        let jpeg = compress_for_test(ColorSpace::JCS_RGB, 8, 8, &[50; 8 * 8 * 3], |c| {
+            c.set_color_space(color_space);
+            if let Some(preset) = preset {
+                c.set_quant_table_preset(preset);
+            }
+            c.set_quality(quality as f32);
+        });
+        assert_eq!(Some(quality), Decompress::new_mem(&jpeg).unwrap().estimated_quality(), "{preset:?}");
+    }
+    let quality = Decompress::new_path("tests/test.jpg").unwrap().estimated_quality().unwrap();
+    assert!((1..=100).contains(&quality));
+}
+
+#[test]
+fn huffman_tables() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress};
+
+    let compress = |optimize: bool| compress_for_test(ColorSpace::JCS_RGB, 16, 16, &[120; 16 * 16 * 3], |c| {
+        c.set_fastest_defaults();
+        c.set_optimize_coding(optimize);
+    });
+    let standard = compress(false);
+    let dinfo = Decompress::new_mem(&standard).unwrap();
+    assert_eq!(Some(HuffmanTable::std_luma_dc()), dinfo.dc_huffman_table(0));
+    assert_eq!(Some(HuffmanTable::std_luma_ac()), dinfo.ac_huffman_table(0));
+    assert_eq!(Some(HuffmanTable::std_chroma_ac()), dinfo.ac_huffman_table(1));
+    assert_eq!(None, dinfo.ac_huffman_table(2));
+    assert_eq!(None, dinfo.ac_huffman_table(9));
+
+    let optimized = compress(true);
+    let dinfo = Decompress::new_mem(&optimized).unwrap();
+    let table = dinfo.ac_huffman_table(0).unwrap();
+    assert_ne!(HuffmanTable::std_luma_ac(), table);
+    assert!(HuffmanTable::new(*table.bits(), table.values()).is_some());
+}
+
+#[test]
+fn progressive_and_multiple_scans() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress};
+
+    let dinfo = Decompress::new_path("tests/test.jpg").unwrap();
+    assert!(dinfo.is_progressive() && dinfo.has_multiple_scans());
+    assert!(!dinfo.uses_arithmetic_coding());
+
+    let compress = |setup: &dyn Fn(&mut Compress)| compress_for_test(ColorSpace::JCS_RGB, 8, 8, &[0; 8 * 8 * 3], |c| {
+        c.set_fastest_defaults();
+        setup(c);
+    });
+    let baseline = compress(&|_| {});
+    let dinfo = Decompress::new_mem(&baseline).unwrap();
+    assert!(!dinfo.is_progressive() && !dinfo.has_multiple_scans());
+
+    let separate = compress(&|c| c.set_scan_script(&[0, 1, 2].map(|ci| crate::ScanInfo {
+        comps_in_scan: 1, component_index: [ci, 0, 0, 0], Ss: 0, Se: 63, Ah: 0, Al: 0,
+    })));
+    let dinfo = Decompress::new_mem(&separate).unwrap();
+    assert!(!dinfo.is_progressive() && dinfo.has_multiple_scans());
+}
+
+#[test]
+fn scan_structure() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress};
+
+    let jpeg = std::fs::read("tests/test.jpg").unwrap();
+    let scans = Decompress::new_mem(&jpeg).unwrap().scans().unwrap();
+    let expected = crate::transcode::scan_script(&jpeg).unwrap();
+    assert_eq!(expected.len(), scans.len());
+    for (a, b) in scans.iter().zip(&expected) {
+        assert_eq!((a.comps_in_scan, a.component_index, a.Ss, a.Se, a.Ah, a.Al), (b.comps_in_scan, b.component_index, b.Ss, b.Se, b.Ah, b.Al));
+    }
+
+    let script = [
+        ScanInfo { comps_in_scan: 3, component_index: [0, 1, 2, 0], Ss: 0, Se: 0, Ah: 0, Al: 1 },
+        ScanInfo { comps_in_scan: 1, component_index: [2, 0, 0, 0], Ss: 1, Se: 63, Ah: 0, Al: 0 },
+        ScanInfo { comps_in_scan: 1, component_index: [1, 0, 0, 0], Ss: 1, Se: 63, Ah: 0, Al: 0 },
+        ScanInfo { comps_in_scan: 1, component_index: [0, 0, 0, 0], Ss: 1, Se: 63, Ah: 0, Al: 0 },
+        ScanInfo { comps_in_scan: 3, component_index: [0, 1, 2, 0], Ss: 0, Se: 0, Ah: 1, Al: 0 },
+    ];
+    let jpeg = compress_for_test(ColorSpace::JCS_RGB, 16, 16, &[100; 16 * 16 * 3], |c| c.set_scan_script(&script));
+    let scans = Decompress::new_mem(&jpeg).unwrap().scans().unwrap();
+    assert_eq!(script.len(), scans.len());
+    for (a, b) in scans.iter().zip(&script) {
+        assert_eq!((a.comps_in_scan, a.component_index, a.Ss, a.Se, a.Ah, a.Al), (b.comps_in_scan, b.component_index, b.Ss, b.Se, b.Ah, b.Al));
+    }
+}
+
+#[test]
+fn jfif_pixel_density() {
+    use crate::DensityUnit;
+
+    let mut jpeg = std::fs::read("tests/test.jpg").unwrap();
+    let dinfo = Decompress::new_mem(&jpeg).unwrap();
+    assert!(dinfo.has_jfif_marker());
+    assert_eq!(Some(PixelDensity { unit: DensityUnit::AspectRatio, x: 1, y: 1 }), dinfo.pixel_density());
+    assert_eq!(None, dinfo.pixel_density().unwrap().dpi());
+    drop(dinfo);
+
+    // units, Xdensity, Ydensity of APP0
+    jpeg[13..18].copy_from_slice(&[2, 0, 118, 0, 59]);
+    let density = Decompress::new_mem(&jpeg).unwrap().pixel_density().unwrap();
+    assert_eq!(DensityUnit::Centimeter, density.unit);
+    let (x, y) = density.dpi().unwrap();
+    assert!((x - 299.72).abs() < 0.01 && (y - 149.86).abs() < 0.01);
+
+    // APP0 renamed to APP15
+    jpeg[3] = 0xEF;
+    let dinfo = Decompress::new_mem(&jpeg).unwrap();
+    assert!(!dinfo.has_jfif_marker());
+    assert_eq!(None, dinfo.pixel_density());
+}
+
+#[test]
+fn unsupported_formats() {
+    let mut data = std::fs::read("tests/test.jpg").unwrap();
+    let sof = crate::segments::Segments::new(&data).unwrap().map(|s| s.unwrap()).find(|s| s.marker == 0xC2).unwrap().offset;
+    // P field of SOF2
+    data[sof + 4] = 12;
+    let err = Error::from(Decompress::new_mem(&data).err().unwrap());
+    assert!(matches!(err, Error::Unsupported { .. }), "{err}");
+    assert_eq!(Some(ffi::JERR_BAD_PRECISION), err.code());
+    assert_eq!(io::ErrorKind::Unsupported, io::Error::from(err).kind());
+
+    // lossless SOF3
+    data[sof + 4] = 8;
+    data[sof + 1] = 0xC3;
+    let err = Error::from(Decompress::new_mem(&data).err().unwrap());
+    assert!(matches!(err, Error::Unsupported { .. }), "{err}");
+    assert_eq!(Some(ffi::JERR_SOF_UNSUPPORTED), err.code());
+}
+
+#[test]
+fn memory_limit() {
+    use crate::Compress;
+
+    // progressive files need a buffer for the whole image
+    let mut cinfo = Compress::config().size(256, 256).progressive().build();
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&[128; 256 * 256 * 3]).unwrap());
+    let data = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+
+    let decode = |limit| Decompress::config().max_memory(limit).from_mem(&data)?.rgb()?.read_scanlines_flat().map(|px| px.len());
+    let err = Error::from(decode(10_000).unwrap_err());
+    assert!(matches!(err, Error::OutOfMemory { .. }), "{err}");
+    assert_eq!(256 * 256 * 3, decode(10 << 20).unwrap());
+}
+
+#[test]
+fn size_limits() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    assert!(Decompress::config().max_dimensions(45, 30).max_pixels(45 * 30).from_mem(&data).is_ok());
+    assert!(Decompress::config().max_dimensions(44, 100).from_mem(&data).is_err());
+    assert!(Decompress::config().max_dimensions(100, 29).from_mem(&data).is_err());
+    let err = Decompress::config().max_pixels(1000).from_mem(&data).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    assert!(err.to_string().contains("45x30"));
+}
+
+#[test]
+fn reuse() {
+    use crate::Compress;
+
+    let first = std::fs::read("tests/test.jpg").unwrap();
+    let mut cinfo = Compress::config().size(64, 16).progressive().build();
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    let pixels: Vec<u8> = (0..64 * 16 * 3).map(|i| (i * 7) as u8).collect();
+    assert!(cinfo.write_scanlines(&pixels).unwrap());
+    let second = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+
+    let fresh = |data| Decompress::new_mem(data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+
+    let mut dinfo = Decompress::new_mem(&first).unwrap().rgb().unwrap();
+    assert_eq!(fresh(&first), dinfo.read_scanlines_flat().unwrap());
+    let dinfo = dinfo.reuse_for_mem(&second).unwrap();
+    assert_eq!((64, 16), dinfo.size());
+    let mut dinfo = dinfo.rgb().unwrap();
+    // abandoned after one row
+    dinfo.read_row_uninit(&mut [MaybeUninit::uninit(); 64 * 3]).unwrap();
+    let mut dinfo = dinfo.reuse_for_reader(&second[..]).unwrap().rgb().unwrap();
+    assert_eq!(fresh(&second), dinfo.read_scanlines_flat().unwrap());
+    let mut dinfo = dinfo.reuse_for_mem(&first).unwrap().rgb().unwrap();
+    assert_eq!(fresh(&first), dinfo.read_scanlines_flat().unwrap());
+    dinfo.finish_decompress().unwrap();
+
+    let limited = Decompress::config().max_dimensions(50, 50).from_mem(&first).unwrap();
+    assert!(limited.reuse_for_mem(&second).is_err());
+}
+
+#[test]
+fn batched_rows() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    for fancy in [true, false] {
+        let decode = || {
+            let mut dinfo = Decompress::new_mem(&data).unwrap();
+            dinfo.do_fancy_upsampling(fancy);
+            dinfo.rgb().unwrap()
+        };
+        let mut dinfo = decode();
+        let mut one_by_one = vec![0; 45 * 30 * 3];
+        for row in one_by_one.chunks_exact_mut(45 * 3) {
+            dinfo.read_row(row).unwrap();
+        }
+        assert_eq!(one_by_one, decode().read_scanlines_flat().unwrap());
+    }
+}
+
+#[test]
+fn read_strided() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let flat = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    let stride = 45 * 3 + 13;
+    let mut padded = vec![7; stride * 29 + 45 * 3];
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, dinfo.read_scanlines_into_strided(&mut padded, 45 * 3 - 1).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::InvalidInput, dinfo.read_scanlines_into_strided(&mut padded[1..], stride).unwrap_err().kind());
+    dinfo.read_scanlines_into_strided(&mut padded, stride).unwrap();
+    for (row, expected) in padded.chunks(stride).zip(flat.chunks_exact(45 * 3)) {
+        assert_eq!(expected, &row[..45 * 3]);
+        assert!(row[45 * 3..].iter().all(|&b| b == 7));
+    }
+}
+
+#[test]
+fn rows() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let flat = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let mut rows = dinfo.rows::<u8>();
+    let mut n = 0;
+    while let Some(row) = rows.next_row() {
+        assert_eq!(&flat[n * 45 * 3..][..45 * 3], row.unwrap());
+        n += 1;
+        assert_eq!(n, rows.current_row());
+    }
+    assert_eq!(30, n);
+    assert!(rows.next_row().is_none());
+    drop(rows);
+    dinfo.finish_decompress().unwrap();
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    dinfo.skip_rows(10).unwrap();
+    let mut rows = dinfo.rows::<[u8; 3]>();
+    assert_eq!(&flat[10 * 45 * 3..][..45 * 3], rows.next_row().unwrap().unwrap().concat());
+}
+
+#[test]
+fn read_stripes() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let flat = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let mut stripe = vec![0; 8 * 45 * 3];
+    let mut joined = Vec::new();
+    let mut sizes = Vec::new();
+    loop {
+        let rows = dinfo.read_rows_into(&mut stripe, 8).unwrap();
+        if rows == 0 {
+            break;
+        }
+        sizes.push(rows);
+        joined.extend_from_slice(&stripe[..rows * 45 * 3]);
+    }
+    assert_eq!([8, 8, 8, 6][..], sizes);
+    assert_eq!(flat, joined);
+    dinfo.finish_decompress().unwrap();
+}
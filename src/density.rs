@@ -0,0 +1,49 @@
+/// Units of `PixelDensity`, as in the JFIF APP0 marker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DensityUnit {
+    /// No physical size, only the pixel aspect ratio
+    AspectRatio,
+    /// Dots per inch
+    Inch,
+    /// Dots per centimeter
+    Centimeter,
+}
+
+impl DensityUnit {
+    /// `density_unit` of libjpeg
+    pub(crate) fn jfif_code(self) -> u8 {
+        match self {
+            Self::AspectRatio => 0,
+            Self::Inch => 1,
+            Self::Centimeter => 2,
+        }
+    }
+}
+
+/// Physical resolution of the image (or just the shape of its pixels), from the JFIF APP0 marker
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PixelDensity {
+    pub unit: DensityUnit,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl PixelDensity {
+    pub(crate) fn from_jfif(unit: u8, x: u16, y: u16) -> Self {
+        let unit = match unit {
+            1 => DensityUnit::Inch,
+            2 => DensityUnit::Centimeter,
+            _ => DensityUnit::AspectRatio,
+        };
+        Self { unit, x, y }
+    }
+
+    /// Horizontal and vertical density in dots per inch. `None` if the unit is only the aspect ratio.
+    pub fn dpi(&self) -> Option<(f32, f32)> {
+        match self.unit {
+            DensityUnit::AspectRatio => None,
+            DensityUnit::Inch => Some((self.x.into(), self.y.into())),
+            DensityUnit::Centimeter => Some((f32::from(self.x) * 2.54, f32::from(self.y) * 2.54)),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::colorspace::ColorSpace;
+use crate::colorspace::ColorSpaceExt;
+use crate::decompress::Decompress;
+use crate::vec::{try_filled, wipe};
+use std::io;
+
+/// Per-MCU map of quantization error, see `Compress::enable_distortion_map()`
+///
+/// It's a small grayscale image with one pixel per MCU. Brighter pixels had more error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistortionMap {
+    /// Number of MCUs horizontally
+    pub width: usize,
+    /// Number of MCUs vertically
+    pub height: usize,
+    /// Width and height of a single MCU in image pixels
+    pub mcu_size: (usize, usize),
+    /// Mean absolute difference between input and decoded samples in each MCU, saturated at 255
+    pub pixels: Vec<u8>,
+}
+
+impl DistortionMap {
+    /// Decodes `jpeg` in the `color_space` of the `original` pixels and compares them block by block.
+    ///
+    /// `original` is a flat buffer of `width`×`height` pixels, as given to `write_scanlines`.
+    /// Fails with `InvalidInput` if the MCU size is 0.
+    pub fn new(original: &[u8], jpeg: &[u8], color_space: ColorSpace, mcu_size: (usize, usize)) -> io::Result<Self> {
+        let (mcu_width, mcu_height) = mcu_size;
+        if mcu_width == 0 || mcu_height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "MCU size can't be 0"));
+        }
+
+        let mut decoded = Decompress::new_mem(jpeg)?.to_colorspace(color_space)?;
+        let (width, height) = (decoded.width(), decoded.height());
+        let num_components = color_space.num_components();
+        if original.len() < width * height * num_components {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut pixels = decoded.read_scanlines_flat()?;
+
+        let map_width = width.div_ceil(mcu_width);
+        let map_height = height.div_ceil(mcu_height);
+        let mut sums = try_filled(0u64, map_width * map_height)?;
+        let mut counts = try_filled(0u64, map_width * map_height)?;
+
+        // padding or alpha bytes aren't compressed, and decode as 255
+        let ignored = ignored_channel(color_space);
+        let stride = width * num_components;
+        for (y, (orig_row, dec_row)) in original.chunks_exact(stride).zip(pixels.chunks_exact(stride)).enumerate() {
+            let map_row = y / mcu_height * map_width;
+            for (x, (o, d)) in orig_row.chunks_exact(num_components).zip(dec_row.chunks_exact(num_components)).enumerate() {
+                let diff: u64 = o.iter().zip(d).enumerate()
+                    .filter(|&(c, _)| Some(c) != ignored)
+                    .map(|(_, (&o, &d))| u64::from(o.abs_diff(d))).sum();
+                sums[map_row + x / mcu_width] += diff;
+                counts[map_row + x / mcu_width] += (num_components - usize::from(ignored.is_some())) as u64;
+            }
+        }
+        wipe(&mut pixels);
+
+        Ok(Self {
+            width: map_width,
+            height: map_height,
+            mcu_size,
+            pixels: sums.iter().zip(&counts)
+                .map(|(&sum, &count)| sum.checked_div(count).unwrap_or(0).min(255) as u8)
+                .collect(),
+        })
+    }
+}
+
+/// Index of the padding or alpha byte in 4-byte RGB formats
+fn ignored_channel(color_space: ColorSpace) -> Option<usize> {
+    match color_space {
+        ColorSpace::JCS_EXT_RGBX | ColorSpace::JCS_EXT_BGRX | ColorSpace::JCS_EXT_RGBA | ColorSpace::JCS_EXT_BGRA => Some(3),
+        ColorSpace::JCS_EXT_XBGR | ColorSpace::JCS_EXT_XRGB | ColorSpace::JCS_EXT_ABGR | ColorSpace::JCS_EXT_ARGB => Some(0),
+        _ => None,
+    }
+}
+
+#[test]
+fn quality_affects_distortion() {
+    use crate::Compress;
+
+    let (width, height) = (40, 24);
+    let pixels: Vec<u8> = (0..width * height).flat_map(|i| {
+        let (x, y) = (i % width, i / width);
+        [(x * 6) as u8, (y * 10) as u8, ((x * y) % 7 * 36) as u8]
+    }).collect();
+
+    let encode = |quality| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.set_quality(quality);
+        cinfo.enable_distortion_map();
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        assert!(cinfo.write_scanlines(&pixels).unwrap());
+        let cinfo = cinfo.finish_compress().unwrap();
+        cinfo.distortion_map().cloned().unwrap()
+    };
+
+    let low = encode(15.);
+    let high = encode(98.);
+    assert_eq!((3, 2), (low.width, low.height));
+    assert_eq!((16, 16), low.mcu_size);
+    assert_eq!(6, low.pixels.len());
+
+    let total = |map: &DistortionMap| map.pixels.iter().map(|&p| u32::from(p)).sum::<u32>();
+    assert!(total(&low) > total(&high));
+
+    let jpeg = std::fs::read("tests/test.jpg").unwrap();
+    let err = DistortionMap::new(&[0; 45 * 30 * 3], &jpeg, ColorSpace::JCS_RGB, (0, 16)).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
@@ -0,0 +1,80 @@
+use crate::vec::{try_filled, wipe};
+use std::mem;
+
+/// Image downscaled by `DecompressStarted::read_scanlines_downscaled()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScaledImage {
+    pub width: usize,
+    pub height: usize,
+    /// How many times smaller than the decoded image it is
+    pub divisor: usize,
+    /// Flat buffer of pixels, in the decoder's output color space
+    pub pixels: Vec<u8>,
+}
+
+/// Box filter that averages rows as they are added
+pub(crate) struct Downscaler {
+    image: ScaledImage,
+    num_components: usize,
+    src_width: usize,
+    src_height: usize,
+    /// Sums of samples of the current output row
+    sums: Vec<u32>,
+    rows_added: usize,
+}
+
+impl Downscaler {
+    /// `None` if out of memory
+    pub fn new(src_width: usize, src_height: usize, num_components: usize, divisor: usize) -> Option<Self> {
+        let width = src_width.div_ceil(divisor);
+        let height = src_height.div_ceil(divisor);
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(width * height * num_components).ok()?;
+        Some(Self {
+            image: ScaledImage { width, height, divisor, pixels },
+            num_components,
+            src_width,
+            src_height,
+            sums: try_filled(0, width * num_components).ok()?,
+            rows_added: 0,
+        })
+    }
+
+    pub fn add_row(&mut self, row: &[u8]) {
+        let (n, d) = (self.num_components, self.image.divisor);
+        for (x, px) in row.chunks_exact(n).enumerate() {
+            let out = x / d * n;
+            for (sum, &c) in self.sums[out..out + n].iter_mut().zip(px) {
+                *sum += u32::from(c);
+            }
+        }
+        self.rows_added += 1;
+        if self.rows_added.is_multiple_of(d) || self.rows_added == self.src_height {
+            self.flush_row();
+        }
+    }
+
+    fn flush_row(&mut self) {
+        let (n, d) = (self.num_components, self.image.divisor);
+        let box_height = (self.rows_added - 1) % d + 1;
+        for (x, px) in self.sums.chunks_exact_mut(n).enumerate() {
+            let box_width = d.min(self.src_width - x * d);
+            let count = (box_width * box_height) as u32;
+            for sum in px {
+                self.image.pixels.push(((*sum + count / 2) / count) as u8);
+                *sum = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> ScaledImage {
+        let pixels = mem::take(&mut self.image.pixels);
+        ScaledImage { pixels, ..self.image }
+    }
+}
+
+impl Drop for Downscaler {
+    fn drop(&mut self) {
+        wipe(&mut self.sums);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::ffi;
+use std::fmt;
+use std::io;
+
+/// Fatal error reported by libjpeg, or a failure of the reader, writer or file.
+///
+/// Methods of this crate return `io::Error` for compatibility with `Read` and `Write`,
+/// and libjpeg's errors are wrapped in it. Use `Error::from(io_error)` to get this enum back and match on it.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing of a file or stream failed
+    Io(io::Error),
+    /// libjpeg couldn't allocate memory
+    OutOfMemory { code: i32, message: String },
+    /// The file ended before the end of the image
+    Truncated { code: i32, message: String },
+    /// The data isn't a valid JPEG or uses unsupported features, or compression settings are invalid
+    InvalidData { code: i32, message: String },
+    /// The file is valid, but uses a feature this build of libjpeg doesn't support, such as 12-bit samples, lossless coding, or arithmetic coding without the `arith` feature
+    Unsupported { code: i32, message: String },
+    /// An earlier fatal error left libjpeg in an unknown state, so the decoder or encoder can't be used any more
+    Poisoned,
+    /// The progress callback returned `false`
+    Cancelled,
+}
+
+impl Error {
+    /// Classifies libjpeg's `msg_code`
+    pub(crate) fn from_libjpeg(code: i32, message: String) -> Self {
+        match code {
+            // libjpeg would need temporary files to stay under `max_memory_to_use`
+            ffi::JERR_OUT_OF_MEMORY | ffi::JERR_NO_BACKING_STORE => Self::OutOfMemory { code, message },
+            ffi::JERR_INPUT_EOF => Self::Truncated { code, message },
+            ffi::JERR_FILE_READ | ffi::JERR_FILE_WRITE => Self::Io(io::Error::other(message)),
+            ffi::JERR_BAD_PRECISION | ffi::JERR_SOF_UNSUPPORTED | ffi::JERR_ARITH_NOTIMPL | ffi::JERR_NOT_COMPILED => Self::Unsupported { code, message },
+            _ => Self::InvalidData { code, message },
+        }
+    }
+
+    /// libjpeg's `msg_code` (one of `JERR_*` constants), if the error came from libjpeg
+    pub fn code(&self) -> Option<i32> {
+        match *self {
+            Self::OutOfMemory { code, .. } | Self::Truncated { code, .. } | Self::InvalidData { code, .. } | Self::Unsupported { code, .. } => Some(code),
+            Self::Io(_) | Self::Poisoned | Self::Cancelled => None,
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Io(err) => err.kind(),
+            Self::OutOfMemory { .. } => io::ErrorKind::OutOfMemory,
+            Self::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            Self::InvalidData { .. } => io::ErrorKind::InvalidData,
+            Self::Unsupported { .. } => io::ErrorKind::Unsupported,
+            Self::Poisoned | Self::Cancelled => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::OutOfMemory { message, .. } | Self::Truncated { message, .. } | Self::InvalidData { message, .. } | Self::Unsupported { message, .. } => {
+                write!(f, "libjpeg fatal error: {message}")
+            },
+            Self::Poisoned => f.write_str("can't be used after an error"),
+            Self::Cancelled => f.write_str("cancelled by the progress callback"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+/// Unwraps errors of this crate, and wraps other I/O errors in `Error::Io`
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        err.downcast::<Error>().unwrap_or_else(Error::Io)
+    }
+}
+
+#[test]
+fn converts_io_errors() {
+    let err = io::Error::from(Error::from_libjpeg(ffi::JERR_OUT_OF_MEMORY, "Insufficient memory (case 4)".into()));
+    assert_eq!(io::ErrorKind::OutOfMemory, err.kind());
+    assert!(err.to_string().contains("Insufficient memory"));
+    let err = Error::from(err);
+    assert!(matches!(err, Error::OutOfMemory { .. }));
+    assert_eq!(Some(ffi::JERR_OUT_OF_MEMORY), err.code());
+
+    let err = Error::from(io::Error::from(io::ErrorKind::BrokenPipe));
+    assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::BrokenPipe));
+    assert_eq!(None, err.code());
+    assert_eq!(io::ErrorKind::BrokenPipe, io::Error::from(err).kind());
+}
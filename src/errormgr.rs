@@ -1,16 +1,52 @@
+use crate::error::Error;
 use crate::ffi::jpeg_common_struct;
+use crate::progress::Cancelled;
 use crate::ffi;
+use crate::ffi::jpeg_scan_info as ScanInfo;
 use std::borrow::Cow;
+use std::io;
 use std::mem;
 use std::os::raw::c_int;
+use std::panic::AssertUnwindSafe;
 
 pub use crate::ffi::jpeg_error_mgr as ErrorMgr;
 
+/// Error manager owned by `Compress` or `Decompress`. Their `cinfo.err` points to it.
+#[repr(C)]
+pub(crate) struct ErrorState {
+    pub mgr: ErrorMgr,
+    /// Description of the most recent warning, for error messages
+    pub last_warning: Option<String>,
+    /// The first `MAX_SAVED_WARNINGS` warnings
+    pub warnings: Vec<String>,
+    /// Headers of scans read so far, with component ids instead of indices
+    pub scans: Vec<ScanInfo>,
+}
+
+/// Badly corrupted files can have a warning for every MCU
+const MAX_SAVED_WARNINGS: usize = 100;
+
+impl ErrorState {
+    pub fn new(mgr: ErrorMgr) -> Box<Self> {
+        Box::new(Self { mgr, last_warning: None, warnings: Vec::new(), scans: Vec::new() })
+    }
+}
+
+/// Error with libjpeg's description of the most recent warning, which is usually the cause
+pub(crate) fn error(kind: io::ErrorKind, context: &str, cinfo: &jpeg_common_struct) -> io::Error {
+    // cinfo.err always points to ErrorState in this crate
+    match unsafe { cinfo.err.cast::<ErrorState>().as_ref() }.and_then(|state| state.last_warning.as_deref()) {
+        Some(warning) => io::Error::new(kind, format!("{context} ({warning})")),
+        None => io::Error::new(kind, context),
+    }
+}
+
 pub fn unwinding_error_mgr() -> ErrorMgr {
     unsafe {
         let mut err = mem::zeroed();
         ffi::jpeg_std_error(&mut err);
-        err.error_exit = Some(unwind_error_exit);
+        // libjpeg's callback types are declared as non-unwinding, but this one has to unwind
+        err.error_exit = Some(mem::transmute::<extern "C-unwind" fn(&mut jpeg_common_struct), unsafe extern "C" fn(&mut jpeg_common_struct)>(unwind_error_exit));
         err.emit_message = Some(silence_message);
         err
     }
@@ -31,11 +67,98 @@ fn formatted_message(prefix: & str, cinfo: &mut jpeg_common_struct) -> String {
     }
 }
 
-extern "C" fn silence_message(_cinfo: &mut jpeg_common_struct, _level: c_int) {
+extern "C" fn silence_message(cinfo: &mut jpeg_common_struct, level: c_int) {
+    // counted like in libjpeg's default handler
+    if level < 0 {
+        let msg = formatted_message("", cinfo);
+        // this handler is installed only in ErrorState
+        if let Some(state) = unsafe { cinfo.err.cast::<ErrorState>().as_mut() } {
+            state.mgr.num_warnings += 1;
+            if state.warnings.len() < MAX_SAVED_WARNINGS && state.warnings.try_reserve(1).is_ok() {
+                state.warnings.push(msg.clone());
+            }
+            state.last_warning = Some(msg);
+        }
+    } else if level == 1 {
+        if let Some(state) = unsafe { cinfo.err.cast::<ErrorState>().as_mut() } {
+            record_scan(state);
+        }
+    }
+}
+
+/// Collects scan headers from libjpeg's trace messages, because the scan fields of `cinfo` are private
+fn record_scan(state: &mut ErrorState) {
+    let params = unsafe { *state.mgr.msg_parm.i() };
+    match state.mgr.msg_code {
+        ffi::JTRC_SOS => {
+            state.scans.push(ScanInfo { comps_in_scan: 0, component_index: [0; 4], Ss: 0, Se: 0, Ah: 0, Al: 0 });
+        },
+        ffi::JTRC_SOS_COMPONENT => if let Some(scan) = state.scans.last_mut() {
+            if let Some(id) = scan.component_index.get_mut(scan.comps_in_scan as usize) {
+                *id = params[0];
+                scan.comps_in_scan += 1;
+            }
+        },
+        ffi::JTRC_SOS_PARAMS => if let Some(scan) = state.scans.last_mut() {
+            [scan.Ss, scan.Se, scan.Ah, scan.Al] = [params[0], params[1], params[2], params[3]];
+        },
+        _ => {},
+    }
 }
 
-extern "C" fn unwind_error_exit(cinfo: &mut jpeg_common_struct) {
-    let msg = formatted_message("libjpeg fatal error: ", cinfo);
+type MessageCallback = Box<dyn FnMut(i32, &str)>;
+
+/// Receives messages for `DecompressConfig::with_message_handler()`
+pub(crate) struct MessageHandler {
+    pub max_level: i32,
+    pub callback: MessageCallback,
+}
+
+impl MessageHandler {
+    /// Makes `emit_message` call this handler. It must outlive `cinfo`.
+    pub(crate) unsafe fn install(&mut self, cinfo: &mut jpeg_common_struct) {
+        cinfo.client_data = (self as *mut Self).cast();
+        if let Some(err) = cinfo.err.as_mut() {
+            err.emit_message = Some(forward_message);
+        }
+    }
+}
+
+extern "C" fn forward_message(cinfo: &mut jpeg_common_struct, level: c_int) {
+    silence_message(cinfo, level);
+    let Some(handler) = (unsafe { cinfo.client_data.cast::<MessageHandler>().as_mut() }) else { return };
+    if level > handler.max_level {
+        return;
+    }
+    let msg = formatted_message("", cinfo);
+    // can't unwind into C from here, and a failed log line shouldn't abort decoding
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (handler.callback)(level, &msg)));
+}
+
+/// Panic payload of libjpeg's fatal errors, caught by `catch_libjpeg()`
+struct FatalError {
+    code: c_int,
+    message: String,
+}
+
+extern "C-unwind" fn unwind_error_exit(cinfo: &mut jpeg_common_struct) {
+    let message = formatted_message("", cinfo);
+    let code = unsafe { cinfo.err.as_ref() }.map_or(0, |err| err.msg_code);
     // avoids calling panic handler
-    std::panic::resume_unwind(Box::new(msg));
+    std::panic::resume_unwind(Box::new(FatalError { code, message }));
+}
+
+/// Runs libjpeg calls, and returns libjpeg's fatal errors (which unwind from `error_exit`) as `Err`.
+///
+/// Cancellation by the progress callback is returned as `Error::Cancelled`, and errors of the writer as they are.
+/// Other panics, e.g. from a custom `ErrorMgr` or the reader, are propagated.
+pub(crate) fn catch_libjpeg<R>(f: impl FnOnce() -> R) -> io::Result<R> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| match payload.downcast::<FatalError>() {
+        Ok(err) => Error::from_libjpeg(err.code, err.message).into(),
+        Err(payload) if payload.is::<Cancelled>() => Error::Cancelled.into(),
+        Err(payload) => match payload.downcast::<io::Error>() {
+            Ok(err) => *err,
+            Err(payload) => std::panic::resume_unwind(payload),
+        },
+    })
 }
@@ -0,0 +1,267 @@
+//! Reading of EXIF orientation, and removal of privacy-sensitive EXIF tags
+//!
+//! Tags are removed in place: the TIFF structure keeps its size and all offsets stay valid,
+//! and data of removed tags is overwritten with zeros.
+
+use crate::vec::try_to_vec;
+
+const ORIENTATION: u16 = 0x0112;
+
+const EXIF_IFD: u16 = 0x8769;
+const GPS_IFD: u16 = 0x8825;
+
+/// Tags in IFD0/IFD1 that identify the photographer or the device
+const SENSITIVE_TAGS: &[u16] = &[
+    0x013B, // Artist
+    0x013C, // HostComputer
+    0x9C9D, // XPAuthor
+    0xC62F, // CameraSerialNumber
+];
+
+/// Tags in the EXIF IFD that identify the photographer or the device
+const SENSITIVE_EXIF_TAGS: &[u16] = &[
+    0x927C, // MakerNote, usually contains serial numbers
+    0xA420, // ImageUniqueID
+    0xA430, // CameraOwnerName
+    0xA431, // BodySerialNumber
+    0xA435, // LensSerialNumber
+];
+
+/// Returns APP1 data without GPS location, serial numbers and owner names.
+///
+/// Other tags, such as orientation, are kept. `None` if the data isn't valid EXIF.
+pub(crate) fn redact(app1: &[u8]) -> Option<Vec<u8>> {
+    let mut data = try_to_vec(app1.strip_prefix(b"Exif\0\0")?).ok()?;
+    let mut tiff = Tiff::new(&mut data[..])?;
+
+    // IFD0 and the thumbnail's IFD1
+    let mut ifd = tiff.u32(4)?;
+    for _ in 0..2 {
+        if ifd == 0 {
+            break;
+        }
+        if let Some(exif) = tiff.remove_tags(ifd, SENSITIVE_TAGS)? {
+            tiff.remove_tags(exif, SENSITIVE_EXIF_TAGS)?;
+        }
+        ifd = tiff.next_ifd(ifd)?;
+    }
+
+    let mut out = Vec::new();
+    out.try_reserve_exact(6 + data.len()).ok()?;
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&data);
+    Some(out)
+}
+
+/// How the image should be rotated or flipped for display, from the EXIF `Orientation` tag
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Orientation {
+    /// 1, no transformation needed
+    Normal,
+    /// 2
+    FlipHorizontal,
+    /// 3
+    Rotate180,
+    /// 4
+    FlipVertical,
+    /// 5, flipped along the top-left to bottom-right diagonal
+    Transpose,
+    /// 6, needs 90° clockwise rotation
+    Rotate90,
+    /// 7, flipped along the top-right to bottom-left diagonal
+    Transverse,
+    /// 8, needs 270° clockwise rotation
+    Rotate270,
+}
+
+impl Orientation {
+    /// From the value of the EXIF tag (1-8)
+    pub fn from_exif(value: u16) -> Option<Self> {
+        Some(match value {
+            1 => Self::Normal,
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => return None,
+        })
+    }
+}
+
+/// Orientation tag from IFD0 of APP1 data
+pub(crate) fn orientation(app1: &[u8]) -> Option<Orientation> {
+    let tiff = Tiff::new(app1.strip_prefix(b"Exif\0\0")?)?;
+    let ifd = tiff.u32(4)?;
+    let count = usize::from(tiff.u16(ifd)?);
+    let pos = (0..count).map(|i| ifd + 2 + 12 * i).find(|&pos| tiff.u16(pos) == Some(ORIENTATION))?;
+    Orientation::from_exif(tiff.u16(pos + 8)?)
+}
+
+struct Tiff<D> {
+    data: D,
+    big_endian: bool,
+}
+
+struct Entry {
+    tag: u16,
+    /// Position of out-of-line value data
+    value: Option<(usize, usize)>,
+}
+
+impl<D: AsRef<[u8]>> Tiff<D> {
+    fn new(data: D) -> Option<Self> {
+        let big_endian = match data.as_ref().get(..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16(&self, pos: usize) -> Option<u16> {
+        let b = self.data.as_ref().get(pos..pos + 2)?;
+        Some(if self.big_endian { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) })
+    }
+
+    fn u32(&self, pos: usize) -> Option<usize> {
+        let b = self.data.as_ref().get(pos..pos + 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) } as usize)
+    }
+
+    fn entry(&self, pos: usize) -> Option<Entry> {
+        let type_size: usize = match self.u16(pos + 2)? {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 => 8,
+            _ => 0,
+        };
+        let len = type_size.checked_mul(self.u32(pos + 4)?)?;
+        let value = if len > 4 {
+            let offset = self.u32(pos + 8)?;
+            self.data.as_ref().get(offset..offset.checked_add(len)?)?;
+            Some((offset, len))
+        } else {
+            None
+        };
+        Some(Entry { tag: self.u16(pos)?, value })
+    }
+
+    fn next_ifd(&self, ifd: usize) -> Option<usize> {
+        self.u32(ifd + 2 + 12 * usize::from(self.u16(ifd)?))
+    }
+}
+
+impl<D: AsRef<[u8]> + AsMut<[u8]>> Tiff<D> {
+    fn zero(&mut self, pos: usize, len: usize) {
+        if let Some(bytes) = self.data.as_mut().get_mut(pos..pos + len) {
+            bytes.fill(0);
+        }
+    }
+
+    /// Removes the tags and the GPS IFD. Returns the offset of the EXIF IFD, if any.
+    fn remove_tags(&mut self, ifd: usize, tags: &[u16]) -> Option<Option<usize>> {
+        let count = usize::from(self.u16(ifd)?);
+        let next = self.next_ifd(ifd)?;
+        let mut kept = 0;
+        let mut exif_ifd = None;
+        for i in 0..count {
+            let pos = ifd + 2 + 12 * i;
+            let entry = self.entry(pos)?;
+            if entry.tag == GPS_IFD {
+                if let Some(gps) = self.u32(pos + 8) {
+                    self.wipe_ifd(gps);
+                }
+            } else if !tags.contains(&entry.tag) {
+                if entry.tag == EXIF_IFD {
+                    exif_ifd = self.u32(pos + 8);
+                }
+                self.data.as_mut().copy_within(pos..pos + 12, ifd + 2 + 12 * kept);
+                kept += 1;
+                continue;
+            }
+            if let Some((offset, len)) = entry.value {
+                self.zero(offset, len);
+            }
+        }
+
+        let count_bytes = if self.big_endian { (kept as u16).to_be_bytes() } else { (kept as u16).to_le_bytes() };
+        self.data.as_mut()[ifd..ifd + 2].copy_from_slice(&count_bytes);
+        let next_pos = ifd + 2 + 12 * kept;
+        let next_bytes = if self.big_endian { (next as u32).to_be_bytes() } else { (next as u32).to_le_bytes() };
+        self.data.as_mut()[next_pos..next_pos + 4].copy_from_slice(&next_bytes);
+        self.zero(next_pos + 4, 12 * (count - kept));
+        Some(exif_ifd)
+    }
+
+    fn wipe_ifd(&mut self, ifd: usize) {
+        let Some(count) = self.u16(ifd) else { return };
+        for i in 0..usize::from(count) {
+            if let Some(Entry { value: Some((offset, len)), .. }) = self.entry(ifd + 2 + 12 * i) {
+                self.zero(offset, len);
+            }
+        }
+        self.zero(ifd, 2 + 12 * usize::from(count) + 4);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_exif() -> Vec<u8> {
+    // little-endian TIFF with IFD0: Orientation, Artist, ExifIFD, GPSInfo
+    let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+    let entry = |tag: u16, typ: u16, count: u32, value: u32| {
+        [&tag.to_le_bytes()[..], &typ.to_le_bytes(), &count.to_le_bytes(), &value.to_le_bytes()].concat()
+    };
+    tiff.extend_from_slice(&4u16.to_le_bytes());
+    tiff.extend(entry(0x0112, 3, 1, 6));
+    tiff.extend(entry(0x013B, 2, 8, 62));
+    tiff.extend(entry(EXIF_IFD, 4, 1, 70));
+    tiff.extend(entry(GPS_IFD, 4, 1, 100));
+    tiff.extend(0u32.to_le_bytes());
+    assert_eq!(62, tiff.len());
+    tiff.extend_from_slice(b"Someone\0");
+    // EXIF IFD: BodySerialNumber, ExposureTime
+    tiff.extend_from_slice(&2u16.to_le_bytes());
+    tiff.extend(entry(0xA431, 2, 4, u32::from_le_bytes(*b"123\0")));
+    tiff.extend(entry(0x829A, 4, 1, 1));
+    tiff.extend(0u32.to_le_bytes());
+    assert_eq!(100, tiff.len());
+    // GPS IFD: GPSLatitudeRef
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    tiff.extend(entry(0x0001, 2, 2, u32::from_le_bytes(*b"N\0\0\0")));
+    tiff.extend(0u32.to_le_bytes());
+    [&b"Exif\0\0"[..], &tiff].concat()
+}
+
+#[test]
+fn redacts_exif() {
+    let original = test_exif();
+    let redacted = redact(&original).unwrap();
+    assert_eq!(original.len(), redacted.len());
+
+    let tiff = &redacted[6..];
+    // orientation and EXIF IFD pointer are kept
+    assert_eq!(&[2, 0], &tiff[8..10]);
+    assert_eq!(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0], &tiff[10..20]);
+    assert_eq!(&0x8769u16.to_le_bytes(), &tiff[22..24]);
+    assert!(!tiff.windows(7).any(|w| w == b"Someone"));
+    assert!(!tiff.windows(3).any(|w| w == b"123"));
+    assert!(!tiff.windows(2).any(|w| w == b"N\0"));
+    // exposure time moved to the first entry
+    assert_eq!(&[1, 0, 0x9A, 0x82], &tiff[70..74]);
+
+    assert!(redact(b"Exif\0\0junk").is_none());
+    assert!(redact(&original[..40]).is_none());
+}
+
+#[test]
+fn reads_orientation() {
+    assert_eq!(Some(Orientation::Rotate90), orientation(&test_exif()));
+    assert_eq!(Some(Orientation::Rotate90), orientation(&redact(&test_exif()).unwrap()));
+    assert_eq!(None, orientation(b"Exif\0\0II*\0\x08\0\0\0\0\0"));
+    assert_eq!(None, orientation(b"Exif\0\0"));
+}
@@ -0,0 +1,76 @@
+//! Fields of libjpeg structs and functions that mozjpeg-sys doesn't make public
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use crate::ffi;
+use crate::ffi::boolean;
+use crate::ffi::jpeg_decompress_struct;
+use std::mem;
+use std::os::raw::{c_int, c_uint, c_ulong};
+
+/// `global_state` of `jpeg_compress_struct` before `jpeg_start_compress` and after `jpeg_finish_compress`
+pub(crate) const CSTATE_START: c_int = 100;
+
+extern "C-unwind" {
+    pub(crate) fn jpeg_skip_scanlines(cinfo: &mut jpeg_decompress_struct, num_lines: ffi::JDIMENSION) -> ffi::JDIMENSION;
+}
+
+/// From `quant_tbl_ptrs` to `comp_info`, identical in all ABI versions
+#[repr(C)]
+pub(crate) struct TableFields {
+    pub quant_tbl_ptrs: [*mut ffi::JQUANT_TBL; 4],
+    pub dc_huff_tbl_ptrs: [*mut ffi::JHUFF_TBL; 4],
+    pub ac_huff_tbl_ptrs: [*mut ffi::JHUFF_TBL; 4],
+    pub data_precision: c_int,
+    pub comp_info: *mut ffi::jpeg_component_info,
+}
+
+/// From `progressive_mode` to `CCIR601_sampling`. ABI 80 has an extra `is_baseline` before it.
+#[repr(C)]
+pub(crate) struct HeaderFields {
+    pub progressive_mode: boolean,
+    pub arith_code: boolean,
+    pub arith_dc_L: [u8; 16],
+    pub arith_dc_U: [u8; 16],
+    pub arith_ac_K: [u8; 16],
+    pub restart_interval: c_uint,
+    pub saw_JFIF_marker: boolean,
+    pub JFIF_major_version: u8,
+    pub JFIF_minor_version: u8,
+    pub density_unit: u8,
+    pub X_density: u16,
+    pub Y_density: u16,
+    pub saw_Adobe_marker: boolean,
+    pub Adobe_transform: u8,
+    pub CCIR601_sampling: boolean,
+}
+
+pub(crate) fn table_fields(cinfo: &jpeg_decompress_struct) -> &TableFields {
+    let fields = unsafe { &*(&cinfo.quant_tbl_ptrs as *const _ as *const TableFields) };
+    debug_assert_eq!(&fields.comp_info as *const _, &cinfo.comp_info as *const _);
+    fields
+}
+
+pub(crate) fn header_fields(cinfo: &jpeg_decompress_struct) -> &HeaderFields {
+    let is_baseline_size = if ffi::JPEG_LIB_VERSION >= 80 { mem::size_of::<boolean>() } else { 0 };
+    unsafe {
+        let start = (&cinfo.comp_info as *const _ as *const u8).add(mem::size_of_val(&cinfo.comp_info) + is_baseline_size);
+        debug_assert_eq!(
+            (start as usize + mem::size_of::<HeaderFields>()).next_multiple_of(mem::align_of_val(&cinfo.marker_list)),
+            &cinfo.marker_list as *const _ as usize
+        );
+        &*(start as *const HeaderFields)
+    }
+}
+
+/// `my_mem_destination_mgr` from `jdatadst.c`, the destination manager set by `jpeg_mem_dest`
+#[repr(C)]
+pub(crate) struct MemDestination {
+    pub pub_: ffi::jpeg_destination_mgr,
+    pub outbuffer: *mut *mut u8,
+    pub outsize: *mut c_ulong,
+    /// Buffer allocated by libjpeg, freed by the caller
+    pub newbuffer: *mut u8,
+    pub buffer: *mut u8,
+    pub bufsize: usize,
+}
@@ -0,0 +1,133 @@
+use crate::ffi::JHUFF_TBL;
+use std::fmt;
+
+/// Huffman table, as stored in the DHT marker
+#[derive(Clone, PartialEq, Eq)]
+pub struct HuffmanTable {
+    /// Number of codes of each length, from 1 to 16 bits
+    pub(crate) bits: [u8; 16],
+    /// Symbols in order of increasing code length
+    pub(crate) values: Vec<u8>,
+}
+
+impl fmt::Debug for HuffmanTable {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(fmt, "HuffmanTable{{bits:{:?}, values:{:?}}}", self.bits, self.values)
+    }
+}
+
+impl From<&JHUFF_TBL> for HuffmanTable {
+    fn from(table: &JHUFF_TBL) -> Self {
+        let mut bits = [0; 16];
+        bits.copy_from_slice(&table.bits[1..]);
+        let count = bits.iter().map(|&b| usize::from(b)).sum::<usize>().min(table.huffval.len());
+        Self { bits, values: table.huffval[..count].to_vec() }
+    }
+}
+
+impl HuffmanTable {
+    /// `bits` is the number of codes of each length (1 to 16 bits), and `values` are symbols in order of their codes.
+    ///
+    /// Returns `None` if the number of symbols doesn't match, or the code lengths don't make a valid prefix code.
+    pub fn new(bits: [u8; 16], values: &[u8]) -> Option<Self> {
+        let count = bits.iter().map(|&b| usize::from(b)).sum::<usize>();
+        if count == 0 || count > 256 || count != values.len() {
+            return None;
+        }
+        // codes are assigned in order, and must fit in their lengths. The all-ones code is reserved, as in libjpeg.
+        let mut code = 0u32;
+        for (len, &n) in bits.iter().enumerate() {
+            code += u32::from(n);
+            if code >= 1 << (len + 1) {
+                return None;
+            }
+            code <<= 1;
+        }
+        Some(Self { bits, values: values.to_vec() })
+    }
+
+    /// Number of codes of each length, from 1 to 16 bits
+    #[inline]
+    pub fn bits(&self) -> &[u8; 16] {
+        &self.bits
+    }
+
+    /// Symbols in order of increasing code length
+    #[inline]
+    pub fn values(&self) -> &[u8] {
+        &self.values
+    }
+
+    /// Standard luma DC table from Annex K of the JPEG spec
+    pub fn std_luma_dc() -> Self {
+        Self { bits: LUMA_DC_BITS, values: LUMA_DC_VALUES.to_vec() }
+    }
+
+    /// Standard luma AC table from Annex K of the JPEG spec
+    pub fn std_luma_ac() -> Self {
+        Self { bits: LUMA_AC_BITS, values: LUMA_AC_VALUES.to_vec() }
+    }
+
+    /// Standard chroma DC table from Annex K of the JPEG spec
+    pub fn std_chroma_dc() -> Self {
+        Self { bits: CHROMA_DC_BITS, values: CHROMA_DC_VALUES.to_vec() }
+    }
+
+    /// Standard chroma AC table from Annex K of the JPEG spec
+    pub fn std_chroma_ac() -> Self {
+        Self { bits: CHROMA_AC_BITS, values: CHROMA_AC_VALUES.to_vec() }
+    }
+}
+
+const LUMA_DC_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const LUMA_DC_VALUES: [u8; 12] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+];
+
+const CHROMA_DC_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const CHROMA_DC_VALUES: [u8; 12] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+];
+
+const LUMA_AC_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 125];
+const LUMA_AC_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+const CHROMA_AC_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 119];
+const CHROMA_AC_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+#[test]
+fn std_tables_are_valid() {
+    for table in [HuffmanTable::std_luma_dc(), HuffmanTable::std_luma_ac(), HuffmanTable::std_chroma_dc(), HuffmanTable::std_chroma_ac()] {
+        assert_eq!(Some(&table), HuffmanTable::new(table.bits, &table.values).as_ref());
+    }
+    assert!(HuffmanTable::new([3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[1, 2, 3]).is_none());
+    assert!(HuffmanTable::new([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[1, 2]).is_none());
+    // every code of a length used up, including the all-ones code
+    assert!(HuffmanTable::new([2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[1, 2]).is_none());
+    assert!(HuffmanTable::new([1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[1, 2, 3]).is_none());
+    assert!(HuffmanTable::new([1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[1, 2]).is_some());
+}
@@ -3,23 +3,39 @@
 
 use mozjpeg_sys as ffi;
 
+pub use crate::coefficients::{Coefficients, ComponentCoefficients};
 pub use crate::colorspace::ColorSpace;
 pub use crate::colorspace::ColorSpaceExt;
 pub use crate::component::CompInfo;
 pub use crate::component::CompInfoExt;
-pub use crate::compress::Compress;
-pub use crate::compress::ScanMode;
-pub use crate::decompress::{DctMethod, Format};
-pub use crate::decompress::{Decompress, ALL_MARKERS, NO_MARKERS};
+pub use crate::compress::{Compress, CompressConfig, CompressStarted};
+pub use crate::compress::{Preset, QuantPreset, ScanMode};
+pub use crate::decompress::{DctMethod, DitherMode, Format};
+pub use crate::decompress::{decode_rgb, Decompress, ALL_MARKERS, NO_MARKERS};
+pub use crate::decompress::{EntropyCoding, Summary};
+pub use crate::density::{DensityUnit, PixelDensity};
+pub use crate::distortion::DistortionMap;
+pub use crate::push::PushDecoder;
+pub use crate::error::Error;
+pub use crate::downscale::ScaledImage;
+pub use crate::tiles::{Tile, Tiles};
+pub use crate::yuv::{ChromaSiting, YuvFormat, YuvLayout, YuvRange};
+pub use crate::exif::Orientation;
 use crate::ffi::boolean;
 use crate::ffi::jpeg_common_struct;
 use crate::ffi::jpeg_compress_struct;
 pub use crate::ffi::DCTSIZE;
 use crate::ffi::JDIMENSION;
 pub use crate::ffi::JPEG_LIB_VERSION;
+/// One scan of a progressive file, see `Compress::set_scan_script()`
+pub use crate::ffi::jpeg_scan_info as ScanInfo;
 use crate::ffi::J_BOOLEAN_PARAM;
 use crate::ffi::J_INT_PARAM;
 pub use crate::marker::Marker;
+pub use crate::metadata::{MarkerPolicy, MetadataEditor};
+pub use crate::progress::Progress;
+pub use crate::scans::ScanPreset;
+pub use crate::validate::{validate, Report, ValidationError};
 
 use libc::free;
 use std::cmp::min;
@@ -28,16 +44,47 @@ use std::os::raw::{c_int, c_uchar, c_ulong, c_void};
 use std::ptr;
 use std::slice;
 
+/// Decoding and encoding of many images in parallel
+pub mod batch;
+mod coefficients;
 mod colorspace;
 mod component;
 mod compress;
 pub mod decompress;
+mod density;
+mod distortion;
+mod downscale;
+mod error;
 mod errormgr;
+mod exif;
+mod hidden;
+/// Huffman tables for entropy coding
+pub mod huffman;
 mod marker;
+#[cfg(feature = "rust_alloc")]
+mod memory;
+mod metadata;
+mod progress;
+mod push;
 /// Quantization table presets from MozJPEG
 pub mod qtable;
+mod scans;
 mod vec;
 mod readsrc;
+mod writedst;
+#[cfg(feature = "resize")]
+mod resize;
+mod tiles;
+/// Parsing of the file structure without decoding anything
+pub mod segments;
+/// Compression of an image in independent horizontal stripes
+pub mod stripes;
+/// Re-encoding of JPEG files without decoding them to pixels
+pub mod transcode;
+/// Lossless rotation and flipping, like `jpegtran`
+pub mod transform;
+mod validate;
+mod yuv;
 
 #[test]
 fn recompress() {
@@ -51,7 +98,7 @@ fn recompress() {
 
     assert_eq!(1.0, dinfo.gamma());
     assert_eq!(ColorSpace::JCS_YCbCr, dinfo.color_space());
-    assert_eq!(dinfo.components().len(), dinfo.color_space().num_components() as usize);
+    assert_eq!(dinfo.components().len(), dinfo.color_space().num_components());
 
     let samp_factors = dinfo.components().iter().map(|c|c.v_samp_factor).collect::<Vec<_>>();
 
@@ -60,9 +107,9 @@ fn recompress() {
     let mut dinfo = dinfo.raw().unwrap();
 
     let mut bitmaps = [&mut Vec::new(), &mut Vec::new(), &mut Vec::new()];
-    dinfo.read_raw_data(&mut bitmaps);
+    dinfo.read_raw_data(&mut bitmaps).unwrap();
 
-    assert!(dinfo.finish_decompress());
+    dinfo.finish_decompress().unwrap();
 
     fn write_jpeg(bitmaps: &[&mut Vec<u8>; 3], samp_factors: &Vec<i32>, scale: (f32, f32)) -> Vec<u8> {
 
@@ -88,13 +135,13 @@ fn recompress() {
             c.h_samp_factor = *samp;
         }
 
-        cinfo.start_compress();
+        let mut cinfo = cinfo.start_compress().unwrap();
 
-        assert!(cinfo.write_raw_data(&bitmaps.iter().map(|c| &c[..]).collect::<Vec<_>>()));
+        cinfo.write_raw_data(&bitmaps.iter().map(|c| &c[..]).collect::<Vec<_>>()).unwrap();
 
-        cinfo.finish_compress();
+        let mut cinfo = cinfo.finish_compress().unwrap();
 
-        return cinfo.data_to_vec().unwrap();
+        cinfo.data_to_vec().unwrap()
     }
 
     let data1 = &write_jpeg(&bitmaps, &samp_factors, (1., 1.));
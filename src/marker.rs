@@ -1,5 +1,8 @@
 use std::os::raw::c_int;
 
+/// Start of APP2 markers that contain a chunk of an ICC profile
+pub(crate) const ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+
 /// Marker number identifier (APP0-APP14 and comment markers)
 ///
 /// For actual contents of markers, see `MarkerData`
@@ -19,11 +22,11 @@ impl From<u8> for Marker {
     }
 }
 
-impl Into<c_int> for Marker {
-    fn into(self) -> c_int {
-        match self {
-            Self::APP(n) => c_int::from(n) + crate::ffi::jpeg_marker::APP0 as c_int,
-            Self::COM => crate::ffi::jpeg_marker::COM as c_int,
+impl From<Marker> for c_int {
+    fn from(marker: Marker) -> c_int {
+        match marker {
+            Marker::APP(n) => c_int::from(n) + crate::ffi::jpeg_marker::APP0 as c_int,
+            Marker::COM => crate::ffi::jpeg_marker::COM as c_int,
         }
     }
 }
@@ -0,0 +1,258 @@
+//! libjpeg memory manager that allocates from Rust's global allocator
+//!
+//! All of libjpeg's pools and virtual arrays go through `std::alloc`, so the configuration, statistics and limits
+//! of the global allocator apply to them. Virtual arrays are always kept in memory, as in libjpeg's default configuration.
+
+use crate::ffi;
+use crate::ffi::boolean;
+use crate::ffi::jpeg_common_struct;
+use crate::ffi::jpeg_memory_mgr;
+use crate::ffi::jvirt_barray_control;
+use crate::ffi::jvirt_sarray_control;
+use crate::ffi::JBLOCK;
+use crate::ffi::JBLOCKARRAY;
+use crate::ffi::JDIMENSION;
+use crate::ffi::JSAMPARRAY_MUT;
+use crate::vec::wipe_bytes;
+use std::alloc::{self, Layout};
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr::{self, NonNull};
+
+/// SIMD code needs this alignment, same as libjpeg-turbo's `ALIGN_SIZE`
+const ALIGN: usize = 32;
+/// SIMD code may read and write past the width of sample rows
+const SAMPLE_ROW_ALIGN: usize = 2 * ALIGN;
+const NUM_POOLS: usize = 2;
+
+#[repr(C)]
+struct RustMemoryMgr {
+    /// `cinfo.mem` points here
+    methods: jpeg_memory_mgr,
+    /// libjpeg's own manager, which owns objects allocated while `cinfo` was being created
+    original: *mut jpeg_memory_mgr,
+    pools: [Vec<(NonNull<u8>, Layout)>; NUM_POOLS],
+    /// Bytes in `pools`, checked against `max_memory_to_use`
+    allocated: usize,
+    /// Boxed, because libjpeg keeps pointers to them
+    #[allow(clippy::vec_box)]
+    virt_arrays: Vec<Box<VirtArray>>,
+}
+
+struct VirtArray {
+    pool: usize,
+    /// In bytes
+    row_size: usize,
+    num_rows: usize,
+    pre_zero: bool,
+    /// Allocated by `realize_virt_arrays`
+    rows: *mut *mut u8,
+}
+
+/// libjpeg's callback types are declared as non-unwinding, but these have to unwind on errors
+macro_rules! unwinding {
+    ($f:ident: fn($($arg:ty),*) $(-> $ret:ty)?) => {
+        Some(mem::transmute::<unsafe extern "C-unwind" fn($($arg),*) $(-> $ret)?, unsafe extern "C" fn($($arg),*) $(-> $ret)?>($f))
+    };
+}
+
+/// Replaces libjpeg's memory manager. Must be called right after `jpeg_CreateCompress`/`jpeg_CreateDecompress`.
+pub(crate) unsafe fn install(cinfo: &mut jpeg_common_struct) {
+    let original = cinfo.mem;
+    let mgr = Box::new(RustMemoryMgr {
+        methods: jpeg_memory_mgr {
+            alloc_small: unwinding!(alloc_small: fn(&mut jpeg_common_struct, c_int, usize) -> *mut c_void),
+            alloc_large: unwinding!(alloc_small: fn(&mut jpeg_common_struct, c_int, usize) -> *mut c_void),
+            alloc_sarray: unwinding!(alloc_sarray: fn(&mut jpeg_common_struct, c_int, JDIMENSION, JDIMENSION) -> JSAMPARRAY_MUT),
+            alloc_barray: unwinding!(alloc_barray: fn(&mut jpeg_common_struct, c_int, JDIMENSION, JDIMENSION) -> JBLOCKARRAY),
+            request_virt_sarray: unwinding!(request_virt_sarray: fn(&mut jpeg_common_struct, c_int, boolean, JDIMENSION, JDIMENSION, JDIMENSION) -> *mut jvirt_sarray_control),
+            request_virt_barray: unwinding!(request_virt_barray: fn(&mut jpeg_common_struct, c_int, boolean, JDIMENSION, JDIMENSION, JDIMENSION) -> *mut jvirt_barray_control),
+            realize_virt_arrays: unwinding!(realize_virt_arrays: fn(&mut jpeg_common_struct)),
+            access_virt_sarray: unwinding!(access_virt_sarray: fn(&mut jpeg_common_struct, *mut jvirt_sarray_control, JDIMENSION, JDIMENSION, boolean) -> JSAMPARRAY_MUT),
+            access_virt_barray: unwinding!(access_virt_barray: fn(&mut jpeg_common_struct, *mut jvirt_barray_control, JDIMENSION, JDIMENSION, boolean) -> JBLOCKARRAY),
+            free_pool: unwinding!(free_pool: fn(&mut jpeg_common_struct, c_int)),
+            self_destruct: unwinding!(self_destruct: fn(&mut jpeg_common_struct)),
+            max_memory_to_use: (*original).max_memory_to_use,
+            max_alloc_chunk: (*original).max_alloc_chunk,
+        },
+        original,
+        pools: Default::default(),
+        allocated: 0,
+        virt_arrays: Vec::new(),
+    });
+    cinfo.mem = Box::into_raw(mgr).cast();
+}
+
+unsafe fn mgr(cinfo: &mut jpeg_common_struct) -> &mut RustMemoryMgr {
+    &mut *cinfo.mem.cast::<RustMemoryMgr>()
+}
+
+/// Same as libjpeg's `ERREXIT`
+unsafe fn fail(cinfo: &mut jpeg_common_struct, code: ffi::J_MESSAGE_CODE) -> ! {
+    let err = &mut *cinfo.err;
+    err.msg_code = code as c_int;
+    // the crate's error handler unwinds, despite the declared type
+    let error_exit: unsafe extern "C-unwind" fn(&mut jpeg_common_struct) = mem::transmute(err.error_exit.expect("error_exit"));
+    error_exit(cinfo);
+    panic!("libjpeg error_exit returned");
+}
+
+unsafe fn pool_index(cinfo: &mut jpeg_common_struct, pool_id: c_int) -> usize {
+    match usize::try_from(pool_id) {
+        Ok(pool) if pool < NUM_POOLS => pool,
+        _ => fail(cinfo, ffi::JERR_BAD_POOL_ID),
+    }
+}
+
+unsafe fn alloc(cinfo: &mut jpeg_common_struct, pool: usize, size: usize, zeroed: bool) -> *mut u8 {
+    let Ok(layout) = Layout::from_size_align(size.max(1), ALIGN) else {
+        fail(cinfo, ffi::JERR_OUT_OF_MEMORY);
+    };
+    let limit = usize::try_from(mgr(cinfo).methods.max_memory_to_use).unwrap_or(0);
+    if limit != 0 && mgr(cinfo).allocated + layout.size() > limit {
+        fail(cinfo, ffi::JERR_OUT_OF_MEMORY);
+    }
+    let ptr = if zeroed { alloc::alloc_zeroed(layout) } else { alloc::alloc(layout) };
+    let Some(ptr) = NonNull::new(ptr) else {
+        fail(cinfo, ffi::JERR_OUT_OF_MEMORY);
+    };
+    let mgr = mgr(cinfo);
+    mgr.allocated += layout.size();
+    mgr.pools[pool].push((ptr, layout));
+    ptr.as_ptr()
+}
+
+/// Array of pointers to `num_rows` rows in one allocation
+unsafe fn alloc_rows(cinfo: &mut jpeg_common_struct, pool: usize, row_size: usize, num_rows: usize, zeroed: bool) -> *mut *mut u8 {
+    let Some(data_size) = row_size.checked_mul(num_rows) else {
+        fail(cinfo, ffi::JERR_OUT_OF_MEMORY);
+    };
+    let rows = alloc(cinfo, pool, num_rows * mem::size_of::<*mut u8>(), false).cast::<*mut u8>();
+    let data = alloc(cinfo, pool, data_size, zeroed);
+    for i in 0..num_rows {
+        rows.add(i).write(data.add(i * row_size));
+    }
+    rows
+}
+
+fn sample_row_size(samples_per_row: JDIMENSION) -> usize {
+    (samples_per_row as usize).next_multiple_of(SAMPLE_ROW_ALIGN)
+}
+
+fn block_row_size(blocks_per_row: JDIMENSION) -> usize {
+    blocks_per_row as usize * mem::size_of::<JBLOCK>()
+}
+
+unsafe extern "C-unwind" fn alloc_small(cinfo: &mut jpeg_common_struct, pool_id: c_int, size: usize) -> *mut c_void {
+    let pool = pool_index(cinfo, pool_id);
+    alloc(cinfo, pool, size, false).cast()
+}
+
+unsafe extern "C-unwind" fn alloc_sarray(cinfo: &mut jpeg_common_struct, pool_id: c_int, samples_per_row: JDIMENSION, num_rows: JDIMENSION) -> JSAMPARRAY_MUT {
+    let pool = pool_index(cinfo, pool_id);
+    alloc_rows(cinfo, pool, sample_row_size(samples_per_row), num_rows as usize, false)
+}
+
+unsafe extern "C-unwind" fn alloc_barray(cinfo: &mut jpeg_common_struct, pool_id: c_int, blocks_per_row: JDIMENSION, num_rows: JDIMENSION) -> JBLOCKARRAY {
+    let pool = pool_index(cinfo, pool_id);
+    alloc_rows(cinfo, pool, block_row_size(blocks_per_row), num_rows as usize, false).cast()
+}
+
+unsafe fn request_virt_array(cinfo: &mut jpeg_common_struct, pool_id: c_int, pre_zero: boolean, row_size: usize, num_rows: JDIMENSION) -> *mut VirtArray {
+    // same restriction as in libjpeg
+    if pool_id != ffi::JPOOL_IMAGE {
+        fail(cinfo, ffi::JERR_BAD_POOL_ID);
+    }
+    let mut array = Box::new(VirtArray {
+        pool: pool_id as usize,
+        row_size,
+        num_rows: num_rows as usize,
+        pre_zero: pre_zero != 0,
+        rows: ptr::null_mut(),
+    });
+    let ptr = &mut *array as *mut VirtArray;
+    mgr(cinfo).virt_arrays.push(array);
+    ptr
+}
+
+unsafe extern "C-unwind" fn request_virt_sarray(cinfo: &mut jpeg_common_struct, pool_id: c_int, pre_zero: boolean, samples_per_row: JDIMENSION, num_rows: JDIMENSION, _max_access: JDIMENSION) -> *mut jvirt_sarray_control {
+    request_virt_array(cinfo, pool_id, pre_zero, sample_row_size(samples_per_row), num_rows).cast()
+}
+
+unsafe extern "C-unwind" fn request_virt_barray(cinfo: &mut jpeg_common_struct, pool_id: c_int, pre_zero: boolean, blocks_per_row: JDIMENSION, num_rows: JDIMENSION, _max_access: JDIMENSION) -> *mut jvirt_barray_control {
+    request_virt_array(cinfo, pool_id, pre_zero, block_row_size(blocks_per_row), num_rows).cast()
+}
+
+unsafe extern "C-unwind" fn realize_virt_arrays(cinfo: &mut jpeg_common_struct) {
+    let arrays = mgr(cinfo).virt_arrays.iter_mut()
+        .filter(|a| a.rows.is_null())
+        .map(|a| &mut **a as *mut VirtArray)
+        .collect::<Vec<_>>();
+    for array in arrays {
+        let array = &mut *array;
+        array.rows = alloc_rows(cinfo, array.pool, array.row_size, array.num_rows, array.pre_zero);
+    }
+}
+
+unsafe fn access_virt_array(cinfo: &mut jpeg_common_struct, array: *mut VirtArray, start_row: JDIMENSION, num_rows: JDIMENSION) -> *mut *mut u8 {
+    let array = &*array;
+    let end_row = start_row as usize + num_rows as usize;
+    if array.rows.is_null() || end_row > array.num_rows {
+        fail(cinfo, ffi::JERR_BAD_VIRTUAL_ACCESS);
+    }
+    array.rows.add(start_row as usize)
+}
+
+unsafe extern "C-unwind" fn access_virt_sarray(cinfo: &mut jpeg_common_struct, ptr: *mut jvirt_sarray_control, start_row: JDIMENSION, num_rows: JDIMENSION, _writable: boolean) -> JSAMPARRAY_MUT {
+    access_virt_array(cinfo, ptr.cast(), start_row, num_rows)
+}
+
+unsafe extern "C-unwind" fn access_virt_barray(cinfo: &mut jpeg_common_struct, ptr: *mut jvirt_barray_control, start_row: JDIMENSION, num_rows: JDIMENSION, _writable: boolean) -> JBLOCKARRAY {
+    access_virt_array(cinfo, ptr.cast(), start_row, num_rows).cast()
+}
+
+unsafe extern "C-unwind" fn free_pool(cinfo: &mut jpeg_common_struct, pool_id: c_int) {
+    let pool = pool_index(cinfo, pool_id);
+    let mgr = mgr(cinfo);
+    mgr.virt_arrays.retain(|a| a.pool != pool);
+    for (ptr, layout) in mgr.pools[pool].drain(..) {
+        mgr.allocated -= layout.size();
+        wipe_bytes(ptr.as_ptr(), layout.size());
+        alloc::dealloc(ptr.as_ptr(), layout);
+    }
+}
+
+unsafe extern "C-unwind" fn self_destruct(cinfo: &mut jpeg_common_struct) {
+    for pool in (0..NUM_POOLS as c_int).rev() {
+        free_pool(cinfo, pool);
+    }
+    let mgr = Box::from_raw(cinfo.mem.cast::<RustMemoryMgr>());
+    cinfo.mem = mgr.original;
+    drop(mgr);
+    if let Some(original_self_destruct) = (*cinfo.mem).self_destruct {
+        original_self_destruct(cinfo);
+    }
+}
+
+#[test]
+fn uses_rust_allocator() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress, Decompress};
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let dinfo = Decompress::new_mem(&data).unwrap();
+    let pixels = dinfo.rgb().unwrap().read_scanlines_flat().unwrap();
+
+    // progressive decoding and transcoding use virtual arrays
+    let mut dinfo = Decompress::new_mem(&data).unwrap();
+    let coefficients = dinfo.read_coefficients().unwrap();
+    let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+    cinfo.set_mem_dest();
+    let cinfo = cinfo.write_coefficients(&coefficients).unwrap();
+    let mut cinfo = cinfo.finish_compress().unwrap();
+    let transcoded = cinfo.data_to_vec().unwrap();
+    assert_eq!(pixels, Decompress::new_mem(&transcoded).unwrap().rgb().unwrap().read_scanlines_flat().unwrap());
+
+    let jpeg = compress_for_test(ColorSpace::JCS_RGB, 45, 30, &pixels, |c| c.set_progressive_mode());
+    assert!(Decompress::new_mem(&jpeg).unwrap().rgb().unwrap().read_scanlines_flat().is_ok());
+}
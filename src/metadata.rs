@@ -0,0 +1,240 @@
+use crate::marker::Marker;
+use crate::segments::{Segments, EOI, SOI, SOS};
+use std::borrow::Cow;
+use std::io;
+
+/// Adds, replaces or removes APPn and COM markers of an existing JPEG file.
+///
+/// Only the file header is rewritten. Compressed image data is copied as-is, and nothing is decoded.
+///
+/// ```rust,no_run
+/// # fn t(jpeg: &[u8]) -> std::io::Result<()> {
+/// let mut editor = mozjpeg::MetadataEditor::new(jpeg)?;
+/// editor.remove(mozjpeg::Marker::APP(1));
+/// editor.add(mozjpeg::Marker::COM, b"hello".to_vec())?;
+/// let edited = editor.to_vec()?;
+/// # Ok(()) }
+/// ```
+pub struct MetadataEditor<'a> {
+    header: Vec<Segment<'a>>,
+    /// Tables and compressed data after the last header segment
+    rest: &'a [u8],
+}
+
+enum Segment<'a> {
+    Marker(Marker, Cow<'a, [u8]>),
+    /// Other segment, with the marker and length
+    Raw(&'a [u8]),
+}
+
+/// Which markers to keep when copying metadata
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MarkerPolicy {
+    /// Copy all markers unchanged
+    #[default]
+    KeepAll,
+    /// Remove GPS location, serial numbers and owner names from EXIF, and drop XMP and IPTC markers
+    /// (which can contain the same information). Orientation, ICC profiles and comments are kept.
+    Private,
+}
+
+impl MarkerPolicy {
+    /// Data to write for this marker, or `None` if the marker should be removed
+    pub fn apply<'a>(self, marker: Marker, data: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        match self {
+            Self::KeepAll => Some(Cow::Borrowed(data)),
+            Self::Private => match marker {
+                Marker::APP(1) if data.starts_with(b"Exif\0") => crate::exif::redact(data).map(Cow::Owned),
+                Marker::APP(1) if data.starts_with(b"http://ns.adobe.com/xap/1.0/") => None,
+                Marker::APP(13) => None,
+                _ => Some(Cow::Borrowed(data)),
+            },
+        }
+    }
+}
+
+/// Maximum size of data in a marker
+const MAX_MARKER_LEN: usize = 65533;
+
+impl<'a> MetadataEditor<'a> {
+    /// Parses the file header. Fails if the file isn't a JPEG.
+    pub fn new(jpeg: &'a [u8]) -> io::Result<Self> {
+        let mut header = Vec::new();
+        let mut rest = &jpeg[jpeg.len()..];
+        for segment in Segments::new(jpeg)? {
+            let segment = segment?;
+            match segment.marker {
+                0xE0..=0xEF | 0xFE => header.push(Segment::Marker(segment.marker.into(), Cow::Borrowed(segment.data))),
+                SOS | EOI => {
+                    rest = &jpeg[segment.offset..];
+                    break;
+                },
+                SOI => {},
+                _ => header.push(Segment::Raw(&jpeg[segment.offset..segment.offset + segment.len()])),
+            }
+        }
+        Ok(Self { header, rest })
+    }
+
+    /// APPn and COM markers in file order
+    pub fn markers(&self) -> impl Iterator<Item = (Marker, &[u8])> {
+        self.header.iter().filter_map(|s| match s {
+            Segment::Marker(m, data) => Some((*m, &data[..])),
+            Segment::Raw(_) => None,
+        })
+    }
+
+    /// Adds a marker after all other APPn and COM markers at the start of the file
+    pub fn add(&mut self, marker: Marker, data: Vec<u8>) -> io::Result<()> {
+        check_len(&data)?;
+        let pos = self.header.iter().take_while(|s| matches!(s, Segment::Marker(..))).count();
+        self.header.insert(pos, Segment::Marker(marker, Cow::Owned(data)));
+        Ok(())
+    }
+
+    /// Replaces data of a marker of the same type and identifier (e.g. `Exif\0` or XMP namespace in APP1),
+    /// or adds it if there is none.
+    pub fn replace(&mut self, marker: Marker, data: Vec<u8>) -> io::Result<()> {
+        check_len(&data)?;
+        let id = identifier(marker, &data).to_vec();
+        let mut found = false;
+        self.header.retain_mut(|s| match s {
+            Segment::Marker(m, old) if *m == marker && identifier(marker, old) == id => {
+                if found {
+                    return false;
+                }
+                found = true;
+                *old = Cow::Owned(data.clone());
+                true
+            },
+            _ => true,
+        });
+        if !found {
+            self.add(marker, data)?;
+        }
+        Ok(())
+    }
+
+    /// Removes all markers of this type
+    pub fn remove(&mut self, marker: Marker) {
+        self.retain(|m, _| m != marker);
+    }
+
+    /// Keeps only markers for which the callback returns `true`
+    pub fn retain(&mut self, mut keep: impl FnMut(Marker, &[u8]) -> bool) {
+        self.header.retain(|s| match s {
+            Segment::Marker(m, data) => keep(*m, data),
+            Segment::Raw(_) => true,
+        });
+    }
+
+    /// Removes or redacts markers according to the policy
+    pub fn apply_policy(&mut self, policy: MarkerPolicy) {
+        self.header.retain_mut(|s| match s {
+            Segment::Marker(marker, data) => match policy.apply(*marker, data) {
+                Some(Cow::Owned(new_data)) => {
+                    *data = Cow::Owned(new_data);
+                    true
+                },
+                Some(Cow::Borrowed(_)) => true,
+                None => false,
+            },
+            Segment::Raw(_) => true,
+        });
+    }
+
+    /// Writes the edited file. Fails only if out of memory.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        out.try_reserve_exact(self.rest.len() + 2 + self.header.iter().map(|s| match s {
+            Segment::Marker(_, data) => data.len() + 4,
+            Segment::Raw(bytes) => bytes.len(),
+        }).sum::<usize>()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        out.extend_from_slice(&[0xFF, SOI]);
+        for segment in &self.header {
+            match segment {
+                Segment::Marker(marker, data) => {
+                    out.extend_from_slice(&[0xFF, marker_code(*marker)]);
+                    out.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+                    out.extend_from_slice(data);
+                },
+                Segment::Raw(bytes) => out.extend_from_slice(bytes),
+            }
+        }
+        out.extend_from_slice(self.rest);
+        Ok(out)
+    }
+}
+
+fn check_len(data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_MARKER_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "marker data is limited to 65533 bytes"));
+    }
+    Ok(())
+}
+
+fn marker_code(marker: Marker) -> u8 {
+    match marker {
+        Marker::APP(n) => 0xE0 + n,
+        Marker::COM => 0xFE,
+    }
+}
+
+/// APPn markers start with a NUL-terminated name of their format
+fn identifier(marker: Marker, data: &[u8]) -> &[u8] {
+    match marker {
+        Marker::APP(_) => data.iter().position(|&b| b == 0).map_or(data, |end| &data[..=end]),
+        Marker::COM => &[],
+    }
+}
+
+#[test]
+fn edit_markers() {
+    use crate::decompress::{Decompress, ALL_MARKERS};
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut editor = MetadataEditor::new(&data).unwrap();
+    assert_eq!(1, editor.markers().count());
+    editor.add(Marker::APP(1), b"Exif\0\0old".to_vec()).unwrap();
+    editor.add(Marker::APP(1), b"http://ns.adobe.com/xap/1.0/\0<x/>".to_vec()).unwrap();
+    editor.add(Marker::COM, b"comment".to_vec()).unwrap();
+    editor.replace(Marker::APP(1), b"Exif\0\0new".to_vec()).unwrap();
+    assert!(editor.add(Marker::COM, vec![0; 70000]).is_err());
+    let edited = editor.to_vec().unwrap();
+
+    let dinfo = Decompress::with_markers(ALL_MARKERS).from_mem(&edited).unwrap();
+    let markers = dinfo.markers().map(|m| (m.marker, m.data.to_vec())).collect::<Vec<_>>();
+    assert_eq!(4, markers.len());
+    assert_eq!(Marker::APP(0), markers[0].0);
+    assert_eq!((Marker::APP(1), b"Exif\0\0new".to_vec()), markers[1]);
+    assert_eq!((Marker::COM, b"comment".to_vec()), markers[3]);
+    drop(dinfo);
+
+    // image data is untouched
+    let tail = data.len() - 2000;
+    assert!(edited.ends_with(&data[tail..]));
+
+    let mut editor = MetadataEditor::new(&edited).unwrap();
+    editor.remove(Marker::APP(1));
+    editor.retain(|m, _| m != Marker::COM);
+    assert_eq!(vec![Marker::APP(0)], editor.markers().map(|(m, _)| m).collect::<Vec<_>>());
+    assert_eq!(data, editor.to_vec().unwrap());
+}
+
+#[test]
+fn private_policy() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut editor = MetadataEditor::new(&data).unwrap();
+    let exif = crate::exif::test_exif();
+    editor.add(Marker::APP(1), exif.clone()).unwrap();
+    editor.add(Marker::APP(1), b"http://ns.adobe.com/xap/1.0/\0<x/>".to_vec()).unwrap();
+    editor.add(Marker::APP(2), b"ICC_PROFILE\0\x01\x01".to_vec()).unwrap();
+    editor.apply_policy(MarkerPolicy::Private);
+
+    let markers = editor.markers().map(|(m, d)| (m, d.to_vec())).collect::<Vec<_>>();
+    assert_eq!(3, markers.len());
+    assert_eq!(Marker::APP(1), markers[1].0);
+    assert_eq!(exif.len(), markers[1].1.len());
+    assert_ne!(exif, markers[1].1);
+    assert_eq!(Marker::APP(2), markers[2].0);
+}
@@ -0,0 +1,102 @@
+use crate::ffi;
+use crate::ffi::jpeg_common_struct;
+use std::mem;
+
+/// Progress of compression or decompression, see `Compress::set_progress_callback()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Work units (usually rows of MCUs) completed in the current pass
+    pub pass_counter: u64,
+    /// Total work units in the current pass
+    pub pass_limit: u64,
+    /// Passes completed so far
+    pub completed_passes: u32,
+    /// Expected number of passes. It can change, e.g. when a progressive file turns out to have more scans.
+    pub total_passes: u32,
+}
+
+impl Progress {
+    /// Estimate of the completed part of the whole job, from 0 to 1
+    pub fn fraction(&self) -> f32 {
+        if self.total_passes == 0 {
+            return 0.;
+        }
+        let pass = if self.pass_limit > 0 { self.pass_counter as f32 / self.pass_limit as f32 } else { 0. };
+        ((self.completed_passes as f32 + pass) / self.total_passes as f32).min(1.)
+    }
+}
+
+pub(crate) type ProgressCallback = Box<dyn FnMut(Progress) -> bool>;
+
+/// Panic payload that stops libjpeg when the callback returns `false`, caught by `catch_libjpeg()`
+pub(crate) struct Cancelled;
+
+/// `cinfo.progress` points to it
+#[repr(C)]
+pub(crate) struct ProgressMonitor {
+    mgr: ffi::jpeg_progress_mgr,
+    callback: ProgressCallback,
+}
+
+impl ProgressMonitor {
+    pub(crate) fn new(callback: ProgressCallback) -> Box<Self> {
+        let mut mgr: ffi::jpeg_progress_mgr = unsafe { mem::zeroed() };
+        // libjpeg's callback types are declared as non-unwinding, but this one unwinds to cancel
+        mgr.progress_monitor = Some(unsafe { mem::transmute::<extern "C-unwind" fn(&mut jpeg_common_struct), unsafe extern "C" fn(&mut jpeg_common_struct)>(progress_monitor) });
+        Box::new(Self { mgr, callback })
+    }
+
+    /// Makes libjpeg call this monitor. It must outlive `cinfo`.
+    pub(crate) unsafe fn install(&mut self, cinfo: &mut jpeg_common_struct) {
+        cinfo.progress = &mut self.mgr;
+    }
+}
+
+extern "C-unwind" fn progress_monitor(cinfo: &mut jpeg_common_struct) {
+    // installed only with ProgressMonitor
+    let Some(monitor) = (unsafe { cinfo.progress.cast::<ProgressMonitor>().as_mut() }) else { return };
+    let progress = Progress {
+        pass_counter: monitor.mgr.pass_counter.max(0) as u64,
+        pass_limit: monitor.mgr.pass_limit.max(0) as u64,
+        completed_passes: monitor.mgr.completed_passes.max(0) as u32,
+        total_passes: monitor.mgr.total_passes.max(0) as u32,
+    };
+    if !(monitor.callback)(progress) {
+        std::panic::resume_unwind(Box::new(Cancelled));
+    }
+}
+
+#[test]
+fn progress_and_cancellation() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress, Decompress, Error};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let reports = Rc::new(RefCell::new(Vec::new()));
+    let log = reports.clone();
+    let jpeg = compress_for_test(ColorSpace::JCS_RGB, 256, 256, &[128; 256 * 256 * 3], |c| {
+        c.set_progress_callback(move |progress| {
+            log.borrow_mut().push(progress);
+            true
+        });
+    });
+    assert!(reports.borrow().len() > 2);
+    assert!(reports.borrow().iter().all(|p| p.pass_counter <= p.pass_limit && p.completed_passes < p.total_passes));
+
+    let calls = Rc::new(RefCell::new(0));
+    let counter = calls.clone();
+    let mut dinfo = Decompress::new_mem(&jpeg).unwrap();
+    dinfo.set_progress_callback(move |_| {
+        *counter.borrow_mut() += 1;
+        *counter.borrow() < 3
+    });
+    // progressive files are buffered when decompression starts
+    let err = dinfo.rgb().and_then(|mut dinfo| dinfo.read_scanlines::<[u8; 3]>()).unwrap_err();
+    assert_eq!(3, *calls.borrow());
+    assert!(matches!(Error::from(err), Error::Cancelled));
+
+    let progress = Progress { pass_counter: 5, pass_limit: 10, completed_passes: 1, total_passes: 2 };
+    assert_eq!(0.75, progress.fraction());
+    assert_eq!(0., Progress { total_passes: 0, ..progress }.fraction());
+}
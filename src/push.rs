@@ -0,0 +1,155 @@
+use crate::colorspace::{ColorSpace, ColorSpaceExt};
+use crate::decompress::{Decompress, DecompressConfig};
+use std::io;
+
+/// Decoder that is given the file in chunks, as they arrive, and decodes as much as it can from the data it has.
+///
+/// It never blocks waiting for data, so it can be used in async code without a reader thread.
+/// Create it with `DecompressConfig::push_decoder()`.
+///
+/// 1. `push()` chunks of the file until `header()` is available.
+/// 2. Call `read_rows_into()` after every `push()`. It returns 0 when it needs more data.
+///    Progressive files can't be decoded until all of their scans have been pushed.
+/// 3. Call `finish_input()` after the last chunk. If the file was truncated, the remaining rows are then decoded as gray.
+///
+/// With an async reader (e.g. tokio's `AsyncBufRead`), push every chunk returned by `fill_buf().await`, `consume()` all of it,
+/// and call `finish_input()` when `fill_buf()` returns an empty slice. Decoding of the pushed data doesn't block on I/O.
+pub struct PushDecoder {
+    dec: Decompress<'static>,
+    out_color_space: ColorSpace,
+    header_read: bool,
+    started: bool,
+}
+
+impl PushDecoder {
+    /// Same as `DecompressConfig::new().push_decoder(out_color_space)`
+    pub fn new(out_color_space: ColorSpace) -> io::Result<Self> {
+        DecompressConfig::new().push_decoder(out_color_space)
+    }
+
+    pub(crate) fn new_internal(dec: Decompress<'static>, out_color_space: ColorSpace) -> Self {
+        Self { dec, out_color_space, header_read: false, started: false }
+    }
+
+    /// Appends the next chunk of the file, and reads the header if it's complete.
+    ///
+    /// Fails if the header is invalid, or the image is larger than the config's limits.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.header_read = self.dec.push_data(data, self.header_read)?;
+        Ok(())
+    }
+
+    /// There's no more data. Missing parts of the image will be decoded as gray, and reported in `warnings()`.
+    ///
+    /// Fails if the file ended before the end of the header.
+    pub fn finish_input(&mut self) -> io::Result<()> {
+        self.dec.push_eof(self.header_read)
+    }
+
+    /// Available after enough data has been pushed, e.g. to check the size and markers of the image
+    pub fn header(&self) -> Option<&Decompress<'static>> {
+        self.header_read.then_some(&self.dec)
+    }
+
+    /// Bytes in a row of pixels in the output color space. Available with the `header()`.
+    pub fn row_len(&self) -> Option<usize> {
+        self.header_read.then(|| self.dec.width() * self.out_color_space.num_components())
+    }
+
+    /// Reads up to `n_rows` of the next scanlines into `dest`, as many as can be decoded from the data pushed so far.
+    ///
+    /// Returns the number of rows read, which is 0 if more data is needed, or after the last row.
+    /// `dest` must have space for `n_rows` rows of `row_len()` bytes.
+    #[track_caller]
+    pub fn read_rows_into(&mut self, dest: &mut [u8], n_rows: usize) -> io::Result<usize> {
+        if !self.header_read {
+            return Ok(0);
+        }
+        if !self.started {
+            self.started = self.dec.try_start_decompress(self.out_color_space)?;
+            if !self.started {
+                return Ok(0);
+            }
+        }
+        self.dec.read_available_rows(dest, n_rows)
+    }
+
+    /// Index of the next row `read_rows_into()` will return
+    pub fn current_row(&self) -> usize {
+        if self.started { self.dec.output_scanline() } else { 0 }
+    }
+
+    /// All rows have been read
+    pub fn is_finished(&self) -> bool {
+        self.header_read && self.current_row() >= self.dec.height()
+    }
+
+    /// Corrupt-data warnings libjpeg has reported so far, see `DecompressStarted::warnings()`
+    pub fn warnings(&self) -> &[String] {
+        self.dec.warnings()
+    }
+}
+
+#[test]
+fn push_chunks() {
+    use crate::Compress;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let expected = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+
+    let decode = |data: &[u8], chunk_size: usize| {
+        let mut dec = PushDecoder::new(ColorSpace::JCS_RGB).unwrap();
+        let mut pixels = Vec::new();
+        let mut row = Vec::new();
+        let mut chunks = data.chunks(chunk_size);
+        loop {
+            if let Some(row_len) = dec.row_len() {
+                row.resize(row_len, 0);
+                while dec.read_rows_into(&mut row, 1).unwrap() == 1 {
+                    pixels.extend_from_slice(&row);
+                }
+            }
+            match chunks.next() {
+                Some(chunk) => dec.push(chunk).unwrap(),
+                None if dec.is_finished() => break,
+                None => dec.finish_input().unwrap(),
+            }
+        }
+        (pixels, dec.warnings().len())
+    };
+    for chunk_size in [1, 7, 100, 10000] {
+        assert_eq!((expected.clone(), 0), decode(&data, chunk_size));
+    }
+
+    // truncated
+    let truncated = &data[..data.len() / 2];
+    let expected_truncated = {
+        let mut dec = Decompress::new_mem(truncated).unwrap().rgb().unwrap();
+        (dec.read_scanlines_flat().unwrap(), dec.warnings().len())
+    };
+    assert!(expected_truncated.1 > 0);
+    assert_eq!(expected_truncated, decode(truncated, 50));
+
+    let mut dec = PushDecoder::new(ColorSpace::JCS_RGB).unwrap();
+    dec.push(&data[..10]).unwrap();
+    assert!(dec.header().is_none());
+    dec.push(&data[10..400]).unwrap();
+    assert_eq!((45, 30), dec.header().unwrap().size());
+    assert_eq!(Some(45 * 3), dec.row_len());
+    let mut dec = PushDecoder::new(ColorSpace::JCS_RGB).unwrap();
+    dec.push(&data[..10]).unwrap();
+    assert_eq!(io::ErrorKind::UnexpectedEof, dec.finish_input().unwrap_err().kind());
+
+    // with large markers, skipped across chunks
+    let comment = vec![b'x'; 3000];
+    let mut cinfo = Compress::config().size(16, 16).progressive().build();
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    cinfo.write_marker(crate::Marker::APP(5), &comment).unwrap();
+    assert!(cinfo.write_scanlines(&[77; 16 * 16 * 3]).unwrap());
+    let data = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+    let expected = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    for chunk_size in [1, 33, 1000] {
+        assert_eq!((expected.clone(), 0), decode(&data, chunk_size));
+    }
+}
@@ -1,18 +1,19 @@
 #![allow(non_upper_case_globals)]
 
-use std::cmp::{max, min};
+use crate::ffi::JQUANT_TBL;
 use std::fmt;
-use std::os::raw::c_uint;
+use std::os::raw::{c_int, c_uint};
 type Coef = c_uint;
 
+#[derive(Clone)]
 pub struct QTable {
     pub(crate) coeffs: [Coef; 64],
 }
 
 impl PartialEq for QTable {
     fn eq(&self, other: &Self) -> bool {
-        let iter2 = (&other.coeffs).iter().cloned();
-        (&self.coeffs).iter().cloned().zip(iter2).all(|(s,o)|s==o)
+        let iter2 = other.coeffs.iter().cloned();
+        self.coeffs.iter().cloned().zip(iter2).all(|(s,o)|s==o)
     }
 }
 
@@ -22,6 +23,16 @@ impl fmt::Debug for QTable {
     }
 }
 
+impl From<&JQUANT_TBL> for QTable {
+    fn from(table: &JQUANT_TBL) -> Self {
+        let mut qtable = QTable { coeffs: [0; 64] };
+        for (out, q) in qtable.coeffs.iter_mut().zip(table.quantval.iter()) {
+            *out = Coef::from(*q);
+        }
+        qtable
+    }
+}
+
 const low_weights : [f32; 19] = [
     1.00, 0.85, 0.55, 0., 0., 0., 0., 0.,
     0.85, 0.75, 0.10, 0., 0., 0., 0., 0.,
@@ -54,15 +65,28 @@ impl QTable {
 
             // TODO: that could be improved for 1x2 and 2x1 subsampling
             for ((out, coef), w) in low_out.iter_mut().zip(low_coefs).zip(&low_weights) {
-                *out = min(255, max(1, (*coef as f32 * (dc_scaling * w + ac_scaling * (1.-w))).round() as Coef));
+                *out = ((*coef as f32 * (dc_scaling * w + ac_scaling * (1.-w))).round() as Coef).clamp(1, 255);
             }
             for (out, coef) in high_out.iter_mut().zip(high_coefs) {
-                *out = min(255, max(1, (*coef as f32 * ac_scaling).round() as Coef));
+                *out = ((*coef as f32 * ac_scaling).round() as Coef).clamp(1, 255);
             }
         }
         Self { coeffs: out }
     }
 
+    /// Scales the table like libjpeg's `set_quality()` does with its built-in tables, e.g. for custom base tables.
+    ///
+    /// Quality is truncated to an integer and clamped to 1-100. At quality 50 the table is unchanged.
+    #[must_use]
+    pub fn scaled_to_quality(&self, quality: f32) -> Self {
+        let scale = libjpeg_quality_scaling(quality);
+        let mut out = [0; 64];
+        for (out, &coef) in out.iter_mut().zip(&self.coeffs) {
+            *out = ((coef * scale + 50) / 100).clamp(1, 32767);
+        }
+        Self { coeffs: out }
+    }
+
     pub fn as_ptr(&self) -> *const c_uint {
         self.coeffs.as_ptr()
     }
@@ -71,15 +95,44 @@ impl QTable {
     fn quality_scaling(quality: f32) -> f32 {
         assert!(quality > 0. && quality <= 100.);
 
-        return if quality < 50. {
+        if quality < 50. {
             50. / quality
         } else {
             (100. - quality) / 50.
-        };
+        }
     }
 }
 
-pub static AnnexK_Luma: QTable = QTable{coeffs:[
+/// Finds the quality that gives the closest tables with one of MozJPEG's presets, like `set_quality()` would.
+/// Exact for files saved by libjpeg and MozJPEG with their built-in tables.
+pub(crate) fn estimate_quality(luma: &QTable, chroma: Option<&QTable>) -> u8 {
+    let distance = |a: &QTable, b: &QTable| a.coeffs.iter().zip(&b.coeffs).map(|(&a, &b)| a.abs_diff(b) as u64).sum::<u64>();
+    let mut best = (u64::MAX, 0);
+    for preset in crate::QuantPreset::ALL {
+        let (base_luma, base_chroma) = preset.tables();
+        for quality in 1..=100u8 {
+            let mut dist = distance(luma, &base_luma.scaled_to_quality(quality.into()));
+            if let Some(chroma) = chroma {
+                dist += distance(chroma, &base_chroma.scaled_to_quality(quality.into()));
+            }
+            // several qualities can give the same tables, and the highest one is a safer guess for recompression
+            if dist <= best.0 {
+                best = (dist, quality);
+            }
+        }
+    }
+    best.1
+}
+
+/// libjpeg's conversion of quality (clamped to 1-100) to a percentage by which the tables are scaled.
+///
+/// libjpeg takes an integer quality, so the fraction is truncated (e.g. 75.9 is scaled like 75).
+pub fn libjpeg_quality_scaling(quality: f32) -> u32 {
+    unsafe { crate::ffi::jpeg_quality_scaling(quality as c_int) as u32 }
+}
+
+/// Luma table from the JPEG standard (Annex K), used by libjpeg
+pub const ANNEX_K_LUMA: QTable = QTable{coeffs:[
     16,  11,  10,  16,  24,  40,  51,  61,
     12,  12,  14,  19,  26,  58,  60,  55,
     14,  13,  16,  24,  40,  57,  69,  56,
@@ -90,7 +143,8 @@ pub static AnnexK_Luma: QTable = QTable{coeffs:[
     72,  92,  95,  98, 112, 100, 103,  99
 ]};
 
-pub static AnnexK_Chroma: QTable = QTable{coeffs:[
+/// Chroma table from the JPEG standard (Annex K), used by libjpeg
+pub const ANNEX_K_CHROMA: QTable = QTable{coeffs:[
     17,  18,  24,  47,  99,  99,  99,  99,
     18,  21,  26,  66,  99,  99,  99,  99,
     24,  26,  56,  99,  99,  99,  99,  99,
@@ -101,7 +155,8 @@ pub static AnnexK_Chroma: QTable = QTable{coeffs:[
     99,  99,  99,  99,  99,  99,  99,  99
 ]};
 
-pub static Flat: QTable = QTable{coeffs:[
+/// Flat table
+pub const FLAT: QTable = QTable{coeffs:[
     16,  16,  16,  16,  16,  16,  16,  16,
     16,  16,  16,  16,  16,  16,  16,  16,
     16,  16,  16,  16,  16,  16,  16,  16,
@@ -112,7 +167,8 @@ pub static Flat: QTable = QTable{coeffs:[
     16,  16,  16,  16,  16,  16,  16,  16
 ]};
 
-pub static MSSSIM_Luma: QTable = QTable{coeffs:[
+/// Luma table tuned for MS-SSIM
+pub const MSSSIM_LUMA: QTable = QTable{coeffs:[
     12, 17, 20, 21, 30, 34, 56, 63,
     18, 20, 20, 26, 28, 51, 61, 55,
     19, 20, 21, 26, 33, 58, 69, 55,
@@ -123,7 +179,8 @@ pub static MSSSIM_Luma: QTable = QTable{coeffs:[
     68, 90, 90, 96, 113, 102, 105, 103
 ]};
 
-pub static MSSSIM_Chroma: QTable = QTable{coeffs:[
+/// Chroma table tuned for MS-SSIM
+pub const MSSSIM_CHROMA: QTable = QTable{coeffs:[
     8, 12, 15, 15, 86, 96, 96, 98,
     13, 13, 15, 26, 90, 96, 99, 98,
     12, 15, 18, 96, 99, 99, 99, 99,
@@ -134,7 +191,8 @@ pub static MSSSIM_Chroma: QTable = QTable{coeffs:[
     99, 99, 99, 99, 99, 99, 99, 99
 ]};
 
-pub static NRobidoux: QTable = QTable{coeffs:[
+/// ImageMagick's table by N. Robidoux, MozJPEG's default
+pub const N_ROBIDOUX: QTable = QTable{coeffs:[
     16,  16,  16,  18,  25,  37,  56,  85,
     16,  17,  20,  27,  34,  40,  53,  75,
     16,  20,  24,  31,  43,  62,  91,  135,
@@ -145,7 +203,8 @@ pub static NRobidoux: QTable = QTable{coeffs:[
     85,  75,  135, 156, 189, 238, 311, 418
 ]};
 
-pub static PSNRHVS_Luma: QTable = QTable{coeffs:[
+/// Luma table tuned for PSNR-HVS
+pub const PSNRHVS_LUMA: QTable = QTable{coeffs:[
     9, 10, 12, 14, 27, 32, 51, 62,
     11, 12, 14, 19, 27, 44, 59, 73,
     12, 14, 18, 25, 42, 59, 79, 78,
@@ -155,7 +214,8 @@ pub static PSNRHVS_Luma: QTable = QTable{coeffs:[
     42, 64, 78, 95, 105, 126, 125, 99,
     70, 75, 100, 102, 116, 100, 107, 98
 ]};
-pub static PSNRHVS_Chroma: QTable = QTable{coeffs:[
+/// Chroma table tuned for PSNR-HVS
+pub const PSNRHVS_CHROMA: QTable = QTable{coeffs:[
     9, 10, 17, 19, 62, 89, 91, 97,
     12, 13, 18, 29, 84, 91, 88, 98,
     14, 19, 29, 93, 95, 95, 98, 97,
@@ -166,7 +226,8 @@ pub static PSNRHVS_Chroma: QTable = QTable{coeffs:[
     97, 97, 99, 99, 99, 99, 97, 99
 ]};
 
-pub static KleinSilversteinCarney: QTable = QTable{coeffs:[
+/// From the paper by Klein, Silverstein and Carney
+pub const KLEIN_SILVERSTEIN_CARNEY: QTable = QTable{coeffs:[
     /* Relevance of human vision to JPEG-DCT compression (1992) Klein, Silverstein and Carney.
      */
     10, 12, 14, 19, 26, 38, 57, 86,
@@ -179,7 +240,8 @@ pub static KleinSilversteinCarney: QTable = QTable{coeffs:[
     86, 76, 136, 157, 190, 239, 312, 419
 ]};
 
-pub static WatsonTaylorBorthwick: QTable = QTable{coeffs:[
+/// From the paper by Watson, Taylor and Borthwick
+pub const WATSON_TAYLOR_BORTHWICK: QTable = QTable{coeffs:[
     /* DCTune perceptual optimization of compressed dental X-Rays (1997) Watson, Taylor, Borthwick
      */
     7, 8, 10, 14, 23, 44, 95, 241,
@@ -192,7 +254,8 @@ pub static WatsonTaylorBorthwick: QTable = QTable{coeffs:[
     241, 255, 255, 255, 255, 255, 255, 255
   ]};
 
-pub static AhumadaWatsonPeterson: QTable = QTable{coeffs:[
+/// From the paper by Ahumada, Watson and Peterson
+pub const AHUMADA_WATSON_PETERSON: QTable = QTable{coeffs:[
     /* A visual detection model for DCT coefficient quantization (12/9/93) Ahumada, Watson, Peterson
      */
     15, 11, 11, 12, 15, 19, 25, 32,
@@ -205,7 +268,8 @@ pub static AhumadaWatsonPeterson: QTable = QTable{coeffs:[
     32, 24, 27, 33, 42, 53, 65, 77
   ]};
 
-pub static PetersonAhumadaWatson: QTable = QTable { coeffs:[
+/// From the paper by Peterson, Ahumada and Watson
+pub const PETERSON_AHUMADA_WATSON: QTable = QTable { coeffs:[
     /* An improved detection model for DCT coefficient quantization (1993) Peterson, Ahumada and Watson
      */
     14, 10, 11, 14, 19, 25, 34, 45,
@@ -218,6 +282,31 @@ pub static PetersonAhumadaWatson: QTable = QTable { coeffs:[
     45, 33, 38, 47, 59, 74, 91, 108
 ]};
 
+/// Same as `ANNEX_K_LUMA`
+pub static AnnexK_Luma: QTable = ANNEX_K_LUMA;
+/// Same as `ANNEX_K_CHROMA`
+pub static AnnexK_Chroma: QTable = ANNEX_K_CHROMA;
+/// Same as `FLAT`
+pub static Flat: QTable = FLAT;
+/// Same as `MSSSIM_LUMA`
+pub static MSSSIM_Luma: QTable = MSSSIM_LUMA;
+/// Same as `MSSSIM_CHROMA`
+pub static MSSSIM_Chroma: QTable = MSSSIM_CHROMA;
+/// Same as `N_ROBIDOUX`
+pub static NRobidoux: QTable = N_ROBIDOUX;
+/// Same as `PSNRHVS_LUMA`
+pub static PSNRHVS_Luma: QTable = PSNRHVS_LUMA;
+/// Same as `PSNRHVS_CHROMA`
+pub static PSNRHVS_Chroma: QTable = PSNRHVS_CHROMA;
+/// Same as `KLEIN_SILVERSTEIN_CARNEY`
+pub static KleinSilversteinCarney: QTable = KLEIN_SILVERSTEIN_CARNEY;
+/// Same as `WATSON_TAYLOR_BORTHWICK`
+pub static WatsonTaylorBorthwick: QTable = WATSON_TAYLOR_BORTHWICK;
+/// Same as `AHUMADA_WATSON_PETERSON`
+pub static AhumadaWatsonPeterson: QTable = AHUMADA_WATSON_PETERSON;
+/// Same as `PETERSON_AHUMADA_WATSON`
+pub static PetersonAhumadaWatson: QTable = PETERSON_AHUMADA_WATSON;
+
 pub static ALL_TABLES: [(&str, &QTable); 12] = [
     ("Annex-K Luma", &AnnexK_Luma),
     ("Annex-K Chroma", &AnnexK_Chroma),
@@ -256,3 +345,33 @@ fn scaling() {
     assert_eq!(QTable { coeffs: [1; 64] }, NRobidoux.scaled(99.9, 99.9));
     assert_eq!(QTable { coeffs: [1; 64] }, MSSSIM_Chroma.scaled(99.8, 99.8));
 }
+
+#[test]
+fn scaling_to_quality() {
+    use crate::{ColorSpace, Compress, QuantPreset};
+
+    assert_eq!(5000, libjpeg_quality_scaling(1.));
+    assert_eq!(100, libjpeg_quality_scaling(50.));
+    assert_eq!(50, libjpeg_quality_scaling(75.));
+    assert_eq!(0, libjpeg_quality_scaling(100.));
+    assert_eq!(5000, libjpeg_quality_scaling(-5.));
+    assert_eq!(libjpeg_quality_scaling(75.), libjpeg_quality_scaling(75.9));
+
+    assert_eq!(AnnexK_Luma, AnnexK_Luma.scaled_to_quality(50.));
+    assert_eq!(QTable { coeffs: [1; 64] }, AnnexK_Luma.scaled_to_quality(100.));
+    for quality in [5., 33., 75., 92.] {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_quant_table_preset(QuantPreset::AnnexK);
+        cinfo.set_quality(quality);
+        assert_eq!(Some(AnnexK_Luma.scaled_to_quality(quality)), cinfo.qtable(0));
+        assert_eq!(Some(AnnexK_Chroma.scaled_to_quality(quality)), cinfo.qtable(1));
+    }
+}
+
+#[test]
+fn const_tables() {
+    const LUMA: QTable = ANNEX_K_LUMA;
+    assert_eq!(LUMA, AnnexK_Luma);
+    assert_eq!(ALL_TABLES.len(), ALL_TABLES.iter().filter(|(_, t)| t.coeffs.iter().all(|&q| q > 0)).count());
+    assert_eq!(Some(&&PETERSON_AHUMADA_WATSON), ALL_TABLES.last().map(|(_, t)| t));
+}
@@ -6,11 +6,16 @@ use mozjpeg_sys::{JERR_INPUT_EOF, JERR_BUFFER_SIZE, JERR_FILE_READ};
 use mozjpeg_sys::{jpeg_common_struct, jpeg_resync_to_restart, jpeg_source_mgr};
 use mozjpeg_sys::{JWRN_JPEG_EOF, JPOOL_IMAGE, JPOOL_PERMANENT};
 use std::io::{Read, BufRead, BufReader};
+use std::mem;
 use std::mem::MaybeUninit;
 use std::os::raw::{c_int, c_long, c_uint};
 use std::ptr::NonNull;
 use std::ptr;
 
+// Type aliases to unify higher-ranked lifetimes
+type FnPtr = for<'a> unsafe extern "C" fn(cinfo: &'a mut jpeg_decompress_struct);
+type UnwindFnPtr = for<'a> unsafe extern "C-unwind" fn(cinfo: &'a mut jpeg_decompress_struct);
+
 #[repr(C)]
 pub(crate) struct SourceMgr<R> {
     iface: jpeg_source_mgr,
@@ -40,11 +45,12 @@ impl<R: BufRead> SourceMgr<R> {
             iface: jpeg_source_mgr {
                 next_input_byte: ptr::null_mut(),
                 bytes_in_buffer: 0,
-                init_source: Some(Self::init_source),
-                fill_input_buffer: Some(Self::fill_input_buffer),
-                skip_input_data: Some(Self::skip_input_data),
+                // The callbacks may unwind via `fail()`, but libjpeg's types are declared as non-unwinding
+                init_source: Some(unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::init_source) }),
+                fill_input_buffer: Some(unsafe { mem::transmute::<unsafe extern "C-unwind" fn(&mut jpeg_decompress_struct) -> boolean, unsafe extern "C" fn(&mut jpeg_decompress_struct) -> boolean>(Self::fill_input_buffer) }),
+                skip_input_data: Some(unsafe { mem::transmute::<unsafe extern "C-unwind" fn(&mut jpeg_decompress_struct, c_long), unsafe extern "C" fn(&mut jpeg_decompress_struct, c_long)>(Self::skip_input_data) }),
                 resync_to_restart: Some(jpeg_resync_to_restart),
-                term_source: Some(Self::term_source),
+                term_source: Some(unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::term_source) }),
             },
             reader,
         }
@@ -52,16 +58,15 @@ impl<R: BufRead> SourceMgr<R> {
 
     unsafe fn cast(cinfo: &mut jpeg_decompress_struct) -> &mut Self {
         let this: &mut Self = &mut *cinfo.src.cast();
-        // Type alias to unify higher-ranked lifetimes
-        type FnPtr<'a> = unsafe extern "C" fn(cinfo: &'a mut jpeg_decompress_struct);
         // This is a redundant safety check to ensure the struct is ours
-        if Some::<FnPtr>(Self::init_source) != this.iface.init_source {
+        let init_source = mem::transmute::<UnwindFnPtr, FnPtr>(Self::init_source);
+        if !this.iface.init_source.is_some_and(|f| ptr::fn_addr_eq(f, init_source)) {
             fail(&mut cinfo.common, JERR_BUFFER_SIZE);
         }
         this
     }
 
-    unsafe extern "C" fn init_source(cinfo: &mut jpeg_decompress_struct) {
+    unsafe extern "C-unwind" fn init_source(cinfo: &mut jpeg_decompress_struct) {
         let _ = Self::cast(cinfo);
     }
 
@@ -87,7 +92,7 @@ impl<R: BufRead> SourceMgr<R> {
         Ok(())
     }
 
-    unsafe extern "C" fn fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
+    unsafe extern "C-unwind" fn fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
         let this = Self::cast(cinfo);
         match this.fill_input_buffer_impl() {
             Ok(()) => 1,
@@ -103,7 +108,7 @@ impl<R: BufRead> SourceMgr<R> {
         }
     }
 
-    unsafe extern "C" fn skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
+    unsafe extern "C-unwind" fn skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
         let this = Self::cast(cinfo);
         let mut num_bytes = num_bytes as usize;
 
@@ -111,6 +116,7 @@ impl<R: BufRead> SourceMgr<R> {
             if this.iface.bytes_in_buffer > 0 {
                 let skip_from_buffer = this.iface.bytes_in_buffer.min(num_bytes);
                 this.iface.bytes_in_buffer -= skip_from_buffer;
+                this.iface.next_input_byte = this.iface.next_input_byte.add(skip_from_buffer);
                 num_bytes -= skip_from_buffer;
             }
             if num_bytes == 0 {
@@ -122,7 +128,129 @@ impl<R: BufRead> SourceMgr<R> {
         }
     }
 
-    unsafe extern "C" fn term_source(cinfo: &mut jpeg_decompress_struct) {
+    unsafe extern "C-unwind" fn term_source(cinfo: &mut jpeg_decompress_struct) {
+        let _ = Self::cast(cinfo); // checks
+        let ptr: *mut Self = cinfo.src.cast();
+        cinfo.src = ptr::null_mut();
+        let _ = Box::from_raw(ptr); // drops
+    }
+}
+
+/// Source that suspends libjpeg when it needs more data than has been pushed so far, for `PushDecoder`.
+///
+/// After suspension libjpeg restarts from the last point it has committed to `iface`,
+/// so the bytes from `next_input_byte` onwards are kept when more data is appended.
+#[repr(C)]
+pub(crate) struct PushSource {
+    iface: jpeg_source_mgr,
+    buf: Vec<u8>,
+    /// Bytes libjpeg wanted to skip past the end of `buf`
+    skip_pending: usize,
+    /// No more data will be pushed, so missing data is a truncated file
+    eof: bool,
+}
+
+impl PushSource {
+    pub(crate) fn set_src(cinfo: &mut jpeg_decompress_struct) -> Result<(), ()> {
+        if !cinfo.src.is_null() {
+            return Err(());
+        }
+        let src = Box::new(Self {
+            iface: jpeg_source_mgr {
+                next_input_byte: ptr::null_mut(),
+                bytes_in_buffer: 0,
+                init_source: Some(unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::init_source) }),
+                fill_input_buffer: Some(unsafe { mem::transmute::<unsafe extern "C-unwind" fn(&mut jpeg_decompress_struct) -> boolean, unsafe extern "C" fn(&mut jpeg_decompress_struct) -> boolean>(Self::fill_input_buffer) }),
+                skip_input_data: Some(unsafe { mem::transmute::<unsafe extern "C-unwind" fn(&mut jpeg_decompress_struct, c_long), unsafe extern "C" fn(&mut jpeg_decompress_struct, c_long)>(Self::skip_input_data) }),
+                resync_to_restart: Some(jpeg_resync_to_restart),
+                term_source: Some(unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::term_source) }),
+            },
+            buf: Vec::new(),
+            skip_pending: 0,
+            eof: false,
+        });
+        cinfo.src = Box::into_raw(src).cast();
+        Ok(())
+    }
+
+    /// `None` if the source isn't a `PushSource`
+    fn get(cinfo: &mut jpeg_decompress_struct) -> Option<&mut Self> {
+        let init_source = unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::init_source) };
+        let src = unsafe { cinfo.src.as_mut()? };
+        if !src.init_source.is_some_and(|f| ptr::fn_addr_eq(f, init_source)) {
+            return None;
+        }
+        Some(unsafe { &mut *cinfo.src.cast::<Self>() })
+    }
+
+    unsafe fn cast(cinfo: &mut jpeg_decompress_struct) -> &mut Self {
+        match Self::get(&mut *(cinfo as *mut jpeg_decompress_struct)) {
+            Some(this) => this,
+            None => fail(&mut cinfo.common, JERR_BUFFER_SIZE),
+        }
+    }
+
+    /// Appends `data` after the bytes libjpeg hasn't consumed yet. Must not be called while libjpeg is running.
+    pub(crate) fn push(cinfo: &mut jpeg_decompress_struct, mut data: &[u8]) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        let this = Self::get(cinfo).ok_or(ErrorKind::InvalidInput)?;
+        if this.eof {
+            return Err(Error::new(ErrorKind::InvalidInput, "the end of input has already been set"));
+        }
+        let consumed = if this.iface.bytes_in_buffer == 0 {
+            this.buf.len()
+        } else {
+            // libjpeg only moves forward within the buffer
+            unsafe { this.iface.next_input_byte.offset_from(this.buf.as_ptr()) as usize }
+        };
+        this.buf.drain(..consumed);
+
+        let skip = this.skip_pending.min(data.len());
+        this.skip_pending -= skip;
+        data = &data[skip..];
+        this.buf.try_reserve(data.len()).map_err(|_| ErrorKind::OutOfMemory)?;
+        this.buf.extend_from_slice(data);
+
+        this.iface.next_input_byte = this.buf.as_ptr();
+        this.iface.bytes_in_buffer = this.buf.len();
+        Ok(())
+    }
+
+    /// No more data will be pushed
+    pub(crate) fn set_eof(cinfo: &mut jpeg_decompress_struct) {
+        if let Some(this) = Self::get(cinfo) {
+            this.eof = true;
+        }
+    }
+
+    unsafe extern "C-unwind" fn init_source(cinfo: &mut jpeg_decompress_struct) {
+        let _ = Self::cast(cinfo);
+    }
+
+    unsafe extern "C-unwind" fn fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
+        let this = Self::cast(cinfo);
+        if !this.eof {
+            // suspends, and libjpeg returns to the caller
+            return 0;
+        }
+        // libjpeg doesn't treat it as error, but fakes it!
+        this.iface.next_input_byte = [0xFF, 0xD9, 0xFF, 0xD9].as_ptr();
+        this.iface.bytes_in_buffer = 4;
+        warn(&mut cinfo.common, JWRN_JPEG_EOF);
+        1
+    }
+
+    unsafe extern "C-unwind" fn skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
+        let this = Self::cast(cinfo);
+        let num_bytes = num_bytes.max(0) as usize;
+        let skip_from_buffer = this.iface.bytes_in_buffer.min(num_bytes);
+        this.iface.bytes_in_buffer -= skip_from_buffer;
+        this.iface.next_input_byte = this.iface.next_input_byte.add(skip_from_buffer);
+        this.skip_pending += num_bytes - skip_from_buffer;
+    }
+
+    unsafe extern "C-unwind" fn term_source(cinfo: &mut jpeg_decompress_struct) {
         let _ = Self::cast(cinfo); // checks
         let ptr: *mut Self = cinfo.src.cast();
         cinfo.src = ptr::null_mut();
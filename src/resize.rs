@@ -0,0 +1,77 @@
+//! Lanczos3 resampling for `Decompress::decode_resized()`
+
+use crate::vec::{try_filled, wipe};
+use std::f64::consts::PI;
+use std::io;
+
+fn lanczos3(x: f64) -> f64 {
+    if x == 0. {
+        1.
+    } else if x.abs() < 3. {
+        let px = PI * x;
+        3. * px.sin() * (px / 3.).sin() / (px * px)
+    } else {
+        0.
+    }
+}
+
+/// First source pixel and normalized weights of source pixels for every destination pixel
+fn weights(src_len: usize, dst_len: usize) -> Vec<(usize, Vec<f32>)> {
+    let scale = src_len as f64 / dst_len as f64;
+    // when downscaling, the filter is stretched to cover all source pixels
+    let filter_scale = scale.max(1.);
+    let support = 3. * filter_scale;
+    (0..dst_len).map(|i| {
+        let center = (i as f64 + 0.5) * scale;
+        let start = (center - support).floor().max(0.) as usize;
+        let end = ((center + support).ceil() as usize).min(src_len);
+        let mut w: Vec<f32> = (start..end).map(|j| lanczos3((j as f64 + 0.5 - center) / filter_scale) as f32).collect();
+        let sum: f32 = w.iter().sum();
+        if sum != 0. {
+            w.iter_mut().for_each(|w| *w /= sum);
+        }
+        (start, w)
+    }).collect()
+}
+
+/// Resizes a flat buffer of `num_components`-byte pixels, horizontally first, then vertically
+pub(crate) fn resize(src: &[u8], src_size: (usize, usize), num_components: usize, dst_size: (usize, usize)) -> io::Result<Vec<u8>> {
+    let ((src_width, src_height), (dst_width, dst_height)) = (src_size, dst_size);
+    let n = num_components;
+    assert_eq!(src.len(), src_width * src_height * n);
+    assert!(dst_width > 0 && dst_height > 0);
+
+    let mut tmp = try_filled(0f32, src_height * dst_width * n)?;
+    let columns = weights(src_width, dst_width);
+    for (src_row, tmp_row) in src.chunks_exact(src_width * n).zip(tmp.chunks_exact_mut(dst_width * n)) {
+        for ((start, w), out) in columns.iter().zip(tmp_row.chunks_exact_mut(n)) {
+            for (&w, px) in w.iter().zip(src_row[start * n..].chunks_exact(n)) {
+                out.iter_mut().zip(px).for_each(|(out, &c)| *out += w * f32::from(c));
+            }
+        }
+    }
+
+    let mut dst = try_filled(0u8, dst_width * dst_height * n)?;
+    let row_len = dst_width * n;
+    for ((start, w), dst_row) in weights(src_height, dst_height).iter().zip(dst.chunks_exact_mut(row_len)) {
+        for (x, out) in dst_row.iter_mut().enumerate() {
+            let sum: f32 = w.iter().enumerate().map(|(y, &w)| w * tmp[(start + y) * row_len + x]).sum();
+            *out = sum.round().clamp(0., 255.) as u8;
+        }
+    }
+    wipe(&mut tmp);
+    Ok(dst)
+}
+
+#[test]
+fn resizes() {
+    let flat = vec![77u8; 30 * 20 * 3];
+    assert!(resize(&flat, (30, 20), 3, (7, 45)).unwrap().iter().all(|&c| c == 77));
+
+    // horizontal gradient stays monotonic
+    let gradient: Vec<u8> = (0..20 * 10).map(|i| (i % 20 * 12) as u8).collect();
+    let small = resize(&gradient, (20, 10), 1, (9, 4)).unwrap();
+    assert_eq!(9 * 4, small.len());
+    assert!(small[..9].windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(&small[..9], &small[27..]);
+}
@@ -0,0 +1,88 @@
+use crate::ffi::jpeg_scan_info as ScanInfo;
+use std::os::raw::c_int;
+
+/// Ready-made progressive scan scripts, see `Compress::set_scan_preset()`.
+///
+/// The first component is treated as luma, and the rest as chroma.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScanPreset {
+    /// MozJPEG's own choice, searched for the smallest file when scan optimization is enabled. Same as `set_progressive_mode()`.
+    MozjpegDefault,
+    /// A tiny first scan with the DC of all components (a blurry preview), then low frequencies of luma, chroma, and the rest of luma
+    WebFastFirstScan,
+    /// The whole luma first, so a sharp grayscale image appears before any color
+    LumaPriority,
+    /// The fewest scans: DC of all components, and then all AC coefficients of each component in one scan
+    SpectralOnly,
+}
+
+impl ScanPreset {
+    /// Scan script for an image with this many components. `None` if libjpeg generates the script.
+    pub(crate) fn scans(self, num_components: usize) -> Option<Vec<ScanInfo>> {
+        let chroma = 1..num_components.min(4);
+        let mut scans = Vec::new();
+        match self {
+            Self::MozjpegDefault => return None,
+            Self::WebFastFirstScan => {
+                scans.push(dc(0..num_components));
+                scans.push(ac(0, 1, 5));
+                scans.extend(chroma.map(|ci| ac(ci, 1, 63)));
+                scans.push(ac(0, 6, 63));
+            },
+            Self::LumaPriority => {
+                scans.push(dc(0..1));
+                scans.push(ac(0, 1, 5));
+                scans.push(ac(0, 6, 63));
+                if !chroma.is_empty() {
+                    scans.push(dc(chroma.clone()));
+                    scans.extend(chroma.map(|ci| ac(ci, 1, 63)));
+                }
+            },
+            Self::SpectralOnly => {
+                scans.push(dc(0..num_components));
+                scans.extend((0..num_components.min(4)).map(|ci| ac(ci, 1, 63)));
+            },
+        }
+        Some(scans)
+    }
+}
+
+/// Interleaved DC scan of up to 4 components
+fn dc(components: std::ops::Range<usize>) -> ScanInfo {
+    let mut component_index = [0; 4];
+    let mut comps_in_scan = 0;
+    for (index, ci) in component_index.iter_mut().zip(components) {
+        *index = ci as c_int;
+        comps_in_scan += 1;
+    }
+    ScanInfo { comps_in_scan, component_index, Ss: 0, Se: 0, Ah: 0, Al: 0 }
+}
+
+/// AC coefficients `Ss..=Se` of one component
+fn ac(ci: usize, ss: c_int, se: c_int) -> ScanInfo {
+    ScanInfo { comps_in_scan: 1, component_index: [ci as c_int, 0, 0, 0], Ss: ss, Se: se, Ah: 0, Al: 0 }
+}
+
+#[test]
+fn scan_presets() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Compress, Decompress};
+
+    let pixels: Vec<u8> = (0..32 * 32 * 3).map(|i| (i * 13 % 251) as u8).collect();
+    for preset in [ScanPreset::MozjpegDefault, ScanPreset::WebFastFirstScan, ScanPreset::LumaPriority, ScanPreset::SpectralOnly] {
+        for color_space in [ColorSpace::JCS_YCbCr, ColorSpace::JCS_GRAYSCALE] {
+            let jpeg = compress_for_test(ColorSpace::JCS_RGB, 32, 32, &pixels, |c| {
+                c.set_scan_preset(preset);
+                // the script is regenerated for the new number of components
+                c.set_color_space(color_space);
+            });
+
+            let num_components = if color_space == ColorSpace::JCS_GRAYSCALE { 1 } else { 3 };
+            if let Some(expected) = preset.scans(num_components) {
+                let scans = crate::transcode::scan_script(&jpeg).unwrap();
+                assert_eq!(expected.len(), scans.len(), "{preset:?}");
+            }
+            assert_eq!(32 * 32 * 3, Decompress::new_mem(&jpeg).unwrap().rgb().unwrap().read_scanlines_flat().unwrap().len());
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use std::ops::Range;
+
+/// Start of image marker code
+pub const SOI: u8 = 0xD8;
+/// End of image marker code
+pub const EOI: u8 = 0xD9;
+/// Start of scan marker code
+pub const SOS: u8 = 0xDA;
+
+/// A marker segment
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// Marker code, i.e. the byte after `0xFF`
+    pub marker: u8,
+    /// Position of the marker in the file
+    pub offset: usize,
+    /// Payload, after the length field. Empty for markers without payload, such as SOI and EOI.
+    pub data: &'a [u8],
+    /// Entropy-coded data after an SOS segment, including any RSTn markers. Empty for other markers.
+    pub scan_data: &'a [u8],
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl Segment<'_> {
+    /// Length of the segment in the file, including the marker and length field, but not `scan_data`
+    #[inline]
+    pub fn len(&self) -> usize {
+        if has_length(self.marker) { 4 + self.data.len() } else { 2 }
+    }
+
+    /// Position of the payload in the file
+    #[inline]
+    pub fn data_range(&self) -> Range<usize> {
+        let start = self.offset + self.len() - self.data.len();
+        start..start + self.data.len()
+    }
+
+    /// Position of the entropy-coded data in the file
+    #[inline]
+    pub fn scan_data_range(&self) -> Range<usize> {
+        let start = self.offset + self.len();
+        start..start + self.scan_data.len()
+    }
+}
+
+fn has_length(marker: u8) -> bool {
+    !matches!(marker, SOI | EOI | 0x01 | 0xD0..=0xD7)
+}
+
+/// Iterates marker segments of a file, from SOI to EOI, without decoding anything.
+///
+/// Entropy-coded data is returned as a part of the SOS segment before it.
+/// Parsing stops after the first error.
+///
+/// ```rust
+/// # fn t(jpeg: &[u8]) -> std::io::Result<()> {
+/// for segment in mozjpeg::segments::Segments::new(jpeg)? {
+///     let segment = segment?;
+///     println!("{:02X} at {}, {} bytes", segment.marker, segment.offset, segment.len());
+/// }
+/// # Ok(()) }
+/// ```
+pub struct Segments<'a> {
+    jpeg: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> Segments<'a> {
+    /// Fails if the file doesn't start with SOI
+    pub fn new(jpeg: &'a [u8]) -> io::Result<Self> {
+        if !jpeg.starts_with(&[0xFF, SOI]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a JPEG file"));
+        }
+        Ok(Self { jpeg, pos: 0, done: false })
+    }
+
+    /// Where the next segment is expected
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn next_segment(&mut self) -> io::Result<Segment<'a>> {
+        let eof = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated JPEG file");
+        if self.pos >= self.jpeg.len() {
+            return Err(eof());
+        }
+        if self.jpeg[self.pos] != 0xFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a marker"));
+        }
+        // any number of 0xFF fill bytes may precede a marker
+        let mut pos = self.pos;
+        while self.jpeg.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *self.jpeg.get(pos + 1).ok_or_else(eof)?;
+        if !has_length(marker) {
+            self.pos = pos + 2;
+            return Ok(Segment { marker, offset: pos, data: &[], scan_data: &[] });
+        }
+        let len = self.jpeg.get(pos + 2..pos + 4).ok_or_else(eof)?;
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        if len < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad segment length"));
+        }
+        let data = self.jpeg.get(pos + 4..pos + 2 + len).ok_or_else(eof)?;
+        self.pos = pos + 2 + len;
+        let mut scan_data: &[u8] = &[];
+        if marker == SOS {
+            let scan_start = self.pos;
+            self.pos = scan_data_end(self.jpeg, scan_start);
+            scan_data = &self.jpeg[scan_start..self.pos];
+        }
+        Ok(Segment { marker, offset: pos, data, scan_data })
+    }
+}
+
+/// Positions in the file where each scan ends.
+///
+/// For progressive files, the first `n` bytes of the file (up to the end of a scan) make a valid lower-quality preview,
+/// e.g. for serving a placeholder image with an HTTP range request.
+/// Decoders will treat the missing EOI as truncation and display what has been sent so far.
+pub fn scan_ends(jpeg: &[u8]) -> io::Result<Vec<usize>> {
+    let mut ends = Vec::new();
+    for segment in Segments::new(jpeg)? {
+        let segment = segment?;
+        if segment.marker == SOS {
+            ends.push(segment.scan_data_range().end);
+        }
+    }
+    Ok(ends)
+}
+
+/// Position of the first marker other than RSTn (or end of file)
+fn scan_data_end(jpeg: &[u8], start: usize) -> usize {
+    let mut pos = start;
+    while let Some(ff) = jpeg.get(pos..).and_then(|rest| rest.iter().position(|&b| b == 0xFF)) {
+        pos += ff;
+        match jpeg.get(pos + 1) {
+            // stuffed zero or restart marker
+            Some(0) | Some(0xD0..=0xD7) => pos += 2,
+            _ => return pos,
+        }
+    }
+    jpeg.len()
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = io::Result<Segment<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_segment();
+        self.done = !matches!(&res, Ok(s) if s.marker != EOI);
+        Some(res)
+    }
+}
+
+#[test]
+fn segments() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let segments = Segments::new(&data).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!((SOI, 0, 2), (segments[0].marker, segments[0].offset, segments[0].len()));
+    assert_eq!(0xE0, segments[1].marker);
+    assert!(segments[1].data.starts_with(b"JFIF\0"));
+    assert_eq!(20, segments[1].data_range().end);
+    assert!(segments.iter().any(|s| s.marker == 0xC2));
+    let sos = segments.iter().find(|s| s.marker == SOS).unwrap();
+    assert_eq!(&[0xFF, SOS], &data[sos.offset..sos.offset + 2]);
+    assert!(!sos.scan_data.is_empty());
+    assert_eq!(sos.scan_data, &data[sos.scan_data_range()]);
+    assert!(segments.iter().filter(|s| s.marker == SOS).count() > 1);
+
+    // segments cover the whole file
+    let mut pos = 0;
+    for s in &segments {
+        assert_eq!(pos, s.offset);
+        pos = s.scan_data_range().end;
+    }
+    assert_eq!(data.len(), pos);
+
+    let eoi = segments.last().unwrap();
+    assert_eq!(EOI, eoi.marker);
+    assert_eq!(data.len() - 2, eoi.offset);
+
+    assert!(Segments::new(&data[..30]).unwrap().any(|s| s.is_err()));
+    assert!(Segments::new(&data[..data.len() - 2]).unwrap().any(|s| s.is_err()));
+    assert!(Segments::new(b"GIF89a").is_err());
+}
+
+#[test]
+fn scan_ends_make_previews() {
+    use crate::Decompress;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let ends = scan_ends(&data).unwrap();
+    assert_eq!(6, ends.len());
+    assert_eq!(data.len() - 2, *ends.last().unwrap());
+    assert!(ends.windows(2).all(|w| w[0] < w[1]));
+
+    for &end in &ends {
+        let mut dinfo = Decompress::new_mem(&data[..end]).unwrap().rgb().unwrap();
+        assert_eq!(45 * 30, dinfo.read_scanlines::<[u8; 3]>().unwrap().len());
+    }
+}
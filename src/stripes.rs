@@ -0,0 +1,229 @@
+//! Stripes of an image can be compressed separately (e.g. by different threads or machines),
+//! and then joined into one baseline JPEG file without decoding or re-compressing anything.
+//!
+//! 1. Set up a `Compress` for every stripe identically (same width, color space, quality, etc.),
+//!    and then call `stripes::configure()` on it.
+//! 2. Set the height of the stripe with `set_size()`. Height of every stripe except the last one
+//!    must be a multiple of `stripes::mcu_height()`.
+//! 3. Compress the stripes to memory, and pass the files, top to bottom, to `stripes::assemble()`.
+//!
+//! Stripes are joined at restart markers, which reset the entropy coder's state.
+//! Markers (such as EXIF) are copied only from the first stripe.
+
+use crate::compress::Compress;
+use crate::ffi::DCTSIZE;
+use crate::segments::{Segments, EOI, SOI, SOS};
+use std::io;
+
+const DQT: u8 = 0xDB;
+const DHT: u8 = 0xC4;
+const DRI: u8 = 0xDD;
+
+/// Makes the file's encoding independent of the image content, and adds a restart marker after every row of MCUs.
+///
+/// Huffman tables are fixed, and progressive mode is disabled, because all stripes must share the same tables and a single scan.
+pub fn configure(cinfo: &mut Compress) {
+    cinfo.set_optimize_coding(false);
+    cinfo.set_optimize_scans(false);
+    cinfo.set_smoothing_factor(0);
+    cinfo.set_restart_in_rows(1);
+}
+
+/// Height of every stripe, except the last one, must be a multiple of this
+pub fn mcu_height(cinfo: &Compress) -> usize {
+    let max_v_samp = cinfo.components().iter().map(|c| c.v_samp_factor as usize).max().unwrap_or(1);
+    max_v_samp * DCTSIZE
+}
+
+/// Joins files made from stripes of one image, top to bottom, into one file.
+///
+/// Fails if the stripes have been compressed with different settings, or without `configure()`.
+pub fn assemble<S: AsRef<[u8]>>(stripes: &[S]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("can't join stripes: {msg}"));
+    if stripes.is_empty() {
+        return Err(invalid("no stripes"));
+    }
+    let mut out = Vec::new();
+    out.try_reserve(stripes.iter().map(|s| s.as_ref().len()).sum()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+
+    let mut total_height = 0usize;
+    let mut sof_height_pos = None;
+    let mut first_tables = Vec::new();
+    let mut restarts = 0u8;
+    for (i, stripe) in stripes.iter().enumerate() {
+        let jpeg = stripe.as_ref();
+        let is_first = i == 0;
+        let is_last = i + 1 == stripes.len();
+        let mut tables = Vec::new();
+        let mut scan_data = None;
+        let mut has_restarts = false;
+        for segment in Segments::new(jpeg)? {
+            let segment = segment?;
+            match segment.marker {
+                SOI | EOI => {},
+                0xC0 | 0xC1 => {
+                    let data = segment.data;
+                    if data.len() < 6 || data.len() < 6 + 3 * usize::from(data[5]) {
+                        return Err(invalid("bad frame header"));
+                    }
+                    let height = usize::from(u16::from_be_bytes([data[1], data[2]]));
+                    let max_v_samp = data[6..6 + 3 * usize::from(data[5])].chunks_exact(3).map(|c| usize::from(c[1] & 0x0F)).max().unwrap_or(1);
+                    if !is_last && !height.is_multiple_of(max_v_samp.max(1) * DCTSIZE) {
+                        return Err(invalid("height of every stripe except the last must be a multiple of the MCU height"));
+                    }
+                    total_height += height;
+                    // everything except the height must match
+                    tables.extend([&data[..1], &data[3..]].concat());
+                    if is_first {
+                        sof_height_pos = Some(segment.data_range().start + 1);
+                    }
+                },
+                DQT | DHT | DRI => {
+                    has_restarts |= segment.marker == DRI && segment.data != [0, 0];
+                    tables.extend_from_slice(&jpeg[segment.offset..segment.offset + segment.len()]);
+                },
+                SOS => {
+                    if scan_data.is_some() {
+                        return Err(invalid("stripes must have a single scan"));
+                    }
+                    tables.extend_from_slice(segment.data);
+                    scan_data = Some(segment.scan_data);
+                    if is_first {
+                        out.extend_from_slice(&jpeg[..segment.offset + segment.len()]);
+                    }
+                },
+                0xC2 => return Err(invalid("stripes can't be progressive")),
+                0xC3 | 0xC5..=0xC7 | 0xC9..=0xCF => return Err(invalid("unsupported frame type")),
+                _ => {},
+            }
+        }
+        let scan_data = scan_data.ok_or_else(|| invalid("missing scan"))?;
+        if is_first {
+            if !has_restarts {
+                return Err(invalid("restart markers are required"));
+            }
+            first_tables = tables;
+        } else {
+            if tables != first_tables {
+                return Err(invalid("stripes have different settings"));
+            }
+            out.extend_from_slice(&[0xFF, 0xD0 + restarts]);
+            restarts = (restarts + 1) % 8;
+        }
+
+        // restart markers are numbered modulo 8 within each stripe, and need to continue from the previous stripe
+        let mut rest = scan_data;
+        while let Some(pos) = rest.windows(2).position(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1])) {
+            out.extend_from_slice(&rest[..pos]);
+            out.extend_from_slice(&[0xFF, 0xD0 + restarts]);
+            restarts = (restarts + 1) % 8;
+            rest = &rest[pos + 2..];
+        }
+        out.extend_from_slice(rest);
+    }
+    out.extend_from_slice(&[0xFF, EOI]);
+
+    let total_height = u16::try_from(total_height).map_err(|_| invalid("image too tall"))?;
+    let pos = sof_height_pos.ok_or_else(|| invalid("missing frame header"))?;
+    out[pos..pos + 2].copy_from_slice(&total_height.to_be_bytes());
+    Ok(out)
+}
+
+/// Experimental: compresses the image in stripes on all CPU cores, and joins them with `assemble()`.
+///
+/// `new_compress` is called for every stripe, and must set up the color space and quality identically each time
+/// (the size and destination are set here). Markers can't be added, and the file is baseline with fixed Huffman tables
+/// (see `configure()`), so it's a bit larger than a file compressed in one piece.
+/// `pixels` has `height` rows, in the input color space.
+pub fn parallel_compress(new_compress: impl Fn() -> Compress + Sync, width: usize, height: usize, pixels: &[u8]) -> io::Result<Vec<u8>> {
+    if height == 0 || !pixels.len().is_multiple_of(height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixels don't have whole rows"));
+    }
+    let row_len = pixels.len() / height;
+    let mcu = {
+        let mut cinfo = new_compress();
+        configure(&mut cinfo);
+        mcu_height(&cinfo)
+    };
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let stripe_height = height.div_ceil(threads).div_ceil(mcu) * mcu;
+    let stripes = std::thread::scope(|s| {
+        let workers: Vec<_> = pixels.chunks(stripe_height * row_len).map(|rows| s.spawn(|| {
+            let mut cinfo = new_compress();
+            configure(&mut cinfo);
+            cinfo.set_size(width, rows.len() / row_len);
+            cinfo.set_mem_dest();
+            let mut cinfo = cinfo.start_compress()?;
+            cinfo.write_scanlines(rows)?;
+            let mut cinfo = cinfo.finish_compress()?;
+            cinfo.data_to_vec().map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))
+        })).collect();
+        workers.into_iter().map(|w| w.join().unwrap_or_else(|_| Err(io::Error::other("compression panicked")))).collect::<io::Result<Vec<_>>>()
+    })?;
+    assemble(&stripes)
+}
+
+#[test]
+fn assemble_stripes() {
+    use crate::compress::compress_for_test;
+    use crate::{ColorSpace, Decompress};
+
+    let (width, height) = (40, 50);
+    let pixels: Vec<u8> = (0..width * height * 3).map(|i| ((i / 3) % width * 6 + i % 3 * 40) as u8).collect();
+    let compress = |rows: std::ops::Range<usize>| {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_quality(90.);
+        configure(&mut cinfo);
+        cinfo.set_size(width, rows.len());
+        cinfo.set_mem_dest();
+        let mut cinfo = cinfo.start_compress().unwrap();
+        cinfo.write_marker(crate::Marker::COM, b"hello").unwrap();
+        assert!(cinfo.write_scanlines(&pixels[rows.start * width * 3..rows.end * width * 3]).unwrap());
+        let mut cinfo = cinfo.finish_compress().unwrap();
+        (mcu_height(&cinfo), cinfo.data_to_vec().unwrap())
+    };
+    let (mcu, whole) = compress(0..height);
+    assert_eq!(16, mcu);
+    let stripes = [compress(0..16).1, compress(16..48).1, compress(48..50).1];
+    let joined = assemble(&stripes).unwrap();
+    assert_eq!(whole, joined);
+
+    let decoded = Decompress::new_mem(&joined).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    assert_eq!(pixels.len(), decoded.len());
+
+    let different = compress_for_test(ColorSpace::JCS_RGB, width, 2, &pixels[48 * width * 3..], |c| {
+        configure(c);
+        c.set_quality(50.);
+    });
+    assert!(assemble(&[&stripes[0][..], &stripes[1], &different]).is_err());
+    // 8 rows is half of the MCU height of 4:2:0
+    let misaligned = [compress(0..8).1, compress(8..48).1, compress(48..50).1];
+    assert_eq!(io::ErrorKind::InvalidInput, assemble(&misaligned).unwrap_err().kind());
+    assert!(assemble::<&[u8]>(&[]).is_err());
+}
+
+#[test]
+fn compress_in_parallel() {
+    use crate::{ColorSpace, Decompress};
+
+    let (width, height) = (64, 300);
+    let pixels: Vec<u8> = (0..width * height * 3).map(|i| (i / 3 % width * 4 + i / (width * 3) % 7 * 10) as u8).collect();
+    let new_compress = || {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_quality(85.);
+        cinfo
+    };
+    let jpeg = parallel_compress(new_compress, width, height, &pixels).unwrap();
+
+    let mut cinfo = new_compress();
+    configure(&mut cinfo);
+    cinfo.set_size(width, height);
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.start_compress().unwrap();
+    assert!(cinfo.write_scanlines(&pixels).unwrap());
+    let whole = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+    let decode = |jpeg: &[u8]| Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    assert_eq!(decode(&whole), decode(&jpeg));
+
+    assert!(parallel_compress(new_compress, width, height, &pixels[1..]).is_err());
+}
@@ -0,0 +1,130 @@
+use crate::decompress::DecompressStarted;
+use crate::vec::wipe;
+use std::io;
+
+/// A rectangle of pixels from `DecompressStarted::tiles()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tile {
+    /// Position of the top-left pixel in the image
+    pub x: usize,
+    pub y: usize,
+    /// Tiles at the right and bottom edges of the image may be smaller than requested
+    pub width: usize,
+    pub height: usize,
+    /// Flat buffer of pixels, in the decoder's output color space
+    pub pixels: Vec<u8>,
+}
+
+/// Iterator of tiles, left to right and then top to bottom.
+///
+/// Only one row of tiles is kept in memory at a time.
+pub struct Tiles<'a, 'src> {
+    dec: &'a mut DecompressStarted<'src>,
+    tile_width: usize,
+    tile_height: usize,
+    /// Scanlines of the current row of tiles
+    strip: Vec<u8>,
+    strip_y: usize,
+    strip_height: usize,
+    next_x: usize,
+    failed: bool,
+}
+
+impl<'a, 'src> Tiles<'a, 'src> {
+    #[track_caller]
+    pub(crate) fn new(dec: &'a mut DecompressStarted<'src>, tile_width: usize, tile_height: usize) -> Self {
+        assert!(tile_width > 0 && tile_height > 0);
+        Self {
+            dec,
+            tile_width,
+            tile_height,
+            strip: Vec::new(),
+            strip_y: 0,
+            strip_height: 0,
+            next_x: 0,
+            failed: false,
+        }
+    }
+
+    fn read_strip(&mut self) -> io::Result<()> {
+        let row_len = self.dec.width() * self.dec.output_components();
+        self.strip_y += self.strip_height;
+        self.strip_height = self.tile_height.min(self.dec.height() - self.strip_y);
+        let len = row_len * self.strip_height;
+        if self.strip.len() < len {
+            self.strip.try_reserve_exact(len - self.strip.len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+            self.strip.resize(len, 0);
+        }
+        for row in self.strip[..len].chunks_exact_mut(row_len) {
+            self.dec.read_row(row)?;
+        }
+        self.next_x = 0;
+        Ok(())
+    }
+}
+
+impl Drop for Tiles<'_, '_> {
+    fn drop(&mut self) {
+        wipe(&mut self.strip);
+    }
+}
+
+impl Iterator for Tiles<'_, '_> {
+    type Item = io::Result<Tile>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let image_width = self.dec.width();
+        if self.failed || image_width == 0 {
+            return None;
+        }
+        if self.strip_height == 0 || self.next_x >= image_width {
+            if self.strip_y + self.strip_height >= self.dec.height() {
+                return None;
+            }
+            if let Err(e) = self.read_strip() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+
+        let num_components = self.dec.output_components();
+        let (x, width) = (self.next_x, self.tile_width.min(image_width - self.next_x));
+        self.next_x += width;
+        let row_len = image_width * num_components;
+        let mut pixels = Vec::new();
+        if pixels.try_reserve_exact(width * self.strip_height * num_components).is_err() {
+            self.failed = true;
+            return Some(Err(io::ErrorKind::OutOfMemory.into()));
+        }
+        for row in self.strip.chunks_exact(row_len).take(self.strip_height) {
+            pixels.extend_from_slice(&row[x * num_components..(x + width) * num_components]);
+        }
+        Some(Ok(Tile { x, y: self.strip_y, width, height: self.strip_height, pixels }))
+    }
+}
+
+#[test]
+fn tiles_cover_image() {
+    use crate::Decompress;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let full = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines_flat().unwrap();
+    let mut dec = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let tiles = dec.tiles(16, 16).collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!(3 * 2, tiles.len());
+    assert_eq!((32, 16, 13, 14), (tiles[5].x, tiles[5].y, tiles[5].width, tiles[5].height));
+
+    let mut assembled = vec![0; full.len()];
+    for t in &tiles {
+        for (row, y) in t.pixels.chunks_exact(t.width * 3).zip(t.y..) {
+            let start = (y * 45 + t.x) * 3;
+            assembled[start..start + row.len()].copy_from_slice(row);
+        }
+    }
+    assert_eq!(full, assembled);
+    dec.finish_decompress().unwrap();
+
+    let mut dec = Decompress::new_mem(&data[..data.len() / 2]).unwrap().rgb().unwrap();
+    // truncated files are padded by libjpeg
+    assert_eq!(6, dec.tiles(16, 16).filter(|t| t.is_ok()).count());
+}
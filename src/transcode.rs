@@ -0,0 +1,346 @@
+use crate::coefficients::Coefficients;
+use crate::colorspace::ColorSpace;
+use crate::compress::Compress;
+use crate::decompress::{Decompress, ALL_MARKERS};
+use crate::ffi::DCTSIZE;
+use crate::ffi::jpeg_scan_info as ScanInfo;
+use crate::marker::Marker;
+use crate::metadata::MarkerPolicy;
+use crate::segments::{Segments, EOI, SOS};
+use crate::vec::{try_to_vec, wipe};
+use std::borrow::Cow;
+use std::io;
+
+/// Settings for `requantize_with()`
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Write exactly the same scans as the source file, instead of MozJPEG's optimized progression.
+    ///
+    /// This keeps the structure of the file stable, e.g. for delta compression.
+    pub preserve_scans: bool,
+    /// Which markers to copy from the source file
+    pub marker_policy: MarkerPolicy,
+}
+
+/// Makes the file smaller by dividing its DCT coefficients by coarser quantization tables.
+///
+/// This is much faster than decoding and compressing pixels again,
+/// and the loss of quality is similar to re-compressing at the given `quality` (1-100).
+/// Quantization is never made finer than in the original file, so high qualities may not change the file at all.
+///
+/// Markers (such as EXIF and ICC profiles) are preserved.
+pub fn requantize(jpeg: &[u8], quality: f32) -> io::Result<Vec<u8>> {
+    requantize_with(jpeg, quality, &Options::default())
+}
+
+/// Same as `requantize()`, with more options
+pub fn requantize_with(jpeg: &[u8], quality: f32, options: &Options) -> io::Result<Vec<u8>> {
+    let (mut coefficients, markers) = read_with_markers(jpeg, options.marker_policy)?;
+
+    let mut cinfo = Compress::new(coefficients.color_space);
+    cinfo.set_quality(quality);
+    let targets = cinfo.components().iter()
+        .map(|c| cinfo.qtable(c.quant_tbl_no as usize))
+        .collect::<Vec<_>>();
+    for (ci, comp) in coefficients.components.iter_mut().enumerate() {
+        if let Some(target) = targets.get(ci).or(targets.first()).and_then(|t| t.as_ref()) {
+            comp.requantize(target);
+        }
+    }
+    if options.preserve_scans {
+        cinfo.set_scan_script(&scan_script(jpeg)?);
+    }
+
+    write_with_markers(cinfo, &coefficients, &markers)
+}
+
+/// Replaces a rectangle of the image with new pixels, without re-compressing the rest of the image.
+///
+/// `pixels` are RGB, `width` × `height` in size, and are placed at `x`,`y` of the image.
+/// Only MCUs that overlap the rectangle are compressed again (using the file's own quantization tables),
+/// and all other blocks are copied exactly. Works with YCbCr and grayscale files.
+pub fn patch_region(jpeg: &[u8], x: usize, y: usize, width: usize, height: usize, pixels: &[u8]) -> io::Result<Vec<u8>> {
+    let (mut coefficients, markers) = read_with_markers(jpeg, MarkerPolicy::KeepAll)?;
+    let out_of_bounds = || io::Error::new(io::ErrorKind::InvalidInput, "region out of bounds");
+    let x_end = x.checked_add(width).ok_or_else(out_of_bounds)?;
+    let y_end = y.checked_add(height).ok_or_else(out_of_bounds)?;
+    if width == 0 || height == 0 || x_end > coefficients.width || y_end > coefficients.height {
+        return Err(out_of_bounds());
+    }
+    if Some(pixels.len()) != width.checked_mul(height).and_then(|n| n.checked_mul(3)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixels don't match region size"));
+    }
+    if !matches!(coefficients.color_space, ColorSpace::JCS_YCbCr | ColorSpace::JCS_GRAYSCALE) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "only YCbCr and grayscale files can be patched"));
+    }
+
+    // The patch has to cover whole MCUs
+    let max_h = coefficients.components.iter().map(|c| c.sampling.0).max().unwrap_or(1) as usize;
+    let max_v = coefficients.components.iter().map(|c| c.sampling.1).max().unwrap_or(1) as usize;
+    let (mcu_width, mcu_height) = (max_h * DCTSIZE, max_v * DCTSIZE);
+    let left = x / mcu_width * mcu_width;
+    let top = y / mcu_height * mcu_height;
+    let right = x_end.next_multiple_of(mcu_width).min(coefficients.width);
+    let bottom = y_end.next_multiple_of(mcu_height).min(coefficients.height);
+    let (region_width, region_height) = (right - left, bottom - top);
+
+    let mut region = if (left, top, right, bottom) == (x, y, x_end, y_end) {
+        try_to_vec(pixels)?
+    } else {
+        // Pixels around the patch are needed to fill partially-covered MCUs
+        let mut dinfo = Decompress::new_mem(jpeg)?.rgb()?;
+        let mut image = dinfo.read_scanlines_flat()?;
+        let stride = coefficients.width * 3;
+        let mut region = Vec::new();
+        region.try_reserve_exact(region_width * region_height * 3).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        for row in image.chunks_exact(stride).skip(top).take(region_height) {
+            region.extend_from_slice(&row[left * 3..right * 3]);
+        }
+        for (row, src) in region.chunks_exact_mut(region_width * 3).skip(y - top).zip(pixels.chunks_exact(width * 3)) {
+            row[(x - left) * 3..(x - left + width) * 3].copy_from_slice(src);
+        }
+        wipe(&mut image);
+        region
+    };
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_size(region_width, region_height);
+    cinfo.set_color_space(coefficients.color_space);
+    for (comp, coefs) in cinfo.components_mut().iter_mut().zip(&coefficients.components) {
+        comp.h_samp_factor = coefs.sampling.0.into();
+        comp.v_samp_factor = coefs.sampling.1.into();
+    }
+    cinfo.set_component_qtables(&coefficients.components.iter().map(|c| &c.qtable).collect::<Vec<_>>());
+    cinfo.set_mem_dest();
+    let res = cinfo.start_compress()
+        .and_then(|mut cinfo| if cinfo.write_scanlines(&region)? { cinfo.finish_compress() } else { Err(io::ErrorKind::UnexpectedEof.into()) });
+    wipe(&mut region);
+    let mut cinfo = res?;
+    let mut patch_jpeg = cinfo.data_to_vec().map_err(|_| io::ErrorKind::OutOfMemory)?;
+    let patch = Decompress::new_mem(&patch_jpeg)?.read_coefficients();
+    wipe(&mut patch_jpeg);
+    let patch = patch?;
+
+    for (comp, patch_comp) in coefficients.components.iter_mut().zip(&patch.components) {
+        let block_x = left / mcu_width * comp.sampling.0 as usize;
+        let block_y = top / mcu_height * comp.sampling.1 as usize;
+        let copy_width = patch_comp.width_in_blocks.min(comp.width_in_blocks - block_x);
+        for py in 0..patch_comp.height_in_blocks.min(comp.height_in_blocks - block_y) {
+            let start = (block_y + py) * comp.width_in_blocks + block_x;
+            comp.blocks[start..start + copy_width].copy_from_slice(&patch_comp.row(py)[..copy_width]);
+        }
+    }
+
+    write_with_markers(Compress::new(coefficients.color_space), &coefficients, &markers)
+}
+
+type Markers = Vec<(Marker, Vec<u8>)>;
+
+/// Scans of the file, in the form accepted by `Compress::set_scan_script()`
+///
+/// Only markers are parsed, so this is cheap.
+pub fn scan_script(jpeg: &[u8]) -> io::Result<Vec<ScanInfo>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut component_ids = Vec::new();
+    let mut scans = Vec::new();
+    for segment in Segments::new(jpeg)? {
+        let segment = segment?;
+        match segment.marker {
+            0xC0..=0xCF if !matches!(segment.marker, 0xC4 | 0xC8 | 0xCC) => {
+                let num = usize::from(*segment.data.get(5).ok_or_else(|| invalid("bad SOF"))?);
+                let comps = segment.data.get(6..6 + num * 3).ok_or_else(|| invalid("bad SOF"))?;
+                component_ids = comps.chunks_exact(3).map(|c| c[0]).collect();
+            },
+            SOS => {
+                let data = segment.data;
+                let num = usize::from(*data.first().ok_or_else(|| invalid("bad SOS"))?);
+                let params = data.get(1 + num * 2..4 + num * 2).filter(|_| num <= 4).ok_or_else(|| invalid("bad SOS"))?;
+                let mut scan = ScanInfo {
+                    comps_in_scan: num as _,
+                    component_index: [0; 4],
+                    Ss: params[0].into(),
+                    Se: params[1].into(),
+                    Ah: (params[2] >> 4).into(),
+                    Al: (params[2] & 15).into(),
+                };
+                for (index, id) in scan.component_index.iter_mut().zip(data[1..].iter().step_by(2).take(num)) {
+                    *index = component_ids.iter().position(|c| c == id).ok_or_else(|| invalid("SOS refers to unknown component"))? as _;
+                }
+                scans.push(scan);
+            },
+            _ => {},
+        }
+    }
+    Ok(scans)
+}
+
+/// What `repair()` has found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of warnings about corrupted image data. After corrupted data decoding resumes at the next restart marker,
+    /// and MCUs that couldn't be decoded are filled with gray.
+    pub corrupt_data_warnings: usize,
+    /// The file ended without EOI, usually because it was truncated. Missing MCUs have been filled with gray.
+    pub added_eoi: bool,
+    /// Number of bytes after EOI that have been removed
+    pub removed_trailing_bytes: usize,
+}
+
+/// Makes a clean file out of a damaged one, as far as it can be decoded.
+///
+/// Compressed data is decoded to DCT coefficients and written again, so all scans, tables and markers
+/// in the result are valid, and image quality isn't reduced.
+/// The file header must be intact.
+pub fn repair(jpeg: &[u8]) -> io::Result<(Vec<u8>, RepairReport)> {
+    let mut report = RepairReport::default();
+    let eoi = Segments::new(jpeg)?.map_while(Result::ok).find(|s| s.marker == EOI);
+    match eoi {
+        Some(eoi) => report.removed_trailing_bytes = jpeg.len() - eoi.offset - 2,
+        None => report.added_eoi = !jpeg.ends_with(&[0xFF, EOI]),
+    }
+
+    let mut dinfo = Decompress::with_markers(ALL_MARKERS).from_mem(jpeg)?;
+    let markers = dinfo.markers()
+        .filter(|m| !is_written_automatically(m.marker, m.data))
+        .map(|m| Ok((m.marker, try_to_vec(m.data)?)))
+        .collect::<io::Result<Markers>>()?;
+    let coefficients = dinfo.read_coefficients()?;
+    // the missing EOI is reported separately
+    report.corrupt_data_warnings = dinfo.num_warnings().saturating_sub(report.added_eoi.into());
+    drop(dinfo);
+
+    let repaired = write_with_markers(Compress::new(coefficients.color_space), &coefficients, &markers)?;
+    Ok((repaired, report))
+}
+
+pub(crate) fn read_with_markers(jpeg: &[u8], policy: MarkerPolicy) -> io::Result<(Coefficients, Markers)> {
+    let mut dinfo = Decompress::with_markers(ALL_MARKERS).from_mem(jpeg)?;
+    let markers = dinfo.markers()
+        .filter(|m| !is_written_automatically(m.marker, m.data))
+        .filter_map(|m| Some((m.marker, policy.apply(m.marker, m.data)?)))
+        .map(|(marker, data)| Ok((marker, match data {
+            Cow::Owned(data) => data,
+            Cow::Borrowed(data) => try_to_vec(data)?,
+        })))
+        .collect::<io::Result<_>>()?;
+    Ok((dinfo.read_coefficients()?, markers))
+}
+
+pub(crate) fn write_with_markers(mut cinfo: Compress, coefficients: &Coefficients, markers: &[(Marker, Vec<u8>)]) -> io::Result<Vec<u8>> {
+    cinfo.set_mem_dest();
+    let mut cinfo = cinfo.write_coefficients(coefficients)?;
+    for (marker, data) in markers {
+        cinfo.write_marker(*marker, data)?;
+    }
+    let mut cinfo = cinfo.finish_compress()?;
+    cinfo.data_to_vec().map_err(|_| io::ErrorKind::OutOfMemory.into())
+}
+
+/// libjpeg writes its own JFIF and Adobe markers
+fn is_written_automatically(marker: Marker, data: &[u8]) -> bool {
+    match marker {
+        Marker::APP(0) => data.starts_with(b"JFIF\0"),
+        Marker::APP(14) => data.starts_with(b"Adobe"),
+        _ => false,
+    }
+}
+
+#[test]
+fn requantize_file() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let decode = |jpeg: &[u8]| {
+        let mut dinfo = Decompress::new_mem(jpeg).unwrap().rgb().unwrap();
+        dinfo.read_scanlines::<[u8; 3]>().unwrap()
+    };
+
+    // qtables can't get finer, so this is lossless
+    let same = requantize(&data, 100.).unwrap();
+    assert_eq!(decode(&data), decode(&same));
+
+    let smaller = requantize(&data, 30.).unwrap();
+    assert!(smaller.len() < same.len());
+    assert_eq!(45 * 30, decode(&smaller).len());
+
+    let before = Decompress::new_mem(&data).unwrap().read_coefficients().unwrap();
+    let after = Decompress::new_mem(&smaller).unwrap().read_coefficients().unwrap();
+    assert_eq!((45, 30), (after.width, after.height));
+    for (b, a) in before.components.iter().zip(&after.components) {
+        assert_eq!(b.blocks.len(), a.blocks.len());
+        assert!(b.qtable.coeffs.iter().zip(a.qtable.coeffs.iter()).all(|(b, a)| a >= b));
+        assert_ne!(b.qtable, a.qtable);
+    }
+}
+
+#[test]
+fn patch_region_keeps_other_blocks() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let red = [255, 0, 0].repeat(10 * 12);
+    let patched = patch_region(&data, 20, 3, 10, 12, &red).unwrap();
+
+    let mut dinfo = Decompress::new_mem(&patched).unwrap().rgb().unwrap();
+    let pixels = dinfo.read_scanlines::<[u8; 3]>().unwrap();
+    let [r, g, b] = pixels[8 * 45 + 25];
+    assert!(r > 200 && g < 60 && b < 60, "{r},{g},{b}");
+
+    for (x, width) in [(usize::MAX, 10), (20, usize::MAX)] {
+        let err = patch_region(&data, x, 3, width, 12, &red).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    // MCUs are 16x16, so only the middle column of MCUs may change
+    let before = Decompress::new_mem(&data).unwrap().read_coefficients().unwrap();
+    let after = Decompress::new_mem(&patched).unwrap().read_coefficients().unwrap();
+    for (b, a) in before.components.iter().zip(&after.components) {
+        assert_eq!(b.qtable, a.qtable);
+        let mcu_blocks = b.sampling.0 as usize;
+        for y in 0..b.height_in_blocks {
+            for x in (0..b.width_in_blocks).filter(|&x| x / mcu_blocks != 1) {
+                assert_eq!(b.row(y)[x], a.row(y)[x]);
+            }
+        }
+    }
+}
+
+#[test]
+fn requantize_preserving_scans() {
+    use crate::compress::compress_for_test;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let source_scans = scan_script(&data).unwrap();
+    assert!(source_scans.len() > 1);
+    let key = |scans: &[ScanInfo]| scans.iter().map(|s| (s.comps_in_scan, s.component_index, s.Ss, s.Se, s.Ah, s.Al)).collect::<Vec<_>>();
+
+    let preserved = requantize_with(&data, 60., &Options { preserve_scans: true, ..Options::default() }).unwrap();
+    assert_eq!(key(&source_scans), key(&scan_script(&preserved).unwrap()));
+
+    let baseline = compress_for_test(ColorSpace::JCS_RGB, 8, 8, &[128; 8 * 8 * 3], |c| c.set_fastest_defaults());
+    let scans = scan_script(&baseline).unwrap();
+    let preserved = requantize_with(&baseline, 60., &Options { preserve_scans: true, ..Options::default() }).unwrap();
+    assert_eq!(key(&scans), key(&scan_script(&preserved).unwrap()));
+    assert_ne!(key(&scans), key(&scan_script(&requantize(&baseline, 60.).unwrap()).unwrap()));
+}
+
+#[test]
+fn repair_damaged_files() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let (repaired, report) = repair(&data).unwrap();
+    assert_eq!(RepairReport::default(), report);
+    assert!(crate::validate(&repaired).is_ok());
+
+    let mut trailing = data.clone();
+    trailing.extend_from_slice(b"junk");
+    let (repaired, report) = repair(&trailing).unwrap();
+    assert_eq!(4, report.removed_trailing_bytes);
+    assert!(crate::validate(&repaired).is_ok());
+
+    let (repaired, report) = repair(&data[..1000]).unwrap();
+    assert!(report.added_eoi);
+    let validated = crate::validate(&repaired).unwrap();
+    assert_eq!((45, 30), (validated.width, validated.height));
+
+    // part of the luma AC scan is missing
+    let corrupted = [&data[..600], &data[1000..]].concat();
+    let (repaired, report) = repair(&corrupted).unwrap();
+    assert!(report.corrupt_data_warnings > 0);
+    assert!(crate::validate(&repaired).is_ok());
+}
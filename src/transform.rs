@@ -0,0 +1,184 @@
+use crate::coefficients::{Coefficients, ComponentCoefficients};
+use crate::compress::Compress;
+use crate::exif::Orientation;
+use crate::ffi::{DCTSIZE, JBLOCK};
+use crate::metadata::MarkerPolicy;
+use crate::transcode::{read_with_markers, write_with_markers};
+use std::io;
+
+/// Rotation or flip that can be done without decoding pixels, like `jpegtran`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Transform {
+    /// Mirror left to right
+    FlipHorizontal,
+    /// Mirror top to bottom
+    FlipVertical,
+    /// Flip along the top-left to bottom-right diagonal
+    Transpose,
+    /// Flip along the top-right to bottom-left diagonal
+    Transverse,
+    /// 90° clockwise
+    Rotate90,
+    /// 180°
+    Rotate180,
+    /// 270° clockwise (90° counter-clockwise)
+    Rotate270,
+}
+
+impl Transform {
+    /// Transform that displays an image with this EXIF orientation upright. `None` for `Orientation::Normal`.
+    pub fn from_orientation(orientation: Orientation) -> Option<Self> {
+        Some(match orientation {
+            Orientation::Normal => return None,
+            Orientation::FlipHorizontal => Self::FlipHorizontal,
+            Orientation::Rotate180 => Self::Rotate180,
+            Orientation::FlipVertical => Self::FlipVertical,
+            Orientation::Transpose => Self::Transpose,
+            Orientation::Rotate90 => Self::Rotate90,
+            Orientation::Transverse => Self::Transverse,
+            Orientation::Rotate270 => Self::Rotate270,
+        })
+    }
+
+    /// Mirroring of the source's x and y axes, followed by optional transposition
+    fn steps(self) -> (bool, bool, bool) {
+        match self {
+            Self::FlipHorizontal => (true, false, false),
+            Self::FlipVertical => (false, true, false),
+            Self::Transpose => (false, false, true),
+            Self::Transverse => (true, true, true),
+            Self::Rotate90 => (false, true, true),
+            Self::Rotate180 => (true, true, false),
+            Self::Rotate270 => (true, false, true),
+        }
+    }
+}
+
+/// Rotates or flips the image losslessly, by rearranging its DCT coefficients.
+///
+/// Like `jpegtran -trim`, partial MCUs at the right and bottom edges are dropped when they would end up
+/// at the left or top edge (JPEG can't represent that), so the result may be up to 15 pixels smaller.
+/// Images smaller than one MCU aren't trimmed.
+///
+/// Markers are preserved. EXIF orientation isn't changed, so remove or update it if you use this to rotate photos upright.
+pub fn transform(jpeg: &[u8], transform: Transform) -> io::Result<Vec<u8>> {
+    let (coefficients, markers) = read_with_markers(jpeg, MarkerPolicy::KeepAll)?;
+    let transformed = transform_coefficients(&coefficients, transform)?;
+    write_with_markers(Compress::new(transformed.color_space), &transformed, &markers)
+}
+
+/// Same as `transform()`, for already-read coefficients
+pub fn transform_coefficients(coefficients: &Coefficients, transform: Transform) -> io::Result<Coefficients> {
+    let (mirror_x, mirror_y, transpose) = transform.steps();
+    let max_h = coefficients.components.iter().map(|c| c.sampling.0).max().unwrap_or(1) as usize;
+    let max_v = coefficients.components.iter().map(|c| c.sampling.1).max().unwrap_or(1) as usize;
+    let trim = |size: usize, mcu_size: usize| if size >= mcu_size { size / mcu_size * mcu_size } else { size };
+    let width = if mirror_x { trim(coefficients.width, max_h * DCTSIZE) } else { coefficients.width };
+    let height = if mirror_y { trim(coefficients.height, max_v * DCTSIZE) } else { coefficients.height };
+
+    let mut components = Vec::new();
+    components.try_reserve_exact(coefficients.components.len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+    for comp in &coefficients.components {
+        let (h, v) = (comp.sampling.0 as usize, comp.sampling.1 as usize);
+        let src_width = (width * h).div_ceil(max_h * DCTSIZE).min(comp.width_in_blocks);
+        let src_height = (height * v).div_ceil(max_v * DCTSIZE).min(comp.height_in_blocks);
+        let (width_in_blocks, height_in_blocks) = if transpose { (src_height, src_width) } else { (src_width, src_height) };
+
+        let mut blocks = Vec::new();
+        blocks.try_reserve_exact(width_in_blocks * height_in_blocks).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        for y in 0..height_in_blocks {
+            for x in 0..width_in_blocks {
+                let (sx, sy) = if transpose { (y, x) } else { (x, y) };
+                let sx = if mirror_x { src_width - 1 - sx } else { sx };
+                let sy = if mirror_y { src_height - 1 - sy } else { sy };
+                blocks.push(transform_block(&comp.row(sy)[sx], mirror_x, mirror_y, transpose));
+            }
+        }
+
+        let mut qtable = comp.qtable.clone();
+        if transpose {
+            for (i, q) in qtable.coeffs.iter_mut().enumerate() {
+                *q = comp.qtable.coeffs[i % DCTSIZE * DCTSIZE + i / DCTSIZE];
+            }
+        }
+        components.push(ComponentCoefficients {
+            component_id: comp.component_id,
+            sampling: if transpose { (comp.sampling.1, comp.sampling.0) } else { comp.sampling },
+            width_in_blocks,
+            height_in_blocks,
+            qtable,
+            blocks,
+        });
+    }
+
+    Ok(Coefficients {
+        width: if transpose { height } else { width },
+        height: if transpose { width } else { height },
+        color_space: coefficients.color_space,
+        components,
+    })
+}
+
+/// Mirroring negates odd frequencies along the axis
+fn transform_block(block: &JBLOCK, mirror_x: bool, mirror_y: bool, transpose: bool) -> JBLOCK {
+    let mut out = [0; DCTSIZE * DCTSIZE];
+    for (i, &coef) in block.iter().enumerate() {
+        let (u, v) = (i % DCTSIZE, i / DCTSIZE);
+        let negate = (mirror_x && u % 2 == 1) != (mirror_y && v % 2 == 1);
+        let dst = if transpose { u * DCTSIZE + v } else { i };
+        out[dst] = if negate { -coef } else { coef };
+    }
+    out
+}
+
+#[test]
+fn transforms() {
+    use crate::Decompress;
+
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let decode = |jpeg: &[u8]| {
+        let mut dinfo = Decompress::new_mem(jpeg).unwrap().rgb().unwrap();
+        (dinfo.width(), dinfo.height(), dinfo.read_scanlines::<[u8; 3]>().unwrap())
+    };
+    let (width, height, original) = decode(&data);
+    assert_eq!((45, 30), (width, height));
+
+    // MCUs are 16x16, so mirrored axes are trimmed to 32 and 16 pixels
+    let cases = [
+        (Transform::FlipHorizontal, (32, 30)),
+        (Transform::FlipVertical, (45, 16)),
+        (Transform::Transpose, (30, 45)),
+        (Transform::Transverse, (16, 32)),
+        (Transform::Rotate90, (16, 45)),
+        (Transform::Rotate180, (32, 16)),
+        (Transform::Rotate270, (30, 32)),
+    ];
+    for (t, expected_size) in cases {
+        let (w, h, pixels) = decode(&transform(&data, t).unwrap());
+        assert_eq!(expected_size, (w, h), "{t:?}");
+        let (mirror_x, mirror_y, transpose) = t.steps();
+        // DCT is exact, but upsampling of chroma isn't symmetric, so compare loosely
+        let mut total_diff = 0;
+        for y in 0..h {
+            for x in 0..w {
+                let (sx, sy) = if transpose { (y, x) } else { (x, y) };
+                let (src_w, src_h) = if transpose { (h, w) } else { (w, h) };
+                let sx = if mirror_x { src_w - 1 - sx } else { sx };
+                let sy = if mirror_y { src_h - 1 - sy } else { sy };
+                let (a, b) = (original[sy * width + sx], pixels[y * w + x]);
+                total_diff += a.iter().zip(b).map(|(&a, b)| u32::from(a.abs_diff(b))).sum::<u32>();
+            }
+        }
+        assert!(total_diff < (w * h) as u32 * 2, "{t:?} {total_diff}");
+    }
+
+    // flipping twice is exact
+    let coefficients = Decompress::new_mem(&data).unwrap().coefficients().unwrap();
+    let twice = transform_coefficients(&transform_coefficients(&coefficients, Transform::Transpose).unwrap(), Transform::Transpose).unwrap();
+    for (a, b) in coefficients.components.iter().zip(&twice.components) {
+        assert_eq!(a.blocks, b.blocks);
+        assert_eq!(a.qtable, b.qtable);
+    }
+    assert_eq!(Some(Transform::Rotate90), Transform::from_orientation(Orientation::Rotate90));
+    assert_eq!(None, Transform::from_orientation(Orientation::Normal));
+}
@@ -0,0 +1,297 @@
+use crate::decompress::EntropyCoding;
+use crate::segments::{Segments, EOI, SOS};
+use std::fmt;
+use std::io;
+
+/// Structure of a file that passed `validate()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub width: u16,
+    pub height: u16,
+    pub components: u8,
+    /// Bits per sample
+    pub precision: u8,
+    pub progressive: bool,
+    pub entropy_coding: EntropyCoding,
+    /// Number of SOS segments
+    pub scans: usize,
+    /// Some scans have no Huffman tables defined, and use the standard ones (common in Motion JPEG)
+    pub uses_default_huffman_tables: bool,
+    /// Bytes after the EOI marker
+    pub trailing_bytes: usize,
+}
+
+/// Reason why `validate()` rejected the file. Offsets are in bytes from the start of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationError {
+    /// Doesn't start with SOI
+    NotJpeg,
+    /// File ends in the middle of a segment
+    Truncated { offset: usize },
+    /// There's no marker where one was expected
+    BadMarker { offset: usize },
+    /// Segment's length or contents are invalid
+    BadSegment { marker: u8, offset: usize },
+    /// Lossless, hierarchical, 12-bit or a second frame
+    UnsupportedFrame { marker: u8, offset: usize },
+    /// SOS before SOF
+    MissingFrame { offset: usize },
+    /// Component uses a quantization table that hasn't been defined
+    MissingQuantTable { component: u8, offset: usize },
+    /// Scan refers to a component that isn't in the frame, or has invalid spectral selection or approximation
+    BadScan { offset: usize },
+    /// Progressive scans are out of order or refine coefficients that haven't been sent
+    BadProgression { offset: usize },
+    /// Some components have no data
+    MissingScans,
+    /// File ends without EOI
+    MissingEoi,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::NotJpeg => f.write_str("not a JPEG file"),
+            Self::Truncated { offset } => write!(f, "truncated segment at {offset}"),
+            Self::BadMarker { offset } => write!(f, "expected a marker at {offset}"),
+            Self::BadSegment { marker, offset } => write!(f, "invalid {marker:02X} segment at {offset}"),
+            Self::UnsupportedFrame { marker, offset } => write!(f, "unsupported frame type {marker:02X} at {offset}"),
+            Self::MissingFrame { offset } => write!(f, "scan without a frame header at {offset}"),
+            Self::MissingQuantTable { component, offset } => write!(f, "no quantization table for component {component} at {offset}"),
+            Self::BadScan { offset } => write!(f, "invalid scan header at {offset}"),
+            Self::BadProgression { offset } => write!(f, "invalid progression at {offset}"),
+            Self::MissingScans => f.write_str("not all components have been coded"),
+            Self::MissingEoi => f.write_str("missing EOI marker"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<ValidationError> for io::Error {
+    fn from(err: ValidationError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+struct Frame {
+    progressive: bool,
+    /// id and quantization table number
+    components: Vec<(u8, u8)>,
+    /// Precision of each coefficient sent so far, per component. `None` if not sent yet.
+    coef_bits: Vec<[Option<u8>; 64]>,
+}
+
+/// Checks the structure of the file without decoding any image data.
+///
+/// Verifies marker syntax, frame and scan headers, presence of tables, consistency of progressive scans and presence of EOI.
+/// It doesn't check the entropy-coded data, so a file that passes may still be corrupted.
+pub fn validate(jpeg: &[u8]) -> Result<Report, ValidationError> {
+    let mut segments = Segments::new(jpeg).map_err(|_| ValidationError::NotJpeg)?;
+    let mut report = Report {
+        width: 0,
+        height: 0,
+        components: 0,
+        precision: 0,
+        progressive: false,
+        entropy_coding: EntropyCoding::Huffman,
+        scans: 0,
+        uses_default_huffman_tables: false,
+        trailing_bytes: 0,
+    };
+    let mut frame: Option<Frame> = None;
+    let mut quant_tables = [false; 4];
+    let mut huffman_tables = [[false; 4]; 2];
+
+    loop {
+        let offset = segments.position();
+        let segment = match segments.next() {
+            Some(Ok(segment)) => segment,
+            Some(Err(err)) => return Err(match err.kind() {
+                io::ErrorKind::UnexpectedEof if offset == jpeg.len() => ValidationError::MissingEoi,
+                io::ErrorKind::UnexpectedEof => ValidationError::Truncated { offset },
+                _ => ValidationError::BadMarker { offset },
+            }),
+            None => return Err(ValidationError::MissingEoi),
+        };
+        let (marker, offset, data) = (segment.marker, segment.offset, segment.data);
+        let bad_segment = ValidationError::BadSegment { marker, offset };
+        match marker {
+            0xC0 | 0xC1 | 0xC2 | 0xC9 | 0xCA => {
+                if frame.is_some() {
+                    return Err(ValidationError::UnsupportedFrame { marker, offset });
+                }
+                let &[precision, h1, h0, w1, w0, num, ref comps @ ..] = data else { return Err(bad_segment) };
+                if num == 0 || num > 4 || comps.len() != usize::from(num) * 3 {
+                    return Err(bad_segment);
+                }
+                // 12-bit files are valid, but can't be decoded with this build of libjpeg
+                if precision == 12 {
+                    return Err(ValidationError::UnsupportedFrame { marker, offset });
+                }
+                if precision != 8 {
+                    return Err(bad_segment);
+                }
+                report.width = u16::from_be_bytes([w1, w0]);
+                report.height = u16::from_be_bytes([h1, h0]);
+                if report.width == 0 || report.height == 0 {
+                    return Err(bad_segment);
+                }
+                let mut components = Vec::with_capacity(comps.len() / 3);
+                for c in comps.chunks_exact(3) {
+                    let (h, v) = (c[1] >> 4, c[1] & 15);
+                    if !(1..=4).contains(&h) || !(1..=4).contains(&v) || c[2] > 3 || components.iter().any(|&(id, _)| id == c[0]) {
+                        return Err(bad_segment);
+                    }
+                    components.push((c[0], c[2]));
+                }
+                report.components = num;
+                report.precision = precision;
+                report.progressive = matches!(marker, 0xC2 | 0xCA);
+                report.entropy_coding = if marker >= 0xC9 { EntropyCoding::Arithmetic } else { EntropyCoding::Huffman };
+                frame = Some(Frame {
+                    progressive: report.progressive,
+                    coef_bits: vec![[None; 64]; components.len()],
+                    components,
+                });
+            },
+            0xC3 | 0xC5..=0xC7 | 0xCB | 0xCD..=0xCF => return Err(ValidationError::UnsupportedFrame { marker, offset }),
+            0xDB => {
+                let mut rest = data;
+                while let Some((&pq_tq, tail)) = rest.split_first() {
+                    let len = if pq_tq >> 4 == 0 { 64 } else { 128 };
+                    if pq_tq >> 4 > 1 || pq_tq & 15 > 3 || tail.len() < len {
+                        return Err(bad_segment);
+                    }
+                    quant_tables[usize::from(pq_tq & 15)] = true;
+                    rest = &tail[len..];
+                }
+                if data.is_empty() {
+                    return Err(bad_segment);
+                }
+            },
+            0xC4 => {
+                let mut rest = data;
+                while let Some((&tc_th, tail)) = rest.split_first() {
+                    let counts = tail.get(..16).ok_or_else(|| bad_segment.clone())?;
+                    let len = 16 + counts.iter().map(|&c| usize::from(c)).sum::<usize>();
+                    if tc_th >> 4 > 1 || tc_th & 15 > 3 || len > 16 + 256 || tail.len() < len {
+                        return Err(bad_segment);
+                    }
+                    huffman_tables[usize::from(tc_th >> 4)][usize::from(tc_th & 15)] = true;
+                    rest = &tail[len..];
+                }
+                if data.is_empty() {
+                    return Err(bad_segment);
+                }
+            },
+            0xDD if data.len() != 2 => return Err(bad_segment),
+            SOS => {
+                let frame = frame.as_mut().ok_or(ValidationError::MissingFrame { offset })?;
+                check_scan(frame, data, offset, &quant_tables, &huffman_tables, &mut report)?;
+                report.scans += 1;
+            },
+            EOI => {
+                if report.scans == 0 {
+                    return Err(ValidationError::MissingScans);
+                }
+                if frame.iter().flat_map(|f| &f.coef_bits).any(|bits| bits[0].is_none()) {
+                    return Err(ValidationError::MissingScans);
+                }
+                report.trailing_bytes = jpeg.len() - offset - 2;
+                return Ok(report);
+            },
+            _ => {},
+        }
+    }
+}
+
+fn check_scan(frame: &mut Frame, data: &[u8], offset: usize, quant_tables: &[bool; 4], huffman_tables: &[[bool; 4]; 2], report: &mut Report) -> Result<(), ValidationError> {
+    let bad_scan = ValidationError::BadScan { offset };
+    let (&num, rest) = data.split_first().ok_or(bad_scan.clone())?;
+    let num = usize::from(num);
+    if num == 0 || num > 4 || rest.len() != num * 2 + 3 {
+        return Err(bad_scan);
+    }
+    let (comps, params) = rest.split_at(num * 2);
+    let (ss, se, ah, al) = (params[0], params[1], params[2] >> 4, params[2] & 15);
+
+    let valid_params = if frame.progressive {
+        ss <= se && se < 64 && (ss == 0) == (se == 0) && (ss == 0 || num == 1) && al <= 13 && (ah == 0 || al + 1 == ah)
+    } else {
+        (ss, se, ah, al) == (0, 63, 0, 0)
+    };
+    if !valid_params {
+        return Err(bad_scan);
+    }
+
+    for c in comps.chunks_exact(2) {
+        let ci = frame.components.iter().position(|&(id, _)| id == c[0]).ok_or(bad_scan.clone())?;
+        let tq = frame.components[ci].1;
+        if !quant_tables[usize::from(tq)] {
+            return Err(ValidationError::MissingQuantTable { component: c[0], offset });
+        }
+        if report.entropy_coding == EntropyCoding::Huffman {
+            let (td, ta) = (usize::from(c[1] >> 4), usize::from(c[1] & 15));
+            if td > 3 || ta > 3 {
+                return Err(bad_scan);
+            }
+            let needs_dc = ss == 0 && ah == 0;
+            let needs_ac = se > 0;
+            if (needs_dc && !huffman_tables[0][td]) || (needs_ac && !huffman_tables[1][ta]) {
+                report.uses_default_huffman_tables = true;
+            }
+        }
+
+        let bits = &mut frame.coef_bits[ci];
+        if ss > 0 && bits[0].is_none() {
+            // AC scans need DC first
+            return Err(ValidationError::BadProgression { offset });
+        }
+        for bit in &mut bits[usize::from(ss)..=usize::from(se)] {
+            let expected = if ah == 0 { None } else { Some(ah) };
+            if *bit != expected {
+                return Err(ValidationError::BadProgression { offset });
+            }
+            *bit = Some(al);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn validate_files() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let report = validate(&data).unwrap();
+    assert_eq!((45, 30, 3, 8), (report.width, report.height, report.components, report.precision));
+    assert!(report.progressive);
+    assert_eq!(EntropyCoding::Huffman, report.entropy_coding);
+    assert!(report.scans > 1);
+    assert!(!report.uses_default_huffman_tables);
+    assert_eq!(0, report.trailing_bytes);
+
+    let mut trailing = data.clone();
+    trailing.extend_from_slice(b"junk");
+    assert_eq!(4, validate(&trailing).unwrap().trailing_bytes);
+
+    assert_eq!(Err(ValidationError::NotJpeg), validate(b"GIF89a"));
+    assert_eq!(Err(ValidationError::MissingEoi), validate(&data[..data.len() - 2]));
+    assert!(matches!(validate(&data[..30]), Err(ValidationError::Truncated { .. })));
+
+    // remove the first scan (luma DC), so that the luma AC scan is invalid
+    let scans = crate::segments::Segments::new(&data).unwrap().map(|s| s.unwrap()).filter(|s| s.marker == SOS).collect::<Vec<_>>();
+    let first_luma_ac = scans.iter().find(|s| s.data[1] == 1 && s.data[4] > 0).unwrap().offset;
+    let removed = scans[1].offset - scans[0].offset;
+    let without_dc = [&data[..scans[0].offset], &data[scans[1].offset..]].concat();
+    assert_eq!(Err(ValidationError::BadProgression { offset: first_luma_ac - removed }), validate(&without_dc));
+
+    let sof = crate::segments::Segments::new(&data).unwrap().map(|s| s.unwrap()).find(|s| s.marker == 0xC2).unwrap();
+    let with_precision = |precision| {
+        let mut data = data.clone();
+        data[sof.data_range().start] = precision;
+        validate(&data)
+    };
+    assert_eq!(Err(ValidationError::UnsupportedFrame { marker: 0xC2, offset: sof.offset }), with_precision(12));
+    assert_eq!(Err(ValidationError::BadSegment { marker: 0xC2, offset: sof.offset }), with_precision(9));
+}
@@ -1,13 +1,53 @@
+use std::io;
+use std::mem;
 
-pub trait VecUninitExtender {
-    unsafe fn extend_uninit(&mut self, items: usize);
+/// `vec![value; len]` that returns an error instead of aborting when out of memory
+pub fn try_filled<T: Copy>(value: T, len: usize) -> io::Result<Vec<T>> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len).map_err(|_| io::ErrorKind::OutOfMemory)?;
+    vec.resize(len, value);
+    Ok(vec)
 }
 
-impl<T: Copy> VecUninitExtender for Vec<T> {
-    unsafe fn extend_uninit(&mut self, items: usize) {
-        let new_len = self.len() + items;
-        self.try_reserve(items).expect("oom");
-        debug_assert!(self.capacity() >= new_len);
-        self.set_len(new_len);
+/// `slice.to_vec()` that returns an error instead of aborting when out of memory
+pub fn try_to_vec<T: Copy>(slice: &[T]) -> io::Result<Vec<T>> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(slice.len()).map_err(|_| io::ErrorKind::OutOfMemory)?;
+    vec.extend_from_slice(slice);
+    Ok(vec)
+}
+
+/// Overwrites the data with zeros if the `zeroize` feature is enabled.
+/// Used for image data that is about to be freed.
+#[inline]
+pub fn wipe<T: Copy>(data: &mut [T]) {
+    unsafe { wipe_bytes(data.as_mut_ptr().cast(), mem::size_of_val(data)) }
+}
+
+/// Overwrites `len` bytes with zeros if the `zeroize` feature is enabled, in a way that isn't optimized out
+#[inline]
+#[cfg_attr(not(feature = "zeroize"), allow(unused_variables))]
+pub unsafe fn wipe_bytes(ptr: *mut u8, len: usize) {
+    #[cfg(feature = "zeroize")]
+    {
+        for i in 0..len {
+            std::ptr::write_volatile(ptr.add(i), 0);
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
     }
 }
+
+#[test]
+#[cfg(feature = "zeroize")]
+fn wipes() {
+    let mut data = vec![[1i16; 64]; 3];
+    wipe(&mut data);
+    assert!(data.iter().flatten().all(|&c| c == 0));
+}
+
+#[test]
+fn allocation_failure_is_an_error() {
+    assert_eq!(io::ErrorKind::OutOfMemory, try_filled(0u8, usize::MAX).unwrap_err().kind());
+    assert_eq!(vec![7u8; 3], try_filled(7, 3).unwrap());
+    assert_eq!(b"abc".to_vec(), try_to_vec(b"abc").unwrap());
+}
@@ -0,0 +1,159 @@
+use crate::fail;
+use crate::vec::{try_filled, wipe};
+use mozjpeg_sys::boolean;
+use mozjpeg_sys::jpeg_compress_struct;
+use mozjpeg_sys::jpeg_destination_mgr;
+use mozjpeg_sys::JERR_OUT_OF_MEMORY;
+use std::io::{self, Write};
+use std::mem;
+use std::ptr;
+
+// Type aliases to unify higher-ranked lifetimes
+type FnPtr = for<'a> unsafe extern "C" fn(cinfo: &'a mut jpeg_compress_struct);
+type UnwindFnPtr = for<'a> unsafe extern "C-unwind" fn(cinfo: &'a mut jpeg_compress_struct);
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Destination manager for `Compress::set_writer()`. `cinfo.dest` points to it.
+#[repr(C)]
+pub(crate) struct DestinationMgr<W> {
+    iface: jpeg_destination_mgr,
+    /// Allocated when compression starts
+    buffer: Vec<u8>,
+    writer: W,
+}
+
+impl<W: Write> DestinationMgr<W> {
+    pub(crate) fn new(writer: W) -> Box<Self> {
+        Box::new(Self {
+            iface: jpeg_destination_mgr {
+                next_output_byte: ptr::null_mut(),
+                free_in_buffer: 0,
+                // The callbacks may unwind, but libjpeg's types are declared as non-unwinding
+                init_destination: Some(unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::init_destination) }),
+                empty_output_buffer: Some(unsafe { mem::transmute::<unsafe extern "C-unwind" fn(&mut jpeg_compress_struct) -> boolean, unsafe extern "C" fn(&mut jpeg_compress_struct) -> boolean>(Self::empty_output_buffer) }),
+                term_destination: Some(unsafe { mem::transmute::<UnwindFnPtr, FnPtr>(Self::term_destination) }),
+            },
+            buffer: Vec::new(),
+            writer,
+        })
+    }
+
+    /// Makes libjpeg write to this manager. It must outlive `cinfo`'s use of it.
+    pub(crate) unsafe fn install(&mut self, cinfo: &mut jpeg_compress_struct) {
+        cinfo.dest = &mut self.iface;
+    }
+
+    #[cfg(feature = "zeroize")]
+    pub(crate) fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub(crate) fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub(crate) fn into_writer(self) -> W {
+        let mut this = mem::ManuallyDrop::new(self);
+        wipe(&mut this.buffer);
+        unsafe {
+            ptr::drop_in_place(&mut this.buffer);
+            ptr::read(&this.writer)
+        }
+    }
+
+    unsafe fn cast(cinfo: &mut jpeg_compress_struct) -> &mut Self {
+        &mut *cinfo.dest.cast()
+    }
+
+    fn reset_buffer(&mut self) {
+        self.iface.next_output_byte = self.buffer.as_mut_ptr();
+        self.iface.free_in_buffer = self.buffer.len();
+    }
+
+    unsafe extern "C-unwind" fn init_destination(cinfo: &mut jpeg_compress_struct) {
+        let this = Self::cast(cinfo);
+        if this.buffer.is_empty() {
+            match try_filled(0, BUFFER_SIZE) {
+                Ok(buffer) => this.buffer = buffer,
+                Err(_) => fail(&mut cinfo.common, JERR_OUT_OF_MEMORY),
+            }
+        }
+        this.reset_buffer();
+    }
+
+    unsafe extern "C-unwind" fn empty_output_buffer(cinfo: &mut jpeg_compress_struct) -> boolean {
+        let this = Self::cast(cinfo);
+        // libjpeg calls it only when the whole buffer is full, and ignores free_in_buffer
+        if let Err(err) = this.writer.write_all(&this.buffer) {
+            fail_io(err);
+        }
+        this.reset_buffer();
+        // boolean returned by this function is for async I/O, not errors.
+        1
+    }
+
+    unsafe extern "C-unwind" fn term_destination(cinfo: &mut jpeg_compress_struct) {
+        let this = Self::cast(cinfo);
+        let len = this.buffer.len() - this.iface.free_in_buffer;
+        if let Err(err) = this.writer.write_all(&this.buffer[..len]).and_then(|_| this.writer.flush()) {
+            fail_io(err);
+        }
+        this.reset_buffer();
+    }
+}
+
+impl<W> Drop for DestinationMgr<W> {
+    fn drop(&mut self) {
+        wipe(&mut self.buffer);
+    }
+}
+
+/// Unwinds with the writer's error, which is returned as-is by `catch_libjpeg()`
+fn fail_io(err: io::Error) -> ! {
+    std::panic::resume_unwind(Box::new(err))
+}
+
+/// Destination of `set_mem_dest()` with the `zeroize` feature.
+/// `jpeg_mem_dest` frees its old buffers without wiping them when it grows, and this wipes them.
+#[cfg(feature = "zeroize")]
+#[derive(Default)]
+pub(crate) struct WipingVec(pub Vec<u8>);
+
+#[cfg(feature = "zeroize")]
+impl Write for WipingVec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.0.capacity() - self.0.len() < buf.len() {
+            let mut grown = Vec::new();
+            grown.try_reserve_exact((self.0.len().saturating_add(buf.len())).max(self.0.capacity() * 2))
+                .map_err(|_| io::ErrorKind::OutOfMemory)?;
+            grown.extend_from_slice(&self.0);
+            wipe(&mut self.0);
+            self.0 = grown;
+        }
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for WipingVec {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+#[test]
+#[cfg(feature = "zeroize")]
+fn wiping_vec_grows() {
+    let mut out = WipingVec::default();
+    for i in 0..100u8 {
+        out.write_all(&[i; 1000]).unwrap();
+    }
+    assert_eq!(100 * 1000, out.0.len());
+    assert!(out.0.chunks(1000).enumerate().all(|(i, chunk)| chunk.iter().all(|&b| b == i as u8)));
+}
@@ -0,0 +1,258 @@
+//! Conversion between libjpeg's raw component planes and 4:2:0 video frames
+
+use crate::vec::{try_filled, try_to_vec, wipe};
+use std::io;
+
+/// Arrangement of chroma planes in a 4:2:0 frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum YuvLayout {
+    /// Y plane, then U (Cb) plane, then V (Cr) plane
+    I420,
+    /// Y plane, then one plane of interleaved U (Cb) and V (Cr) samples
+    NV12,
+}
+
+/// Range of sample values
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum YuvRange {
+    /// 0-255, as in JPEG (JFIF)
+    Full,
+    /// Y in 16-235 and chroma in 16-240, as in most video
+    Limited,
+}
+
+impl YuvRange {
+    /// Converts a JPEG (full range) sample to this range
+    fn full_to_range(self, sample: u8, luma: bool) -> u8 {
+        match self {
+            Self::Full => sample,
+            Self::Limited if luma => (16 + (u32::from(sample) * 219 + 127) / 255) as u8,
+            Self::Limited => (128 + (i32::from(sample) - 128) * 224 / 255) as u8,
+        }
+    }
+
+    /// Converts a sample in this range to a JPEG (full range) sample
+    fn range_to_full(self, sample: u8, luma: bool) -> u8 {
+        match self {
+            Self::Full => sample,
+            Self::Limited if luma => ((i32::from(sample) - 16) * 255 + 109).div_euclid(219).clamp(0, 255) as u8,
+            Self::Limited => (128 + ((i32::from(sample) - 128) * 255 + 112).div_euclid(224)).clamp(0, 255) as u8,
+        }
+    }
+}
+
+/// Position of chroma samples relative to luma samples in a 4:2:0 frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChromaSiting {
+    /// Centered between four luma samples, as in JPEG
+    Center,
+    /// Aligned with the left luma samples, vertically centered (MPEG-2, H.264 and HEVC default)
+    Left,
+    /// Aligned with the top-left luma sample (e.g. BT.2020 UHD video)
+    TopLeft,
+}
+
+impl ChromaSiting {
+    /// Moves samples by a quarter of a chroma sample, to the top-left if `from_jpeg`, otherwise to the bottom-right.
+    fn resample(self, plane: &mut [u8], width: usize, from_jpeg: bool) -> io::Result<()> {
+        let (horizontal, vertical) = match self {
+            Self::Center => return Ok(()),
+            Self::Left => (true, false),
+            Self::TopLeft => (true, true),
+        };
+        let height = plane.len() / width;
+        let neighbor = |pos: usize, len: usize| if from_jpeg { pos.saturating_sub(1) } else { (pos + 1).min(len - 1) };
+        let mut orig = try_to_vec(plane)?;
+        if horizontal {
+            for (row, orig_row) in plane.chunks_exact_mut(width).zip(orig.chunks_exact(width)) {
+                for (x, out) in row.iter_mut().enumerate() {
+                    *out = ((3 * u16::from(orig_row[x]) + u16::from(orig_row[neighbor(x, width)]) + 2) / 4) as u8;
+                }
+            }
+            orig.copy_from_slice(plane);
+        }
+        if vertical {
+            for (y, row) in plane.chunks_exact_mut(width).enumerate() {
+                let (orig_row, orig_next) = (&orig[y * width..], &orig[neighbor(y, height) * width..]);
+                for (x, out) in row.iter_mut().enumerate() {
+                    *out = ((3 * u16::from(orig_row[x]) + u16::from(orig_next[x]) + 2) / 4) as u8;
+                }
+            }
+        }
+        wipe(&mut orig);
+        Ok(())
+    }
+}
+
+/// Description of a 4:2:0 video frame, see `DecompressStarted::read_yuv420()`
+///
+/// Planes are tightly packed. Chroma planes are half the width and height of the image, rounded up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct YuvFormat {
+    pub layout: YuvLayout,
+    /// Samples are converted from/to JPEG's full range. Video is usually in limited range.
+    pub range: YuvRange,
+    /// Chroma is resampled if it's not centered like in JPEG
+    pub siting: ChromaSiting,
+}
+
+impl YuvFormat {
+    /// Three separate planes, as used by most video codecs
+    pub const I420: Self = Self { layout: YuvLayout::I420, range: YuvRange::Full, siting: ChromaSiting::Center };
+    /// Interleaved chroma, as used by capture APIs and hardware decoders
+    pub const NV12: Self = Self { layout: YuvLayout::NV12, range: YuvRange::Full, siting: ChromaSiting::Center };
+
+    /// Same layout with a different range of samples, e.g. `YuvFormat::NV12.with_range(YuvRange::Limited)`
+    pub const fn with_range(self, range: YuvRange) -> Self {
+        Self { range, ..self }
+    }
+
+    /// Same layout with a different chroma siting, e.g. `YuvFormat::I420.with_siting(ChromaSiting::Left)`
+    pub const fn with_siting(self, siting: ChromaSiting) -> Self {
+        Self { siting, ..self }
+    }
+
+    /// Size in bytes of a frame of this size
+    pub fn frame_size(&self, width: usize, height: usize) -> usize {
+        width * height + 2 * width.div_ceil(2) * height.div_ceil(2)
+    }
+}
+
+/// Component decoded with `read_raw_data()`
+pub(crate) struct Plane<'a> {
+    pub data: &'a [u8],
+    pub row_stride: usize,
+    /// Sampling factors relative to the largest ones
+    pub h_samp: usize,
+    pub v_samp: usize,
+}
+
+impl Plane<'_> {
+    /// Sample covering the pixel at full image resolution
+    fn at(&self, x: usize, y: usize, max_samp: (usize, usize)) -> u8 {
+        self.data[y * self.v_samp / max_samp.1 * self.row_stride + x * self.h_samp / max_samp.0]
+    }
+}
+
+/// Resamples Y, Cb, Cr planes of any subsampling to a 4:2:0 frame. Chroma is averaged over each 2×2 block of pixels.
+pub(crate) fn to_yuv420(format: YuvFormat, (width, height): (usize, usize), max_samp: (usize, usize), planes: &[Plane<'_>; 3]) -> io::Result<Vec<u8>> {
+    let mut frame = try_filled(0u8, format.frame_size(width, height))?;
+    let (luma, chroma) = frame.split_at_mut(width * height);
+
+    for (y, row) in luma.chunks_exact_mut(width).enumerate() {
+        for (x, out) in row.iter_mut().enumerate() {
+            *out = format.range.full_to_range(planes[0].at(x, y, max_samp), true);
+        }
+    }
+
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let chroma_len = chroma_width * chroma_height;
+    let mut u_plane = try_filled(0u8, chroma_len)?;
+    let mut v_plane = try_filled(0u8, chroma_len)?;
+    for cy in 0..chroma_height {
+        for cx in 0..chroma_width {
+            let mut sums = [0u32; 2];
+            let mut count = 0;
+            for y in 2 * cy..(2 * cy + 2).min(height) {
+                for x in 2 * cx..(2 * cx + 2).min(width) {
+                    sums[0] += u32::from(planes[1].at(x, y, max_samp));
+                    sums[1] += u32::from(planes[2].at(x, y, max_samp));
+                    count += 1;
+                }
+            }
+            let i = cy * chroma_width + cx;
+            [u_plane[i], v_plane[i]] = sums.map(|sum| ((sum + count / 2) / count) as u8);
+        }
+    }
+    format.siting.resample(&mut u_plane, chroma_width, true)?;
+    format.siting.resample(&mut v_plane, chroma_width, true)?;
+
+    for (i, (&u, &v)) in u_plane.iter().zip(&v_plane).enumerate() {
+        let (u_pos, v_pos) = match format.layout {
+            YuvLayout::I420 => (i, chroma_len + i),
+            YuvLayout::NV12 => (2 * i, 2 * i + 1),
+        };
+        chroma[u_pos] = format.range.full_to_range(u, false);
+        chroma[v_pos] = format.range.full_to_range(v, false);
+    }
+    wipe(&mut u_plane);
+    wipe(&mut v_plane);
+    Ok(frame)
+}
+
+/// Splits a 4:2:0 frame into Y, Cb, Cr planes with rows padded to `row_strides` by repeating the last pixel
+pub(crate) fn from_yuv420(frame: &[u8], format: YuvFormat, (width, height): (usize, usize), row_strides: [usize; 3]) -> io::Result<[Vec<u8>; 3]> {
+    if frame.len() < format.frame_size(width, height) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too small"));
+    }
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let chroma_len = chroma_width * chroma_height;
+    let (luma, chroma) = frame.split_at(width * height);
+    let plane = |stride: usize, plane_width: usize, plane_height: usize, sample: &dyn Fn(usize) -> u8| {
+        let mut out = try_filled(0u8, stride * plane_height)?;
+        for (y, row) in out.chunks_exact_mut(stride).enumerate() {
+            for (x, out) in row.iter_mut().enumerate() {
+                *out = sample(y * plane_width + x.min(plane_width - 1));
+            }
+        }
+        Ok::<_, io::Error>(out)
+    };
+    // offset and step of U and V samples
+    let ((u_start, u_step), (v_start, v_step)) = match format.layout {
+        YuvLayout::I420 => ((0, 1), (chroma_len, 1)),
+        YuvLayout::NV12 => ((0, 2), (1, 2)),
+    };
+    let mut u_plane = try_filled(0u8, chroma_len)?;
+    let mut v_plane = try_filled(0u8, chroma_len)?;
+    for (i, (u, v)) in u_plane.iter_mut().zip(&mut v_plane).enumerate() {
+        *u = format.range.range_to_full(chroma[u_start + i * u_step], false);
+        *v = format.range.range_to_full(chroma[v_start + i * v_step], false);
+    }
+    format.siting.resample(&mut u_plane, chroma_width, false)?;
+    format.siting.resample(&mut v_plane, chroma_width, false)?;
+
+    let planes = [
+        plane(row_strides[0], width, height, &|i| format.range.range_to_full(luma[i], true))?,
+        plane(row_strides[1], chroma_width, chroma_height, &|i| u_plane[i])?,
+        plane(row_strides[2], chroma_width, chroma_height, &|i| v_plane[i])?,
+    ];
+    wipe(&mut u_plane);
+    wipe(&mut v_plane);
+    Ok(planes)
+}
+
+#[test]
+fn limited_range() {
+    for sample in 0..=255 {
+        for luma in [true, false] {
+            let limited = YuvRange::Limited.full_to_range(sample, luma);
+            assert!((16..=if luma { 235 } else { 240 }).contains(&limited));
+            assert!(sample.abs_diff(YuvRange::Limited.range_to_full(limited, luma)) <= 1);
+            assert_eq!(sample, YuvRange::Full.range_to_full(YuvRange::Full.full_to_range(sample, luma), luma));
+        }
+    }
+    assert_eq!(0, YuvRange::Limited.range_to_full(0, true));
+    assert_eq!(255, YuvRange::Limited.range_to_full(250, false));
+    assert_eq!(128, YuvRange::Limited.full_to_range(128, false));
+}
+
+#[test]
+fn chroma_siting() {
+    let flat = [100u8; 12];
+    let mut plane = flat;
+    ChromaSiting::TopLeft.resample(&mut plane, 4, true).unwrap();
+    assert_eq!(flat, plane);
+
+    let ramp = [0u8, 40, 80, 120, 0, 40, 80, 120];
+    let mut plane = ramp;
+    ChromaSiting::Center.resample(&mut plane, 4, true).unwrap();
+    assert_eq!(ramp, plane);
+    ChromaSiting::Left.resample(&mut plane, 4, true).unwrap();
+    assert_eq!([0, 30, 70, 110, 0, 30, 70, 110], plane);
+    ChromaSiting::Left.resample(&mut plane, 4, false).unwrap();
+    assert!(plane[1..3].iter().zip(&ramp[1..3]).all(|(&a, &b)| a.abs_diff(b) <= 3));
+
+    let mut plane = [0u8, 0, 80, 80];
+    ChromaSiting::TopLeft.resample(&mut plane, 2, true).unwrap();
+    assert_eq!([0, 0, 60, 60], plane);
+}
@@ -0,0 +1,43 @@
+#![cfg(feature = "rust_alloc")]
+
+use mozjpeg::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+/// Counts allocations with the alignment of libjpeg's pools, which Rust code doesn't use
+struct CountingAllocator;
+
+static POOL_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() == 32 {
+            POOL_ALLOCATIONS.fetch_add(1, Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[test]
+fn libjpeg_allocates_from_global_allocator() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+
+    let before = POOL_ALLOCATIONS.load(Relaxed);
+    let coefficients = Decompress::new_mem(&data).unwrap().coefficients().unwrap();
+    let after_decode = POOL_ALLOCATIONS.load(Relaxed);
+    assert!(after_decode > before);
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_YCbCr);
+    cinfo.set_mem_dest();
+    let cinfo = cinfo.write_coefficients(&coefficients).unwrap();
+    let jpeg = cinfo.finish_compress().unwrap().data_to_vec().unwrap();
+    assert!(POOL_ALLOCATIONS.load(Relaxed) > after_decode);
+    assert!(!jpeg.is_empty());
+}
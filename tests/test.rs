@@ -10,11 +10,11 @@ pub fn decompress_jpeg(jpeg: &[u8]) -> Vec<Vec<u8>> {
     let mut decomp = decomp.raw().unwrap();
     {
         let mut bitmap_refs: Vec<_> = bitmaps.iter_mut().collect();
-        decomp.read_raw_data(&mut bitmap_refs);
-        decomp.finish_decompress();
+        decomp.read_raw_data(&mut bitmap_refs).unwrap();
+        decomp.finish_decompress().unwrap();
     }
 
-    return bitmaps;
+    bitmaps
 }
 
 #[test]
@@ -25,12 +25,12 @@ fn color_jpeg() {
         comp.set_scan_optimization_mode(mozjpeg::ScanMode::AllComponentsTogether);
         comp.set_size(size, size);
         comp.set_mem_dest();
-        comp.start_compress();
+        let mut comp = comp.start_compress().unwrap();
 
         let lines = vec![128; size * size * 3];
-        assert!(comp.write_scanlines(&lines[..]));
+        assert!(comp.write_scanlines(&lines[..]).unwrap());
 
-        comp.finish_compress();
+        let mut comp = comp.finish_compress().unwrap();
         let jpeg = comp.data_to_vec().unwrap();
 
         decompress_jpeg(&jpeg);
@@ -48,14 +48,14 @@ fn raw_jpeg() {
         comp.set_size(size, size);
 
         comp.set_mem_dest();
-        comp.start_compress();
+        let mut comp = comp.start_compress().unwrap();
 
-        let rounded_size = (size + 7) / 8 * 8;
+        let rounded_size = size.div_ceil(8) * 8;
         let t = vec![128; rounded_size * rounded_size];
-        let components = vec![&t[..], &t[..], &t[..]];
-        comp.write_raw_data(&components[..]);
+        let components = [&t[..], &t[..], &t[..]];
+        comp.write_raw_data(&components[..]).unwrap();
 
-        comp.finish_compress();
+        let mut comp = comp.finish_compress().unwrap();
         let jpeg = comp.data_to_vec().unwrap();
 
         decompress_jpeg(&jpeg);
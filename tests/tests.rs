@@ -42,7 +42,7 @@ fn encode_subsampled_jpeg((width, height, data): (usize, usize, Vec<[u8; 3]>)) -
 
     encoder.set_color_space(mozjpeg::ColorSpace::JCS_YCbCr);
     {
-        let mut comp = encoder.components_mut();
+        let comp = encoder.components_mut();
         comp[0].h_samp_factor = 1;
         comp[0].v_samp_factor = 1;
 
@@ -54,9 +54,9 @@ fn encode_subsampled_jpeg((width, height, data): (usize, usize, Vec<[u8; 3]>)) -
         comp[2].v_samp_factor = v;
     }
 
-    encoder.start_compress();
-    let _ = encoder.write_scanlines(bytemuck::cast_slice(&data));
-    encoder.finish_compress();
+    let mut encoder = encoder.start_compress().unwrap();
+    encoder.write_scanlines(bytemuck::cast_slice(&data)).unwrap();
+    let mut encoder = encoder.finish_compress().unwrap();
 
     encoder.data_to_vec().unwrap()
 }